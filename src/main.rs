@@ -1,824 +1,9192 @@
-//! c4.rs – A Minimal Self‑Hosting C Compiler in Rust
-//!
-//! This file is a reimplementation of the original C4 compiler (written in C)
-//! using Rust. It is organized into modules for lexing, parsing (with an advanced
-//! symbol table and support for control flow), and a virtual machine (VM) that
-//! executes the generated opcodes. The compiler supports a subset of C, including:
-//!   - Global and local variable declarations
-//!   - A single function definition (e.g., int main() { ... })
-//!   - Statements: expression statements, if–else, while, return
-//!   - Expressions: assignments and basic arithmetic operations
-//!
-//! Usage (via Cargo):
-//!     cargo run -- <file.c>
-//!
-//! The program reads a C source file, tokenizes it, parses it into opcodes, and
-//! then executes the opcodes using a stack-based virtual machine. Errors at any phase
-//! are reported with descriptive messages.
-
-use std::env;
-use std::fs;
-use std::process;
-
-//
-// Module: lexer
-//
-mod lexer {
-    //! The lexer module converts C source code into a sequence of tokens.
-    //!
-    //! It recognizes keywords (int, char, return, if, else, while), identifiers,
-//! numeric literals, operators, and punctuation.
-
-    #[derive(Debug, Clone, PartialEq)]
-    pub enum Token {
-        // Keywords
-        Int,
-        Char,
-        Return,
-        If,
-        Else,
-        While,
-        // Identifiers
-        Ident(String),
-        // Literals
-        Num(i64),
-        // Operators
-        Plus,      // +
-        Minus,     // -
-        Mul,       // *
-        Div,       // /
-        Assign,    // =
-        Eq,        // ==
-        Ne,        // !=
-        Lt,        // <
-        Gt,        // >
-        Le,        // <=
-        Ge,        // >=
-        // Punctuation
-        Semicolon,
-        Comma,
-        LParen,
-        RParen,
-        LBrace,
-        RBrace,
-        EOF,
-    }
-
-    pub type LexResult = Result<Vec<Token>, String>;
-
-    /// Tokenizes the input C source code into a vector of tokens.
-    pub fn tokenize(source: &str) -> LexResult {
-        let mut tokens = Vec::new();
-        let mut chars = source.chars().peekable();
-
-        while let Some(&ch) = chars.peek() {
-            match ch {
-                ' ' | '\t' | '\n' | '\r' => { chars.next(); },
-                '0'..='9' => {
-                    let mut num_str = String::new();
-                    while let Some(&digit) = chars.peek() {
-                        if digit.is_digit(10) {
-                            num_str.push(digit);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    let value = num_str.parse::<i64>().map_err(|e| e.to_string())?;
-                    tokens.push(Token::Num(value));
-                },
-                'a'..='z' | 'A'..='Z' | '_' => {
-                    let mut ident = String::new();
-                    while let Some(&c) = chars.peek() {
-                        if c.is_alphanumeric() || c == '_' {
-                            ident.push(c);
-                            chars.next();
-                        } else {
-                            break;
-                        }
-                    }
-                    // Check for keywords.
-                    match ident.as_str() {
-                        "int" => tokens.push(Token::Int),
-                        "char" => tokens.push(Token::Char),
-                        "return" => tokens.push(Token::Return),
-                        "if" => tokens.push(Token::If),
-                        "else" => tokens.push(Token::Else),
-                        "while" => tokens.push(Token::While),
-                        _ => tokens.push(Token::Ident(ident)),
-                    }
-                },
-                '+' => { tokens.push(Token::Plus); chars.next(); },
-                '-' => { tokens.push(Token::Minus); chars.next(); },
-                '*' => { tokens.push(Token::Mul); chars.next(); },
-                '/' => {
-                    chars.next();
-                    // Handle C++–style single-line comments.
-                    if let Some(&'/') = chars.peek() {
-                        while let Some(&c) = chars.peek() {
-                            if c == '\n' { break; }
-                            chars.next();
-                        }
-                    } else {
-                        tokens.push(Token::Div);
-                    }
-                },
-                '=' => {
-                    chars.next();
-                    if let Some(&'=') = chars.peek() {
-                        chars.next();
-                        tokens.push(Token::Eq);
-                    } else {
-                        tokens.push(Token::Assign);
-                    }
-                },
-                '!' => {
-                    chars.next();
-                    if let Some(&'=') = chars.peek() {
-                        chars.next();
-                        tokens.push(Token::Ne);
-                    } else {
-                        return Err("Unexpected '!'".to_string());
-                    }
-                },
-                '<' => {
-                    chars.next();
-                    if let Some(&'=') = chars.peek() {
-                        chars.next();
-                        tokens.push(Token::Le);
-                    } else {
-                        tokens.push(Token::Lt);
-                    }
-                },
-                '>' => {
-                    chars.next();
-                    if let Some(&'=') = chars.peek() {
-                        chars.next();
-                        tokens.push(Token::Ge);
-                    } else {
-                        tokens.push(Token::Gt);
-                    }
-                },
-                ';' => { tokens.push(Token::Semicolon); chars.next(); },
-                ',' => { tokens.push(Token::Comma); chars.next(); },
-                '(' => { tokens.push(Token::LParen); chars.next(); },
-                ')' => { tokens.push(Token::RParen); chars.next(); },
-                '{' => { tokens.push(Token::LBrace); chars.next(); },
-                '}' => { tokens.push(Token::RBrace); chars.next(); },
-                _ => return Err(format!("Unexpected character: {}", ch)),
-            }
-        }
-        tokens.push(Token::EOF);
-        Ok(tokens)
-    }
-}
-
-//
-// Module: parser
-//
-mod parser {
-    //! The parser module implements a recursive descent parser for a subset of C.
-    //!
-    //! It supports global and local variable declarations, a single function definition
-    //! (e.g., int main() { ... }), and statements including expression statements, if–else,
-    //! while, and return. It also builds an advanced symbol table for variables.
-    //!
-    //! The parser emits opcodes for a stack-based virtual machine.
-
-    use crate::lexer::Token;
-    use crate::vm::Opcode;
-    use std::collections::HashMap;
-
-    pub type ParseResult = Result<Vec<Opcode>, String>;
-
-    #[derive(Debug, Clone, PartialEq)]
-    pub enum SymbolClass {
-        Global,
-        Local,
-        Function,
-    }
-
-    #[derive(Debug, Clone)]
-    pub struct Symbol {
-        pub name: String,
-        pub class: SymbolClass,
-        pub offset: i64, // For local variables: offset in the stack frame.
-    }
-
-    pub struct Parser {
-        tokens: Vec<Token>,
-        pos: usize,
-        opcodes: Vec<Opcode>,
-        globals: HashMap<String, Symbol>,
-        locals: HashMap<String, Symbol>,
-        local_offset: i64,
-    }
-
-    impl Parser {
-        /// Creates a new parser instance.
-        pub fn new(tokens: Vec<Token>) -> Self {
-            Parser {
-                tokens,
-                pos: 0,
-                opcodes: Vec::new(),
-                globals: HashMap::new(),
-                locals: HashMap::new(),
-                local_offset: 0,
-            }
-        }
-
-        /// Returns a reference to the current token.
-        fn current(&self) -> &Token {
-            self.tokens.get(self.pos).unwrap_or(&Token::EOF)
-        }
-
-        /// Consumes the current token if it matches the given token.
-        fn eat(&mut self, token: &Token) -> bool {
-            if self.current() == token {
-                self.pos += 1;
-                true
-            } else {
-                false
-            }
-        }
-
-        /// Expects that the current token matches the given token.
-        fn expect(&mut self, token: &Token) -> Result<(), String> {
-            if self.eat(token) {
-                Ok(())
-            } else {
-                Err(format!("Expected {:?}, found {:?}", token, self.current()))
-            }
-        }
-
-        /// Parses the entire program.
-        ///
-        /// The program may contain global variable declarations and one function definition.
-        pub fn parse_program(&mut self) -> Result<(), String> {
-            while self.current() != &Token::EOF {
-                match self.current() {
-                    Token::Int => {
-                        self.pos += 1; // consume 'int'
-                        match self.current() {
-                            Token::Ident(ref name) => {
-                                let ident = name.clone();
-                                self.pos += 1; // consume identifier
-                                if self.eat(&Token::LParen) {
-                                    // Function definition.
-                                    if ident != "main" {
-                                        return Err("Only main function is supported".to_string());
-                                    }
-                                    self.expect(&Token::RParen)?;
-                                    self.expect(&Token::LBrace)?;
-                                    // Enter new local scope.
-                                    self.locals.clear();
-                                    self.local_offset = 0;
-                                    while self.current() != &Token::RBrace {
-                                        self.parse_stmt()?;
-                                    }
-                                    self.expect(&Token::RBrace)?;
-                                    // Function end.
-                                    self.opcodes.push(Opcode::Ret);
-                                } else {
-                                    // Global variable declaration.
-                                    self.globals.insert(ident.clone(), Symbol { name: ident, class: SymbolClass::Global, offset: 0 });
-                                    // Consume remaining declaration tokens until semicolon.
-                                    while self.current() != &Token::Semicolon && self.current() != &Token::EOF {
-                                        self.pos += 1;
-                                    }
-                                    self.expect(&Token::Semicolon)?;
-                                }
-                            },
-                            _ => return Err("Expected identifier after type".to_string()),
-                        }
-                    },
-                    _ => return Err(format!("Unexpected token at global scope: {:?}", self.current())),
-                }
-            }
-            Ok(())
-        }
-
-        /// Parses a statement.
-        fn parse_stmt(&mut self) -> Result<(), String> {
-            match self.current() {
-                Token::Return => {
-                    self.pos += 1; // consume 'return'
-                    self.parse_expr()?;
-                    self.expect(&Token::Semicolon)?;
-                    self.opcodes.push(Opcode::Ret);
-                    Ok(())
-                },
-                Token::If => self.parse_if(),
-                Token::While => self.parse_while(),
-                Token::LBrace => {
-                    self.pos += 1;
-                    while self.current() != &Token::RBrace {
-                        self.parse_stmt()?;
-                    }
-                    self.expect(&Token::RBrace)?;
-                    Ok(())
-                },
-                Token::Int => self.parse_local_decl(),
-                _ => {
-                    // Expression statement.
-                    self.parse_expr()?;
-                    self.expect(&Token::Semicolon)?;
-                    Ok(())
-                }
-            }
-        }
-
-        /// Parses an if–else statement: if ( expr ) stmt [ else stmt ]
-        fn parse_if(&mut self) -> Result<(), String> {
-            self.pos += 1; // consume 'if'
-            self.expect(&Token::LParen)?;
-            self.parse_expr()?;
-            self.expect(&Token::RParen)?;
-            let jz_index = self.opcodes.len();
-            self.opcodes.push(Opcode::Jz(0)); // placeholder for jump if false
-            self.parse_stmt()?;
-            if self.eat(&Token::Else) {
-                let jmp_index = self.opcodes.len();
-                self.opcodes.push(Opcode::Jmp(0)); // placeholder for jump over else
-                let else_addr = self.opcodes.len() as i64;
-                self.opcodes[jz_index] = Opcode::Jz(else_addr);
-                self.parse_stmt()?;
-                let end_addr = self.opcodes.len() as i64;
-                self.opcodes[jmp_index] = Opcode::Jmp(end_addr);
-            } else {
-                let addr = self.opcodes.len() as i64;
-                self.opcodes[jz_index] = Opcode::Jz(addr);
-            }
-            Ok(())
-        }
-
-        /// Parses a while statement: while ( expr ) stmt
-        fn parse_while(&mut self) -> Result<(), String> {
-            self.pos += 1; // consume 'while'
-            let loop_start = self.opcodes.len() as i64;
-            self.expect(&Token::LParen)?;
-            self.parse_expr()?;
-            self.expect(&Token::RParen)?;
-            let jz_index = self.opcodes.len();
-            self.opcodes.push(Opcode::Jz(0)); // placeholder for loop exit
-            self.parse_stmt()?;
-            self.opcodes.push(Opcode::Jmp(loop_start));
-            let loop_end = self.opcodes.len() as i64;
-            self.opcodes[jz_index] = Opcode::Jz(loop_end);
-            Ok(())
-        }
-
-        /// Parses a local variable declaration: int x, y;
-        fn parse_local_decl(&mut self) -> Result<(), String> {
-            self.pos += 1; // consume 'int'
-            loop {
-                match self.current() {
-                    Token::Ident(name) => {
-                        let var_name = name.clone();
-                        self.pos += 1;
-                        self.local_offset += 1;
-                        let offset = self.local_offset;
-                        self.locals.insert(var_name.clone(), Symbol { name: var_name, class: SymbolClass::Local, offset });
-
-                    },
-                    _ => return Err("Expected identifier in local declaration".to_string()),
-                }
-                if self.eat(&Token::Comma) {
-                    continue;
-                } else {
-                    break;
-                }
-            }
-            self.expect(&Token::Semicolon)?;
-            Ok(())
-        }
-
-        /// Parses an expression.
-        ///
-        /// Supports assignment (identifier = expr) and additive expressions.
-        fn parse_expr(&mut self) -> Result<(), String> {
-            self.parse_assignment()
-        }
-
-        fn parse_assignment(&mut self) -> Result<(), String> {
-            let start = self.pos;
-            if let Token::Ident(ref name) = self.current() {
-                let ident = name.clone();
-                self.pos += 1;
-                if self.eat(&Token::Assign) {
-                    self.parse_assignment()?;
-                    // Generate store opcode.
-                    if let Some(sym) = self.locals.get(&ident) {
-                        self.opcodes.push(Opcode::St(sym.offset));
-                        return Ok(());
-                    } else if let Some(sym) = self.globals.get(&ident) {
-                        self.opcodes.push(Opcode::St(sym.offset));
-                        return Ok(());
-                    } else {
-                        return Err(format!("Undefined variable: {}", ident));
-                    }
-                } else {
-                    self.pos = start;
-                }
-            }
-            self.parse_additive()
-        }
-
-        fn parse_additive(&mut self) -> Result<(), String> {
-            self.parse_term()?;
-            while let Token::Plus | Token::Minus = self.current() {
-                let op = self.current().clone();
-                self.pos += 1;
-                self.parse_term()?;
-                match op {
-                    Token::Plus => self.opcodes.push(Opcode::Add),
-                    Token::Minus => self.opcodes.push(Opcode::Sub),
-                    _ => {},
-                }
-            }
-            Ok(())
-        }
-
-        fn parse_term(&mut self) -> Result<(), String> {
-            self.parse_factor()?;
-            while let Token::Mul | Token::Div = self.current() {
-                let op = self.current().clone();
-                self.pos += 1;
-                self.parse_factor()?;
-                match op {
-                    Token::Mul => self.opcodes.push(Opcode::Mul),
-                    Token::Div => self.opcodes.push(Opcode::Div),
-                    _ => {},
-                }
-            }
-            Ok(())
-        }
-
-        fn parse_factor(&mut self) -> Result<(), String> {
-            match self.current() {
-                Token::Num(n) => {
-                    let value = *n;
-                    self.pos += 1;
-                    self.opcodes.push(Opcode::Imm(value));
-                    Ok(())
-                },
-                Token::Ident(name) => {
-                    let var_name = name.clone();
-                    self.pos += 1;
-                    if let Some(sym) = self.locals.get(&var_name) {
-                        self.opcodes.push(Opcode::Ld(sym.offset));
-                        Ok(())
-                    } else if let Some(sym) = self.globals.get(&var_name) {
-                        self.opcodes.push(Opcode::Ld(sym.offset));
-                        Ok(())
-                    } else {
-                        Err(format!("Undefined variable: {}", var_name))
-                    }
-                },
-                Token::LParen => {
-                    self.pos += 1;
-                    self.parse_expr()?;
-                    self.expect(&Token::RParen)?;
-                    Ok(())
-                },
-                _ => Err(format!("Unexpected token in factor: {:?}", self.current())),
-            }
-        }
-
-        /// Public API: parses tokens into opcodes.
-        pub fn parse(mut self) -> ParseResult {
-            self.parse_program()?;
-            Ok(self.opcodes)
-        }
-    }
-
-    /// Public function to parse tokens.
-    pub fn parse(tokens: Vec<Token>) -> ParseResult {
-        let parser = Parser::new(tokens);
-        parser.parse()
-    }
-}
-
-//
-// Module: vm
-//
-mod vm {
-    //! The virtual machine (VM) executes opcodes generated by the parser.
-    //!
-    //! This stack-based VM supports integer arithmetic, variable load/store,
-    //! and control flow instructions (jumps and conditional jumps).
-
-    #[derive(Debug, Clone, PartialEq)]
-    pub enum Opcode {
-        Imm(i64),   // Push immediate value onto the stack.
-        Ld(i64),    // Load variable from local offset.
-        St(i64),    // Store top of stack into local variable at offset.
-        Add,        // Add top two values.
-        Sub,        // Subtract top two values.
-        Mul,        // Multiply top two values.
-        Div,        // Divide top two values.
-        Jmp(i64),   // Unconditional jump to opcode index.
-        Jz(i64),    // Jump if top of stack is zero.
-        Ret,        // Return from function.
-    }
-
-    /// Executes a sequence of opcodes and returns the final result.
-    pub fn execute(opcodes: Vec<Opcode>) -> Result<i64, String> {
-        let mut stack: Vec<i64> = Vec::new();
-        stack.resize(32, 0); // ✅ Reserve space for local variables
-    
-        let mut pc: i64 = 0;
-        while (pc as usize) < opcodes.len() {
-            match opcodes[pc as usize].clone() {
-                Opcode::Imm(n) => {
-                    stack.push(n);
-                    pc += 1;
-                },
-                Opcode::Ld(offset) => {
-                    if (offset as usize) < stack.len() {
-                        let val = stack[offset as usize];
-                        stack.push(val);
-                        pc += 1;
-                    } else {
-                        return Err("Invalid local offset in Ld".into());
-                    }
-                },
-                Opcode::St(offset) => {
-                    if let Some(val) = stack.pop() {
-                        if (offset as usize) < stack.len() {
-                            stack[offset as usize] = val;
-                            pc += 1;
-                        } else {
-                            return Err("Invalid local offset in St".into());
-                        }
-                    } else {
-                        return Err("Stack underflow in St".into());
-                    }
-                },
-                Opcode::Add => {
-                    if stack.len() < 2 {
-                        return Err("Stack underflow in Add".into());
-                    }
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    stack.push(a + b);
-                    pc += 1;
-                },
-                Opcode::Sub => {
-                    if stack.len() < 2 {
-                        return Err("Stack underflow in Sub".into());
-                    }
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    stack.push(a - b);
-                    pc += 1;
-                },
-                Opcode::Mul => {
-                    if stack.len() < 2 {
-                        return Err("Stack underflow in Mul".into());
-                    }
-                    let b = stack.pop().unwrap();
-                    let a = stack.pop().unwrap();
-                    stack.push(a * b);
-                    pc += 1;
-                },
-                Opcode::Div => {
-                    if stack.len() < 2 {
-                        return Err("Stack underflow in Div".into());
-                    }
-                    let b = stack.pop().unwrap();
-                    if b == 0 {
-                        return Err("Division by zero".into());
-                    }
-                    let a = stack.pop().unwrap();
-                    stack.push(a / b);
-                    pc += 1;
-                },
-                Opcode::Jmp(addr) => {
-                    pc = addr;
-                },
-                Opcode::Jz(addr) => {
-                    if let Some(&top) = stack.last() {
-                        if top == 0 {
-                            pc = addr;
-                        } else {
-                            pc += 1;
-                        }
-                    } else {
-                        return Err("Stack underflow in Jz".into());
-                    }
-                },
-                Opcode::Ret => {
-                    if let Some(result) = stack.pop() {
-                        return Ok(result);
-                    } else {
-                        return Err("Stack underflow in Ret".into());
-                    }
-                },
-            }
-        }
-        Err("No Ret opcode encountered".into())
-    }
-}
-
-//
-// Main entry point
-//
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: c4 <file.c>");
-        process::exit(1);
-    }
-    let filename = &args[1];
-    let source = fs::read_to_string(filename).unwrap_or_else(|err| {
-        eprintln!("Error reading {}: {}", filename, err);
-        process::exit(1);
-    });
-
-    // Lexical analysis.
-    let tokens = match lexer::tokenize(&source) {
-        Ok(t) => t,
-        Err(e) => {
-            eprintln!("Lexing error: {}", e);
-            process::exit(1);
-        }
-    };
-
-    // Parsing.
-    let opcodes = match parser::parse(tokens) {
-        Ok(o) => o,
-        Err(e) => {
-            eprintln!("Parsing error: {}", e);
-            process::exit(1);
-        }
-    };
-
-    // Execution.
-    match vm::execute(opcodes) {
-        Ok(result) => {
-            println!("Program executed successfully. Result: {}", result);
-        },
-        Err(e) => {
-            eprintln!("Runtime error: {}", e);
-            process::exit(1);
-        }
-    }
-}
-
-
-#[cfg(test)]
-mod additional_tests {
-    use super::*;
-    use crate::lexer::tokenize;
-    use crate::parser::parse;
-    use crate::vm::execute;
-
-    /// Test a nested if–else construct.
-    #[test]
-    fn test_nested_if_else() {
-        let source = r#"
-        int main() {
-            if (1) {
-                if (0) {
-                    return 1;
-                } else {
-                    return 2;
-                }
-            } else {
-                return 3;
-            }
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes).expect("Execution failed");
-        // In this case, the outer condition is true (1), so we go into the inner if.
-        // The inner condition is false (0), so the else branch returns 2.
-        assert_eq!(result, 2);
-    }
-
-    /// Test a nested while loop.
-    #[test]
-    fn test_nested_while_loops() {
-        // This test simulates a nested loop that decrements a variable.
-        // Note: Our minimal compiler only supports basic arithmetic and control flow.
-        // The following code initializes i to 3, then uses a nested loop to decrement it.
-        let source = r#"
-        int main() {
-            int i;
-            i = 3;
-            while (i) {
-                while (i - 1) {
-                    i = i - 1;
-                }
-                i = 0;
-            }
-            return i;
-        }
-        "#;
-        // Depending on how the compiler handles variable declarations and expressions,
-        // the expected result should be 0.
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes).expect("Execution failed");
-        assert_eq!(result, 0);
-    }
-
-    /// Test that an undefined variable causes an error.
-    #[test]
-    fn test_undefined_variable_error() {
-        let source = r#"
-        int main() {
-            return x;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let parse_result = parse(tokens);
-        assert!(parse_result.is_err(), "Parsing should fail due to undefined variable");
-    }
-
-    /// Test that division by zero is handled as an error.
-    #[test]
-    fn test_division_by_zero_error() {
-        let source = r#"
-        int main() {
-            return 10 / 0;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes);
-        assert!(result.is_err(), "Execution should fail with division by zero");
-    }
-
-    /// Test that invalid syntax produces a parse error.
-    #[test]
-    fn test_invalid_syntax_error() {
-        let source = r#"
-        int main( { return 0; }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let parse_result = parse(tokens);
-        assert!(parse_result.is_err(), "Parsing should fail due to invalid syntax");
-    }
-
-    /// A simple self-hosting test that uses a minimal C source resembling the compiler's own code.
-    #[test]
-    fn test_self_hosting() {
-        // This is a minimal C program that our compiler should handle.
-        let source = r#"
-        int main() {
-            return 42;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes).expect("Execution failed");
-        assert_eq!(result, 42);
-    }
-
-    /// Test long arithmetic expression with precedence
-    #[test]
-    fn test_complex_expression() {
-        let source = r#"
-        int main() {
-            return 2 + 3 * 4 - 6 / 2;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes).expect("Execution failed");
-        assert_eq!(result, 2 + 3 * 4 - 6 / 2);
-    }
-
-    /// Test multiple variable declarations and usage
-    #[test]
-    fn test_multiple_variables() {
-        let source = r#"
-        int main() {
-            int a, b;
-            a = 5;
-            b = 10;
-            return a + b;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let opcodes = parse(tokens).expect("Failed to parse");
-        let result = execute(opcodes).expect("Execution failed");
-        assert_eq!(result, 15);
-    }
-
-    /// Test unmatched parentheses to simulate a syntax error
-    #[test]
-    fn test_unmatched_parentheses() {
-        let source = r#"
-        int main() {
-            return (5 + 2;
-        }
-        "#;
-        let tokens = tokenize(source).expect("Failed to tokenize");
-        let parse_result = parse(tokens);
-        assert!(parse_result.is_err(), "Expected error due to unmatched parentheses");
-    }
-}
+//! c4.rs – A Minimal Self‑Hosting C Compiler in Rust
+//!
+//! This file is a reimplementation of the original C4 compiler (written in C)
+//! using Rust. It is organized into modules for lexing, parsing (with an advanced
+//! symbol table and support for control flow), a syntax tree for tools, and a
+//! virtual machine (VM) that executes the generated opcodes. The compiler
+//! supports a subset of C, including:
+//!   - Global and local variable declarations, including static locals
+//!   - Function definitions with parameters (e.g., int add(int a, int b) { ... }) and calls,
+//!     including recursive ones; `--call` picks a parameterless one to run
+//!   - Statements: expression statements, if–else, while, do–while, for, break, continue, return
+//!   - Expressions: assignments, arithmetic and comparisons on int, char and double values
+//!   - Arrays, pointers, structs with `.` and `->`, sizeof, and heap memory via the malloc/free builtins
+//!   - String literals, stored in a read-only data segment
+//!   - A preprocessor for object-like and function-like #define macros, #undef,
+//!     and #ifdef/#ifndef/#else/#endif
+//!
+//! Usage (via Cargo):
+//!     cargo run -- <file.c>
+//!
+//! The program reads a C source file, tokenizes it, parses it into opcodes, and
+//! then executes the opcodes using a stack-based virtual machine. Errors at any phase
+//! are reported with descriptive messages.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// Module: preprocessor
+//
+mod preprocessor {
+    //! A minimal preprocessor that runs over the source text before lexing.
+    //!
+    //! It supports object-like macros (`#define NAME text`), function-like
+    //! macros (`#define MAX(a, b) ((a) > (b) ? (a) : (b))`, whose calls must fit
+    //! on one line), `#undef NAME`, and conditional compilation with `#ifdef`,
+    //! `#ifndef`, `#else` and `#endif`, which may nest. A directive continues
+    //! onto the next line when it ends with a backslash. Directive lines and
+    //! excluded lines are left empty rather than removed, so later errors still
+    //! report the original line numbers.
+
+    use std::collections::BTreeMap;
+
+    /// A macro's parameters, if it is function-like, and its replacement text.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Macro {
+        pub params: Option<Vec<String>>, // `None` for an object-like macro.
+        pub body: String,
+    }
+
+    /// Macro names and their definitions, ordered by name.
+    pub type Macros = BTreeMap<String, Macro>;
+
+    /// An `#ifdef` or `#ifndef` whose `#endif` has not been reached yet.
+    struct Conditional {
+        line: usize,        // Line of the opening directive, for error messages.
+        outer_active: bool, // Whether the enclosing region is included.
+        taken: bool,        // Whether the current branch is included.
+        seen_else: bool,
+    }
+
+    /// Preprocesses `source` with the `(name, value)` macros in `defines`
+    /// defined beforehand, as `-D` does.
+    pub fn preprocess(source: &str, defines: &[(String, String)]) -> Result<String, String> {
+        run(source, defines).map(|(text, _)| text)
+    }
+
+    /// Returns the macros in effect at the end of `source`, sorted by name:
+    /// those from `defines` and the source's `#define`s, less any `#undef`s.
+    /// A function-like macro is listed with its parameters, as `MAX(a, b)`.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn macros(source: &str, defines: &[(String, String)]) -> Result<Vec<(String, String)>, String> {
+        let (_, macros) = run(source, defines)?;
+        let list = macros.into_iter().map(|(name, m)| match m.params {
+            Some(params) => (format!("{}({})", name, params.join(", ")), m.body),
+            None => (name, m.body),
+        });
+        Ok(list.collect())
+    }
+
+    /// Preprocesses `source`, returning the output text and the macros
+    /// defined at the end of it.
+    fn run(source: &str, defines: &[(String, String)]) -> Result<(String, Macros), String> {
+        let mut macros: Macros = defines
+            .iter()
+            .map(|(name, value)| (name.clone(), Macro { params: None, body: value.clone() }))
+            .collect();
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        let mut out = String::with_capacity(source.len());
+        let lines: Vec<&str> = source.split('\n').collect();
+        let mut continued = false; // Whether the previous line ended with a backslash.
+        let mut index = 0;
+        while index < lines.len() {
+            let line = lines[index];
+            let active = conditionals.last().is_none_or(|c| c.outer_active && c.taken);
+            let trimmed = line.trim_start();
+            let directive = trimmed.strip_prefix('#').filter(|_| !continued);
+            continued = directive.is_none() && spliced(line).is_some();
+            if let Some(directive) = directive {
+                let at = format!("{}:{}", index + 1, line.len() - trimmed.len() + 1);
+                // Line splicing comes first (C translation phase 2), so a
+                // directive takes in the lines its backslashes continue onto;
+                // they are left empty like the directive's own line.
+                let mut directive = directive.to_string();
+                while let (Some(head), Some(next)) = (spliced(&directive), lines.get(index + 1)) {
+                    directive = format!("{}{}", head, next);
+                    index += 1;
+                    out.push('\n');
+                }
+                let (keyword, rest) = split_name(directive.trim_start());
+                let rest = rest.trim();
+                match keyword {
+                    "ifdef" | "ifndef" => {
+                        let name = macro_name(rest, keyword, &at)?;
+                        let taken = macros.contains_key(name) == (keyword == "ifdef");
+                        conditionals.push(Conditional { line: index + 1, outer_active: active, taken, seen_else: false });
+                    },
+                    "else" => match conditionals.last_mut() {
+                        Some(c) if !c.seen_else => {
+                            c.taken = !c.taken;
+                            c.seen_else = true;
+                        },
+                        Some(_) => return Err(format!("#else after #else at {}", at)),
+                        None => return Err(format!("#else without #ifdef at {}", at)),
+                    },
+                    "endif" => {
+                        if conditionals.pop().is_none() {
+                            return Err(format!("#endif without #ifdef at {}", at));
+                        }
+                    },
+                    // Other directives in excluded regions are skipped unchecked.
+                    _ if !active => {},
+                    "define" => {
+                        let (name, body) = split_name(rest);
+                        macro_name(name, keyword, &at)?;
+                        // Only a `(` right after the name starts a parameter list.
+                        let (params, body) = match body.strip_prefix('(') {
+                            Some(list) => {
+                                let (params, body) = parse_params(list)
+                                    .ok_or_else(|| format!("Invalid parameter list for macro '{}' at {}", name, at))?;
+                                (Some(params), body)
+                            },
+                            None => (None, body),
+                        };
+                        macros.insert(name.to_string(), Macro { params, body: body.trim().to_string() });
+                    },
+                    "undef" => {
+                        macros.remove(macro_name(rest, keyword, &at)?);
+                    },
+                    "" => {}, // A lone `#` is the null directive.
+                    _ => return Err(format!("Unknown directive #{} at {}", keyword, at)),
+                }
+            } else if active {
+                let expanded = expand(line, &macros, &mut Vec::new()).map_err(|e| format!("{} on line {}", e, index + 1))?;
+                out.push_str(&expanded);
+            }
+            out.push('\n');
+            index += 1;
+        }
+        out.pop(); // There is one more line than there are newlines.
+        match conditionals.last() {
+            Some(c) => Err(format!("Unterminated #ifdef or #ifndef from line {}", c.line)),
+            None => Ok((out, macros)),
+        }
+    }
+
+    /// Returns `line` without its trailing backslash if it continues onto the next line.
+    fn spliced(line: &str) -> Option<&str> {
+        line.trim_end_matches('\r').strip_suffix('\\')
+    }
+
+    /// Splits a leading identifier off `text`.
+    fn split_name(text: &str) -> (&str, &str) {
+        let len = text.find(|c: char| !c.is_ascii_alphanumeric() && c != '_').unwrap_or(text.len());
+        text.split_at(len)
+    }
+
+    /// Checks that `text` is exactly one macro name and returns it.
+    fn macro_name<'t>(text: &'t str, directive: &str, at: &str) -> Result<&'t str, String> {
+        let (name, rest) = split_name(text);
+        let valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+        if valid && (directive == "define" || rest.trim().is_empty()) {
+            Ok(name)
+        } else {
+            Err(format!("#{} expects a macro name at {}", directive, at))
+        }
+    }
+
+    /// Parses a macro parameter list after its `(`: names separated by commas
+    /// and a `)`. Returns the names and the text after the list, or `None` if
+    /// the list is malformed or repeats a name.
+    fn parse_params(text: &str) -> Option<(Vec<String>, &str)> {
+        let (list, body) = text.split_once(')')?;
+        if list.trim().is_empty() {
+            return Some((Vec::new(), body));
+        }
+        let mut params: Vec<String> = Vec::new();
+        for param in list.split(',') {
+            let (name, rest) = split_name(param.trim());
+            let valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit()) && rest.is_empty();
+            if !valid || params.iter().any(|p| p == name) {
+                return None;
+            }
+            params.push(name.to_string());
+        }
+        Some((params, body))
+    }
+
+    /// Returns the length of the string or character literal at the start of
+    /// `text`, including both quotes, or the rest of `text` if it is unterminated.
+    fn literal_len(text: &str) -> usize {
+        let quote = text.chars().next().unwrap_or('"');
+        let mut chars = text.char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            if c == '\\' {
+                chars.next();
+            } else if c == quote {
+                return i + 1;
+            }
+        }
+        text.len()
+    }
+
+    /// Splits the arguments of a function-like macro call off `text`, which
+    /// follows the macro name. Returns `Ok(None)` if no `(` follows, as the
+    /// name is then not a call. Commas inside nested parentheses or literals
+    /// do not separate arguments.
+    fn split_args(text: &str) -> Result<Option<(Vec<&str>, &str)>, String> {
+        let after_space = text.trim_start();
+        let Some(inner) = after_space.strip_prefix('(') else {
+            return Ok(None);
+        };
+        let mut args = Vec::new();
+        let (mut depth, mut start, mut i) = (0, 0, 0);
+        while let Some(c) = inner[i..].chars().next() {
+            match c {
+                '"' | '\'' => {
+                    i += literal_len(&inner[i..]);
+                    continue;
+                },
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    args.push(inner[start..i].trim());
+                    return Ok(Some((args, &inner[i + 1..])));
+                },
+                ')' => depth -= 1,
+                ',' if depth == 0 => {
+                    args.push(inner[start..i].trim());
+                    start = i + 1;
+                },
+                _ => {},
+            }
+            i += c.len_utf8();
+        }
+        Err("Unterminated argument list".to_string())
+    }
+
+    /// Replaces each parameter name in `body` with the matching argument.
+    fn substitute(body: &str, params: &[String], args: &[String]) -> String {
+        let mut out = String::with_capacity(body.len());
+        let mut rest = body;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '"' | '\'' => literal_len(rest),
+                _ if c.is_ascii_alphanumeric() || c == '_' => split_name(rest).0.len(),
+                _ => c.len_utf8(),
+            };
+            let (piece, after) = rest.split_at(len);
+            match params.iter().position(|p| p == piece) {
+                Some(index) => out.push_str(&args[index]),
+                None => out.push_str(piece),
+            }
+            rest = after;
+        }
+        out
+    }
+
+    /// Replaces macro names in `line` with their replacement text, itself
+    /// expanded. A function-like macro is replaced only where its name is
+    /// followed by an argument list; its arguments are expanded before they are
+    /// substituted. A macro is not expanded again inside its own replacement
+    /// (`expanding` holds those in progress), which bounds recursion, and
+    /// string and character literals and `//` comments are copied unchanged.
+    fn expand(line: &str, macros: &Macros, expanding: &mut Vec<String>) -> Result<String, String> {
+        let mut out = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '"' | '\'' => {
+                    let len = literal_len(rest);
+                    out.push_str(&rest[..len]);
+                    rest = &rest[len..];
+                },
+                '/' if rest.starts_with("//") => {
+                    out.push_str(rest);
+                    break;
+                },
+                _ if c.is_ascii_alphanumeric() || c == '_' => {
+                    let (word, after) = split_name(rest);
+                    rest = after;
+                    let definition = match macros.get(word) {
+                        Some(m) if !c.is_ascii_digit() && !expanding.iter().any(|name| name == word) => m,
+                        _ => {
+                            out.push_str(word);
+                            continue;
+                        },
+                    };
+                    let replacement = match &definition.params {
+                        None => definition.body.clone(),
+                        Some(params) => {
+                            let (args, after) = match split_args(rest) {
+                                Ok(Some(call)) => call,
+                                Ok(None) => {
+                                    out.push_str(word);
+                                    continue;
+                                },
+                                Err(e) => return Err(format!("{} for macro '{}'", e, word)),
+                            };
+                            rest = after;
+                            let args = if params.is_empty() && args == [""] { Vec::new() } else { args };
+                            if args.len() != params.len() {
+                                return Err(format!(
+                                    "Macro '{}' expects {} argument(s), found {}",
+                                    word,
+                                    params.len(),
+                                    args.len()
+                                ));
+                            }
+                            let args = args
+                                .into_iter()
+                                .map(|arg| expand(arg, macros, expanding))
+                                .collect::<Result<Vec<_>, _>>()?;
+                            substitute(&definition.body, params, &args)
+                        },
+                    };
+                    expanding.push(word.to_string());
+                    let expanded = expand(&replacement, macros, expanding);
+                    expanding.pop();
+                    out.push_str(&expanded?);
+                },
+                _ => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                },
+            }
+        }
+        Ok(out)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn defines(names: &[&str]) -> Vec<(String, String)> {
+            names.iter().map(|name| (name.to_string(), String::new())).collect()
+        }
+
+        #[test]
+        fn test_define_and_undef() {
+            let src = "#define N 4\n#define M (N + 1)\nint a[M]; // N\n#undef N\nreturn N;";
+            assert_eq!(preprocess(src, &[]).unwrap(), "\n\nint a[(4 + 1)]; // N\n\nreturn N;");
+            assert_eq!(preprocess("#define S \"N\" 'N'\nS N", &defines(&["N"])).unwrap(), "\n\"N\" 'N' ");
+            // A self-referential macro expands once.
+            assert_eq!(preprocess("#define X X + 1\nX", &[]).unwrap(), "\nX + 1");
+            assert_eq!(preprocess("#define N 2\nN1 1N N_", &[]).unwrap(), "\nN1 1N N_");
+        }
+
+        #[test]
+        fn test_multi_line_define() {
+            let src = "#define X 1 + \\\n2\nint a = X;\n#define MAX(a, b) \\\r\n  ((a) > (b) ? \\\n  (a) : (b))\nMAX(3, 4)";
+            assert_eq!(preprocess(src, &[]).unwrap(), "\n\nint a = 1 + 2;\n\n\n\n((3) > (4) ?   (3) : (4))");
+            // A line continued onto a `#` line makes it ordinary text, not a directive.
+            assert_eq!(preprocess("#define N 1\nx = \\\n#define N 2\nN", &[]).unwrap(), "\nx = \\\n#define 1 2\n1");
+        }
+
+        #[test]
+        fn test_conditional_blocks() {
+            let src = "#ifdef DEBUG\ndebug\n#ifndef QUIET\nloud\n#else\nquiet\n#endif\n#else\nrelease\n#endif\nend";
+            assert_eq!(preprocess(src, &[]).unwrap(), "\n\n\n\n\n\n\n\nrelease\n\nend");
+            assert_eq!(preprocess(src, &defines(&["DEBUG"])).unwrap(), "\ndebug\n\nloud\n\n\n\n\n\n\nend");
+            assert_eq!(preprocess(src, &defines(&["DEBUG", "QUIET"])).unwrap(), "\ndebug\n\n\n\nquiet\n\n\n\n\nend");
+            // Directives inside an excluded block are not applied.
+            assert_eq!(preprocess("#ifdef A\n#define B\n#bogus\n#endif\n#ifdef B\nb\n#endif", &[]).unwrap(), "\n\n\n\n\n\n");
+        }
+
+        #[test]
+        fn test_macros_in_effect() {
+            let src = "#define SIZE 8\n#define GONE\n#ifdef DEBUG\n#define TRACE 1\n#endif\n#undef GONE";
+            let pair = |name: &str, value: &str| (name.to_string(), value.to_string());
+            let cli = vec![pair("DEBUG", "1")];
+            assert_eq!(macros(src, &cli), Ok(vec![pair("DEBUG", "1"), pair("SIZE", "8"), pair("TRACE", "1")]));
+            assert_eq!(macros(src, &[]), Ok(vec![pair("SIZE", "8")]));
+            // A source definition replaces a command-line one.
+            assert_eq!(macros("#define DEBUG 2", &cli), Ok(vec![pair("DEBUG", "2")]));
+        }
+
+        #[test]
+        fn test_function_like_macros() {
+            let src = "#define MAX(a,b) ((a)>(b)?(a):(b))\n#define SQ(x) ((x) * (x))\nMAX(3, 5) SQ(MAX(1, f(2, 3))) MAX";
+            assert_eq!(
+                preprocess(src, &[]).unwrap(),
+                "\n\n((3)>(5)?(3):(5)) ((((1)>(f(2, 3))?(1):(f(2, 3)))) * (((1)>(f(2, 3))?(1):(f(2, 3))))) MAX"
+            );
+            // Literals in arguments and bodies are kept whole; `(` must follow the name directly in #define.
+            assert_eq!(preprocess("#define S(x) \"x\" x\nS(\"a,)\")", &[]).unwrap(), "\n\"x\" \"a,)\"");
+            assert_eq!(preprocess("#define P (x) x\nP", &[]).unwrap(), "\n(x) x");
+            assert_eq!(preprocess("#define NONE() 7\nNONE() + NONE( )", &[]).unwrap(), "\n7 + 7");
+            // Mutual recursion stops once a macro would expand inside itself.
+            assert_eq!(preprocess("#define F(x) G(x + 1)\n#define G(x) F(x * 2)\nF(0)", &[]).unwrap(), "\n\nF(0 + 1 * 2)");
+            assert_eq!(
+                macros("#define MAX(a, b) a\n#define N 1", &[]),
+                Ok(vec![("MAX(a, b)".to_string(), "a".to_string()), ("N".to_string(), "1".to_string())])
+            );
+            assert_eq!(preprocess("#define F(a, b) a\n\nF(1)", &[]).unwrap_err(), "Macro 'F' expects 2 argument(s), found 1 on line 3");
+            assert_eq!(preprocess("#define F(a) a\nF(1, (2)", &[]).unwrap_err(), "Unterminated argument list for macro 'F' on line 2");
+        }
+
+        #[test]
+        fn test_directive_errors() {
+            assert_eq!(preprocess("int a;\n  #endif", &[]).unwrap_err(), "#endif without #ifdef at 2:3");
+            assert_eq!(preprocess("#else", &[]).unwrap_err(), "#else without #ifdef at 1:1");
+            assert_eq!(preprocess("#ifdef A\n#else\n#else\n#endif", &[]).unwrap_err(), "#else after #else at 3:1");
+            assert_eq!(preprocess("\n#ifndef A\nint a;", &[]).unwrap_err(), "Unterminated #ifdef or #ifndef from line 2");
+            assert_eq!(preprocess("#ifdef 1A\n#endif", &[]).unwrap_err(), "#ifdef expects a macro name at 1:1");
+            assert_eq!(preprocess("#define F(x, x) x", &[]).unwrap_err(), "Invalid parameter list for macro 'F' at 1:1");
+            assert_eq!(preprocess("#define F(x x", &[]).unwrap_err(), "Invalid parameter list for macro 'F' at 1:1");
+            assert_eq!(preprocess("#include <stdio.h>", &[]).unwrap_err(), "Unknown directive #include at 1:1");
+        }
+    }
+}
+
+//
+// Module: lexer
+//
+mod lexer {
+    //! The lexer module converts C source code into a sequence of tokens.
+    //!
+    //! It recognizes the keywords listed in `KEYWORDS` (types such as int,
+    //! char and double, qualifiers such as const and static, and the control
+    //! flow keywords from if to switch), identifiers, integer and floating
+    //! literals, character literals (lexed as their integer code), string
+    //! literals, and the operators and punctuation in `OPERATORS` and
+    //! `PUNCTUATION`. Backslash-newline pairs are spliced out first and `//`
+    //! comments are skipped. Every token carries its start and end positions.
+    //!
+    //! As in C, identifiers use ASCII letters, digits and `_` only. Non-ASCII
+    //! text is allowed in string literals, which hold its UTF-8 bytes, and in
+    //! comments, and rejected elsewhere. A character literal holds a single
+    //! byte, so it must be ASCII.
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[allow(clippy::upper_case_acronyms)]
+    pub enum Token {
+        // Keywords
+        Int,
+        Char,
+        Double,
+        Void,
+        Return,
+        If,
+        Else,
+        While,
+        For,
+        Do,
+        Switch,
+        Case,
+        Default,
+        Break,
+        Continue,
+        Signed,
+        Unsigned,
+        Const,
+        Register,
+        Static,
+        Sizeof,
+        Struct,
+        // Identifiers
+        Ident(String),
+        // Literals
+        Num(i64),
+        Float(f64),
+        Str(String),
+        // Operators
+        Plus,      // +
+        Minus,     // -
+        Mul,       // *
+        Div,       // /
+        Mod,       // %
+        Tilde,     // ~
+        Bang,      // !
+        Assign,    // =
+        PlusAssign,  // +=
+        MinusAssign, // -=
+        MulAssign,   // *=
+        DivAssign,   // /=
+        ModAssign,   // %=
+        Eq,        // ==
+        Ne,        // !=
+        Lt,        // <
+        Gt,        // >
+        Le,        // <=
+        Ge,        // >=
+        Question,  // ?
+        Colon,     // :
+        Amp,       // &
+        And,       // &&
+        Or,        // ||
+        Arrow,     // ->
+        Dot,       // .
+        // Punctuation
+        Semicolon,
+        Comma,
+        LParen,
+        RParen,
+        LBrace,
+        RBrace,
+        LBracket,
+        RBracket,
+        EOF,
+    }
+
+    /// Keywords and the tokens they lex to.
+    pub const KEYWORDS: &[(&str, Token)] = &[
+        ("int", Token::Int),
+        ("char", Token::Char),
+        ("double", Token::Double),
+        ("void", Token::Void),
+        ("return", Token::Return),
+        ("if", Token::If),
+        ("else", Token::Else),
+        ("while", Token::While),
+        ("for", Token::For),
+        ("do", Token::Do),
+        ("switch", Token::Switch),
+        ("case", Token::Case),
+        ("default", Token::Default),
+        ("break", Token::Break),
+        ("continue", Token::Continue),
+        ("signed", Token::Signed),
+        ("unsigned", Token::Unsigned),
+        ("const", Token::Const),
+        ("register", Token::Register),
+        ("static", Token::Static),
+        ("sizeof", Token::Sizeof),
+        ("struct", Token::Struct),
+    ];
+
+    /// Operator spellings; the lexer always takes the longest match.
+    pub const OPERATORS: &[(&str, Token)] = &[
+        ("+", Token::Plus),
+        ("-", Token::Minus),
+        ("*", Token::Mul),
+        ("/", Token::Div),
+        ("%", Token::Mod),
+        ("~", Token::Tilde),
+        ("!", Token::Bang),
+        ("=", Token::Assign),
+        ("+=", Token::PlusAssign),
+        ("-=", Token::MinusAssign),
+        ("*=", Token::MulAssign),
+        ("/=", Token::DivAssign),
+        ("%=", Token::ModAssign),
+        ("==", Token::Eq),
+        ("!=", Token::Ne),
+        ("<", Token::Lt),
+        (">", Token::Gt),
+        ("<=", Token::Le),
+        (">=", Token::Ge),
+        ("?", Token::Question),
+        (":", Token::Colon),
+        ("&", Token::Amp),
+        ("&&", Token::And),
+        ("||", Token::Or),
+        ("->", Token::Arrow),
+        (".", Token::Dot),
+    ];
+
+    /// Punctuation spellings.
+    pub const PUNCTUATION: &[(&str, Token)] = &[
+        (";", Token::Semicolon),
+        (",", Token::Comma),
+        ("(", Token::LParen),
+        (")", Token::RParen),
+        ("{", Token::LBrace),
+        ("}", Token::RBrace),
+        ("[", Token::LBracket),
+        ("]", Token::RBracket),
+    ];
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub type LexResult = Result<Vec<Token>, String>;
+
+    /// A 1-based source position (line and column) of a token.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub struct Span {
+        pub line: usize,
+        pub col: usize,
+    }
+
+    impl std::fmt::Display for Span {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "{}:{}", self.line, self.col)
+        }
+    }
+
+    /// A value paired with the source position it came from.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Spanned<T> {
+        pub value: T,
+        pub span: Span,
+        pub end: Span, // Just past the value's last character.
+    }
+
+    pub type SpannedLexResult = Result<Vec<Spanned<Token>>, String>;
+
+    /// Positioned tokens together with the lint warnings raised while lexing them.
+    pub type LintedLexResult = Result<(Vec<Spanned<Token>>, Vec<Spanned<String>>), String>;
+
+    /// Limits applied while tokenizing.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Config {
+        /// Longest identifier accepted, in characters. Scanning stops with an
+        /// error as soon as an identifier exceeds it, so a pathological input
+        /// cannot make the lexer buffer an arbitrarily long name.
+        pub max_identifier_len: usize,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config { max_identifier_len: 1024 }
+        }
+    }
+
+    /// A character stream over spliced source that remembers each character's
+    /// physical position.
+    struct Cursor {
+        chars: Vec<(char, Span)>,
+        pos: usize,
+        end: Span,
+    }
+
+    impl Cursor {
+        /// Returns the next character without consuming it.
+        fn peek(&self) -> Option<&char> {
+            self.chars.get(self.pos).map(|(c, _)| c)
+        }
+
+        /// Returns true if the remaining input starts with `text`.
+        fn starts_with(&self, text: &str) -> bool {
+            let mut rest = self.chars[self.pos..].iter().map(|&(c, _)| c);
+            text.chars().all(|c| rest.next() == Some(c))
+        }
+
+        /// Returns the position of the next character (or the end of input).
+        fn span(&self) -> Span {
+            self.chars.get(self.pos).map(|&(_, span)| span).unwrap_or(self.end)
+        }
+    }
+
+    impl Iterator for Cursor {
+        type Item = char;
+
+        fn next(&mut self) -> Option<char> {
+            let ch = self.chars.get(self.pos).map(|&(c, _)| c);
+            if ch.is_some() {
+                self.pos += 1;
+            }
+            ch
+        }
+    }
+
+    /// Decodes the character after a backslash in a string or character
+    /// literal, or returns `None` for an unknown escape sequence.
+    fn unescape(c: char) -> Option<char> {
+        match c {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            '0' => Some('\0'),
+            '\\' | '"' | '\'' => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Encodes a character of a string literal as the character to write after
+    /// a backslash, or returns `None` if it can be written as is.
+    pub fn escape(c: char) -> Option<char> {
+        match c {
+            '\n' => Some('n'),
+            '\t' => Some('t'),
+            '\0' => Some('0'),
+            '\\' | '"' => Some(c),
+            _ => None,
+        }
+    }
+
+    /// Removes backslash-newline pairs, joining continued physical lines into
+    /// one logical line before tokenization (C translation phase 2).
+    ///
+    /// Every remaining character keeps its physical line and column, so spliced
+    /// lines still advance the line counter used in diagnostics.
+    fn splice_lines(source: &str) -> Cursor {
+        let mut spliced = Vec::with_capacity(source.len());
+        let mut chars = source.chars().peekable();
+        let mut span = Span { line: 1, col: 1 };
+        while let Some(ch) = chars.next() {
+            if ch == '\\' {
+                match chars.peek() {
+                    Some('\n') => {
+                        chars.next();
+                        span = Span { line: span.line + 1, col: 1 };
+                        continue;
+                    },
+                    Some('\r') => {
+                        let mut ahead = chars.clone();
+                        ahead.next();
+                        if let Some('\n') = ahead.peek() {
+                            chars.next();
+                            chars.next();
+                            span = Span { line: span.line + 1, col: 1 };
+                            continue;
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            spliced.push((ch, span));
+            if ch == '\n' {
+                span = Span { line: span.line + 1, col: 1 };
+            } else {
+                span.col += 1;
+            }
+        }
+        Cursor { chars: spliced, pos: 0, end: span }
+    }
+
+    /// Tokenizes the input C source code into a vector of tokens.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn tokenize(source: &str) -> LexResult {
+        let tokens = tokenize_spanned(source)?;
+        Ok(tokens.into_iter().map(|t| t.value).collect())
+    }
+
+    /// Tokenizes the input C source code, attaching the starting position of each token.
+    pub fn tokenize_spanned(source: &str) -> SpannedLexResult {
+        tokenize_with_config(source, Config::default())
+    }
+
+    /// Tokenizes like `tokenize_spanned`, with the given limits.
+    pub fn tokenize_with_config(source: &str, config: Config) -> SpannedLexResult {
+        lex(source, false, config).map(|(tokens, _)| tokens)
+    }
+
+    /// Tokenizes like `tokenize_spanned`, also returning style warnings: a line
+    /// whose leading indentation mixes tabs and spaces, and an assignment used
+    /// directly as an `if` or `while` condition.
+    pub fn tokenize_linted(source: &str) -> LintedLexResult {
+        lex(source, true, Config::default())
+    }
+
+    /// The tokenizer proper; style warnings are collected only when `lint` is set.
+    fn lex(source: &str, lint: bool, config: Config) -> LintedLexResult {
+        let mut tokens = Vec::new();
+        let mut spans: Vec<Span> = Vec::new();
+        let mut ends: Vec<Span> = Vec::new();
+        let mut warnings = Vec::new();
+        let mut chars = splice_lines(source);
+
+        while let Some(&ch) = chars.peek() {
+            let start = chars.span();
+            match ch {
+                ' ' | '\t' if lint && start.col == 1 => {
+                    let (mut spaces, mut tabs) = (false, false);
+                    while let Some(&c @ (' ' | '\t')) = chars.peek() {
+                        spaces |= c == ' ';
+                        tabs |= c == '\t';
+                        chars.next();
+                    }
+                    if spaces && tabs {
+                        let value = "mixed tabs and spaces in indentation".to_string();
+                        warnings.push(Spanned { value, span: start, end: chars.span() });
+                    }
+                },
+                ' ' | '\t' | '\n' | '\r' => { chars.next(); },
+                '0'..='9' => {
+                    let mut num_str = String::new();
+                    while let Some(&digit) = chars.peek() {
+                        if digit.is_ascii_digit() {
+                            num_str.push(digit);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    // Check for a fractional part.
+                    if let Some(&'.') = chars.peek() {
+                        num_str.push('.');
+                        chars.next(); // consume dot
+                        while let Some(&digit) = chars.peek() {
+                            if digit.is_ascii_digit() {
+                                num_str.push(digit);
+                                chars.next();
+                            } else {
+                                break;
+                            }
+                        }
+                    }
+                    // No suffixes are supported, so letters may not follow the digits.
+                    let mut literal = num_str.clone();
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                        literal.push(c);
+                        chars.next();
+                    }
+                    if literal != num_str {
+                        return Err(format!("invalid numeric literal '{}' at {}", literal, start));
+                    }
+                    if num_str.contains('.') {
+                        let value = num_str.parse::<f64>().map_err(|e| format!("{} at {}", e, start))?;
+                        tokens.push(Token::Float(value));
+                    } else {
+                        let value = num_str.parse::<i64>().map_err(|e| format!("{} at {}", e, start))?;
+                        tokens.push(Token::Num(value));
+                    }
+                },
+                'a'..='z' | 'A'..='Z' | '_' => {
+                    let mut ident = String::new();
+                    let mut len = 0;
+                    while let Some(&c) = chars.peek() {
+                        if c.is_ascii_alphanumeric() || c == '_' {
+                            len += 1;
+                            if len > config.max_identifier_len {
+                                return Err(format!(
+                                    "identifier too long (more than {} characters) at {}",
+                                    config.max_identifier_len, start
+                                ));
+                            }
+                            ident.push(c);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    // Check for keywords.
+                    match KEYWORDS.iter().find(|(word, _)| *word == ident) {
+                        Some((_, keyword)) => tokens.push(keyword.clone()),
+                        None => tokens.push(Token::Ident(ident)),
+                    }
+                },
+                '"' => {
+                    chars.next(); // consume opening quote
+                    let mut text = String::new();
+                    loop {
+                        let at = chars.span();
+                        match chars.next() {
+                            Some('"') => break,
+                            Some('\\') => match chars.next() {
+                                Some(c) => text.push(unescape(c).ok_or_else(|| format!("Unknown escape sequence: \\{} at {}", c, at))?),
+                                None => return Err(format!("Unterminated string literal at {}", start)),
+                            },
+                            Some('\n') | None => return Err(format!("Unterminated string literal at {}", start)),
+                            Some(c) => text.push(c),
+                        }
+                    }
+                    tokens.push(Token::Str(text));
+                },
+                // A character literal is an int holding the character's code.
+                '\'' => {
+                    chars.next(); // consume opening quote
+                    let at = chars.span();
+                    let value = match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(c) => unescape(c).ok_or_else(|| format!("Unknown escape sequence: \\{} at {}", c, at))?,
+                            None => return Err(format!("Unterminated character literal at {}", start)),
+                        },
+                        Some('\'') => return Err(format!("empty character literal at {}", start)),
+                        Some('\n') | None => return Err(format!("Unterminated character literal at {}", start)),
+                        // A string holds the UTF-8 bytes of such a character, which
+                        // no single char can.
+                        Some(c) if !c.is_ascii() => {
+                            return Err(format!("non-ASCII character '{}' in character literal at {} (use a string literal)", c, at));
+                        },
+                        Some(c) => c,
+                    };
+                    match chars.next() {
+                        Some('\'') => tokens.push(Token::Num(value as i64)),
+                        Some('\n') | None => return Err(format!("Unterminated character literal at {}", start)),
+                        Some(_) => return Err(format!("multi-character character literal at {}", start)),
+                    }
+                },
+                // Handle C++–style single-line comments.
+                '/' if chars.starts_with("//") => {
+                    while let Some(&c) = chars.peek() {
+                        if c == '\n' { break; }
+                        chars.next();
+                    }
+                },
+                _ if !ch.is_ascii() => {
+                    return Err(format!(
+                        "non-ASCII character '{}' at {} (only string literals and comments may contain non-ASCII text)",
+                        ch, start
+                    ));
+                },
+                _ => {
+                    // Operators and punctuation, preferring the longest spelling.
+                    let matched = OPERATORS
+                        .iter()
+                        .chain(PUNCTUATION)
+                        .filter(|(text, _)| chars.starts_with(text))
+                        .max_by_key(|(text, _)| text.len());
+                    match matched {
+                        Some((text, token)) => {
+                            tokens.push(token.clone());
+                            for _ in 0..text.chars().count() {
+                                chars.next();
+                            }
+                        },
+                        None => return Err(format!("Unexpected character: {} at {}", ch, start)),
+                    }
+                },
+            }
+            // Every token produced in this iteration starts at `start` and
+            // ends where the next one may begin.
+            spans.resize(tokens.len(), start);
+            ends.resize(tokens.len(), chars.span());
+        }
+        tokens.push(Token::EOF);
+        spans.push(chars.span());
+        ends.push(chars.span());
+        let tokens: Vec<_> = tokens
+            .into_iter()
+            .zip(spans.into_iter().zip(ends))
+            .map(|(value, (span, end))| Spanned { value, span, end })
+            .collect();
+        if lint {
+            warnings.extend(assignment_conditions(&tokens));
+            warnings.sort_by_key(|w| (w.span.line, w.span.col));
+        }
+        Ok((tokens, warnings))
+    }
+
+    /// Warns about each `=` at the top level of an `if` or `while` condition,
+    /// which is usually a mistyped `==`. Extra parentheses around the
+    /// assignment, as in `if ((x = next()))`, mark it as intended.
+    fn assignment_conditions(tokens: &[Spanned<Token>]) -> Vec<Spanned<String>> {
+        let mut warnings = Vec::new();
+        for (i, window) in tokens.windows(2).enumerate() {
+            if !matches!(window[0].value, Token::If | Token::While) || window[1].value != Token::LParen {
+                continue;
+            }
+            let mut depth = 0;
+            for token in &tokens[i + 1..] {
+                match token.value {
+                    Token::LParen | Token::LBracket => depth += 1,
+                    Token::RParen | Token::RBracket => depth -= 1,
+                    Token::Assign if depth == 1 => warnings.push(Spanned {
+                        value: "assignment used as condition; use '==' to compare, or add parentheses if intended".to_string(),
+                        span: token.span,
+                        end: token.end,
+                    }),
+                    _ => {},
+                }
+                if depth == 0 || token.value == Token::EOF {
+                    break;
+                }
+            }
+        }
+        warnings
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_string_literals_and_escapes() {
+            let result = tokenize(r#"s = "a\tb\"c\0";"#).unwrap();
+            assert_eq!(result[2], Token::Str("a\tb\"c\0".to_string()));
+            assert_eq!(result[3], Token::Semicolon);
+            assert_eq!(tokenize("\"abc").unwrap_err(), "Unterminated string literal at 1:1");
+            assert_eq!(tokenize("x = \"a\nb\"").unwrap_err(), "Unterminated string literal at 1:5");
+            assert_eq!(tokenize(r#"s = "ab\q""#).unwrap_err(), "Unknown escape sequence: \\q at 1:8");
+        }
+
+        #[test]
+        fn test_character_literals() {
+            let result = tokenize(r#"c = 'A' + '\n' + '\t' + '\0' + '\\' + '\'' + '"' + '~';"#).unwrap();
+            let values: Vec<i64> = result.iter().filter_map(|t| if let Token::Num(n) = t { Some(*n) } else { None }).collect();
+            assert_eq!(values, [65, 10, 9, 0, 92, 39, 34, 126]);
+            assert_eq!(
+                tokenize("c = 'é';").unwrap_err(),
+                "non-ASCII character 'é' in character literal at 1:6 (use a string literal)"
+            );
+            assert_eq!(tokenize("c = 'ab';").unwrap_err(), "multi-character character literal at 1:5");
+            assert_eq!(tokenize("c = 'a").unwrap_err(), "Unterminated character literal at 1:5");
+            assert_eq!(tokenize("c = '\n';").unwrap_err(), "Unterminated character literal at 1:5");
+            assert_eq!(tokenize("c = '';").unwrap_err(), "empty character literal at 1:5");
+            assert_eq!(tokenize(r"c = '\q';").unwrap_err(), "Unknown escape sequence: \\q at 1:6");
+        }
+
+        #[test]
+        fn test_invalid_numeric_literal() {
+            assert_eq!(tokenize("x = 123abc;").unwrap_err(), "invalid numeric literal '123abc' at 1:5");
+            assert_eq!(tokenize("\n  1.5f_2 + 1").unwrap_err(), "invalid numeric literal '1.5f_2' at 2:3");
+            assert_eq!(tokenize("a[12]+3").unwrap()[2], Token::Num(12));
+        }
+
+        #[test]
+        fn test_identifier_length_limit() {
+            let config = Config { max_identifier_len: 8 };
+            let names = tokenize_with_config("int abcdefgh;", config).unwrap();
+            assert_eq!(names[1].value, Token::Ident("abcdefgh".to_string()));
+            assert_eq!(
+                tokenize_with_config("int x;\n  abcdefghi = 1;", config).unwrap_err(),
+                "identifier too long (more than 8 characters) at 2:3"
+            );
+            let huge = format!("int {};", "x".repeat(1 << 20));
+            assert_eq!(tokenize(&huge).unwrap_err(), "identifier too long (more than 1024 characters) at 1:5");
+        }
+
+        #[test]
+        fn test_identifiers_are_ascii() {
+            let tokens = tokenize("int _count2 = 1;").unwrap();
+            assert_eq!(tokens[1], Token::Ident("_count2".to_string()));
+            let ascii_only = " (only string literals and comments may contain non-ASCII text)";
+            assert_eq!(tokenize("int café = 1;").unwrap_err(), format!("non-ASCII character 'é' at 1:8{}", ascii_only));
+            assert_eq!(tokenize("int x;\nint ñ;").unwrap_err(), format!("non-ASCII character 'ñ' at 2:5{}", ascii_only));
+            assert_eq!(tokenize("x = 2×3;").unwrap_err(), format!("non-ASCII character '×' at 1:6{}", ascii_only));
+            // Non-ASCII text stays allowed where C allows it.
+            let tokens = tokenize("s = \"naïve\"; // résumé").unwrap();
+            assert_eq!(tokens[2], Token::Str("naïve".to_string()));
+        }
+
+        #[test]
+        fn test_mixed_indentation_lint() {
+            let src = "int main() {\n\tint a;\n \ta = 1;\n\t return a;\n    }";
+            let (tokens, warnings) = tokenize_linted(src).unwrap();
+            assert_eq!(tokens, tokenize_spanned(src).unwrap());
+            let lines: Vec<usize> = warnings.iter().map(|w| w.span.line).collect();
+            assert_eq!(lines, vec![3, 4]);
+            assert_eq!(warnings[0].value, "mixed tabs and spaces in indentation");
+            // Tabs after code on a line are not indentation.
+            assert!(tokenize_linted("int a; \t\nint b;").unwrap().1.is_empty());
+        }
+
+        #[test]
+        fn test_assignment_in_condition_lint() {
+            let warnings = tokenize_linted("if (x = 0) y = 1;\nwhile (a[i = 1] == (b = 2)) c = 3;").unwrap().1;
+            assert_eq!(warnings.len(), 1);
+            assert_eq!(warnings[0].span.to_string(), "1:7");
+            assert!(warnings[0].value.contains("use '=='"), "unexpected warning: {}", warnings[0].value);
+            assert!(tokenize_linted("if ((x = 0)) y = 1; while (x == 0) x = 1;").unwrap().1.is_empty());
+            assert!(tokenize_linted("do x = 1; while ((x = 0));").unwrap().1.is_empty());
+            assert_eq!(tokenize_linted("do x = 1; while (x = 0);").unwrap().1.len(), 1);
+            // Only --lint reports it.
+            assert_eq!(lex("if (x = 0) y = 1;", false, Config::default()).unwrap().1, vec![]);
+        }
+
+        #[test]
+        fn test_comment_at_end_of_file_without_newline() {
+            assert_eq!(tokenize("return 0; // end").unwrap(), vec![Token::Return, Token::Num(0), Token::Semicolon, Token::EOF]);
+            let tokens = tokenize_spanned("x;\n//").unwrap();
+            assert_eq!(tokens.last().map(|t| (&t.value, t.span.to_string())), Some((&Token::EOF, "2:3".to_string())));
+            assert_eq!(tokenize("// only a comment").unwrap(), vec![Token::EOF]);
+        }
+
+        #[test]
+        fn test_line_continuation_joins_split_token() {
+            let src = "ret\\\nurn 4\\\r\n2;";
+            let result = tokenize(src).unwrap();
+            let expected = vec![
+                Token::Return,
+                Token::Num(42),
+                Token::Semicolon,
+                Token::EOF,
+            ];
+            assert_eq!(result, expected);
+        }
+
+        #[test]
+        fn test_spans_track_lines_across_continuations() {
+            let src = "a = \\\n 1;\nb";
+            let result = tokenize_spanned(src).unwrap();
+            let spans: Vec<Span> = result.iter().map(|t| t.span).collect();
+            let expected = vec![
+                Span { line: 1, col: 1 },
+                Span { line: 1, col: 3 },
+                Span { line: 2, col: 2 },
+                Span { line: 2, col: 3 },
+                Span { line: 3, col: 1 },
+                Span { line: 3, col: 2 },
+            ];
+            assert_eq!(spans, expected);
+        }
+
+        #[test]
+        fn test_line_continuation_between_tokens() {
+            let src = "a = \\\n 1;";
+            let result = tokenize(src).unwrap();
+            let expected = vec![
+                Token::Ident("a".to_string()),
+                Token::Assign,
+                Token::Num(1),
+                Token::Semicolon,
+                Token::EOF,
+            ];
+            assert_eq!(result, expected);
+        }
+    }
+}
+
+//
+// Module: parser
+//
+mod parser {
+    //! The parser module implements a recursive descent parser for a subset of C.
+    //!
+    //! It supports global, local and static local variable declarations of
+    //! int, char, double, pointer and struct types, with const and array
+    //! dimensions; struct definitions with member access through `.` and
+    //! `->`; function definitions with parameters and calls between them; and the
+    //! statements if–else, while, do–while, for, switch with case and default,
+    //! break, continue, return and blocks. Expressions cover assignment and
+    //! compound assignment, `?:`, the comma operator, `&&` and `||`,
+    //! comparisons, arithmetic, unary operators, sizeof, indexing, string
+    //! literals and the builtins in `BUILTINS`. A symbol table tracks each
+    //! variable's storage class, type and slot.
+    //!
+    //! The parser emits opcodes for a stack-based virtual machine directly,
+    //! recording each opcode's source position, and builds the program's
+    //! syntax tree as it goes.
+
+    use crate::ast::{self, Ast, Decl, Declarator, Expr, ExprKind, Item, Param, Range, Stmt, StmtKind};
+    use crate::lexer::{Span, Spanned, Token, OPERATORS};
+    use crate::vm::{self, Opcode, Value};
+    use std::collections::HashMap;
+    use std::fmt;
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub type ParseResult = Result<Vec<Opcode>, String>;
+
+    /// The C types the parser distinguishes.
+    ///
+    /// Plain `char` is signed. Storing into a `char` keeps only the low byte,
+    /// sign-extended for `Char` and zero-extended for `UChar`, so a char holding
+    /// 200 reads back as -56 when signed and 200 when unsigned.
+    ///
+    /// Every expression is given a static type. Arithmetic on a `Double`
+    /// operand yields `Double`; any other arithmetic yields `Int`.
+    ///
+    /// Memory is addressed in cells, one per object or array element whatever
+    /// its type, so pointer arithmetic moves by whole elements without scaling.
+    /// A struct takes one cell per scalar member and is named by its tag; the
+    /// parser keeps the members of each tag in a `StructDef`. An expression of
+    /// struct type leaves the struct's address on the stack.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Type {
+        Void,
+        Int,
+        Char,
+        UChar,
+        Double,
+        Ptr(Box<Type>),
+        Struct(String),
+    }
+
+    impl Type {
+        /// The type of a binary arithmetic expression with these operand types.
+        pub fn arithmetic(left: &Type, right: &Type) -> Type {
+            if *left == Type::Double || *right == Type::Double {
+                Type::Double
+            } else {
+                Type::Int
+            }
+        }
+
+        /// Returns a pointer to this type.
+        pub fn ptr_to(self) -> Type {
+            Type::Ptr(Box::new(self))
+        }
+
+        /// Returns the size of a value of this type in bytes, as C4 counts them,
+        /// or `None` for the incomplete type `void`. A struct's size depends on
+        /// its definition; see `Parser::byte_size`.
+        pub fn byte_size(&self) -> Option<i64> {
+            match self {
+                Type::Void | Type::Struct(_) => None,
+                Type::Char | Type::UChar => Some(1),
+                Type::Int | Type::Double | Type::Ptr(_) => Some(8),
+            }
+        }
+
+        /// Returns the pointed-to type, or `None` if this is not a pointer.
+        pub fn pointee(&self) -> Option<&Type> {
+            match self {
+                Type::Ptr(inner) => Some(inner),
+                _ => None,
+            }
+        }
+    }
+
+    impl fmt::Display for Type {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Type::Void => write!(f, "void"),
+                Type::Int => write!(f, "int"),
+                Type::Char => write!(f, "char"),
+                Type::UChar => write!(f, "unsigned char"),
+                Type::Double => write!(f, "double"),
+                Type::Ptr(inner) => write!(f, "{}*", inner),
+                Type::Struct(tag) => write!(f, "struct {}", tag),
+            }
+        }
+    }
+
+    /// The members of a struct, in declaration order.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct StructDef {
+        pub members: Vec<Member>,
+        pub cells: i64, // Cells one struct occupies: the sum over its members.
+        pub bytes: i64, // Size in bytes, as `sizeof` reports it.
+    }
+
+    /// A struct member and its offset, in cells, from the start of the struct.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Member {
+        pub name: String,
+        pub ty: Type,
+        pub offset: i64,
+    }
+
+    /// What the most recent load read from, so assignment can turn it into a store.
+    #[derive(Debug, Clone)]
+    enum Lvalue {
+        Var(Symbol),  // A variable or array element.
+        Deref(Type),  // A `Load` through a pointer to the given type.
+    }
+
+    /// A compiled function: where its code starts and what it returns.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Function {
+        pub name: String,
+        pub entry: usize, // Opcode index of the function's `Ent`.
+        pub ret_type: Type,
+        pub params: Vec<Type>,   // Parameter types, in order; the parameters are locals 1..=n.
+        pub locals: Vec<Symbol>, // Parameters and variables declared in the body, by slot, for debuggers.
+        pub inline_body: Option<(usize, usize)>, // Token range of `expr` if the body is just `return expr;` with no calls.
+    }
+
+    /// A compiled program: its opcodes plus everything needed to run or inspect them.
+    #[derive(Debug, Clone)]
+    pub struct Program {
+        pub opcodes: Vec<Opcode>,
+        pub spans: Vec<Span>,       // Source position of each opcode, parallel to `opcodes`.
+        pub entry: usize,           // Opcode index where main begins.
+        pub globals: usize,         // Cells the global variables take, including static locals.
+        pub frame_size: usize,      // Largest number of locals any function declares.
+        pub main_type: Type,        // Declared return type of main.
+        pub data: Vec<Value>,       // Read-only data segment: string literal bytes, each NUL-terminated.
+        pub functions: Vec<Function>, // Every defined function, in source order.
+        pub variables: Vec<Symbol>, // Global variables and static locals by slot, for debuggers.
+        pub ast: Ast,               // The syntax tree of the source, for editors and `--emit=ast`.
+    }
+
+    impl Program {
+        /// Looks up a defined function by name.
+        pub fn function(&self, name: &str) -> Option<&Function> {
+            self.functions.iter().find(|f| f.name == name)
+        }
+
+        /// Index of the first opcode compiled from source line `line`, where a
+        /// breakpoint stops before any of the line's code runs.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn line_start(&self, line: usize) -> Option<usize> {
+            self.spans.iter().position(|span| span.line == line)
+        }
+
+        /// Finds variable `name` as seen from opcode `pc`: a local of the
+        /// function containing `pc`, or else a global.
+        pub fn variable_at(&self, pc: usize, name: &str) -> Option<&Symbol> {
+            let function = self.functions.iter().rev().find(|f| f.entry <= pc);
+            function
+                .and_then(|f| f.locals.iter().find(|sym| sym.name == name))
+                .or_else(|| self.variables.iter().find(|sym| sym.name == name))
+        }
+    }
+
+    pub type SpannedParseResult = Result<Program, String>;
+
+    /// The statement forms `parse_stmt` accepts.
+    pub const STATEMENT_KINDS: &[&str] = &[
+        "expression;",
+        "{ block }",
+        "declaration",
+        "if (...) ... [else ...]",
+        "while (...) ...",
+        "for ([init]; [cond]; [post]) ...",
+        "do ... while (...);",
+        "switch (...) { case N: ... default: ... }",
+        "break;",
+        "continue;",
+        "return expr;",
+    ];
+
+    /// Returns the opcode a binary operator token compiles to, if the parser supports it.
+    pub fn binary_opcode(token: &Token) -> Option<Opcode> {
+        match token {
+            Token::Plus => Some(Opcode::Add),
+            Token::Minus => Some(Opcode::Sub),
+            Token::Mul => Some(Opcode::Mul),
+            Token::Div => Some(Opcode::Div),
+            Token::Mod => Some(Opcode::Mod),
+            Token::Eq => Some(Opcode::Eq),
+            Token::Ne => Some(Opcode::Ne),
+            Token::Lt => Some(Opcode::Lt),
+            Token::Gt => Some(Opcode::Gt),
+            Token::Le => Some(Opcode::Le),
+            Token::Ge => Some(Opcode::Ge),
+            _ => None,
+        }
+    }
+
+    /// Returns the binary operator a compound assignment token such as `+=` applies.
+    pub fn compound_operator(token: &Token) -> Option<Token> {
+        match token {
+            Token::PlusAssign => Some(Token::Plus),
+            Token::MinusAssign => Some(Token::Minus),
+            Token::MulAssign => Some(Token::Mul),
+            Token::DivAssign => Some(Token::Div),
+            Token::ModAssign => Some(Token::Mod),
+            _ => None,
+        }
+    }
+
+    /// Returns the opcode a prefix operator token compiles to, if the parser supports it.
+    pub fn unary_opcode(token: &Token) -> Option<Opcode> {
+        match token {
+            Token::Minus => Some(Opcode::Neg),
+            Token::Bang => Some(Opcode::LogicalNot),
+            Token::Tilde => Some(Opcode::Not),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the token can start a type specifier.
+    fn is_type_token(token: &Token) -> bool {
+        matches!(
+            token,
+            Token::Int
+                | Token::Char
+                | Token::Double
+                | Token::Void
+                | Token::Signed
+                | Token::Unsigned
+                | Token::Const
+                | Token::Register
+                | Token::Struct
+        )
+    }
+
+    /// Functions the compiler provides without a definition.
+    pub const BUILTINS: &[&str] = &["malloc", "free", "print"];
+
+    /// Most cells one array may take: the size of the stack region, the
+    /// largest region that holds variables.
+    const MAX_ARRAY_CELLS: i64 = vm::STACK_CELLS as i64;
+
+    /// Returns true if the parser accepts the operator token in expressions.
+    pub fn supports_operator(token: &Token) -> bool {
+        binary_opcode(token).is_some()
+            || unary_opcode(token).is_some()
+            || compound_operator(token).is_some()
+            || matches!(
+                token,
+                Token::Assign | Token::Question | Token::Colon | Token::Amp | Token::And | Token::Or | Token::Arrow | Token::Dot
+            )
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[allow(dead_code)]
+    pub enum SymbolClass {
+        Global,
+        Local,
+        Function,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    #[allow(dead_code)]
+    pub struct Symbol {
+        pub name: String,
+        pub class: SymbolClass,
+        pub ty: Type,
+        pub offset: i64, // Slot index: in the stack frame for locals, in the globals area for globals.
+        pub is_const: bool, // Declared `const`: may be initialized but not assigned.
+        pub dims: Vec<i64>, // Array dimensions, outermost first; empty for a scalar.
+        pub cells: i64, // Slots per element: one, or the cells of a struct.
+    }
+
+    impl Symbol {
+        /// Number of slots the variable occupies: its cells, once per array element.
+        pub fn size(&self) -> i64 {
+            self.dims.iter().product::<i64>() * self.cells
+        }
+    }
+
+    /// Language settings for one parse.
+    #[derive(Debug, Clone, PartialEq, Default)]
+    pub struct Config {
+        /// Operators rejected wherever they are applied in an expression, for
+        /// exercises that have students implement them. Declarators such as the
+        /// `*` of `int *p` are unaffected.
+        pub disabled_operators: Vec<Token>,
+        /// Inline calls to functions whose body is a single `return` of an
+        /// expression that calls nothing, instead of emitting a `Call`.
+        pub inline: bool,
+        /// Data segment to append string literals to, so that addresses into an
+        /// earlier program's literals stay valid, as a REPL session needs.
+        pub data: Vec<Value>,
+    }
+
+    /// Jumps out of the loop being compiled, patched once their targets are known.
+    #[derive(Debug, Default)]
+    struct LoopJumps {
+        breaks: Vec<usize>,    // Indices of the `Jmp` placeholders emitted for `break`.
+        continues: Vec<usize>, // Indices of the `Jmp` placeholders emitted for `continue`.
+        switch: bool,          // A switch takes `break` but leaves `continue` to the enclosing loop.
+    }
+
+    /// The labels of the switch being compiled.
+    #[derive(Debug, Default)]
+    struct SwitchLabels {
+        cases: Vec<(i64, usize)>, // Each `case` value and the opcode index it labels, in source order.
+        default: Option<usize>,   // Opcode index labelled `default`, wherever it appears.
+    }
+
+    pub struct Parser {
+        tokens: Vec<Token>,
+        spans: Vec<Span>,
+        ends: Vec<Span>, // Position just past each token, parallel to `spans`.
+        pos: usize,
+        opcodes: Vec<Opcode>,
+        opcode_spans: Vec<Span>,
+        globals: HashMap<String, Symbol>,
+        locals: HashMap<String, Symbol>,
+        local_offset: i64,
+        last_load: Option<(Lvalue, usize)>, // The most recent load and the opcode index
+                                            // where its access began, for lvalue checks.
+        frame_size: usize,
+        entry: usize,     // Opcode index where the function being parsed begins.
+        ret_type: Type,   // Declared return type of the function being parsed.
+        functions: Vec<Function>,
+        data: Vec<Value>,
+        strings: HashMap<String, i64>, // Data segment offset of each distinct string literal.
+        loops: Vec<LoopJumps>, // Enclosing loops and switches, innermost last.
+        switches: Vec<SwitchLabels>, // Enclosing switches, innermost last.
+        exprs: Vec<Expr>, // Trees of the expressions being parsed, innermost last.
+        items: Vec<Item>, // Top-level items of the syntax tree parsed so far.
+        structs: HashMap<String, StructDef>, // Struct definitions by tag; tags share one program-wide scope.
+        config: Config,
+    }
+
+    impl Parser {
+        /// Creates a new parser instance.
+        pub fn new(tokens: Vec<Token>) -> Self {
+            let spans = vec![Span::default(); tokens.len()];
+            Parser {
+                tokens,
+                ends: spans.clone(),
+                spans,
+                pos: 0,
+                opcodes: Vec::new(),
+                opcode_spans: Vec::new(),
+                globals: HashMap::new(),
+                locals: HashMap::new(),
+                local_offset: 0,
+                last_load: None,
+                frame_size: 0,
+                entry: 0,
+                ret_type: Type::Int,
+                functions: Vec::new(),
+                data: Vec::new(),
+                strings: HashMap::new(),
+                loops: Vec::new(),
+                switches: Vec::new(),
+                exprs: Vec::new(),
+                items: Vec::new(),
+                structs: HashMap::new(),
+                config: Config::default(),
+            }
+        }
+
+        /// Creates a parser over tokens that carry their source positions.
+        pub fn with_spans(tokens: Vec<Spanned<Token>>) -> Self {
+            let mut parser = Parser::new(Vec::new());
+            parser.set_tokens(tokens);
+            parser
+        }
+
+        /// Replaces the tokens to parse with positioned ones, starting at the first.
+        fn set_tokens(&mut self, tokens: Vec<Spanned<Token>>) {
+            self.spans = tokens.iter().map(|t| t.span).collect();
+            self.ends = tokens.iter().map(|t| t.end).collect();
+            self.tokens = tokens.into_iter().map(|t| t.value).collect();
+            self.pos = 0;
+        }
+
+        /// Returns a reference to the current token.
+        fn current(&self) -> &Token {
+            self.tokens.get(self.pos).unwrap_or(&Token::EOF)
+        }
+
+        /// Returns the source position of the most recently consumed token.
+        fn prev_span(&self) -> Span {
+            let index = self.pos.saturating_sub(1);
+            self.spans.get(index).copied().unwrap_or_default()
+        }
+
+        /// Returns the source position of the current token.
+        fn current_span(&self) -> Span {
+            self.spans.get(self.pos).copied().unwrap_or_default()
+        }
+
+        /// Returns the range from `start` to the end of the most recently consumed token.
+        fn range_from(&self, start: Span) -> Range {
+            let index = self.pos.saturating_sub(1);
+            Range { start, end: self.ends.get(index).copied().unwrap_or(start) }
+        }
+
+        /// Pushes the tree of an expression that began at `start` and ends with
+        /// the most recently consumed token.
+        fn push_expr(&mut self, kind: ExprKind, start: Span) {
+            let range = self.range_from(start);
+            self.exprs.push(Expr { kind, range });
+        }
+
+        /// Pops the tree of the expression parsed last.
+        fn pop_expr(&mut self) -> Expr {
+            self.exprs.pop().expect("every expression pushes its tree")
+        }
+
+        /// Pops the trees of the last `n` expressions parsed, in source order.
+        fn pop_exprs(&mut self, n: usize) -> Vec<Expr> {
+            self.exprs.split_off(self.exprs.len() - n)
+        }
+
+        /// Replaces the trees of the two operands parsed last with their binary `op`.
+        fn push_binary(&mut self, op: Token, start: Span) {
+            let right = self.pop_expr();
+            let left = self.pop_expr();
+            self.push_expr(ExprKind::Binary(op, Box::new(left), Box::new(right)), start);
+        }
+
+        /// Replaces the tree of the operand parsed last with its unary `op`.
+        fn push_unary(&mut self, op: Token, start: Span) {
+            let operand = self.pop_expr();
+            self.push_expr(ExprKind::Unary(op, Box::new(operand)), start);
+        }
+
+        /// Appends an opcode attributed to the most recently consumed token.
+        fn emit(&mut self, op: Opcode) {
+            let span = self.prev_span();
+            self.emit_at(op, span);
+        }
+
+        /// Appends an opcode attributed to the given source position.
+        fn emit_at(&mut self, op: Opcode, span: Span) {
+            self.opcodes.push(op);
+            self.opcode_spans.push(span);
+        }
+
+        /// Rejects the operator `token`, applied at `span`, if the configuration
+        /// disables it.
+        fn check_enabled(&self, token: &Token, span: Span) -> Result<(), String> {
+            if !self.config.disabled_operators.contains(token) {
+                return Ok(());
+            }
+            let spelling = OPERATORS.iter().find(|(_, op)| op == token).map_or("?", |(text, _)| *text);
+            Err(format!("operator '{}' is disabled in this configuration at {}", spelling, span))
+        }
+
+        /// Consumes the current token if it matches the given token.
+        fn eat(&mut self, token: &Token) -> bool {
+            if self.current() == token {
+                self.pos += 1;
+                true
+            } else {
+                false
+            }
+        }
+
+        /// Expects that the current token matches the given token.
+        fn expect(&mut self, token: &Token) -> Result<(), String> {
+            if self.eat(token) {
+                Ok(())
+            } else {
+                Err(format!("Expected {:?}, found {:?} at {}", token, self.current(), self.current_span()))
+            }
+        }
+
+        /// Expects the opening parenthesis of a control-flow condition.
+        ///
+        /// Reports the construct by name so that `if x > 0 { ... }` yields a
+        /// targeted diagnostic instead of a bare token mismatch.
+        fn expect_condition_open(&mut self, keyword: &str) -> Result<(), String> {
+            if self.eat(&Token::LParen) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "Expected '(' after '{}', found {:?} at {}",
+                    keyword,
+                    self.current(),
+                    self.current_span()
+                ))
+            }
+        }
+
+        /// Returns true if the current token can start a type specifier.
+        fn at_type(&self) -> bool {
+            is_type_token(self.current())
+        }
+
+        /// Consumes any `const`/`register` qualifiers, returning true if `const` was seen.
+        ///
+        /// `register` is accepted and ignored, as the VM has no registers to assign.
+        fn parse_qualifiers(&mut self) -> bool {
+            let mut is_const = false;
+            loop {
+                match self.current() {
+                    Token::Const => is_const = true,
+                    Token::Register => {},
+                    _ => return is_const,
+                }
+                self.pos += 1;
+            }
+        }
+
+        /// Parses declaration specifiers: a type with qualifiers on either side,
+        /// as in `const int` or `int const`. Returns the type, its const-ness
+        /// and, if the specifier defines a struct, the member declarations.
+        fn parse_decl_specifiers(&mut self) -> Result<(Type, bool, Option<Vec<Decl>>), String> {
+            let leading = self.parse_qualifiers();
+            let (ty, members) = match self.current() {
+                Token::Struct => self.parse_struct()?,
+                _ => (self.parse_type()?, None),
+            };
+            let trailing = self.parse_qualifiers();
+            Ok((ty, leading || trailing, members))
+        }
+
+        /// Parses a type specifier: [signed | unsigned] (int | char), double or
+        /// void. Struct types are parsed by `parse_struct` instead.
+        ///
+        /// `signed` alone means `int`; of the unsigned types only `unsigned char`
+        /// is supported.
+        fn parse_type(&mut self) -> Result<Type, String> {
+            let signedness = match self.current() {
+                Token::Signed | Token::Unsigned => {
+                    let token = self.current().clone();
+                    self.pos += 1;
+                    Some(token)
+                },
+                _ => None,
+            };
+            match (signedness, self.current()) {
+                (None, Token::Char) | (Some(Token::Signed), Token::Char) => { self.pos += 1; Ok(Type::Char) },
+                (Some(Token::Unsigned), Token::Char) => { self.pos += 1; Ok(Type::UChar) },
+                (Some(Token::Unsigned), _) => Err(format!("Only unsigned char is supported at {}", self.prev_span())),
+                (_, Token::Int) => { self.pos += 1; Ok(Type::Int) },
+                (None, Token::Double) => { self.pos += 1; Ok(Type::Double) },
+                (None, Token::Void) => { self.pos += 1; Ok(Type::Void) },
+                (Some(_), _) => Ok(Type::Int),
+                (None, other) => Err(format!("Expected type, found {:?} at {}", other, self.current_span())),
+            }
+        }
+
+        /// Parses `struct tag`, optionally followed by the definition of its
+        /// members: `struct node { int value; struct node *next; }`. Members are
+        /// laid out in order, each taking its cells. A tag used before it is
+        /// defined names an incomplete type, usable only behind a pointer.
+        ///
+        /// Returns the type and, for a definition, the trees of its member declarations.
+        fn parse_struct(&mut self) -> Result<(Type, Option<Vec<Decl>>), String> {
+            self.pos += 1; // consume 'struct'
+            let tag_span = self.current_span();
+            let tag = self
+                .eat_ident()
+                .ok_or_else(|| format!("Expected struct tag, found {:?} at {}", self.current(), tag_span))?;
+            if !self.eat(&Token::LBrace) {
+                return Ok((Type::Struct(tag), None));
+            }
+            if self.structs.contains_key(&tag) {
+                return Err(format!("redefinition of 'struct {}' at {}", tag, tag_span));
+            }
+            let mut members: Vec<Member> = Vec::new();
+            let mut decls = Vec::new();
+            let (mut cells, mut bytes) = (0, 0);
+            while !self.eat(&Token::RBrace) {
+                let start = self.current_span();
+                let (base, is_const, nested) = self.parse_decl_specifiers()?;
+                if is_const {
+                    return Err(format!("const struct members are not supported at {}", self.prev_span()));
+                }
+                let mut declarators = Vec::new();
+                loop {
+                    let declarator_start = self.current_span();
+                    let ty = self.parse_pointers(base.clone());
+                    let span = self.current_span();
+                    let name = self.eat_ident().ok_or_else(|| format!("Expected member name at {}", span))?;
+                    self.check_object_type(&name, &ty)?;
+                    if self.current() == &Token::LBracket {
+                        return Err(format!("array members are not supported at {}", self.current_span()));
+                    }
+                    if members.iter().any(|member| member.name == name) {
+                        return Err(format!("duplicate member '{}' at {}", name, span));
+                    }
+                    let offset = cells;
+                    cells += self.cells(&ty);
+                    bytes += self.byte_size(&ty).unwrap_or(0); // Complete, as checked above.
+                    let range = self.range_from(declarator_start);
+                    declarators.push(Declarator { name: name.clone(), ty: ty.clone(), dims: Vec::new(), init: None, range });
+                    members.push(Member { name, ty, offset });
+                    if !self.eat(&Token::Comma) {
+                        break;
+                    }
+                }
+                self.expect(&Token::Semicolon)?;
+                let range = self.range_from(start);
+                decls.push(Decl { is_static: false, is_const, base, members: nested, declarators, range });
+            }
+            if members.is_empty() {
+                return Err(format!("struct '{}' has no members at {}", tag, tag_span));
+            }
+            self.structs.insert(tag.clone(), StructDef { members, cells, bytes });
+            Ok((Type::Struct(tag), Some(decls)))
+        }
+
+        /// Returns the number of cells an object of type `ty` occupies.
+        fn cells(&self, ty: &Type) -> i64 {
+            match ty {
+                Type::Struct(tag) => self.structs.get(tag).map_or(1, |def| def.cells),
+                _ => 1,
+            }
+        }
+
+        /// Returns the size of `ty` in bytes, or `None` if the type is incomplete.
+        fn byte_size(&self, ty: &Type) -> Option<i64> {
+            match ty {
+                Type::Struct(tag) => self.structs.get(tag).map(|def| def.bytes),
+                _ => ty.byte_size(),
+            }
+        }
+
+        /// Parses the `*`s of a declarator, wrapping the base type in one pointer per star.
+        fn parse_pointers(&mut self, mut ty: Type) -> Type {
+            while self.eat(&Token::Mul) {
+                ty = ty.ptr_to();
+            }
+            ty
+        }
+
+        /// Rejects `void` and incomplete structs as the type of a variable.
+        fn check_object_type(&self, name: &str, ty: &Type) -> Result<(), String> {
+            match ty {
+                Type::Void => Err(format!("variable '{}' declared void at {}", name, self.prev_span())),
+                Type::Struct(tag) if !self.structs.contains_key(tag) => Err(format!(
+                    "storage size of '{}' isn't known (incomplete type 'struct {}') at {}",
+                    name,
+                    tag,
+                    self.prev_span()
+                )),
+                _ => Ok(()),
+            }
+        }
+
+        /// Rejects a struct operand where a scalar value is required.
+        fn check_scalar(&self, ty: &Type, span: Span) -> Result<(), String> {
+            match ty {
+                Type::Struct(_) => Err(format!("used '{}' value where a scalar is required at {}", ty, span)),
+                _ => Ok(()),
+            }
+        }
+
+        /// Parses the array dimensions following a declarator name of type
+        /// `ty`: `[3][4]`. Arrays of structs are not supported. The tree of
+        /// each dimension is left on `exprs`.
+        fn parse_dims(&mut self, ty: &Type) -> Result<Vec<i64>, String> {
+            if matches!(ty, Type::Struct(_)) && self.current() == &Token::LBracket {
+                return Err(format!("arrays of structs are not supported at {}", self.current_span()));
+            }
+            let mut dims = Vec::new();
+            let mut cells: i64 = 1;
+            while self.eat(&Token::LBracket) {
+                let span = self.current_span();
+                if self.current() == &Token::RBracket {
+                    return Err(format!("Array size must be a positive integer constant, found RBracket at {}", span));
+                }
+                match self.eval_const()? {
+                    n if n > 0 => dims.push(n),
+                    n => return Err(format!("Array size must be a positive integer constant, found {} at {}", n, span)),
+                }
+                cells = cells.saturating_mul(dims[dims.len() - 1]);
+                if cells > MAX_ARRAY_CELLS {
+                    return Err(format!("array is too large ({} cells, at most {}) at {}", cells, MAX_ARRAY_CELLS, span));
+                }
+                self.expect(&Token::RBracket)?;
+            }
+            Ok(dims)
+        }
+
+        /// Parses an integer constant expression and evaluates it at compile time.
+        ///
+        /// The expression is compiled as usual and, provided it reads no
+        /// variables or memory, run on the VM; its code is then discarded and
+        /// its tree left on `exprs`.
+        fn eval_const(&mut self) -> Result<i64, String> {
+            let span = self.current_span();
+            let begin = self.opcodes.len();
+            let ty = self.parse_conditional()?;
+            let mut code: Vec<Opcode> = self.opcodes.drain(begin..).collect();
+            self.opcode_spans.truncate(begin);
+            self.last_load = None;
+            if !matches!(ty, Type::Int | Type::Char | Type::UChar) {
+                return Err(format!("constant expression has non-integer type '{}' at {}", ty, span));
+            }
+            for op in &mut code {
+                match op {
+                    Opcode::Jmp(target) | Opcode::Jz(target) => *target -= begin as i64,
+                    Opcode::Imm(_) | Opcode::FImm(_) | Opcode::Pop | Opcode::Dup | Opcode::SignExt8 | Opcode::ZeroExt8
+                    | Opcode::IToF | Opcode::FToI | Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div
+                    | Opcode::Mod | Opcode::Eq | Opcode::Ne | Opcode::Lt | Opcode::Gt | Opcode::Le
+                    | Opcode::Ge | Opcode::Neg | Opcode::LogicalNot | Opcode::Not => {},
+                    _ => return Err(format!("expression is not a constant at {}", span)),
+                }
+            }
+            code.push(Opcode::Ret);
+            vm::execute_from(&code, 0)
+                .map(Value::as_int)
+                .map_err(|e| format!("{} in constant expression at {}", e.message, span))
+        }
+
+        /// Declares a global variable in the next free global slots.
+        ///
+        /// Redeclaring an existing global (a C tentative definition) keeps its slot.
+        /// All globals together must fit below the data segment, in `vm::GLOBAL_CELLS` cells.
+        fn declare_global(&mut self, name: String, ty: Type, is_const: bool, dims: Vec<i64>, span: Span) -> Result<Symbol, String> {
+            if !self.globals.contains_key(&name) {
+                let offset: i64 = self.globals.values().map(Symbol::size).sum();
+                let cells = self.cells(&ty);
+                let symbol = Symbol { name: name.clone(), class: SymbolClass::Global, ty, offset, is_const, dims, cells };
+                if offset + symbol.size() > vm::GLOBAL_CELLS as i64 {
+                    let shown = name.rsplit('.').next().unwrap_or(&name); // A static local without its function prefix.
+                    return Err(format!("global storage for '{}' exceeds the limit of {} cells at {}", shown, vm::GLOBAL_CELLS, span));
+                }
+                self.globals.insert(name.clone(), symbol);
+            }
+            Ok(self.globals[&name].clone())
+        }
+
+        /// Declares a static local of the current function: a global slot under
+        /// a name no identifier can spell, visible only through the function's scope.
+        fn declare_static(&mut self, name: String, ty: Type, is_const: bool, dims: Vec<i64>, span: Span) -> Result<Symbol, String> {
+            let function = self.functions.last().map_or("", |f| f.name.as_str());
+            let storage_name = format!("{}.{}", function, name);
+            let storage = self.declare_global(storage_name, ty, is_const, dims, span)?;
+            let symbol = Symbol { name: name.clone(), ..storage };
+            self.locals.insert(name, symbol.clone());
+            Ok(symbol)
+        }
+
+        /// Emits a load of a variable from its local or global slot.
+        fn emit_load(&mut self, sym: &Symbol) {
+            self.last_load = Some((Lvalue::Var(sym.clone()), self.opcodes.len()));
+            match sym.class {
+                SymbolClass::Global => self.emit(Opcode::LdG(sym.offset)),
+                _ => self.emit(Opcode::Ld(sym.offset)),
+            }
+        }
+
+        /// Emits the address of a struct variable, which stands for the struct.
+        fn emit_address(&mut self, sym: &Symbol) {
+            self.last_load = None;
+            match sym.class {
+                SymbolClass::Global => self.emit(Opcode::AddrG(sym.offset, sym.size())),
+                _ => self.emit(Opcode::Lea(sym.offset)),
+            }
+        }
+
+        /// Parses the subscripts of an array access and emits the element load,
+        /// wrapping the array's tree on `exprs` in one index per subscript.
+        ///
+        /// Elements are laid out in row-major order, so `m[i][j]` of `int m[3][4]`
+        /// reads element `i*4 + j`. Every dimension must be subscripted.
+        fn parse_index(&mut self, sym: &Symbol) -> Result<(), String> {
+            let start = self.opcodes.len();
+            for (depth, &dim) in sym.dims.iter().enumerate() {
+                if !self.eat(&Token::LBracket) {
+                    return Err(format!(
+                        "Array '{}' must be indexed in all {} dimensions at {}",
+                        sym.name,
+                        sym.dims.len(),
+                        self.current_span()
+                    ));
+                }
+                let bracket_span = self.prev_span();
+                if depth > 0 {
+                    self.emit_at(Opcode::Imm(dim), bracket_span);
+                    self.emit_at(Opcode::Mul, bracket_span);
+                }
+                if self.parse_expr()? == Type::Double {
+                    return Err(format!("array subscript is not an integer at {}", bracket_span));
+                }
+                self.expect(&Token::RBracket)?;
+                let index = self.pop_expr();
+                let base = self.pop_expr();
+                let start = base.range.start;
+                self.push_expr(ExprKind::Index(Box::new(base), Box::new(index)), start);
+                // The flattened index is checked on access; each subscript of a
+                // multi-dimensional array must also stay within its own dimension.
+                if sym.dims.len() > 1 {
+                    self.emit_at(Opcode::Bound(dim), bracket_span);
+                }
+                if depth > 0 {
+                    self.emit_at(Opcode::Add, bracket_span);
+                }
+            }
+            self.last_load = Some((Lvalue::Var(sym.clone()), start));
+            match sym.class {
+                SymbolClass::Global => self.emit(Opcode::LdGX(sym.offset, sym.size())),
+                _ => self.emit(Opcode::LdX(sym.offset, sym.size())),
+            }
+            Ok(())
+        }
+
+        /// Looks up a variable, preferring locals over globals.
+        fn lookup(&self, name: &str) -> Option<&Symbol> {
+            self.locals.get(name).or_else(|| self.globals.get(name))
+        }
+
+        /// Emits the conversion of the value on top of the stack from one type to another.
+        ///
+        /// Doubles are truncated toward zero when converted to an integer type, and
+        /// char types keep only their low byte.
+        /// Pointers convert to and from integers unchanged. A `void` expression
+        /// has no value to convert.
+        fn emit_convert(&mut self, from: &Type, to: &Type, span: Span) -> Result<(), String> {
+            if *from == Type::Void {
+                return Err(format!("void value not ignored as it ought to be at {}", span));
+            }
+            self.check_scalar(from, span)?;
+            match (from, to) {
+                (Type::Double, Type::Double) => {},
+                (_, Type::Double) => self.emit_at(Opcode::IToF, span),
+                (Type::Double, _) => self.emit_at(Opcode::FToI, span),
+                _ => {},
+            }
+            match to {
+                Type::Char if *from != Type::Char => self.emit_at(Opcode::SignExt8, span),
+                Type::UChar if *from != Type::UChar => self.emit_at(Opcode::ZeroExt8, span),
+                _ => {},
+            }
+            Ok(())
+        }
+
+        /// Emits a store to a variable, first converting the value from `from` to
+        /// the variable's type. For an array the element index is already on the
+        /// stack beneath the value.
+        fn emit_store(&mut self, sym: &Symbol, from: &Type, span: Span) -> Result<(), String> {
+            self.emit_convert(from, &sym.ty, span)?;
+            match (&sym.class, sym.dims.is_empty()) {
+                (SymbolClass::Global, true) => self.emit_at(Opcode::StG(sym.offset), span),
+                (SymbolClass::Global, false) => self.emit_at(Opcode::StGX(sym.offset, sym.size()), span),
+                (_, true) => self.emit_at(Opcode::St(sym.offset), span),
+                (_, false) => self.emit_at(Opcode::StX(sym.offset, sym.size()), span),
+            }
+            Ok(())
+        }
+
+        /// Parses the entire program.
+        ///
+        /// The program may contain global variable declarations and one function definition.
+        pub fn parse_program(&mut self) -> Result<(), String> {
+            while self.current() != &Token::EOF {
+                if !self.at_type() {
+                    return Err(format!(
+                        "Unexpected token at global scope: {:?} at {}",
+                        self.current(),
+                        self.current_span()
+                    ));
+                }
+                let start = self.current_span();
+                let (base, is_const, members) = self.parse_decl_specifiers()?;
+                let mut declarators = Vec::new();
+                if !(matches!(base, Type::Struct(_)) && self.eat(&Token::Semicolon)) {
+                    // Anything but a struct definition on its own: struct tag { ... };
+                    let declarator_start = self.current_span();
+                    let ty = self.parse_pointers(base.clone());
+                    let Token::Ident(ident) = self.current().clone() else {
+                        return Err(format!(
+                            "Expected identifier after type, found {:?} at {}",
+                            self.current(),
+                            self.current_span()
+                        ));
+                    };
+                    let ident_span = self.current_span();
+                    self.pos += 1; // consume identifier
+                    if self.eat(&Token::LParen) {
+                        if members.is_some() {
+                            return Err(format!("struct definitions are not supported in a return type at {}", start));
+                        }
+                        self.parse_function(start, ident, ident_span, ty)?;
+                        continue;
+                    }
+                    // Global variable declaration: int g, *p, h[4];
+                    declarators.push(self.parse_global_declarator(ident, ty, is_const, declarator_start, ident_span)?);
+                    while self.eat(&Token::Comma) {
+                        let declarator_start = self.current_span();
+                        let ty = self.parse_pointers(base.clone());
+                        let name_span = self.current_span();
+                        let Some(name) = self.eat_ident() else {
+                            return Err(format!(
+                                "Expected identifier in global declaration, found {:?} at {}",
+                                self.current(),
+                                self.current_span()
+                            ));
+                        };
+                        declarators.push(self.parse_global_declarator(name, ty, is_const, declarator_start, name_span)?);
+                    }
+                    self.expect(&Token::Semicolon)?;
+                }
+                let range = self.range_from(start);
+                self.items.push(Item::Decl(Decl { is_static: false, is_const, base, members, declarators, range }));
+            }
+            Ok(())
+        }
+
+        /// Parses the array dimensions of global `name`, whose pointers and name
+        /// have been consumed, and declares it.
+        fn parse_global_declarator(
+            &mut self,
+            name: String,
+            ty: Type,
+            is_const: bool,
+            start: Span,
+            name_span: Span,
+        ) -> Result<Declarator, String> {
+            self.check_object_type(&name, &ty)?;
+            let dims = self.parse_dims(&ty)?;
+            let dim_exprs = self.pop_exprs(dims.len());
+            self.declare_global(name.clone(), ty.clone(), is_const, dims, name_span)?;
+            Ok(Declarator { name, ty, dims: dim_exprs, init: None, range: self.range_from(start) })
+        }
+
+        /// Parses a function definition after its `(`: `params) { body }`. Each
+        /// function gets its own frame of locals, starting with its parameters.
+        /// The definition began at `start`.
+        fn parse_function(&mut self, start: Span, name: String, name_span: Span, ret_type: Type) -> Result<(), String> {
+            if self.functions.iter().any(|f| f.name == name) {
+                return Err(format!("Redefinition of function '{}' at {}", name, name_span));
+            }
+            if let Type::Struct(_) = ret_type {
+                return Err(format!("functions returning structs are not supported at {}", name_span));
+            }
+            self.ret_type = ret_type.clone();
+            self.entry = self.opcodes.len();
+            // Enter new local scope.
+            self.locals.clear();
+            self.local_offset = 0;
+            let params = self.parse_parameters()?;
+            let types: Vec<Type> = params.iter().map(|param| param.ty.clone()).collect();
+            if name == "main" && !types.is_empty() && types != [Type::Int, Type::Char.ptr_to().ptr_to()] {
+                return Err(format!("main must take no parameters or (int argc, char **argv) at {}", name_span));
+            }
+            let function = Function {
+                name: name.clone(),
+                entry: self.entry,
+                ret_type: ret_type.clone(),
+                params: types,
+                locals: Vec::new(),
+                inline_body: None,
+            };
+            self.functions.push(function);
+            self.emit(Opcode::Ent(0)); // frame size patched below
+            self.expect(&Token::LBrace)?;
+            let body_start = self.pos;
+            let mut body = Vec::new();
+            while self.current() != &Token::RBrace {
+                body.push(self.parse_stmt()?);
+            }
+            let inline_body = (body.len() == 1).then(|| self.inline_body(body_start, self.pos)).flatten();
+            self.expect(&Token::RBrace)?;
+            let range = self.range_from(start);
+            self.items.push(Item::Function(ast::Function { ret_type, name, params, body, range }));
+            if let Some(function) = self.functions.last_mut() {
+                function.locals = storage_order(&self.locals);
+                function.inline_body = inline_body;
+            }
+            self.opcodes[self.entry] = Opcode::Ent(self.local_offset);
+            self.frame_size = self.frame_size.max(self.local_offset as usize);
+            // Function end: falling off the closing brace
+            // behaves like `return 0;` (as for C99 main).
+            if self.end_reachable() {
+                self.emit(Opcode::Imm(0));
+                self.emit(Opcode::Ret);
+            }
+            Ok(())
+        }
+
+        /// Returns the token range of `expr` if the single statement from `start`
+        /// to `end` is `return expr;` and `expr` calls nothing, so that calls to
+        /// the function can be inlined without recursion or extra side effects.
+        fn inline_body(&self, start: usize, end: usize) -> Option<(usize, usize)> {
+            let (first, last) = (start + 1, end - 1);
+            let single_return = self.tokens[start] == Token::Return && self.tokens[last] == Token::Semicolon && first < last;
+            let calls = self.tokens[first..last].windows(2).any(|pair| matches!(pair, [Token::Ident(_), Token::LParen]));
+            (single_return && !calls).then_some((first, last))
+        }
+
+        /// Parses a parameter list through its `)`, declaring each parameter as the
+        /// next local, and returns the parameters. `()` and `(void)` declare none.
+        fn parse_parameters(&mut self) -> Result<Vec<Param>, String> {
+            let mut params = Vec::new();
+            if self.current() == &Token::Void && self.tokens.get(self.pos + 1) == Some(&Token::RParen) {
+                self.pos += 1;
+            }
+            while self.current() != &Token::RParen {
+                if !params.is_empty() {
+                    self.expect(&Token::Comma)?;
+                }
+                let start = self.current_span();
+                let (base, is_const, members) = self.parse_decl_specifiers()?;
+                if members.is_some() {
+                    return Err(format!("struct definitions are not supported in parameter lists at {}", start));
+                }
+                let ty = self.parse_pointers(base);
+                let span = self.current_span();
+                let name = self.eat_ident().ok_or_else(|| format!("Expected parameter name at {}", span))?;
+                self.check_object_type(&name, &ty)?;
+                if let Type::Struct(_) = ty {
+                    return Err(format!("struct parameters are not supported; pass a pointer at {}", span));
+                }
+                if self.locals.contains_key(&name) {
+                    return Err(format!("Redefinition of parameter '{}' at {}", name, span));
+                }
+                self.local_offset += 1;
+                let symbol = Symbol {
+                    name: name.clone(),
+                    class: SymbolClass::Local,
+                    ty: ty.clone(),
+                    offset: self.local_offset,
+                    is_const,
+                    dims: Vec::new(),
+                    cells: 1,
+                };
+                self.locals.insert(name.clone(), symbol);
+                params.push(Param { is_const, ty, name, range: self.range_from(start) });
+            }
+            self.pos += 1; // consume ')'
+            Ok(params)
+        }
+
+        /// Returns true if control entering the current function can run off the
+        /// end of the code emitted so far, following jumps and stopping at every `Ret`.
+        fn end_reachable(&self) -> bool {
+            let end = self.opcodes.len();
+            let mut seen = vec![false; end + 1];
+            let mut pending = vec![self.entry];
+            while let Some(pc) = pending.pop() {
+                if pc > end || seen[pc] {
+                    continue;
+                }
+                seen[pc] = true;
+                match self.opcodes.get(pc) {
+                    Some(Opcode::Ret) | None => {},
+                    Some(Opcode::Jmp(target)) => pending.push(*target as usize),
+                    Some(Opcode::Jz(target)) => pending.extend([*target as usize, pc + 1]),
+                    Some(_) => pending.push(pc + 1),
+                }
+            }
+            seen[end]
+        }
+
+        /// Parses a statement, returning its tree.
+        fn parse_stmt(&mut self) -> Result<Stmt, String> {
+            let start = self.current_span();
+            let kind = match self.current() {
+                Token::Return => {
+                    let return_span = self.current_span();
+                    self.pos += 1; // consume 'return'
+                    let ty = self.parse_expr()?;
+                    let ret_type = self.ret_type.clone();
+                    self.emit_convert(&ty, &ret_type, return_span)?;
+                    self.expect(&Token::Semicolon)?;
+                    self.emit(Opcode::Ret);
+                    StmtKind::Return(self.pop_expr())
+                },
+                Token::If => self.parse_if()?,
+                Token::While => self.parse_while()?,
+                Token::For => self.parse_for()?,
+                Token::Do => self.parse_do_while()?,
+                Token::Switch => self.parse_switch()?,
+                Token::Case | Token::Default => self.parse_case_label()?,
+                Token::Break | Token::Continue => self.parse_loop_jump()?,
+                Token::LBrace => {
+                    self.pos += 1;
+                    let mut body = Vec::new();
+                    while self.current() != &Token::RBrace {
+                        body.push(self.parse_stmt()?);
+                    }
+                    self.expect(&Token::RBrace)?;
+                    StmtKind::Block(body)
+                },
+                _ if self.at_type() || self.current() == &Token::Static => StmtKind::Decl(self.parse_local_decl()?),
+                _ => {
+                    // Expression statement: its value is discarded.
+                    self.parse_expr()?;
+                    self.expect(&Token::Semicolon)?;
+                    self.emit(Opcode::Pop);
+                    StmtKind::Expr(self.pop_expr())
+                }
+            };
+            Ok(Stmt { kind, range: self.range_from(start) })
+        }
+
+        /// Parses an if–else statement: if ( expr ) stmt [ else stmt ]
+        fn parse_if(&mut self) -> Result<StmtKind, String> {
+            self.pos += 1; // consume 'if'
+            self.expect_condition_open("if")?;
+            self.parse_condition()?;
+            self.expect(&Token::RParen)?;
+            let cond = self.pop_expr();
+            let jz_index = self.opcodes.len();
+            self.emit(Opcode::Jz(0)); // placeholder for jump if false
+            let then = self.parse_stmt()?;
+            let jmp_index = self.opcodes.len();
+            self.emit(Opcode::Jmp(0)); // placeholder for jump over else
+            let else_addr = self.opcodes.len() as i64;
+            self.opcodes[jz_index] = Opcode::Jz(else_addr);
+            let otherwise = if self.eat(&Token::Else) { Some(Box::new(self.parse_stmt()?)) } else { None };
+            let end_addr = self.opcodes.len() as i64;
+            self.opcodes[jmp_index] = Opcode::Jmp(end_addr);
+            Ok(StmtKind::If(cond, Box::new(then), otherwise))
+        }
+
+        /// Parses the controlling expression of a statement, which must have a
+        /// scalar type.
+        fn parse_condition(&mut self) -> Result<(), String> {
+            let span = self.current_span();
+            let ty = self.parse_expr()?;
+            self.check_scalar(&ty, span)
+        }
+
+        /// Parses a while statement: while ( expr ) stmt
+        fn parse_while(&mut self) -> Result<StmtKind, String> {
+            self.pos += 1; // consume 'while'
+            let loop_start = self.opcodes.len() as i64;
+            self.expect_condition_open("while")?;
+            self.parse_condition()?;
+            self.expect(&Token::RParen)?;
+            let cond = self.pop_expr();
+            let constant = self.take_constant_condition(loop_start as usize);
+            let jz_index = self.opcodes.len();
+            self.emit_loop_exit(constant);
+            let (body, jumps) = self.parse_loop_body()?;
+            self.patch_jumps(&jumps.continues, loop_start as usize);
+            self.emit(Opcode::Jmp(loop_start));
+            self.close_loop(constant, jz_index, &jumps.breaks);
+            Ok(StmtKind::While(cond, Box::new(body)))
+        }
+
+        /// Removes a just-compiled loop condition that is a single constant,
+        /// returning whether it is true; returns `None` for any other condition.
+        fn take_constant_condition(&mut self, start: usize) -> Option<bool> {
+            let truth = match self.opcodes[start..] {
+                [Opcode::Imm(n)] => n != 0,
+                [Opcode::FImm(f)] => f != 0.0,
+                _ => return None,
+            };
+            self.discard_from(start);
+            Some(truth)
+        }
+
+        /// Emits the placeholder exit test of a loop with the given constant
+        /// condition: a `Jz` for a computed one, none for a true one, and for a
+        /// false one a `Jmp` past the body, which only a `case` label can enter.
+        fn emit_loop_exit(&mut self, constant: Option<bool>) {
+            match constant {
+                None => self.emit(Opcode::Jz(0)),
+                Some(false) => self.emit(Opcode::Jmp(0)),
+                Some(true) => {},
+            }
+        }
+
+        /// Finishes a loop whose exit test is at `jz_index`: patches the exit and
+        /// the `break` jumps, or for a constant-false condition drops the never-run
+        /// body, unless a `case` or `default` label jumps into it.
+        fn close_loop(&mut self, constant: Option<bool>, jz_index: usize, breaks: &[usize]) {
+            let loop_end = self.opcodes.len() as i64;
+            match constant {
+                None => self.opcodes[jz_index] = Opcode::Jz(loop_end),
+                Some(false) if self.labels_from(jz_index) => self.opcodes[jz_index] = Opcode::Jmp(loop_end),
+                Some(false) => return self.discard_from(jz_index),
+                Some(true) => {},
+            }
+            self.patch_jumps(breaks, loop_end as usize);
+        }
+
+        /// Whether an enclosing switch has a label at opcode index `start` or later.
+        fn labels_from(&self, start: usize) -> bool {
+            self.switches.iter().any(|labels| {
+                labels.default.is_some_and(|target| target >= start) || labels.cases.iter().any(|&(_, target)| target >= start)
+            })
+        }
+
+        /// Parses a loop body, returning it with the `break` and `continue` jumps it contains.
+        fn parse_loop_body(&mut self) -> Result<(Stmt, LoopJumps), String> {
+            self.loops.push(LoopJumps::default());
+            let body = self.parse_stmt();
+            let jumps = self.loops.pop().unwrap_or_default();
+            body.map(|body| (body, jumps))
+        }
+
+        /// Parses `break;` or `continue;`, emitting a jump that the enclosing
+        /// loop or switch patches when it is closed. `continue` skips switches.
+        fn parse_loop_jump(&mut self) -> Result<StmtKind, String> {
+            let keyword = if self.current() == &Token::Break { "break" } else { "continue" };
+            let span = self.current_span();
+            self.pos += 1;
+            self.expect(&Token::Semicolon)?;
+            let index = self.opcodes.len();
+            if keyword == "break" {
+                let jumps = self.loops.last_mut();
+                let jumps = jumps.ok_or_else(|| format!("break statement not within loop or switch at {}", span))?;
+                jumps.breaks.push(index);
+            } else {
+                let jumps = self.loops.iter_mut().rev().find(|jumps| !jumps.switch);
+                let jumps = jumps.ok_or_else(|| format!("continue statement not within loop at {}", span))?;
+                jumps.continues.push(index);
+            }
+            self.emit_at(Opcode::Jmp(0), span); // placeholder, patched by the loop
+            Ok(if keyword == "break" { StmtKind::Break } else { StmtKind::Continue })
+        }
+
+        /// Points every jump placeholder in `jumps` at `target`.
+        fn patch_jumps(&mut self, jumps: &[usize], target: usize) {
+            for &index in jumps {
+                self.opcodes[index] = Opcode::Jmp(target as i64);
+            }
+        }
+
+        /// Parses a switch statement: switch ( expr ) stmt
+        ///
+        /// The value is kept in a hidden local and the body is compiled in order,
+        /// followed by a dispatch table that compares the value with each `case`
+        /// and jumps to the first match, else to `default` wherever it appears in
+        /// the body, else past the switch. Labels are only jump targets, so
+        /// control falls through from one case into the next.
+        fn parse_switch(&mut self) -> Result<StmtKind, String> {
+            let switch_span = self.current_span();
+            self.pos += 1; // consume 'switch'
+            self.expect_condition_open("switch")?;
+            let ty = self.parse_expr()?;
+            self.expect(&Token::RParen)?;
+            let value = self.pop_expr();
+            if !matches!(ty, Type::Int | Type::Char | Type::UChar) {
+                return Err(format!("switch quantity not an integer at {}", switch_span));
+            }
+            self.local_offset += 1;
+            let slot = self.local_offset;
+            self.emit_at(Opcode::St(slot), switch_span);
+            self.emit_at(Opcode::Pop, switch_span);
+            let dispatch_jmp = self.opcodes.len();
+            self.emit_at(Opcode::Jmp(0), switch_span); // placeholder, patched to the dispatch table
+            self.switches.push(SwitchLabels::default());
+            self.loops.push(LoopJumps { switch: true, ..LoopJumps::default() });
+            let body = self.parse_stmt();
+            let jumps = self.loops.pop().unwrap_or_default();
+            let labels = self.switches.pop().unwrap_or_default();
+            let body = body?;
+            let mut exits = jumps.breaks;
+            exits.push(self.opcodes.len());
+            self.emit_at(Opcode::Jmp(0), switch_span); // the body's end leaves the switch
+            self.opcodes[dispatch_jmp] = Opcode::Jmp(self.opcodes.len() as i64);
+            for (value, target) in labels.cases {
+                self.emit_at(Opcode::Ld(slot), switch_span);
+                self.emit_at(Opcode::Imm(value), switch_span);
+                self.emit_at(Opcode::Ne, switch_span);
+                self.emit_at(Opcode::Jz(target as i64), switch_span);
+            }
+            if let Some(target) = labels.default {
+                self.emit_at(Opcode::Jmp(target as i64), switch_span);
+            }
+            self.patch_jumps(&exits, self.opcodes.len());
+            Ok(StmtKind::Switch(value, Box::new(body)))
+        }
+
+        /// Parses a `case N:` or `default:` label of the innermost switch, then
+        /// the statement it labels.
+        fn parse_case_label(&mut self) -> Result<StmtKind, String> {
+            let span = self.current_span();
+            let is_default = self.current() == &Token::Default;
+            if self.switches.is_empty() {
+                let label = if is_default { "'default'" } else { "case" };
+                return Err(format!("{} label not within a switch statement at {}", label, span));
+            }
+            self.pos += 1; // consume 'case' or 'default'
+            let value = if is_default { None } else { Some((self.eval_const()?, self.pop_expr())) };
+            self.expect(&Token::Colon)?;
+            let target = self.opcodes.len();
+            let labels = self.switches.last_mut().expect("checked above");
+            match &value {
+                None if labels.default.is_some() => {
+                    return Err(format!("multiple default labels in one switch at {}", span));
+                },
+                None => labels.default = Some(target),
+                Some((value, _)) if labels.cases.iter().any(|&(case, _)| case == *value) => {
+                    return Err(format!("duplicate case value {} at {}", value, span));
+                },
+                Some((value, _)) => labels.cases.push((*value, target)),
+            }
+            let body = Box::new(self.parse_stmt()?);
+            Ok(match value {
+                Some((_, expr)) => StmtKind::Case(expr, body),
+                None => StmtKind::Default(body),
+            })
+        }
+
+        /// Parses a do-while statement: do stmt while ( expr ) ;
+        ///
+        /// The body runs once before the condition is first tested; `continue`
+        /// jumps to the test.
+        fn parse_do_while(&mut self) -> Result<StmtKind, String> {
+            self.pos += 1; // consume 'do'
+            let loop_start = self.opcodes.len() as i64;
+            let (body, jumps) = self.parse_loop_body()?;
+            self.expect(&Token::While)?;
+            let test_start = self.opcodes.len();
+            self.expect_condition_open("while")?;
+            self.parse_condition()?;
+            self.expect(&Token::RParen)?;
+            self.expect(&Token::Semicolon)?;
+            let cond = self.pop_expr();
+            match self.take_constant_condition(test_start) {
+                Some(true) => self.emit(Opcode::Jmp(loop_start)),
+                Some(false) => {},
+                None => {
+                    let jz_index = self.opcodes.len();
+                    self.emit(Opcode::Jz(0)); // placeholder for loop exit
+                    self.emit(Opcode::Jmp(loop_start));
+                    self.opcodes[jz_index] = Opcode::Jz(self.opcodes.len() as i64);
+                },
+            }
+            self.patch_jumps(&jumps.continues, test_start);
+            self.patch_jumps(&jumps.breaks, self.opcodes.len());
+            Ok(StmtKind::DoWhile(Box::new(body), cond))
+        }
+
+        /// Drops the code emitted from `start` onwards.
+        fn discard_from(&mut self, start: usize) {
+            self.opcodes.truncate(start);
+            self.opcode_spans.truncate(start);
+            self.last_load = None;
+        }
+
+        /// Parses a for statement: for ( [init] ; [cond] ; [post] ) stmt
+        ///
+        /// Compiles like `init; while (cond) { stmt post; }`, with an empty
+        /// condition always true. The post clause is skipped on the first pass
+        /// and compiled after the body.
+        fn parse_for(&mut self) -> Result<StmtKind, String> {
+            self.pos += 1; // consume 'for'
+            self.expect_condition_open("for")?;
+            let mut init = None;
+            if !self.eat(&Token::Semicolon) {
+                self.parse_expr()?;
+                self.expect(&Token::Semicolon)?;
+                self.emit(Opcode::Pop);
+                init = Some(self.pop_expr());
+            }
+            let loop_start = self.opcodes.len() as i64;
+            let mut cond = None;
+            if self.current() == &Token::Semicolon {
+                self.emit(Opcode::Imm(1));
+            } else {
+                self.parse_condition()?;
+                cond = Some(self.pop_expr());
+            }
+            self.expect(&Token::Semicolon)?;
+            let constant = self.take_constant_condition(loop_start as usize);
+            let jz_index = self.opcodes.len();
+            self.emit_loop_exit(constant);
+            let post_start = self.pos;
+            self.skip_to_close_paren()?;
+            let (body, jumps) = self.parse_loop_body()?;
+            self.patch_jumps(&jumps.continues, self.opcodes.len());
+            let body_end = self.pos;
+            self.pos = post_start;
+            let mut post = None;
+            if self.current() != &Token::RParen {
+                self.parse_expr()?;
+                self.emit(Opcode::Pop);
+                post = Some(self.pop_expr());
+            }
+            self.expect(&Token::RParen)?;
+            self.pos = body_end;
+            self.emit(Opcode::Jmp(loop_start));
+            self.close_loop(constant, jz_index, &jumps.breaks);
+            Ok(StmtKind::For(init, cond, post, Box::new(body)))
+        }
+
+        /// Advances past the `)` matching an already consumed `(`.
+        fn skip_to_close_paren(&mut self) -> Result<(), String> {
+            let mut depth = 0;
+            loop {
+                match self.current() {
+                    Token::LParen => depth += 1,
+                    Token::RParen if depth == 0 => break,
+                    Token::RParen => depth -= 1,
+                    Token::EOF => return Err(format!("Expected ')' before end of input at {}", self.current_span())),
+                    _ => {},
+                }
+                self.pos += 1;
+            }
+            self.pos += 1; // consume ')'
+            Ok(())
+        }
+
+        /// Parses a local variable declaration: int x, y = expr, *p, a[4];
+        ///
+        /// With a leading `static` the variables live in global storage instead of
+        /// the frame, so they keep their values between runs of the function.
+        fn parse_local_decl(&mut self) -> Result<Decl, String> {
+            let start = self.current_span();
+            let is_static = self.eat(&Token::Static);
+            let (base, is_const, members) = self.parse_decl_specifiers()?;
+            let mut declarators = Vec::new();
+            if matches!(base, Type::Struct(_)) && self.eat(&Token::Semicolon) {
+                let range = self.range_from(start);
+                return Ok(Decl { is_static, is_const, base, members, declarators, range });
+            }
+            loop {
+                let declarator_start = self.current_span();
+                let ty = self.parse_pointers(base.clone());
+                match self.current() {
+                    Token::Ident(name) => {
+                        let var_name = name.clone();
+                        let name_span = self.current_span();
+                        self.pos += 1;
+                        self.check_object_type(&var_name, &ty)?;
+                        let dims = self.parse_dims(&ty)?;
+                        let dim_exprs = self.pop_exprs(dims.len());
+                        let symbol = if is_static {
+                            self.declare_static(var_name, ty, is_const, dims, name_span)?
+                        } else {
+                            let offset = self.local_offset + 1;
+                            let cells = self.cells(&ty);
+                            let symbol = Symbol { name: var_name.clone(), class: SymbolClass::Local, ty, offset, is_const, dims, cells };
+                            self.local_offset += symbol.size();
+                            self.locals.insert(var_name, symbol.clone());
+                            symbol
+                        };
+                        if !symbol.dims.is_empty() && self.current() == &Token::Assign {
+                            return Err(format!("Array initializers are not supported at {}", self.current_span()));
+                        }
+                        if matches!(symbol.ty, Type::Struct(_)) && self.current() == &Token::Assign {
+                            return Err(format!("Struct initializers are not supported at {}", self.current_span()));
+                        }
+                        if is_static && self.current() == &Token::Assign {
+                            // Statics start at zero; running an initializer on every
+                            // call would reset them.
+                            return Err(format!("Static local initializers are not supported at {}", self.current_span()));
+                        }
+                        let mut init = None;
+                        if self.eat(&Token::Assign) {
+                            // Initializer: stored like an assignment, value discarded.
+                            let assign_span = self.prev_span();
+                            let value_ty = self.parse_assignment()?;
+                            self.emit_store(&symbol, &value_ty, assign_span)?;
+                            self.emit_at(Opcode::Pop, assign_span);
+                            init = Some(self.pop_expr());
+                        }
+                        let range = self.range_from(declarator_start);
+                        declarators.push(Declarator { name: symbol.name, ty: symbol.ty, dims: dim_exprs, init, range });
+                    },
+                    _ => return Err(format!(
+                        "Expected identifier in local declaration, found {:?} at {}",
+                        self.current(),
+                        self.current_span()
+                    )),
+                }
+                if self.eat(&Token::Comma) {
+                    continue;
+                } else {
+                    break;
+                }
+            }
+            self.expect(&Token::Semicolon)?;
+            let range = self.range_from(start);
+            Ok(Decl { is_static, is_const, base, members, declarators, range })
+        }
+
+        /// Parses an expression.
+        ///
+        /// Supports the comma operator, assignment (identifier = expr) and additive
+        /// expressions. Every expression leaves exactly one value on the stack,
+        /// pushes its tree onto `exprs` and returns its static type.
+        fn parse_expr(&mut self) -> Result<Type, String> {
+            self.parse_comma()
+        }
+
+        /// Parses a comma expression: evaluates operands left to right, discarding
+        /// all but the last value.
+        ///
+        /// Contexts where a comma is a separator (declarators and call arguments)
+        /// must parse each item with `parse_assignment` instead,
+        /// so that only a parenthesized comma expression is treated as an operator.
+        fn parse_comma(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_assignment()?;
+            while self.current() == &Token::Comma {
+                let comma_span = self.current_span();
+                self.pos += 1;
+                self.emit_at(Opcode::Pop, comma_span);
+                ty = self.parse_assignment()?;
+                self.push_binary(Token::Comma, start);
+            }
+            Ok(ty)
+        }
+
+        /// Parses an assignment expression: lvalue = expr (right-associative).
+        ///
+        /// The left side is parsed as an ordinary expression; it is an lvalue only
+        /// if it compiled to a single variable load or ends in an array element or
+        /// pointer load, which is then replaced by the matching store (as in the
+        /// original C4). A `const` variable is rejected here; its declaration
+        /// initializer does not go through this path.
+        fn parse_assignment(&mut self) -> Result<Type, String> {
+            let start_span = self.current_span();
+            let start = self.opcodes.len();
+            let ty = self.parse_conditional()?;
+            let compound = compound_operator(self.current());
+            if self.current() != &Token::Assign && compound.is_none() {
+                return Ok(ty);
+            }
+            let assign_span = self.current_span();
+            self.check_enabled(self.current(), assign_span)?;
+            // For an array element the index computation stays; only the element
+            // load is replaced by the store.
+            let (target, keep) = match (&self.opcodes[start..], &self.last_load) {
+                ([Opcode::Ld(_)] | [Opcode::LdG(_)], Some((lvalue @ Lvalue::Var(_), _))) => (lvalue.clone(), start),
+                ([.., Opcode::LdX(..) | Opcode::LdGX(..) | Opcode::Load], Some((lvalue, begin))) if *begin == start => {
+                    (lvalue.clone(), self.opcodes.len() - 1)
+                },
+                _ => return Err(format!("lvalue required as left operand of assignment at {}", assign_span)),
+            };
+            if let Lvalue::Var(sym) = &target {
+                if sym.is_const {
+                    return Err(format!("assignment of read-only variable '{}' at {}", sym.name, assign_span));
+                }
+            }
+            let op = self.current().clone();
+            self.pos += 1; // consume '=' or 'op='
+            let value_ty = match compound {
+                // `x op= e` keeps the load of `x` as the left operand. An element
+                // or pointer target duplicates its index or address, which the
+                // store needs again.
+                Some(op) => {
+                    if let Some(Opcode::LdX(..) | Opcode::LdGX(..) | Opcode::Load) = self.opcodes.last() {
+                        self.opcodes.insert(keep, Opcode::Dup);
+                        self.opcode_spans.insert(keep, assign_span);
+                    }
+                    let right = self.parse_assignment()?;
+                    let result = self.binary_type(&op, &ty, &right, assign_span)?;
+                    if let Some(opcode) = binary_opcode(&op) {
+                        self.check_divisor(&opcode, &result, assign_span)?;
+                        self.emit_at(opcode, assign_span);
+                    }
+                    result
+                },
+                None => {
+                    self.opcodes.truncate(keep);
+                    self.opcode_spans.truncate(keep);
+                    self.parse_assignment()?
+                },
+            };
+            let value = self.pop_expr();
+            let target_expr = self.pop_expr();
+            self.push_expr(ExprKind::Assign(op, Box::new(target_expr), Box::new(value)), start_span);
+            // Generate store opcode.
+            match target {
+                Lvalue::Var(sym) => {
+                    self.emit_store(&sym, &value_ty, assign_span)?;
+                    Ok(sym.ty)
+                },
+                Lvalue::Deref(ty) => {
+                    self.emit_convert(&value_ty, &ty, assign_span)?;
+                    self.emit_at(Opcode::Store, assign_span);
+                    Ok(ty)
+                },
+            }
+        }
+
+        /// Parses a conditional expression: cond ? expr : conditional (right-associative).
+        ///
+        /// If either arm is a double the result is a double; the trailing `IToF`
+        /// is a no-op when the double arm ran.
+        fn parse_conditional(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let cond_ty = self.parse_logical_or()?;
+            if self.current() != &Token::Question {
+                return Ok(cond_ty);
+            }
+            let question_span = self.current_span();
+            self.check_enabled(&Token::Question, question_span)?;
+            self.check_scalar(&cond_ty, question_span)?;
+            self.pos += 1; // consume '?'
+            let jz_index = self.opcodes.len();
+            self.emit_at(Opcode::Jz(0), question_span); // placeholder for the false arm
+            let then_ty = self.parse_expr()?;
+            self.expect(&Token::Colon)?;
+            let colon_span = self.prev_span();
+            let jmp_index = self.opcodes.len();
+            self.emit_at(Opcode::Jmp(0), colon_span); // placeholder for the end
+            self.opcodes[jz_index] = Opcode::Jz(self.opcodes.len() as i64);
+            let else_ty = self.parse_conditional()?;
+            self.opcodes[jmp_index] = Opcode::Jmp(self.opcodes.len() as i64);
+            let arms = self.pop_exprs(3);
+            let [cond, then, otherwise] = arms.try_into().expect("three operands were parsed");
+            self.push_expr(ExprKind::Conditional(Box::new(cond), Box::new(then), Box::new(otherwise)), start);
+            if then_ty.pointee().is_some() {
+                return Ok(then_ty);
+            } else if else_ty.pointee().is_some() {
+                return Ok(else_ty);
+            }
+            let ty = Type::arithmetic(&then_ty, &else_ty);
+            if ty == Type::Double && (then_ty != Type::Double || else_ty != Type::Double) {
+                self.emit_at(Opcode::IToF, question_span);
+            }
+            Ok(ty)
+        }
+
+        /// Parses `||`. The result is 1 as soon as an operand is nonzero, and the
+        /// remaining operands are skipped; otherwise it is 0.
+        fn parse_logical_or(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_logical_and()?;
+            if self.current() != &Token::Or {
+                return Ok(ty);
+            }
+            let span = self.current_span();
+            self.check_enabled(&Token::Or, span)?;
+            let mut to_true = Vec::new();
+            loop {
+                self.check_logical_operand(&ty, span)?;
+                let jz_index = self.opcodes.len();
+                self.emit_at(Opcode::Jz(0), span); // placeholder for the next operand
+                to_true.push(self.opcodes.len());
+                self.emit_at(Opcode::Jmp(0), span); // placeholder for the true result
+                self.opcodes[jz_index] = Opcode::Jz(self.opcodes.len() as i64);
+                if !self.eat(&Token::Or) {
+                    break;
+                }
+                ty = self.parse_logical_and()?;
+                self.push_binary(Token::Or, start);
+            }
+            self.emit_at(Opcode::Imm(0), span);
+            let jmp_index = self.opcodes.len();
+            self.emit_at(Opcode::Jmp(0), span); // placeholder for the end
+            self.patch_jumps(&to_true, self.opcodes.len());
+            self.emit_at(Opcode::Imm(1), span);
+            self.opcodes[jmp_index] = Opcode::Jmp(self.opcodes.len() as i64);
+            self.last_load = None;
+            Ok(Type::Int)
+        }
+
+        /// Parses `&&`. The result is 0 as soon as an operand is zero, and the
+        /// remaining operands are skipped; otherwise it is 1.
+        fn parse_logical_and(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_equality()?;
+            if self.current() != &Token::And {
+                return Ok(ty);
+            }
+            let span = self.current_span();
+            self.check_enabled(&Token::And, span)?;
+            let mut to_false = Vec::new();
+            loop {
+                self.check_logical_operand(&ty, span)?;
+                to_false.push(self.opcodes.len());
+                self.emit_at(Opcode::Jz(0), span); // placeholder for the false result
+                if !self.eat(&Token::And) {
+                    break;
+                }
+                ty = self.parse_equality()?;
+                self.push_binary(Token::And, start);
+            }
+            self.emit_at(Opcode::Imm(1), span);
+            let jmp_index = self.opcodes.len();
+            self.emit_at(Opcode::Jmp(0), span); // placeholder for the end
+            let false_addr = self.opcodes.len() as i64;
+            for index in to_false {
+                self.opcodes[index] = Opcode::Jz(false_addr);
+            }
+            self.emit_at(Opcode::Imm(0), span);
+            self.opcodes[jmp_index] = Opcode::Jmp(self.opcodes.len() as i64);
+            self.last_load = None;
+            Ok(Type::Int)
+        }
+
+        /// Rejects a `void` or struct operand of `&&` or `||`, which has no truth value.
+        fn check_logical_operand(&self, ty: &Type, span: Span) -> Result<(), String> {
+            if *ty == Type::Void {
+                return Err(format!("void value not ignored as it ought to be at {}", span));
+            }
+            self.check_scalar(ty, span)
+        }
+
+        /// Parses `==` and `!=`.
+        fn parse_equality(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_relational()?;
+            while let Token::Eq | Token::Ne = self.current() {
+                ty = self.parse_comparison(start, ty, Self::parse_relational)?;
+            }
+            Ok(ty)
+        }
+
+        /// Parses `<`, `>`, `<=` and `>=`.
+        fn parse_relational(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_additive()?;
+            while let Token::Lt | Token::Gt | Token::Le | Token::Ge = self.current() {
+                ty = self.parse_comparison(start, ty, Self::parse_additive)?;
+            }
+            Ok(ty)
+        }
+
+        /// Parses the comparison operator at the current token and its right
+        /// operand, whose left operand began at `start` and has type `left`. The
+        /// result is an int, 1 or 0; an int compared with a double is promoted
+        /// by the VM.
+        fn parse_comparison(
+            &mut self,
+            start: Span,
+            left: Type,
+            operand: fn(&mut Self) -> Result<Type, String>,
+        ) -> Result<Type, String> {
+            let op = self.current().clone();
+            let op_span = self.current_span();
+            self.check_enabled(&op, op_span)?;
+            self.pos += 1;
+            let right = operand(self)?;
+            self.push_binary(op.clone(), start);
+            self.check_scalar(&left, op_span)?;
+            self.check_scalar(&right, op_span)?;
+            let mixed_pointer = (left.pointee().is_some() && right == Type::Double)
+                || (right.pointee().is_some() && left == Type::Double);
+            if mixed_pointer {
+                return Err(format!("invalid operands to binary {:?} ('{}' and '{}') at {}", op, left, right, op_span));
+            }
+            if let Some(opcode) = binary_opcode(&op) {
+                self.emit_at(opcode, op_span);
+            }
+            Ok(Type::Int)
+        }
+
+        /// Parses `+` and `-`. Mixed int/double operands are promoted by the VM;
+        /// the parser only computes the result type.
+        ///
+        /// A pointer plus or minus an integer is a pointer that many elements
+        /// away; the difference of two pointers is an element count.
+        fn parse_additive(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_term()?;
+            while let Token::Plus | Token::Minus = self.current() {
+                let op = self.current().clone();
+                let op_span = self.current_span();
+                self.check_enabled(&op, op_span)?;
+                self.pos += 1;
+                let right = self.parse_term()?;
+                self.push_binary(op.clone(), start);
+                if let Some(opcode) = binary_opcode(&op) {
+                    self.emit_binary(opcode, op_span);
+                }
+                ty = self.binary_type(&op, &ty, &right, op_span)?;
+            }
+            Ok(ty)
+        }
+
+        /// Returns the type of an arithmetic binary operation, rejecting operands
+        /// the operator does not accept. A pointer may be offset by an integer,
+        /// and two pointers subtracted; `%` takes integers only.
+        fn binary_type(&self, op: &Token, left: &Type, right: &Type, span: Span) -> Result<Type, String> {
+            self.check_scalar(left, span)?;
+            self.check_scalar(right, span)?;
+            if let Some(Type::Struct(_)) = left.pointee().or(right.pointee()) {
+                return Err(format!("arithmetic on '{}' and '{}' is not supported at {}", left, right, span));
+            }
+            let pointers = (left.pointee().is_some(), right.pointee().is_some());
+            let double_mod = *op == Token::Mod && (*left == Type::Double || *right == Type::Double);
+            match (pointers, op) {
+                ((false, false), _) if !double_mod => Ok(Type::arithmetic(left, right)),
+                ((true, true), Token::Minus) => Ok(Type::Int),
+                ((true, false), Token::Plus | Token::Minus) if *right != Type::Double => Ok(left.clone()),
+                ((false, true), Token::Plus) if *left != Type::Double => Ok(right.clone()),
+                _ => Err(format!("invalid operands to binary {:?} ('{}' and '{}') at {}", op, left, right, span)),
+            }
+        }
+
+        /// Emits a binary operator, folding `Imm a; Imm b; op` into a single `Imm`
+        /// for `+`, `-` and `*`. Division is left to the VM, whose rounding is
+        /// chosen at run time. Nothing is folded when a jump lands between the
+        /// operands, as at the end of a `?:`.
+        fn emit_binary(&mut self, opcode: Opcode, span: Span) {
+            let len = self.opcodes.len();
+            let jumped_into = self.jumped_near_end();
+            if let [.., Opcode::Imm(a), Opcode::Imm(b)] = self.opcodes[..] {
+                let folded = match opcode {
+                    Opcode::Add => Some(a.wrapping_add(b)),
+                    Opcode::Sub => Some(a.wrapping_sub(b)),
+                    Opcode::Mul => Some(a.wrapping_mul(b)),
+                    _ => None,
+                };
+                if let (Some(value), false) = (folded, jumped_into) {
+                    self.discard_from(len - 1);
+                    self.opcodes[len - 2] = Opcode::Imm(value);
+                    return;
+                }
+            }
+            self.emit_at(opcode, span);
+        }
+
+        /// Rejects an integer `/` or `%` whose divisor compiled to the constant
+        /// zero, which would always fail at run time. Double division by zero
+        /// is left to the VM, which yields an infinity.
+        fn check_divisor(&self, opcode: &Opcode, result: &Type, span: Span) -> Result<(), String> {
+            let divides = matches!(opcode, Opcode::Div | Opcode::Mod) && *result != Type::Double;
+            if divides && self.opcodes.last() == Some(&Opcode::Imm(0)) && !self.jumped_near_end() {
+                return Err(format!("division by zero at {}", span));
+            }
+            Ok(())
+        }
+
+        /// Returns true if a jump in the current function lands on the last
+        /// opcode emitted or just after it, so that the last two opcodes are
+        /// not straight-line code that can be folded.
+        fn jumped_near_end(&self) -> bool {
+            let len = self.opcodes.len();
+            self.opcodes[self.entry..].iter().any(|op| match op {
+                Opcode::Jmp(target) | Opcode::Jz(target) => *target as usize + 1 >= len,
+                _ => false,
+            })
+        }
+
+        /// Emits a prefix `-`, `!` or `~` applied to an operand of type `ty`,
+        /// returning the result type.
+        ///
+        /// Each operator has its own opcode: `Neg`, `LogicalNot` and `Not`. An
+        /// operand that ends in an immediate is folded into a single immediate
+        /// instead, as for binary operators.
+        fn emit_unary(&mut self, op: &Token, ty: &Type, span: Span) -> Result<Type, String> {
+            if *ty == Type::Void {
+                return Err(format!("void value not ignored as it ought to be at {}", span));
+            }
+            self.check_scalar(ty, span)?;
+            let result = match op {
+                Token::Tilde if *ty == Type::Double || ty.pointee().is_some() => {
+                    return Err(format!("wrong type argument to bit-complement at {}", span));
+                },
+                Token::Minus if ty.pointee().is_some() => {
+                    return Err(format!("wrong type argument to unary minus at {}", span));
+                },
+                Token::Minus if *ty == Type::Double => Type::Double,
+                _ => Type::Int,
+            };
+            let folded = match (op, self.opcodes.last()) {
+                (Token::Minus, Some(Opcode::Imm(n))) => Some(Opcode::Imm(n.wrapping_neg())),
+                (Token::Minus, Some(Opcode::FImm(f))) => Some(Opcode::FImm(-f)),
+                (Token::Bang, Some(Opcode::Imm(n))) => Some(Opcode::Imm((*n == 0) as i64)),
+                (Token::Bang, Some(Opcode::FImm(f))) => Some(Opcode::Imm((*f == 0.0) as i64)),
+                (Token::Tilde, Some(Opcode::Imm(n))) => Some(Opcode::Imm(!n)),
+                _ => None,
+            };
+            match folded {
+                Some(opcode) if !self.jumped_near_end() => {
+                    let last = self.opcodes.len() - 1;
+                    self.opcodes[last] = opcode;
+                },
+                _ => {
+                    let opcode = unary_opcode(op).expect("parse_unary passes only unary operators");
+                    self.emit_at(opcode, span);
+                },
+            }
+            Ok(result)
+        }
+
+        fn parse_term(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let mut ty = self.parse_unary()?;
+            while let Token::Mul | Token::Div | Token::Mod = self.current() {
+                let op = self.current().clone();
+                let op_span = self.current_span();
+                self.check_enabled(&op, op_span)?;
+                self.pos += 1;
+                let right = self.parse_unary()?;
+                self.push_binary(op.clone(), start);
+                let result = self.binary_type(&op, &ty, &right, op_span)?;
+                if let Some(opcode) = binary_opcode(&op) {
+                    self.check_divisor(&opcode, &result, op_span)?;
+                    self.emit_binary(opcode, op_span);
+                }
+                ty = result;
+            }
+            Ok(ty)
+        }
+
+        /// Parses a unary expression: a prefix operator applied to a unary
+        /// expression, or a postfix expression.
+        fn parse_unary(&mut self) -> Result<Type, String> {
+            if self.current() == &Token::Sizeof {
+                return self.parse_sizeof();
+            }
+            if self.current() == &Token::Amp {
+                return self.parse_address_of();
+            }
+            if self.current() == &Token::Mul {
+                // Dereference: *expr
+                let begin = self.opcodes.len();
+                let op_span = self.current_span();
+                self.check_enabled(&Token::Mul, op_span)?;
+                self.pos += 1;
+                let ty = self.parse_unary()?;
+                self.push_unary(Token::Mul, op_span);
+                let pointee = self.deref_type(&ty, op_span)?;
+                if let Type::Struct(_) = pointee {
+                    // The pointer is the struct's address, which stands for the struct.
+                    self.last_load = None;
+                    return Ok(pointee);
+                }
+                self.emit_at(Opcode::Load, op_span);
+                self.last_load = Some((Lvalue::Deref(pointee.clone()), begin));
+                return Ok(pointee);
+            }
+            match self.current() {
+                Token::Minus | Token::Bang | Token::Tilde => {
+                    let op = self.current().clone();
+                    let op_span = self.current_span();
+                    self.check_enabled(&op, op_span)?;
+                    self.pos += 1;
+                    let ty = self.parse_unary()?;
+                    self.push_unary(op.clone(), op_span);
+                    self.emit_unary(&op, &ty, op_span)
+                },
+                _ => self.parse_postfix(),
+            }
+        }
+
+        /// Parses `sizeof (type-name)` or `sizeof expr` and emits the size in
+        /// bytes: 8 for int, double and pointers, 1 for char, and the element
+        /// size times the element count for arrays. The operand is not evaluated.
+        fn parse_sizeof(&mut self) -> Result<Type, String> {
+            let sizeof_span = self.current_span();
+            self.pos += 1; // consume 'sizeof'
+            let type_name = self.current() == &Token::LParen && self.tokens.get(self.pos + 1).is_some_and(is_type_token);
+            let (ty, dims) = if type_name {
+                self.pos += 1; // consume '('
+                let (base, _, members) = self.parse_decl_specifiers()?;
+                if members.is_some() {
+                    return Err(format!("struct definitions are not supported in sizeof at {}", sizeof_span));
+                }
+                let ty = self.parse_pointers(base);
+                let dims = self.parse_dims(&ty)?;
+                self.expect(&Token::RParen)?;
+                let dim_exprs = self.pop_exprs(dims.len());
+                self.push_expr(ExprKind::SizeofType(ty.clone(), dim_exprs), sizeof_span);
+                (ty, dims)
+            } else if let Some(sym) = self.whole_array_operand() {
+                let operand = self.pop_expr();
+                self.push_expr(ExprKind::SizeofExpr(Box::new(operand)), sizeof_span);
+                (sym.ty, sym.dims)
+            } else {
+                let begin = self.opcodes.len();
+                let ty = self.parse_unary()?;
+                self.discard_from(begin);
+                let operand = self.pop_expr();
+                self.push_expr(ExprKind::SizeofExpr(Box::new(operand)), sizeof_span);
+                (ty, Vec::new())
+            };
+            match self.byte_size(&ty) {
+                Some(size) => {
+                    self.emit_at(Opcode::Imm(size * dims.iter().product::<i64>()), sizeof_span);
+                    Ok(Type::Int)
+                },
+                None => Err(format!("invalid application of 'sizeof' to incomplete type '{}' at {}", ty, sizeof_span)),
+            }
+        }
+
+        /// Consumes `name` or `(name)` when it names a whole, unsubscripted
+        /// array, pushing its tree and returning the array's symbol.
+        fn whole_array_operand(&mut self) -> Option<Symbol> {
+            let parens = self.current() == &Token::LParen;
+            let at = self.pos + parens as usize;
+            let Some(Token::Ident(name)) = self.tokens.get(at) else {
+                return None;
+            };
+            let sym = self.lookup(name).filter(|sym| !sym.dims.is_empty())?.clone();
+            let next = self.tokens.get(at + 1);
+            if (parens && next != Some(&Token::RParen)) || (!parens && next == Some(&Token::LBracket)) {
+                return None;
+            }
+            self.pos = at + 1 + parens as usize;
+            let range = Range { start: self.spans[at], end: self.ends[at] };
+            self.exprs.push(Expr { kind: ExprKind::Ident(sym.name.clone()), range });
+            Some(sym)
+        }
+
+        /// Parses `&operand`, turning the operand's load into its address.
+        ///
+        /// Globals and global array elements have fixed addresses and locals are
+        /// addressed relative to the current frame; `&*p`, `&p[i]` and `&p->m`
+        /// simply drop the final `Load`, and a struct is its address already.
+        fn parse_address_of(&mut self) -> Result<Type, String> {
+            let amp_span = self.current_span();
+            self.check_enabled(&Token::Amp, amp_span)?;
+            self.pos += 1; // consume '&'
+            let begin = self.opcodes.len();
+            let ty = self.parse_unary()?;
+            self.push_unary(Token::Amp, amp_span);
+            if let Type::Struct(_) = ty {
+                // A struct operand has already left its address.
+                self.last_load = None;
+                return Ok(ty.ptr_to());
+            }
+            let last = self.opcodes.len().checked_sub(1).filter(|&last| last >= begin);
+            let lvalue = match &self.last_load {
+                Some((lvalue, start)) if *start == begin => Some(lvalue.clone()),
+                _ => None,
+            };
+            match (last.map(|last| self.opcodes[last].clone()), lvalue) {
+                (Some(Opcode::LdG(offset)), Some(Lvalue::Var(_))) if last == Some(begin) => {
+                    self.opcodes[begin] = Opcode::AddrG(offset, 1);
+                },
+                (Some(Opcode::LdGX(offset, len)), Some(Lvalue::Var(_))) => {
+                    let last = self.opcodes.len() - 1;
+                    self.opcodes[last] = Opcode::AddrG(offset, len);
+                    self.emit_at(Opcode::Add, amp_span);
+                },
+                (Some(Opcode::Ld(offset)), Some(Lvalue::Var(_))) if last == Some(begin) => {
+                    self.opcodes[begin] = Opcode::Lea(offset);
+                },
+                (Some(Opcode::LdX(offset, _)), Some(Lvalue::Var(_))) => {
+                    let last = self.opcodes.len() - 1;
+                    self.opcodes[last] = Opcode::Lea(offset);
+                    self.emit_at(Opcode::Add, amp_span);
+                },
+                (Some(Opcode::Load), Some(Lvalue::Deref(_))) => {
+                    self.opcodes.pop();
+                    self.opcode_spans.pop();
+                },
+                _ => return Err(format!("lvalue required as unary '&' operand at {}", amp_span)),
+            }
+            self.last_load = None;
+            Ok(ty.ptr_to())
+        }
+
+        /// Returns the type a pointer of type `ty` points to, rejecting non-pointers
+        /// and `void *`.
+        fn deref_type(&self, ty: &Type, span: Span) -> Result<Type, String> {
+            match ty.pointee() {
+                Some(Type::Void) => Err(format!("dereferencing 'void *' pointer at {}", span)),
+                Some(pointee) => Ok(pointee.clone()),
+                None => Err(format!("invalid type argument of unary '*' (have '{}') at {}", ty, span)),
+            }
+        }
+
+        /// Parses a factor followed by pointer subscripts and member accesses:
+        /// `p[i]` is `*(p + i)`, `s.m` is the member `m` of the struct `s`, and
+        /// `p->m` is `(*p).m`.
+        fn parse_postfix(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            let begin = self.opcodes.len();
+            let mut ty = self.parse_factor()?;
+            loop {
+                let span = self.current_span();
+                ty = match self.current() {
+                    Token::LBracket => {
+                        self.pos += 1;
+                        if ty.pointee().is_none() {
+                            return Err(format!("subscripted value is neither array nor pointer at {}", span));
+                        }
+                        let pointee = self.deref_type(&ty, span)?;
+                        if let Type::Struct(_) = pointee {
+                            return Err(format!("subscripting '{}' is not supported at {}", ty, span));
+                        }
+                        if self.parse_expr()? == Type::Double {
+                            return Err(format!("array subscript is not an integer at {}", span));
+                        }
+                        self.expect(&Token::RBracket)?;
+                        let index = self.pop_expr();
+                        let base = self.pop_expr();
+                        self.push_expr(ExprKind::Index(Box::new(base), Box::new(index)), start);
+                        self.emit_at(Opcode::Add, span);
+                        self.emit_at(Opcode::Load, span);
+                        self.last_load = Some((Lvalue::Deref(pointee.clone()), begin));
+                        pointee
+                    },
+                    Token::Dot => {
+                        self.check_enabled(&Token::Dot, span)?;
+                        self.pos += 1;
+                        match &ty {
+                            Type::Struct(tag) => self.parse_member(&tag.clone(), Token::Dot, begin, start, span)?,
+                            _ => return Err(format!("request for a member in '{}', which is not a struct at {}", ty, span)),
+                        }
+                    },
+                    Token::Arrow => {
+                        self.check_enabled(&Token::Arrow, span)?;
+                        self.pos += 1;
+                        match ty.pointee() {
+                            Some(Type::Struct(tag)) => self.parse_member(&tag.clone(), Token::Arrow, begin, start, span)?,
+                            _ => return Err(format!("invalid type argument of '->' (have '{}') at {}", ty, span)),
+                        }
+                    },
+                    _ => return Ok(ty),
+                };
+            }
+        }
+
+        /// Parses the member name after `op`, `.` or `->` (at `span`), applied
+        /// to a `struct tag` whose address is on the stack and whose expression
+        /// began at `start`, and emits the member's load. A member that is
+        /// itself a struct is left as its address.
+        fn parse_member(&mut self, tag: &str, op: Token, begin: usize, start: Span, span: Span) -> Result<Type, String> {
+            let name_span = self.current_span();
+            let name = self.eat_ident().ok_or_else(|| format!("Expected member name at {}", name_span))?;
+            let object = self.pop_expr();
+            self.push_expr(ExprKind::Member(op, Box::new(object), name.clone()), start);
+            let Some(def) = self.structs.get(tag) else {
+                return Err(format!("invalid use of incomplete type 'struct {}' at {}", tag, span));
+            };
+            let Some(member) = def.members.iter().find(|member| member.name == name).cloned() else {
+                return Err(format!("'struct {}' has no member named '{}' at {}", tag, name, name_span));
+            };
+            if member.offset != 0 {
+                self.emit_at(Opcode::Imm(member.offset), span);
+                self.emit_at(Opcode::Add, span);
+            }
+            if let Type::Struct(_) = member.ty {
+                self.last_load = None;
+            } else {
+                self.emit_at(Opcode::Load, span);
+                self.last_load = Some((Lvalue::Deref(member.ty.clone()), begin));
+            }
+            Ok(member.ty)
+        }
+
+        /// Parses a call to a builtin after its name: `malloc(n)`, `free(p)` or `print(x)`.
+        ///
+        /// `malloc` reserves `n` cells, which covers any object of `n` bytes since
+        /// no element is smaller than a byte. `free` leaves a dummy value so that
+        /// the call is still an expression; its type is `void`. `print` yields
+        /// its argument unchanged.
+        fn parse_builtin(&mut self, name: &str) -> Result<Type, String> {
+            let call_span = self.prev_span();
+            self.expect(&Token::LParen)?;
+            let arg = self.parse_assignment()?;
+            self.expect(&Token::RParen)?;
+            let arg_expr = self.pop_expr();
+            self.push_expr(ExprKind::Call(name.to_string(), vec![arg_expr]), call_span);
+            self.check_scalar(&arg, call_span)?;
+            match name {
+                "malloc" if arg == Type::Double || arg.pointee().is_some() => {
+                    Err(format!("malloc expects an integer size, found '{}' at {}", arg, call_span))
+                },
+                "malloc" => {
+                    self.emit_at(Opcode::Malloc, call_span);
+                    Ok(Type::Void.ptr_to())
+                },
+                "print" if arg == Type::Void => Err(format!("print expects a value, found 'void' at {}", call_span)),
+                "print" => {
+                    self.emit_at(Opcode::Print(arg.clone()), call_span);
+                    Ok(arg)
+                },
+                _ if arg.pointee().is_none() => {
+                    Err(format!("free expects a pointer, found '{}' at {}", arg, call_span))
+                },
+                _ => {
+                    self.emit_at(Opcode::Free, call_span);
+                    Ok(Type::Void)
+                },
+            }
+        }
+
+        /// Parses a call after the function's name: `f(a, b)`. The callee must be
+        /// defined before the call, or be the function being compiled; the
+        /// call has the callee's return type.
+        ///
+        /// Arguments are evaluated left to right and converted to the parameter
+        /// types, as for assignment.
+        fn parse_call(&mut self, name: &str) -> Result<Type, String> {
+            let call_span = self.prev_span();
+            if self.lookup(name).is_some() {
+                return Err(format!("called object '{}' is not a function at {}", name, call_span));
+            }
+            let callee = match self.functions.iter().find(|f| f.name == name) {
+                Some(function) => function.clone(),
+                None => return Err(format!("Undefined function '{}' at {}", name, call_span)),
+            };
+            let params = callee.params.clone();
+            self.expect(&Token::LParen)?;
+            let mut args = 0;
+            while self.current() != &Token::RParen {
+                if args > 0 {
+                    self.expect(&Token::Comma)?;
+                }
+                let arg_span = self.current_span();
+                let ty = self.parse_assignment()?;
+                if let Some(param) = params.get(args) {
+                    self.emit_convert(&ty, param, arg_span)?;
+                }
+                args += 1;
+            }
+            self.pos += 1; // consume ')'
+            if args != params.len() {
+                let which = if args < params.len() { "few" } else { "many" };
+                return Err(format!(
+                    "too {} arguments to function '{}' (expected {}, found {}) at {}",
+                    which, name, params.len(), args, call_span
+                ));
+            }
+            let arg_exprs = self.pop_exprs(args);
+            self.push_expr(ExprKind::Call(name.to_string(), arg_exprs), call_span);
+            match callee.inline_body {
+                Some(body) if self.config.inline => self.emit_inline(&callee, body, call_span)?,
+                _ => self.emit_at(Opcode::Call(callee.entry as i64, args as i64), call_span),
+            }
+            self.last_load = None;
+            Ok(callee.ret_type)
+        }
+
+        /// Emits the body of `callee` in place of a call whose arguments are on
+        /// the operand stack. The arguments are stored in fresh locals of the
+        /// caller, so each is evaluated once, and the returned expression is
+        /// compiled again with the parameters bound to those locals and nothing
+        /// else of the caller's scope visible.
+        fn emit_inline(&mut self, callee: &Function, body: (usize, usize), call_span: Span) -> Result<(), String> {
+            let base = self.local_offset;
+            self.local_offset += callee.params.len() as i64;
+            for slot in (base + 1..=self.local_offset).rev() {
+                self.emit_at(Opcode::St(slot), call_span);
+                self.emit_at(Opcode::Pop, call_span);
+            }
+            let params = callee
+                .locals
+                .iter()
+                .filter(|sym| sym.class == SymbolClass::Local && sym.offset <= callee.params.len() as i64)
+                .map(|sym| (sym.name.clone(), Symbol { offset: base + sym.offset, ..sym.clone() }));
+            let caller_locals = std::mem::replace(&mut self.locals, params.collect());
+            let (resume, exprs) = (self.pos, self.exprs.len());
+            self.pos = body.0;
+            let ty = self.parse_expr();
+            self.pos = resume;
+            self.exprs.truncate(exprs); // The call's tree stands for the inlined body.
+            self.locals = caller_locals;
+            self.emit_convert(&ty?, &callee.ret_type, call_span)
+        }
+
+        /// Parses a string literal, joining adjacent literals as C does, and
+        /// returns its offset in the data segment. Identical literals share storage.
+        fn parse_string(&mut self) -> Result<i64, String> {
+            let start = self.current_span();
+            let mut text = String::new();
+            while let Token::Str(part) = self.current() {
+                text.push_str(part);
+                self.pos += 1;
+            }
+            self.push_expr(ExprKind::Str(text.clone()), start);
+            if let Some(&offset) = self.strings.get(&text) {
+                return Ok(offset);
+            }
+            let offset = self.data.len() as i64;
+            self.data.extend(text.bytes().map(|b| Value::Int(b as i8 as i64)));
+            self.data.push(Value::Int(0));
+            self.strings.insert(text, offset);
+            Ok(offset)
+        }
+
+        fn parse_factor(&mut self) -> Result<Type, String> {
+            let start = self.current_span();
+            match self.current() {
+                Token::Num(n) => {
+                    let value = *n;
+                    self.pos += 1;
+                    self.push_expr(ExprKind::Num(value), start);
+                    self.emit(Opcode::Imm(value));
+                    Ok(Type::Int)
+                },
+                Token::Float(f) => {
+                    let value = *f;
+                    self.pos += 1;
+                    self.push_expr(ExprKind::Float(value), start);
+                    self.emit(Opcode::FImm(value));
+                    Ok(Type::Double)
+                },
+                Token::Str(_) => {
+                    let offset = self.parse_string()?;
+                    self.emit(Opcode::Addr(offset));
+                    Ok(Type::Char.ptr_to())
+                },
+                Token::Ident(name) => {
+                    let var_name = name.clone();
+                    self.pos += 1;
+                    if BUILTINS.contains(&var_name.as_str()) && self.current() == &Token::LParen {
+                        return self.parse_builtin(&var_name);
+                    }
+                    if self.current() == &Token::LParen {
+                        return self.parse_call(&var_name);
+                    }
+                    if let Some(sym) = self.lookup(&var_name).cloned() {
+                        self.push_expr(ExprKind::Ident(var_name), start);
+                        if let Type::Struct(_) = sym.ty {
+                            self.emit_address(&sym);
+                        } else if sym.dims.is_empty() {
+                            self.emit_load(&sym);
+                        } else {
+                            self.parse_index(&sym)?;
+                        }
+                        Ok(sym.ty.clone())
+                    } else {
+                        Err(format!("Undefined variable: {} at {}", var_name, self.prev_span()))
+                    }
+                },
+                Token::LParen => {
+                    self.pos += 1;
+                    let ty = self.parse_expr()?;
+                    self.expect(&Token::RParen)?;
+                    Ok(ty)
+                },
+                _ => Err(format!("Unexpected token in factor: {:?} at {}", self.current(), self.current_span())),
+            }
+        }
+
+        /// Public API: parses tokens into opcodes.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn parse(mut self) -> ParseResult {
+            self.parse_program()?;
+            Ok(self.opcodes)
+        }
+
+        /// Public API: parses tokens into a `Program` with source positions.
+        pub fn parse_spanned(mut self) -> SpannedParseResult {
+            self.parse_program()?;
+            Ok(self.into_program())
+        }
+
+        /// Public API: parses a script, whose top-level statements run in order
+        /// in an implicit `main` returning the value of the final expression
+        /// statement (its `;` may be omitted).
+        ///
+        /// Top-level declarations become locals of that `main`. Function
+        /// definitions may appear between statements; their code is jumped over.
+        pub fn parse_script(mut self) -> SpannedParseResult {
+            self.functions.push(Function {
+                name: "main".to_string(),
+                entry: 0,
+                ret_type: Type::Int,
+                params: Vec::new(),
+                locals: Vec::new(),
+                inline_body: None,
+            });
+            self.emit(Opcode::Ent(0)); // frame size patched below
+            let mut result = None;
+            while self.current() != &Token::EOF {
+                if self.at_function_definition() {
+                    self.parse_script_function()?;
+                    continue;
+                }
+                let is_expr = !self.at_type() && !matches!(
+                    self.current(),
+                    Token::Return | Token::If | Token::While | Token::For | Token::Do | Token::Switch
+                        | Token::Break | Token::Continue | Token::LBrace | Token::Static
+                );
+                if !is_expr {
+                    let stmt = self.parse_stmt()?;
+                    self.items.push(Item::Stmt(stmt));
+                    continue;
+                }
+                let span = self.current_span();
+                let ty = self.parse_expr()?;
+                if self.current() != &Token::EOF {
+                    self.expect(&Token::Semicolon)?;
+                }
+                let stmt = Stmt { kind: StmtKind::Expr(self.pop_expr()), range: self.range_from(span) };
+                self.items.push(Item::Stmt(stmt));
+                if self.current() == &Token::EOF {
+                    self.check_scalar(&ty, span)?;
+                    self.emit(Opcode::Ret);
+                    result = Some(ty);
+                } else {
+                    self.emit(Opcode::Pop);
+                }
+            }
+            if result.is_none() && self.end_reachable() {
+                self.emit(Opcode::Imm(0));
+                self.emit(Opcode::Ret);
+            }
+            self.opcodes[0] = Opcode::Ent(self.local_offset);
+            self.frame_size = self.frame_size.max(self.local_offset as usize);
+            if let Some(main) = self.functions.last_mut() {
+                main.locals = storage_order(&self.locals);
+                main.ret_type = result.unwrap_or(Type::Int);
+            }
+            Ok(self.into_program())
+        }
+
+        /// Returns true if the tokens ahead start a function definition:
+        /// a type, a name and `(`.
+        fn at_function_definition(&mut self) -> bool {
+            let start = self.pos;
+            let structs = self.structs.clone(); // A struct defined while looking ahead is defined again later.
+            let found = self.at_type() && self.parse_decl_specifiers().is_ok() && {
+                self.parse_pointers(Type::Void);
+                self.eat_ident().is_some() && self.current() == &Token::LParen
+            };
+            self.pos = start;
+            self.structs = structs;
+            found
+        }
+
+        /// Parses a function definition inside a script, keeping the implicit
+        /// `main`'s locals and jumping over the function's code.
+        fn parse_script_function(&mut self) -> Result<(), String> {
+            let start = self.current_span();
+            let (base, _, members) = self.parse_decl_specifiers()?;
+            if members.is_some() {
+                return Err(format!("struct definitions are not supported in a return type at {}", start));
+            }
+            let ret_type = self.parse_pointers(base);
+            let name_span = self.current_span();
+            let name = self.eat_ident().ok_or_else(|| format!("Expected function name at {}", name_span))?;
+            self.expect(&Token::LParen)?;
+            let skip = self.opcodes.len();
+            self.emit(Opcode::Jmp(0)); // patched past the function
+            let locals = std::mem::take(&mut self.locals);
+            let local_offset = self.local_offset;
+            self.parse_function(start, name, name_span, ret_type)?;
+            self.opcodes[skip] = Opcode::Jmp(self.opcodes.len() as i64);
+            // Keep the implicit main last, so its statics are named after it.
+            let function = self.functions.pop().expect("function was just parsed");
+            self.functions.insert(self.functions.len() - 1, function);
+            self.locals = locals;
+            self.local_offset = local_offset;
+            self.entry = 0;
+            self.ret_type = Type::Int;
+            Ok(())
+        }
+
+        /// Consumes an identifier, returning its name.
+        fn eat_ident(&mut self) -> Option<String> {
+            match self.current() {
+                Token::Ident(name) => {
+                    let name = name.clone();
+                    self.pos += 1;
+                    Some(name)
+                },
+                _ => None,
+            }
+        }
+
+        /// Bundles the parsed code and symbols into a `Program` entered at `main`.
+        fn into_program(self) -> Program {
+            let main = self.functions.iter().find(|f| f.name == "main");
+            Program {
+                entry: main.map_or(0, |f| f.entry),
+                main_type: main.map_or(Type::Int, |f| f.ret_type.clone()),
+                opcodes: self.opcodes,
+                spans: self.opcode_spans,
+                globals: self.globals.values().map(Symbol::size).sum::<i64>() as usize,
+                frame_size: self.frame_size,
+                data: self.data,
+                functions: self.functions,
+                variables: storage_order(&self.globals),
+                ast: Ast { items: self.items },
+            }
+        }
+    }
+
+    /// Returns the symbols of a scope ordered by where they are stored: frame
+    /// slots first, then global slots.
+    fn storage_order(symbols: &HashMap<String, Symbol>) -> Vec<Symbol> {
+        let mut symbols: Vec<Symbol> = symbols.values().cloned().collect();
+        symbols.sort_by_key(|sym| (sym.class == SymbolClass::Global, sym.offset));
+        symbols
+    }
+
+    /// Public function to parse tokens.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn parse(tokens: Vec<Token>) -> ParseResult {
+        let parser = Parser::new(tokens);
+        parser.parse()
+    }
+
+    /// Returns the static type of a bare expression, resolving names against the
+    /// global declarations in `definitions`. The expression is not run.
+    pub fn expression_type(definitions: Vec<Spanned<Token>>, expr: Vec<Spanned<Token>>) -> Result<Type, String> {
+        let mut parser = Parser::with_spans(definitions);
+        parser.parse_program()?;
+        // Leave the scope of the last function parsed, so that only globals resolve.
+        parser.locals.clear();
+        parser.local_offset = 0;
+        parser.set_tokens(expr);
+        let ty = parser.parse_expr()?;
+        if parser.current() != &Token::EOF {
+            return Err(format!(
+                "Unexpected token after expression: {:?} at {}",
+                parser.current(),
+                parser.current_span()
+            ));
+        }
+        Ok(ty)
+    }
+
+    /// Public function to parse positioned tokens, returning a span for every opcode.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn parse_spanned(tokens: Vec<Spanned<Token>>) -> SpannedParseResult {
+        parse_with_config(tokens, Config::default())
+    }
+
+    /// Public function to parse positioned tokens under the given language settings.
+    pub fn parse_with_config(tokens: Vec<Spanned<Token>>, mut config: Config) -> SpannedParseResult {
+        let mut parser = Parser::with_spans(tokens);
+        parser.data = std::mem::take(&mut config.data);
+        parser.config = config;
+        parser.parse_spanned()
+    }
+
+    /// Public function to parse positioned tokens as a script with an implicit `main`.
+    pub fn parse_script(tokens: Vec<Spanned<Token>>) -> SpannedParseResult {
+        Parser::with_spans(tokens).parse_script()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::lexer::tokenize_spanned;
+
+        #[test]
+        fn test_opcode_spans_parallel_opcodes() {
+            let src = "int main() {\n    int a;\n    a = 1;\n    return a + 42;\n}";
+            let Program { opcodes, spans, .. } = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            assert_eq!(opcodes.len(), spans.len());
+            let imm = opcodes.iter().position(|op| *op == Opcode::Imm(42)).unwrap();
+            assert_eq!(spans[imm], Span { line: 4, col: 16 });
+            let add = opcodes.iter().position(|op| *op == Opcode::Add).unwrap();
+            assert_eq!(spans[add], Span { line: 4, col: 14 });
+        }
+
+        #[test]
+        fn test_constant_loop_conditions_elided() {
+            let compile = |src: &str| parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            let program = compile("int main() { int n; n = 0; while (1) { n = n + 1; if (n > 4) return n; } }");
+            let loop_start = program.opcodes.iter().position(|op| *op == Opcode::Ld(1)).unwrap();
+            assert!(program.opcodes.contains(&Opcode::Jmp(loop_start as i64)));
+            // Only the if's test remains; the loop itself checks nothing.
+            assert_eq!(program.opcodes.iter().filter(|op| matches!(op, Opcode::Jz(_))).count(), 1);
+            assert_eq!(vm::execute_from(&program.opcodes, program.entry), Ok(Value::Int(5)));
+
+            let forever = compile("int main() { for (;;) return 3; }");
+            assert!(!forever.opcodes.iter().any(|op| matches!(op, Opcode::Jz(_))));
+            assert_eq!(vm::execute_from(&forever.opcodes, forever.entry), Ok(Value::Int(3)));
+
+            let never = compile("int main() { int n; n = 2; while (0) n = 9; for (n = n + 1; 0.0; n = 7) n = 8; return n; }");
+            assert!(!never.opcodes.iter().any(|op| matches!(op, Opcode::Jz(_) | Opcode::Jmp(_) | Opcode::Imm(8) | Opcode::Imm(9))));
+            assert_eq!(vm::execute_from(&never.opcodes, never.entry), Ok(Value::Int(3)));
+        }
+
+        #[test]
+        fn test_unary_operators_fold_constants() {
+            let body = |expr: &str| {
+                let src = format!("int main() {{ return {}; }}", expr);
+                let program = parse_spanned(tokenize_spanned(&src).unwrap()).unwrap();
+                program.opcodes[1..program.opcodes.len() - 1].to_vec()
+            };
+            assert_eq!(body("-(2+3)"), [Opcode::Imm(-5)]);
+            assert_eq!(body("!0"), [Opcode::Imm(1)]);
+            assert_eq!(body("~0"), [Opcode::Imm(-1)]);
+            assert_eq!(body("!-~4 + 10 - -2.5"), [Opcode::Imm(10), Opcode::FImm(-2.5), Opcode::Sub, Opcode::FToI]);
+            // Only immediates fold; a `?:` result is not one, though its false arm ends in one.
+            let src = "int main() { int c; double d; int *p; c = 1; d = 0.5; return -c * 100 + -d * 10 + !p * 1000 + -(c ? 1 : 2); }";
+            let program = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            assert_eq!(vm::execute_from(&program.opcodes, program.entry), Ok(Value::Int(-100 - 5 + 1000 - 1)));
+
+            let err = |src: &str| parse_spanned(tokenize_spanned(src).unwrap()).unwrap_err();
+            assert_eq!(err("int main() { int *p; return -p; }"), "wrong type argument to unary minus at 1:29");
+            assert_eq!(err("int main() { return ~1.5; }"), "wrong type argument to bit-complement at 1:21");
+        }
+
+        #[test]
+        fn test_globals_get_incrementing_offsets() {
+            let src = "int a; int b; int main() { int l; a = 30; b = 12; l = 1; return a + b + l; }";
+            let program = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            let offsets: Vec<(String, i64)> = program.variables.iter().map(|sym| (sym.name.clone(), sym.offset)).collect();
+            assert_eq!(offsets, [("a".to_string(), 0), ("b".to_string(), 1)]);
+            assert_eq!(program.globals, 2);
+            for op in [Opcode::StG(0), Opcode::StG(1), Opcode::LdG(0), Opcode::LdG(1), Opcode::St(1), Opcode::Ld(1)] {
+                assert!(program.opcodes.contains(&op), "missing {:?}", op);
+            }
+            assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(43));
+        }
+
+        #[test]
+        fn test_syntax_tree_keeps_source_order() {
+            // The post clause of a for is compiled after the body and array
+            // sizes are evaluated as they are parsed, but the tree follows the source.
+            let src = "int main() { int a[2 * 3], i; for (i = 0; i < 6; i = i + 1) a[i] = i; switch (i) { case 1 + 1: break; } return 0; }";
+            let program = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            let [ast::Item::Function(main)] = &program.ast.items[..] else {
+                panic!("expected one function, found {:?}", program.ast.items);
+            };
+            let StmtKind::Decl(decl) = &main.body[0].kind else {
+                panic!("expected a declaration");
+            };
+            assert!(matches!(&decl.declarators[0].dims[..], [Expr { kind: ExprKind::Binary(Token::Mul, ..), .. }]));
+            let StmtKind::For(Some(init), Some(cond), Some(post), body) = &main.body[1].kind else {
+                panic!("expected a for with all clauses");
+            };
+            assert!(matches!(init.kind, ExprKind::Assign(..)));
+            assert!(matches!(cond.kind, ExprKind::Binary(Token::Lt, ..)));
+            assert!(matches!(&post.kind, ExprKind::Assign(_, _, value) if matches!(value.kind, ExprKind::Binary(Token::Plus, ..))));
+            assert!(matches!(&body.kind, StmtKind::Expr(Expr { kind: ExprKind::Assign(_, target, _), .. }) if matches!(target.kind, ExprKind::Index(..))));
+            let StmtKind::Switch(_, switch_body) = &main.body[2].kind else {
+                panic!("expected a switch");
+            };
+            assert!(matches!(&switch_body.kind, StmtKind::Block(body) if matches!(&body[0].kind, StmtKind::Case(..))));
+            // The statement's range runs from `for` to the end of its body.
+            assert_eq!((main.body[1].range.start.col, main.body[1].range.end.col), (31, 70));
+        }
+
+        #[test]
+        fn test_implicit_return_only_when_reachable() {
+            let count_rets = |src: &str| {
+                let program = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+                program.opcodes.iter().filter(|op| **op == Opcode::Ret).count()
+            };
+            assert_eq!(count_rets("int main() { int x; x = 2; return x; }"), 1);
+            assert_eq!(count_rets("int main() { int x; x = 2; }"), 1);
+            assert_eq!(count_rets("int main() { if (1) return 1; else return 2; }"), 2);
+            // The if jumps past its return, so the implicit one is still needed.
+            assert_eq!(count_rets("int main() { int x; if (x) return 1; }"), 2);
+        }
+
+        #[test]
+        fn test_inline_small_functions() {
+            let src = "int g;
+                int sq(int x) { return x * x; }
+                char low(int a, double b) { return a * 2 + b; }
+                int global(int a) { return a + g; }
+                int twice(int n) { return sq(n) + sq(n); }
+                int main() {
+                    int r;
+                    g = 3;
+                    r = sq(g = g + 1) * 1000;
+                    r = r + low(100, 0.5) + global(5) * 10 + twice(2) * 100000;
+                    { int g; g = 40; r = r + global(1) * 1000000; }
+                    return r;
+                }";
+            let compile = |inline| parse_with_config(tokenize_spanned(src).unwrap(), Config { inline, ..Config::default() }).unwrap();
+            let calls = |program: &Program| program.opcodes.iter().filter(|op| matches!(op, Opcode::Call(..))).count();
+            let (plain, inlined) = (compile(false), compile(true));
+            // sq(g = g + 1) increments g once: 16 * 1000; low narrows 200.5 to char -56;
+            // global sees the global g (4), not the block's g; twice(2) is 8.
+            let expected = Value::Int(16_000 - 56 + 90 + 800_000 + 5_000_000);
+            assert_eq!(vm::execute_from(&plain.opcodes, plain.entry), Ok(expected));
+            assert_eq!(vm::execute_from(&inlined.opcodes, inlined.entry), Ok(expected));
+            assert_eq!(calls(&plain), 7);
+            // Only twice, which calls sq, is still called; its own calls to sq are inlined.
+            assert_eq!(calls(&inlined), 1);
+            assert_eq!(inlined.ast, plain.ast);
+            assert_eq!(crate::analysis::verify(&inlined), Ok(()));
+        }
+
+        #[test]
+        fn test_disabled_operators() {
+            let config = Config { disabled_operators: vec![Token::Mul, Token::Div, Token::PlusAssign], ..Config::default() };
+            let parse = |src: &str| parse_with_config(tokenize_spanned(src).unwrap(), config.clone());
+            assert_eq!(
+                parse("int main() { return 2 * 3; }").unwrap_err(),
+                "operator '*' is disabled in this configuration at 1:23"
+            );
+            assert!(parse("int main() { int x; x = 6; return x / 2; }").unwrap_err().contains("operator '/' is disabled"));
+            assert!(parse("int main() { int x; x += 1; return x; }").unwrap_err().contains("operator '+=' is disabled"));
+            assert!(parse("int main() { int *p, x; p = &x; return *p; }").unwrap_err().contains("operator '*' is disabled"));
+            // Declarators and the remaining operators still work.
+            let program = parse("int main() { int *p, x; p = &x; x = 7 - 2; return x % 3 + (p == &x); }").unwrap();
+            assert_eq!(vm::execute_from(&program.opcodes, program.entry), Ok(Value::Int(3)));
+        }
+
+        #[test]
+        fn test_pointer_star_binds_to_one_declarator() {
+            let src = "int *g, h; int main() { int *a, b, **c, d[2]; return 0; }";
+            let mut parser = Parser::with_spans(tokenize_spanned(src).unwrap());
+            parser.parse_program().unwrap();
+            assert_eq!(parser.globals["g"].ty, Type::Int.ptr_to());
+            assert_eq!(parser.globals["h"].ty, Type::Int);
+            assert_eq!(parser.locals["a"].ty, Type::Int.ptr_to());
+            assert_eq!(parser.locals["b"].ty, Type::Int);
+            assert_eq!(parser.locals["c"].ty, Type::Int.ptr_to().ptr_to());
+            assert_eq!(parser.locals["d"].ty, Type::Int);
+
+            let err = parse_spanned(tokenize_spanned("int main() { int *a, b; return *b; }").unwrap()).unwrap_err();
+            assert!(err.contains("invalid type argument of unary '*' (have 'int')"), "unexpected error: {}", err);
+        }
+
+        #[test]
+        fn test_main_return_type_recorded() {
+            let int_main = parse_spanned(tokenize_spanned("int main() { return 1; }").unwrap()).unwrap();
+            assert_eq!(int_main.main_type, Type::Int);
+            let char_main = parse_spanned(tokenize_spanned("char main() { return 65; }").unwrap()).unwrap();
+            assert_eq!(char_main.main_type, Type::Char);
+        }
+
+        #[test]
+        fn test_functions_recorded_with_entries() {
+            let src = "int add() { return 1 + 2; } double half() { return 0.5; } int main() { return 7; }";
+            let program = parse_spanned(tokenize_spanned(src).unwrap()).unwrap();
+            let names: Vec<&str> = program.functions.iter().map(|f| f.name.as_str()).collect();
+            assert_eq!(names, ["add", "half", "main"]);
+            let entries: Vec<usize> = program.functions.iter().map(|f| f.entry).collect();
+            assert_eq!(entries, [0, 3, 6]);
+            for (i, &entry) in entries.iter().enumerate() {
+                assert!(matches!(program.opcodes[entry], Opcode::Ent(_)));
+                // Each body ends in its own Ret, right before the next function.
+                let end = entries.get(i + 1).copied().unwrap_or(program.opcodes.len());
+                assert_eq!(program.opcodes[end - 1], Opcode::Ret);
+            }
+            assert_eq!(program.function("half").unwrap().ret_type, Type::Double);
+            assert_eq!(program.entry, 6);
+            assert_eq!(vm::execute_from(&program.opcodes, program.entry), Ok(Value::Int(7)));
+            assert_eq!(vm::execute_from(&program.opcodes, entries[0]), Ok(Value::Int(3)));
+        }
+
+        #[test]
+        fn test_const_qualifier_recorded_on_symbol() {
+            let src = "const int g; int main() { int const a = 1; register int b; return a; }";
+            let mut parser = Parser::with_spans(tokenize_spanned(src).unwrap());
+            parser.parse_program().unwrap();
+            assert!(parser.globals["g"].is_const);
+            assert!(parser.locals["a"].is_const);
+            assert!(!parser.locals["b"].is_const);
+        }
+    }
+}
+
+//
+// Module: ast
+//
+mod ast {
+    //! The syntax tree of a program, for tools that need its structure rather
+    //! than its opcodes.
+    //!
+    //! The parser builds the tree as it compiles and returns it in
+    //! `Program::ast`. Parentheses and the spelling of literals are not kept.
+    //! Every node records the source range it was parsed from, and
+    //! `Ast::node_at` finds the innermost node at a position. `to_c` prints a
+    //! tree back as C source.
+
+    use crate::lexer::{escape, Span, Token, OPERATORS, PUNCTUATION};
+    use crate::parser::Type;
+
+    /// Where a node starts and ends in the source.
+    #[derive(Debug, Clone, Copy, Default, PartialEq)]
+    pub struct Range {
+        pub start: Span, // Position of the node's first character.
+        pub end: Span,   // Just past the node's last character.
+    }
+
+    impl Range {
+        /// Returns true if the position lies within the range.
+        pub fn contains(&self, at: Span) -> bool {
+            let key = |span: Span| (span.line, span.col);
+            key(self.start) <= key(at) && key(at) < key(self.end)
+        }
+    }
+
+    /// A parsed program: its top-level items in source order.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Ast {
+        pub items: Vec<Item>,
+    }
+
+    /// A top-level declaration, function definition or, in a script, statement.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Item {
+        Decl(Decl),
+        Function(Function),
+        Stmt(Stmt),
+    }
+
+    /// A function definition.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Function {
+        pub ret_type: Type,
+        pub name: String,
+        pub params: Vec<Param>,
+        pub body: Vec<Stmt>,
+        pub range: Range,
+    }
+
+    /// A function parameter.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Param {
+        pub is_const: bool,
+        pub ty: Type,
+        pub name: String,
+        pub range: Range,
+    }
+
+    /// A declaration such as `static const int x = 1, *p, a[3];`. A specifier
+    /// that defines a struct, as in `struct point { int x; int y; } p;`, keeps
+    /// the member declarations; the declaration may then declare no variables.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Decl {
+        pub is_static: bool,
+        pub is_const: bool,
+        pub base: Type,
+        pub members: Option<Vec<Decl>>,
+        pub declarators: Vec<Declarator>,
+        pub range: Range,
+    }
+
+    /// One declared name with its full type, array dimensions and initializer.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Declarator {
+        pub name: String,
+        pub ty: Type,
+        pub dims: Vec<Expr>,
+        pub init: Option<Expr>,
+        pub range: Range,
+    }
+
+    /// A statement.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Stmt {
+        pub kind: StmtKind,
+        pub range: Range,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum StmtKind {
+        Expr(Expr),
+        Decl(Decl),
+        Return(Expr),
+        If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+        While(Expr, Box<Stmt>),
+        DoWhile(Box<Stmt>, Expr),
+        For(Option<Expr>, Option<Expr>, Option<Expr>, Box<Stmt>), // init; cond; post
+        Switch(Expr, Box<Stmt>),
+        Case(Expr, Box<Stmt>),
+        Default(Box<Stmt>),
+        Break,
+        Continue,
+        Block(Vec<Stmt>),
+    }
+
+    /// An expression.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Expr {
+        pub kind: ExprKind,
+        pub range: Range,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum ExprKind {
+        Num(i64),   // An integer or character literal.
+        Float(f64),
+        Str(String), // Adjacent string literals, joined.
+        Ident(String),
+        Call(String, Vec<Expr>),
+        Unary(Token, Box<Expr>), // `-`, `!`, `~`, `*` (dereference) or `&` (address).
+        Binary(Token, Box<Expr>, Box<Expr>), // Arithmetic, comparison, `&&`, `||` or the comma operator.
+        Assign(Token, Box<Expr>, Box<Expr>), // `=` or a compound assignment such as `+=`.
+        Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+        Index(Box<Expr>, Box<Expr>),
+        Member(Token, Box<Expr>, String), // `.` or `->`, with the member name.
+        SizeofType(Type, Vec<Expr>),      // `sizeof (type-name)`, with any array dimensions.
+        SizeofExpr(Box<Expr>),
+    }
+
+    /// Any node of the tree, as `Ast::node_at` returns it.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum AstNode<'a> {
+        Function(&'a Function),
+        Param(&'a Param),
+        Decl(&'a Decl),
+        Declarator(&'a Declarator),
+        Stmt(&'a Stmt),
+        Expr(&'a Expr),
+    }
+
+    impl<'a> AstNode<'a> {
+        fn item(item: &'a Item) -> AstNode<'a> {
+            match item {
+                Item::Decl(decl) => AstNode::Decl(decl),
+                Item::Function(function) => AstNode::Function(function),
+                Item::Stmt(stmt) => AstNode::Stmt(stmt),
+            }
+        }
+
+        /// The source range the node was parsed from.
+        pub fn range(&self) -> Range {
+            match self {
+                AstNode::Function(function) => function.range,
+                AstNode::Param(param) => param.range,
+                AstNode::Decl(decl) => decl.range,
+                AstNode::Declarator(declarator) => declarator.range,
+                AstNode::Stmt(stmt) => stmt.range,
+                AstNode::Expr(expr) => expr.range,
+            }
+        }
+
+        /// A one-line description of the node, without its children.
+        pub fn label(&self) -> String {
+            match *self {
+                AstNode::Function(function) => format!("function {} -> {}", function.name, function.ret_type),
+                AstNode::Param(param) => format!("param {}{} {}", if param.is_const { "const " } else { "" }, param.ty, param.name),
+                AstNode::Decl(decl) => {
+                    let storage = if decl.is_static { "static " } else { "" };
+                    let qualifier = if decl.is_const { "const " } else { "" };
+                    let defines = if decl.members.is_some() { " {}" } else { "" };
+                    format!("declaration {}{}{}{}", storage, qualifier, decl.base, defines)
+                },
+                AstNode::Declarator(declarator) => format!("declarator {}: {}", declarator.name, declarator.ty),
+                AstNode::Stmt(stmt) => match &stmt.kind {
+                    StmtKind::Expr(_) => "expression statement",
+                    StmtKind::Decl(_) => "declaration statement",
+                    StmtKind::Return(_) => "return",
+                    StmtKind::If(..) => "if",
+                    StmtKind::While(..) => "while",
+                    StmtKind::DoWhile(..) => "do-while",
+                    StmtKind::For(..) => "for",
+                    StmtKind::Switch(..) => "switch",
+                    StmtKind::Case(..) => "case",
+                    StmtKind::Default(_) => "default",
+                    StmtKind::Break => "break",
+                    StmtKind::Continue => "continue",
+                    StmtKind::Block(_) => "block",
+                }
+                .to_string(),
+                AstNode::Expr(expr) => match &expr.kind {
+                    ExprKind::Num(n) => format!("number {}", n),
+                    ExprKind::Float(f) => format!("float {:?}", f),
+                    ExprKind::Str(text) => format!("string {:?}", text),
+                    ExprKind::Ident(name) => format!("identifier {}", name),
+                    ExprKind::Call(name, _) => format!("call {}", name),
+                    ExprKind::Unary(op, _) => format!("unary {}", spelling(op)),
+                    ExprKind::Binary(op, ..) => format!("binary {}", spelling(op)),
+                    ExprKind::Assign(op, ..) => format!("assign {}", spelling(op)),
+                    ExprKind::Conditional(..) => "conditional ?:".to_string(),
+                    ExprKind::Index(..) => "index []".to_string(),
+                    ExprKind::Member(op, _, member) => format!("member {}{}", spelling(op), member),
+                    ExprKind::SizeofType(ty, _) => format!("sizeof {}", ty),
+                    ExprKind::SizeofExpr(_) => "sizeof".to_string(),
+                },
+            }
+        }
+
+        /// The node's children, in source order.
+        pub fn children(&self) -> Vec<AstNode<'a>> {
+            let exprs = |exprs: &'a [Expr]| exprs.iter().map(AstNode::Expr);
+            let stmts = |stmts: &'a [Stmt]| stmts.iter().map(AstNode::Stmt);
+            match *self {
+                AstNode::Function(function) => {
+                    function.params.iter().map(AstNode::Param).chain(stmts(&function.body)).collect()
+                },
+                AstNode::Param(_) => Vec::new(),
+                AstNode::Decl(decl) => {
+                    let members = decl.members.iter().flatten().map(AstNode::Decl);
+                    members.chain(decl.declarators.iter().map(AstNode::Declarator)).collect()
+                },
+                AstNode::Declarator(declarator) => exprs(&declarator.dims).chain(declarator.init.iter().map(AstNode::Expr)).collect(),
+                AstNode::Stmt(stmt) => match &stmt.kind {
+                    StmtKind::Expr(expr) | StmtKind::Return(expr) => vec![AstNode::Expr(expr)],
+                    StmtKind::Decl(decl) => vec![AstNode::Decl(decl)],
+                    StmtKind::If(cond, then, otherwise) => {
+                        let mut children = vec![AstNode::Expr(cond), AstNode::Stmt(then)];
+                        children.extend(otherwise.iter().map(|stmt| AstNode::Stmt(stmt)));
+                        children
+                    },
+                    StmtKind::While(cond, body) | StmtKind::Switch(cond, body) | StmtKind::Case(cond, body) => {
+                        vec![AstNode::Expr(cond), AstNode::Stmt(body)]
+                    },
+                    StmtKind::DoWhile(body, cond) => vec![AstNode::Stmt(body), AstNode::Expr(cond)],
+                    StmtKind::For(init, cond, post, body) => {
+                        let clauses = [init, cond, post].into_iter().flatten().map(AstNode::Expr);
+                        clauses.chain([AstNode::Stmt(body)]).collect()
+                    },
+                    StmtKind::Default(body) => vec![AstNode::Stmt(body)],
+                    StmtKind::Break | StmtKind::Continue => Vec::new(),
+                    StmtKind::Block(body) => stmts(body).collect(),
+                },
+                AstNode::Expr(expr) => match &expr.kind {
+                    ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Str(_) | ExprKind::Ident(_) => Vec::new(),
+                    ExprKind::Call(_, args) => exprs(args).collect(),
+                    ExprKind::Unary(_, operand) | ExprKind::Member(_, operand, _) | ExprKind::SizeofExpr(operand) => {
+                        vec![AstNode::Expr(operand)]
+                    },
+                    ExprKind::Binary(_, left, right) | ExprKind::Assign(_, left, right) | ExprKind::Index(left, right) => {
+                        vec![AstNode::Expr(left), AstNode::Expr(right)]
+                    },
+                    ExprKind::Conditional(cond, then, otherwise) => {
+                        vec![AstNode::Expr(cond), AstNode::Expr(then), AstNode::Expr(otherwise)]
+                    },
+                    ExprKind::SizeofType(_, dims) => exprs(dims).collect(),
+                },
+            }
+        }
+    }
+
+    impl Ast {
+        /// Renders the tree as an outline: one node per line, indented two
+        /// spaces per level, with its source range.
+        pub fn outline(&self) -> String {
+            fn write_node(out: &mut String, node: AstNode, depth: usize) {
+                let range = node.range();
+                out.push_str(&format!("{}{} ; {}-{}\n", "  ".repeat(depth), node.label(), range.start, range.end));
+                for child in node.children() {
+                    write_node(out, child, depth + 1);
+                }
+            }
+            let mut out = String::new();
+            for item in &self.items {
+                write_node(&mut out, AstNode::item(item), 0);
+            }
+            out
+        }
+
+        /// Returns the innermost node whose source range contains the 1-based
+        /// position, or `None` if no item covers it.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn node_at(&self, line: usize, col: usize) -> Option<AstNode<'_>> {
+            let at = Span { line, col };
+            let mut found = None;
+            let mut candidates: Vec<AstNode> = self.items.iter().map(AstNode::item).collect();
+            while let Some(node) = candidates.into_iter().find(|node| node.range().contains(at)) {
+                found = Some(node);
+                candidates = node.children();
+            }
+            found
+        }
+
+        /// Returns a copy of the tree with every range cleared, so that the
+        /// trees of differently laid out sources can be compared.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn without_ranges(&self) -> Ast {
+            fn clear_decl(decl: &mut Decl) {
+                decl.range = Range::default();
+                decl.members.iter_mut().flatten().for_each(clear_decl);
+                for declarator in &mut decl.declarators {
+                    declarator.range = Range::default();
+                    declarator.dims.iter_mut().chain(declarator.init.iter_mut()).for_each(clear_expr);
+                }
+            }
+            fn clear_stmt(stmt: &mut Stmt) {
+                stmt.range = Range::default();
+                match &mut stmt.kind {
+                    StmtKind::Expr(expr) | StmtKind::Return(expr) => clear_expr(expr),
+                    StmtKind::Decl(decl) => clear_decl(decl),
+                    StmtKind::If(cond, then, otherwise) => {
+                        clear_expr(cond);
+                        clear_stmt(then);
+                        otherwise.iter_mut().for_each(|stmt| clear_stmt(stmt));
+                    },
+                    StmtKind::While(cond, body)
+                    | StmtKind::DoWhile(body, cond)
+                    | StmtKind::Switch(cond, body)
+                    | StmtKind::Case(cond, body) => {
+                        clear_expr(cond);
+                        clear_stmt(body);
+                    },
+                    StmtKind::For(init, cond, post, body) => {
+                        init.iter_mut().chain(cond.iter_mut()).chain(post.iter_mut()).for_each(clear_expr);
+                        clear_stmt(body);
+                    },
+                    StmtKind::Default(body) => clear_stmt(body),
+                    StmtKind::Break | StmtKind::Continue => {},
+                    StmtKind::Block(body) => body.iter_mut().for_each(clear_stmt),
+                }
+            }
+            fn clear_expr(expr: &mut Expr) {
+                expr.range = Range::default();
+                match &mut expr.kind {
+                    ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Str(_) | ExprKind::Ident(_) => {},
+                    ExprKind::Call(_, args) => args.iter_mut().for_each(clear_expr),
+                    ExprKind::Unary(_, operand) | ExprKind::Member(_, operand, _) | ExprKind::SizeofExpr(operand) => {
+                        clear_expr(operand)
+                    },
+                    ExprKind::Binary(_, left, right) | ExprKind::Assign(_, left, right) | ExprKind::Index(left, right) => {
+                        clear_expr(left);
+                        clear_expr(right);
+                    },
+                    ExprKind::Conditional(cond, then, otherwise) => {
+                        clear_expr(cond);
+                        clear_expr(then);
+                        clear_expr(otherwise);
+                    },
+                    ExprKind::SizeofType(_, dims) => dims.iter_mut().for_each(clear_expr),
+                }
+            }
+            let mut ast = self.clone();
+            for item in &mut ast.items {
+                match item {
+                    Item::Decl(decl) => clear_decl(decl),
+                    Item::Function(function) => {
+                        function.range = Range::default();
+                        function.params.iter_mut().for_each(|param| param.range = Range::default());
+                        function.body.iter_mut().for_each(clear_stmt);
+                    },
+                    Item::Stmt(stmt) => clear_stmt(stmt),
+                }
+            }
+            ast
+        }
+    }
+
+    /// Renders the tree as C source in a canonical layout: one statement per
+    /// line, blocks indented by four spaces, single spaces around binary
+    /// operators, and parentheses only where precedence requires them.
+    /// Parsing the result gives back an equal tree.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn to_c(ast: &Ast) -> String {
+        let mut printer = Printer { out: String::new() };
+        for (i, item) in ast.items.iter().enumerate() {
+            // Function definitions are set off from their neighbours by a blank line.
+            if i > 0 && (matches!(item, Item::Function(_)) || matches!(ast.items[i - 1], Item::Function(_))) {
+                printer.out.push('\n');
+            }
+            match item {
+                Item::Decl(decl) => {
+                    printer.decl(decl, 0);
+                    printer.out.push('\n');
+                },
+                Item::Function(function) => printer.function(function),
+                Item::Stmt(stmt) => printer.stmt(stmt, 0),
+            }
+        }
+        printer.out
+    }
+
+    // Binding strength of each kind of expression, from the comma operator up
+    // to primary expressions; the binary operators take one level each from
+    // `BINARY` on, in the order of `BINARY_LEVELS`.
+    const COMMA: usize = 0;
+    const ASSIGNMENT: usize = 1;
+    const CONDITIONAL: usize = 2;
+    const BINARY: usize = 3;
+    const UNARY: usize = BINARY + BINARY_LEVELS.len();
+    const POSTFIX: usize = UNARY + 1;
+    const PRIMARY: usize = POSTFIX + 1;
+
+    /// How tightly an expression binds.
+    fn precedence(expr: &Expr) -> usize {
+        match &expr.kind {
+            ExprKind::Binary(Token::Comma, ..) => COMMA,
+            ExprKind::Assign(..) => ASSIGNMENT,
+            ExprKind::Conditional(..) => CONDITIONAL,
+            ExprKind::Binary(op, ..) => BINARY + BINARY_LEVELS.iter().position(|level| level.contains(op)).unwrap_or(0),
+            ExprKind::Unary(..) | ExprKind::SizeofType(..) | ExprKind::SizeofExpr(_) => UNARY,
+            ExprKind::Index(..) | ExprKind::Member(..) => POSTFIX,
+            ExprKind::Num(_) | ExprKind::Float(_) | ExprKind::Str(_) | ExprKind::Ident(_) | ExprKind::Call(..) => PRIMARY,
+        }
+    }
+
+    /// Source text of an expression in a position that needs at least
+    /// precedence `min`, parenthesized if it binds more loosely.
+    fn expr_at(expr: &Expr, min: usize) -> String {
+        let text = expr_text(expr);
+        if precedence(expr) < min { format!("({})", text) } else { text }
+    }
+
+    /// Source text of an expression.
+    fn expr_text(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Num(n) => n.to_string(),
+            ExprKind::Float(f) => {
+                // Floats lex only with a decimal point, which whole numbers print without.
+                let text = f.to_string();
+                if text.contains('.') { text } else { text + ".0" }
+            },
+            ExprKind::Str(text) => {
+                let mut out = String::from('"');
+                for c in text.chars() {
+                    match escape(c) {
+                        Some(e) => {
+                            out.push('\\');
+                            out.push(e);
+                        },
+                        None => out.push(c),
+                    }
+                }
+                out.push('"');
+                out
+            },
+            ExprKind::Ident(name) => name.clone(),
+            ExprKind::Call(name, args) => {
+                let args: Vec<String> = args.iter().map(|arg| expr_at(arg, ASSIGNMENT)).collect();
+                format!("{}({})", name, args.join(", "))
+            },
+            ExprKind::Unary(op, operand) => {
+                let operand = expr_at(operand, UNARY);
+                // Keep a space where the two would read as one longer operator,
+                // as `& &x` would here and `- -x` would in C.
+                let joined = format!("{}{}", spelling(op), operand.chars().next().unwrap_or(' '));
+                let space = if joined == "--" || OPERATORS.iter().any(|(text, _)| *text == joined) { " " } else { "" };
+                format!("{}{}{}", spelling(op), space, operand)
+            },
+            ExprKind::Binary(Token::Comma, left, right) => format!("{}, {}", expr_at(left, COMMA), expr_at(right, ASSIGNMENT)),
+            ExprKind::Binary(op, left, right) => {
+                let level = precedence(expr);
+                format!("{} {} {}", expr_at(left, level), spelling(op), expr_at(right, level + 1))
+            },
+            ExprKind::Assign(op, target, value) => {
+                format!("{} {} {}", expr_at(target, CONDITIONAL), spelling(op), expr_at(value, ASSIGNMENT))
+            },
+            ExprKind::Conditional(cond, then, otherwise) => {
+                format!("{} ? {} : {}", expr_at(cond, BINARY), expr_at(then, COMMA), expr_at(otherwise, CONDITIONAL))
+            },
+            ExprKind::Index(base, index) => format!("{}[{}]", expr_at(base, POSTFIX), expr_at(index, COMMA)),
+            ExprKind::Member(op, base, member) => format!("{}{}{}", expr_at(base, POSTFIX), spelling(op), member),
+            ExprKind::SizeofType(ty, dims) => format!("sizeof({}{})", type_name(ty), dims_text(dims)),
+            ExprKind::SizeofExpr(operand) => format!("sizeof {}", expr_at(operand, UNARY)),
+        }
+    }
+
+    /// Array dimensions as written after a declarator: `[3][4]`.
+    fn dims_text(dims: &[Expr]) -> String {
+        dims.iter().map(|dim| format!("[{}]", expr_at(dim, CONDITIONAL))).collect()
+    }
+
+    /// Splits a type into its base type and the number of pointers to it.
+    fn pointers(mut ty: &Type) -> (&Type, usize) {
+        let mut count = 0;
+        while let Type::Ptr(inner) = ty {
+            ty = inner;
+            count += 1;
+        }
+        (ty, count)
+    }
+
+    /// A type as written in `sizeof`: `int`, `char **`.
+    fn type_name(ty: &Type) -> String {
+        match pointers(ty) {
+            (base, 0) => base.to_string(),
+            (base, count) => format!("{} {}", base, "*".repeat(count)),
+        }
+    }
+
+    /// A name declared with a type, as in a parameter list: `char **argv`.
+    fn typed_name(ty: &Type, name: &str) -> String {
+        let (base, count) = pointers(ty);
+        format!("{} {}{}", base, "*".repeat(count), name)
+    }
+
+    /// Writes statements and declarations at a given nesting depth.
+    struct Printer {
+        out: String,
+    }
+
+    impl Printer {
+        fn indent(&mut self, depth: usize) {
+            self.out.push_str(&"    ".repeat(depth));
+        }
+
+        fn function(&mut self, function: &Function) {
+            let params: Vec<String> = function
+                .params
+                .iter()
+                .map(|param| format!("{}{}", if param.is_const { "const " } else { "" }, typed_name(&param.ty, &param.name)))
+                .collect();
+            self.out.push_str(&format!("{}({}) ", typed_name(&function.ret_type, &function.name), params.join(", ")));
+            self.block(&function.body, 0);
+            self.out.push('\n');
+        }
+
+        /// Writes a declaration from the current column through its `;`.
+        fn decl(&mut self, decl: &Decl, depth: usize) {
+            if decl.is_static {
+                self.out.push_str("static ");
+            }
+            if decl.is_const {
+                self.out.push_str("const ");
+            }
+            self.out.push_str(&decl.base.to_string());
+            if let Some(members) = &decl.members {
+                self.out.push_str(" {\n");
+                for member in members {
+                    self.indent(depth + 1);
+                    self.decl(member, depth + 1);
+                    self.out.push('\n');
+                }
+                self.indent(depth);
+                self.out.push('}');
+            }
+            let declarators: Vec<String> = decl
+                .declarators
+                .iter()
+                .map(|declarator| {
+                    let (_, count) = pointers(&declarator.ty);
+                    let init = declarator.init.as_ref().map_or(String::new(), |init| format!(" = {}", expr_at(init, ASSIGNMENT)));
+                    format!("{}{}{}{}", "*".repeat(count), declarator.name, dims_text(&declarator.dims), init)
+                })
+                .collect();
+            if !declarators.is_empty() {
+                self.out.push(' ');
+                self.out.push_str(&declarators.join(", "));
+            }
+            self.out.push(';');
+        }
+
+        /// Writes a statement on lines of its own.
+        fn stmt(&mut self, stmt: &Stmt, depth: usize) {
+            self.indent(depth);
+            self.stmt_text(stmt, depth);
+            self.out.push('\n');
+        }
+
+        /// Writes `{`, the statements indented one level, and `}`.
+        fn block(&mut self, body: &[Stmt], depth: usize) {
+            if body.is_empty() {
+                self.out.push_str("{}");
+                return;
+            }
+            self.out.push_str("{\n");
+            for stmt in body {
+                self.stmt(stmt, depth + 1);
+            }
+            self.indent(depth);
+            self.out.push('}');
+        }
+
+        /// Writes the body of a control statement after its header: a block
+        /// on the same line, anything else indented on the next.
+        fn body(&mut self, body: &Stmt, depth: usize) {
+            if let StmtKind::Block(stmts) = &body.kind {
+                self.out.push(' ');
+                self.block(stmts, depth);
+            } else {
+                self.out.push('\n');
+                self.indent(depth + 1);
+                self.stmt_text(body, depth + 1);
+            }
+        }
+
+        /// Continues after a body written by `body` with `text`: on the line
+        /// of a block's `}`, otherwise on a new line.
+        fn after_body(&mut self, body: &Stmt, depth: usize, text: &str) {
+            if matches!(body.kind, StmtKind::Block(_)) {
+                self.out.push(' ');
+            } else {
+                self.out.push('\n');
+                self.indent(depth);
+            }
+            self.out.push_str(text);
+        }
+
+        /// Writes a statement from the current column, without a final newline.
+        fn stmt_text(&mut self, stmt: &Stmt, depth: usize) {
+            match &stmt.kind {
+                StmtKind::Expr(expr) => self.out.push_str(&format!("{};", expr_text(expr))),
+                StmtKind::Decl(decl) => self.decl(decl, depth),
+                StmtKind::Return(value) => self.out.push_str(&format!("return {};", expr_text(value))),
+                StmtKind::If(cond, then, otherwise) => {
+                    self.out.push_str(&format!("if ({})", expr_text(cond)));
+                    self.body(then, depth);
+                    if let Some(otherwise) = otherwise {
+                        self.after_body(then, depth, "else");
+                        // `else if` chains stay at one level.
+                        if let StmtKind::If(..) = otherwise.kind {
+                            self.out.push(' ');
+                            self.stmt_text(otherwise, depth);
+                        } else {
+                            self.body(otherwise, depth);
+                        }
+                    }
+                },
+                StmtKind::While(cond, body) => {
+                    self.out.push_str(&format!("while ({})", expr_text(cond)));
+                    self.body(body, depth);
+                },
+                StmtKind::DoWhile(body, cond) => {
+                    self.out.push_str("do");
+                    self.body(body, depth);
+                    self.after_body(body, depth, &format!("while ({});", expr_text(cond)));
+                },
+                StmtKind::For(init, cond, post, body) => {
+                    let clause = |expr: &Option<Expr>| expr.as_ref().map_or(String::new(), |expr| format!(" {}", expr_text(expr)));
+                    let init = init.as_ref().map_or(String::new(), expr_text);
+                    self.out.push_str(&format!("for ({};{};{})", init, clause(cond), clause(post)));
+                    self.body(body, depth);
+                },
+                StmtKind::Switch(value, body) => {
+                    self.out.push_str(&format!("switch ({})", expr_text(value)));
+                    self.body(body, depth);
+                },
+                StmtKind::Case(value, body) => {
+                    self.out.push_str(&format!("case {}: ", expr_at(value, CONDITIONAL)));
+                    self.stmt_text(body, depth);
+                },
+                StmtKind::Default(body) => {
+                    self.out.push_str("default: ");
+                    self.stmt_text(body, depth);
+                },
+                StmtKind::Break => self.out.push_str("break;"),
+                StmtKind::Continue => self.out.push_str("continue;"),
+                StmtKind::Block(body) => self.block(body, depth),
+            }
+        }
+    }
+
+    /// The source spelling of an operator or punctuation token.
+    fn spelling(token: &Token) -> &'static str {
+        OPERATORS.iter().chain(PUNCTUATION).find(|(_, t)| t == token).map_or("?", |(text, _)| *text)
+    }
+
+    /// Left-associative binary operators from loosest to tightest binding.
+    const BINARY_LEVELS: &[&[Token]] = &[
+        &[Token::Or],
+        &[Token::And],
+        &[Token::Eq, Token::Ne],
+        &[Token::Lt, Token::Gt, Token::Le, Token::Ge],
+        &[Token::Plus, Token::Minus],
+        &[Token::Mul, Token::Div, Token::Mod],
+    ];
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::lexer::tokenize_spanned;
+        use crate::parser::{parse_script, parse_spanned};
+
+        fn parse_source(source: &str) -> Ast {
+            parse_spanned(tokenize_spanned(source).unwrap()).unwrap().ast
+        }
+
+        fn parse_script_source(source: &str) -> Ast {
+            parse_script(tokenize_spanned(source).unwrap()).unwrap().ast
+        }
+
+        #[test]
+        fn test_node_at_finds_identifier_in_tree() {
+            let source = "int g;\nint main() {\n    int a;\n    a = g + 1;\n    return a * 2;\n}";
+            let ast = parse_source(source);
+            let Some(AstNode::Expr(node)) = ast.node_at(4, 9) else {
+                panic!("no expression at 4:9");
+            };
+            assert_eq!(node.kind, ExprKind::Ident("g".to_string()));
+            assert_eq!((node.range.start, node.range.end), (Span { line: 4, col: 9 }, Span { line: 4, col: 10 }));
+            // Between the operands the innermost node is the addition.
+            let Some(AstNode::Expr(sum)) = ast.node_at(4, 11) else {
+                panic!("no expression at 4:11");
+            };
+            assert!(matches!(&sum.kind, ExprKind::Binary(Token::Plus, left, _) if left.kind == ExprKind::Ident("g".to_string())));
+            assert!(matches!(ast.node_at(5, 5), Some(AstNode::Stmt(Stmt { kind: StmtKind::Return(_), .. }))));
+            assert!(matches!(ast.node_at(3, 9), Some(AstNode::Declarator(Declarator { name, .. })) if name == "a"));
+            assert!(matches!(ast.node_at(2, 1), Some(AstNode::Function(Function { name, .. })) if name == "main"));
+            assert_eq!(ast.node_at(7, 1), None);
+        }
+
+        #[test]
+        fn test_parser_builds_tree() {
+            let ast = parse_source("struct p { int x; }; int f(struct p *q) { if (q->x > 1) return -q->x; else return 2 + 3 * 4; }");
+            assert!(matches!(&ast.items[0], Item::Decl(Decl { members: Some(members), declarators, .. }) if members.len() == 1 && declarators.is_empty()));
+            let Item::Function(function) = &ast.items[1] else {
+                panic!("expected a function");
+            };
+            assert_eq!(function.params[0].ty, Type::Struct("p".to_string()).ptr_to());
+            let StmtKind::If(cond, _, Some(otherwise)) = &function.body[0].kind else {
+                panic!("expected if-else");
+            };
+            assert!(matches!(&cond.kind, ExprKind::Binary(Token::Gt, left, _) if matches!(left.kind, ExprKind::Member(Token::Arrow, ..))));
+            let StmtKind::Return(value) = &otherwise.kind else {
+                panic!("expected return");
+            };
+            // `*` binds tighter than `+`.
+            assert!(matches!(&value.kind, ExprKind::Binary(Token::Plus, _, right) if matches!(right.kind, ExprKind::Binary(Token::Mul, ..))));
+            assert_eq!(
+                parse_spanned(tokenize_spanned("int main() { return 1 }").unwrap()).map(|program| program.ast),
+                Err("Expected Semicolon, found RBrace at 1:23".to_string())
+            );
+        }
+
+        #[test]
+        fn test_parse_script_items() {
+            let ast = parse_script_source("int x; int twice(int n) { return 2 * n; } x = 4; twice(x)");
+            assert_eq!(ast.items.len(), 4);
+            assert!(matches!(&ast.items[1], Item::Function(Function { name, .. }) if name == "twice"));
+            assert!(matches!(&ast.items[3], Item::Stmt(Stmt { kind: StmtKind::Expr(Expr { kind: ExprKind::Call(..), .. }), .. })));
+        }
+
+        #[test]
+        fn test_to_c_canonical_layout() {
+            let source = "int f(int a,int*b){return a;}int  main(){char*s;int x;s=\"a\\tb\";x=((-'A'))&&!x;if(x){x=(x*2);}else while(x<3)x=f((x,1),&x);return sizeof(x)+1.0;}";
+            let expected = "int f(int a, int *b) {\n    return a;\n}\n\nint main() {\n    char *s;\n    int x;\n    s = \"a\\tb\";\n    x = -65 && !x;\n    if (x) {\n        x = x * 2;\n    } else\n        while (x < 3)\n            x = f((x, 1), &x);\n    return sizeof x + 1.0;\n}\n";
+            assert_eq!(to_c(&parse_source(source)), expected);
+            assert_eq!(parse_source(expected).without_ranges(), parse_source(source).without_ranges());
+            assert_ne!(parse_source(expected), parse_source(source));
+            let script = |source: &str| to_c(&parse_script_source(source));
+            let ints = "int a, b, c, d, e, x, y, *p;\n";
+            let script_body = |source: &str| script(&format!("{}{}", ints, source)).strip_prefix(ints).map(str::to_string);
+            assert_eq!(script_body("x = *&b - -c - -(-d);").as_deref(), Some("x = *&b - -c - - -d;\n"));
+            assert_eq!(script_body("x = (a - b) - (c - d) * ((e));").as_deref(), Some("x = a - b - (c - d) * e;\n"));
+            assert_eq!(script_body("x = (y = 1); (a = b) ? c : (d ? e : *p)").as_deref(), Some("x = y = 1;\n(a = b) ? c : d ? e : *p;\n"));
+            let members = "struct s { int n; int *q; } *v; int a, b, x; x = sizeof (a + b) + sizeof(int*[2]) + (*v).n + v->q[1]";
+            let printed = "struct s {\n    int n;\n    int *q;\n} *v;\nint a, b, x;\nx = sizeof (a + b) + sizeof(int *[2]) + (*v).n + v->q[1];\n";
+            assert_eq!(script(members), printed);
+            assert_eq!(script_body("switch(x){case 1 :x=**&p;default:break;}").as_deref(), Some("switch (x) {\n    case 1: x = **&p;\n    default: break;\n}\n"));
+            let decls = "int x; static const struct p { int x; char *s; } a, *b; do x = x - 1; while (x);";
+            assert_eq!(script(decls), "int x;\nstatic const struct p {\n    int x;\n    char *s;\n} a, *b;\ndo\n    x = x - 1;\nwhile (x);\n");
+        }
+    }
+}
+
+//
+// Module: vm
+//
+mod vm {
+    //! The virtual machine (VM) executes opcodes generated by the parser.
+    //!
+    //! This stack-based VM supports integer and floating-point arithmetic,
+    //! variable load/store, and control flow instructions (jumps and conditional
+    //! jumps). Every stack entry and variable slot holds a tagged `Value`.
+    //!
+    //! Locals live in a frame of cells allocated by `Ent` on function entry in
+    //! the stack region of memory, where they have addresses like any other
+    //! object. Expression temporaries use a separate operand stack that grows
+    //! on demand, so expression depth can never overwrite a variable.
+
+    use crate::parser::{Program, SymbolClass, Type};
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::fmt;
+    use std::io::{self, Write};
+    use std::mem::Discriminant;
+    use std::time::Instant;
+
+    /// A runtime value: every stack entry and variable slot holds one of these.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Value {
+        Int(i64),
+        Float(f64),
+    }
+
+    impl Value {
+        /// Returns the value as an integer, truncating a float toward zero.
+        pub fn as_int(self) -> i64 {
+            match self {
+                Value::Int(n) => n,
+                Value::Float(f) => f as i64,
+            }
+        }
+
+        /// Returns the value as a float.
+        pub fn as_float(self) -> f64 {
+            match self {
+                Value::Int(n) => n as f64,
+                Value::Float(f) => f,
+            }
+        }
+    }
+
+    impl Default for Value {
+        fn default() -> Self {
+            Value::Int(0)
+        }
+    }
+
+    impl fmt::Display for Value {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Value::Int(n) => write!(f, "{}", n),
+                Value::Float(x) => write!(f, "{:?}", x), // Always shows a decimal point.
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Opcode {
+        Ent(i64),   // Enter a function: allocate a frame of n local slots, numbered from 1, the first holding the call's arguments.
+        Imm(i64),   // Push immediate value onto the stack.
+        FImm(f64),  // Push a floating-point immediate onto the stack.
+        Lea(i64),   // Push the address of the local at offset in the current frame.
+        Ld(i64),    // Load variable from local offset.
+        St(i64),    // Store top of stack into local variable at offset, leaving it on the stack.
+        LdG(i64),   // Load global variable from its slot.
+        StG(i64),   // Store top of stack into a global slot, leaving it on the stack.
+        LdX(i64, i64),  // Pop an index; load element of the local array at (offset, length).
+        StX(i64, i64),  // Pop a value and an index; store into the local array element, pushing the value.
+        LdGX(i64, i64), // Pop an index; load element of the global array at (offset, length).
+        StGX(i64, i64), // Pop a value and an index; store into the global array element, pushing the value.
+        AddrG(i64, i64), // Push the address of the global object at (offset, length in cells).
+        Addr(i64),  // Push the address of the read-only data at offset, such as a string literal.
+        Bound(i64), // Check that the index on top of the stack lies in 0..n, leaving it in place.
+        Load,       // Pop an address; push the value stored there.
+        Store,      // Pop a value and an address; store the value there, pushing it back.
+        Malloc,     // Pop a cell count; push the address of a fresh heap block.
+        Free,       // Pop a heap block address and release it; push 0.
+        Print(Type), // Write the top value with its static type, leaving it in place.
+        Pop,        // Discard the top of the stack.
+        Dup,        // Push a copy of the top of the stack.
+        SignExt8,   // Keep the low byte of the top value, sign-extended (signed char).
+        ZeroExt8,   // Keep the low byte of the top value, zero-extended (unsigned char).
+        IToF,       // Convert the top value to a float.
+        FToI,       // Convert the top value to an integer, truncating toward zero.
+        Add,        // Add top two values.
+        Sub,        // Subtract top two values.
+        Mul,        // Multiply top two values.
+        Div,        // Divide top two values.
+        Mod,        // Remainder of dividing the top two values (integers only).
+        Eq,         // Compare top two values: 1 if equal, else 0.
+        Ne,         // Compare top two values: 1 if not equal, else 0.
+        Lt,         // Compare top two values: 1 if less than, else 0.
+        Gt,         // Compare top two values: 1 if greater than, else 0.
+        Le,         // Compare top two values: 1 if less or equal, else 0.
+        Ge,         // Compare top two values: 1 if greater or equal, else 0.
+        Neg,        // Negate the top value; a float stays a float.
+        LogicalNot, // Replace the top value with 1 if it is zero, else 0.
+        Not,        // Bitwise complement of the top value.
+        Jmp(i64),   // Unconditional jump to opcode index.
+        Jz(i64),    // Pop the top value and jump if it is zero.
+        Call(i64, i64), // Pop n arguments and call the function whose `Ent` is at this opcode index.
+        Ret,        // Pop the result, leave the frame and resume the caller; in the outermost function, halt.
+        #[cfg_attr(not(test), allow(dead_code))]
+        Custom(u16), // Run the handler registered under this id with `VmState::register_opcode`.
+    }
+
+    /// A runtime error together with the index of the opcode that raised it.
+    ///
+    /// Callers holding the parser's span table can map `pc` back to the source.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct RuntimeError {
+        pub pc: usize,
+        pub message: String,
+        pub stack: Option<Vec<Value>>, // Operand stack at the failure, bottom first; kept only with `Config::debug`.
+    }
+
+    impl RuntimeError {
+        /// Describes the failing opcode and the operand stack, or `None` if the
+        /// error was raised without `Config::debug`.
+        pub fn debug_dump(&self, opcodes: &[Opcode]) -> Option<String> {
+            let stack = self.stack.as_ref()?;
+            let op = opcodes.get(self.pc).map_or("<end of program>".to_string(), |op| format!("{:?}", op));
+            let values: Vec<String> = stack.iter().map(Value::to_string).collect();
+            Some(format!("pc {}: {}, stack [{}] (top last)", self.pc, op, values.join(", ")))
+        }
+    }
+
+    /// Renders opcodes, typically those a run executed in order, as a
+    /// deterministic trace in the style of C4's `-d` output: one line per
+    /// opcode, numbered from 1, such as `3> IMM  5`.
+    ///
+    /// Opcodes with a C4 counterpart use its mnemonic (`Jz` is `BZ`, `Call` is
+    /// `JSR`, `Ret` is `LEV`, `Load` is `LI`, `Store` is `SI`), so traces of the shared subset
+    /// can be diffed against the reference implementation; the rest use their
+    /// own name in capitals.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn trace_format(opcodes: &[Opcode]) -> String {
+        let mut out = String::new();
+        for (i, op) in opcodes.iter().enumerate() {
+            let (name, operands) = match op {
+                Opcode::Ent(n) => ("ENT", vec![n.to_string()]),
+                Opcode::Imm(n) => ("IMM", vec![n.to_string()]),
+                Opcode::FImm(f) => ("FIMM", vec![format!("{:?}", f)]),
+                Opcode::Lea(n) => ("LEA", vec![n.to_string()]),
+                Opcode::Ld(n) => ("LD", vec![n.to_string()]),
+                Opcode::St(n) => ("ST", vec![n.to_string()]),
+                Opcode::LdG(n) => ("LDG", vec![n.to_string()]),
+                Opcode::StG(n) => ("STG", vec![n.to_string()]),
+                Opcode::LdX(a, b) => ("LDX", vec![a.to_string(), b.to_string()]),
+                Opcode::StX(a, b) => ("STX", vec![a.to_string(), b.to_string()]),
+                Opcode::LdGX(a, b) => ("LDGX", vec![a.to_string(), b.to_string()]),
+                Opcode::StGX(a, b) => ("STGX", vec![a.to_string(), b.to_string()]),
+                Opcode::AddrG(a, b) => ("ADDRG", vec![a.to_string(), b.to_string()]),
+                Opcode::Addr(n) => ("ADDR", vec![n.to_string()]),
+                Opcode::Bound(n) => ("BOUND", vec![n.to_string()]),
+                Opcode::Load => ("LI", vec![]),
+                Opcode::Store => ("SI", vec![]),
+                Opcode::Malloc => ("MALC", vec![]),
+                Opcode::Free => ("FREE", vec![]),
+                Opcode::Print(ty) => ("PRINT", vec![ty.to_string()]),
+                Opcode::Pop => ("POP", vec![]),
+                Opcode::Dup => ("DUP", vec![]),
+                Opcode::SignExt8 => ("SEXT8", vec![]),
+                Opcode::ZeroExt8 => ("ZEXT8", vec![]),
+                Opcode::IToF => ("ITOF", vec![]),
+                Opcode::FToI => ("FTOI", vec![]),
+                Opcode::Add => ("ADD", vec![]),
+                Opcode::Sub => ("SUB", vec![]),
+                Opcode::Mul => ("MUL", vec![]),
+                Opcode::Div => ("DIV", vec![]),
+                Opcode::Mod => ("MOD", vec![]),
+                Opcode::Eq => ("EQ", vec![]),
+                Opcode::Ne => ("NE", vec![]),
+                Opcode::Lt => ("LT", vec![]),
+                Opcode::Gt => ("GT", vec![]),
+                Opcode::Le => ("LE", vec![]),
+                Opcode::Ge => ("GE", vec![]),
+                Opcode::Neg => ("NEG", vec![]),
+                Opcode::LogicalNot => ("LNOT", vec![]),
+                Opcode::Not => ("NOT", vec![]),
+                Opcode::Jmp(n) => ("JMP", vec![n.to_string()]),
+                Opcode::Jz(n) => ("BZ", vec![n.to_string()]),
+                Opcode::Call(addr, argc) => ("JSR", vec![addr.to_string(), argc.to_string()]),
+                Opcode::Ret => ("LEV", vec![]),
+                Opcode::Custom(id) => ("CUSTOM", vec![id.to_string()]),
+            };
+            let line = format!("{}> {:<4} {}", i + 1, name, operands.join(" "));
+            out.push_str(line.trim_end());
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Executes a sequence of opcodes and returns the final result.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn execute(opcodes: Vec<Opcode>) -> Result<Value, String> {
+        execute_traced(opcodes).map_err(|e| e.message)
+    }
+
+    /// Executes a sequence of opcodes, reporting the failing `pc` on error.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn execute_traced(opcodes: Vec<Opcode>) -> Result<Value, RuntimeError> {
+        execute_from(&opcodes, 0)
+    }
+
+    /// Executes opcodes starting at `entry`, reporting the failing `pc` on error.
+    pub fn execute_from(opcodes: &[Opcode], entry: usize) -> Result<Value, RuntimeError> {
+        execute_with_heap(opcodes, entry, &[], DEFAULT_HEAP_CELLS)
+    }
+
+    /// Executes opcodes starting at `entry` with the given read-only data
+    /// segment and a heap of `heap_cells` cells.
+    pub fn execute_with_heap(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        heap_cells: usize,
+    ) -> Result<Value, RuntimeError> {
+        execute_with_config(opcodes, entry, data, Config { heap_cells, ..Config::default() })
+    }
+
+    /// Executes opcodes starting at `entry` with the given read-only data
+    /// segment and settings, stopping with an error when a limit is exceeded.
+    pub fn execute_with_config(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        config: Config,
+    ) -> Result<Value, RuntimeError> {
+        execute_with_globals(opcodes, entry, data, &mut Vec::new(), config)
+    }
+
+    /// Like `execute_with_config`, but passes `args` to the function at `entry`
+    /// as `Call` would, and writes the output of `print` to `out`.
+    pub fn execute_with_output(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        args: &[Value],
+        config: Config,
+        out: &mut dyn Write,
+    ) -> Result<Value, RuntimeError> {
+        let mut globals = Vec::new();
+        let mut vm = VmState::new(opcodes, entry, data, &mut globals, config);
+        vm.args = args.to_vec();
+        vm.set_output(out);
+        vm.run()
+    }
+
+    /// Appends each of `args` to a data segment as a NUL-terminated string,
+    /// followed by an array of their addresses ending in a null pointer, and
+    /// returns `argc` and `argv` for `main(int argc, char **argv)`.
+    pub fn append_argv(data: &mut Vec<Value>, args: &[String]) -> [Value; 2] {
+        let mut pointers = Vec::new();
+        for arg in args {
+            pointers.push(Value::Int(DATA_BASE + data.len() as i64));
+            data.extend(arg.bytes().map(|b| Value::Int(b as i8 as i64)));
+            data.push(Value::Int(0));
+        }
+        let argv = DATA_BASE + data.len() as i64;
+        data.extend(pointers);
+        data.push(Value::Int(0));
+        [Value::Int(args.len() as i64), Value::Int(argv)]
+    }
+
+    /// How integer `Div` and `Mod` round when the exact quotient is negative.
+    /// `Mod` always satisfies `(a / b) * b + a % b == a`.
+    #[derive(Debug, Clone, Copy, PartialEq, Default)]
+    pub enum Division {
+        #[default]
+        Truncate, // Toward zero, as in C: -7 / 2 == -3, -7 % 2 == -1.
+        Floor,    // Toward negative infinity: -7 / 2 == -4, -7 % 2 == 1.
+    }
+
+    /// Settings and resource limits for one run of the VM.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Config {
+        pub heap_cells: usize,         // Most cells the heap may grow to.
+        pub max_steps: Option<u64>,    // Most opcodes to execute, if bounded.
+        pub deadline: Option<Instant>, // Wall-clock time at which the run is abandoned.
+        pub division: Division,        // Rounding of integer division and remainder.
+        pub debug: bool,               // Attach the operand stack to runtime errors.
+        pub coverage: bool,            // Record which opcodes run, for `execute_debug`.
+        pub profile: bool,             // Count how often each kind of opcode runs, for `execute_debug`.
+        pub memory_cells: usize,       // Most cells the globals and local frames may use together.
+        pub max_call_depth: usize,     // Most calls that may be active at once.
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                heap_cells: DEFAULT_HEAP_CELLS,
+                max_steps: None,
+                deadline: None,
+                division: Division::Truncate,
+                debug: false,
+                coverage: false,
+                profile: false,
+                memory_cells: DEFAULT_MEMORY_CELLS,
+                max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            }
+        }
+    }
+
+    /// How many opcodes run between checks of the deadline, which is far
+    /// slower to read than the step counter.
+    const DEADLINE_INTERVAL: u64 = 1024;
+
+    /// Executes opcodes against caller-owned global storage, which persists
+    /// across runs; it is grown (zero-filled) to cover every global the program uses.
+    pub fn execute_with_globals(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        globals: &mut Vec<Value>,
+        config: Config,
+    ) -> Result<Value, RuntimeError> {
+        VmState::new(opcodes, entry, data, globals, config).run()
+    }
+
+    /// Executes opcodes like `execute_with_globals`, allocating from a
+    /// caller-owned heap, so that blocks a run leaves allocated stay valid in
+    /// later runs. The heap's own capacity applies, not `config.heap_cells`.
+    pub fn execute_with_memory(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        globals: &mut Vec<Value>,
+        heap: &mut Heap,
+        config: Config,
+    ) -> Result<Value, RuntimeError> {
+        let mut vm = VmState::new(opcodes, entry, data, globals, config);
+        std::mem::swap(&mut vm.memory.heap, heap);
+        let result = vm.run();
+        std::mem::swap(&mut vm.memory.heap, heap);
+        result
+    }
+
+    /// Executes opcodes like `execute_with_config`, also returning, when
+    /// `config.coverage` is set, which opcode indices ran and, when
+    /// `config.profile` is set, how often each kind of opcode ran (both kept
+    /// even if the run failed). Without the flags neither is recorded.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn execute_debug(
+        opcodes: &[Opcode],
+        entry: usize,
+        data: &[Value],
+        config: Config,
+    ) -> (Result<Value, RuntimeError>, Option<Vec<bool>>, Option<Profile>) {
+        let mut globals = Vec::new();
+        let mut vm = VmState::new(opcodes, entry, data, &mut globals, config);
+        let result = vm.run();
+        (result, vm.visited.take(), vm.profile.take())
+    }
+
+    /// How many times each kind of opcode ran, recorded with `Config::profile`.
+    /// Opcodes of the same variant count together whatever their operands.
+    #[derive(Debug, Clone, Default, PartialEq)]
+    pub struct Profile {
+        counts: HashMap<Discriminant<Opcode>, u64>,
+    }
+
+    #[cfg_attr(not(test), allow(dead_code))]
+    impl Profile {
+        /// Number of times opcodes of the same variant as `op` ran; the operands of `op` are ignored.
+        pub fn count(&self, op: &Opcode) -> u64 {
+            self.counts.get(&std::mem::discriminant(op)).copied().unwrap_or(0)
+        }
+
+        /// Total number of opcodes that ran.
+        pub fn total(&self) -> u64 {
+            self.counts.values().sum()
+        }
+
+        fn record(&mut self, op: &Opcode) {
+            *self.counts.entry(std::mem::discriminant(op)).or_default() += 1;
+        }
+    }
+
+    /// Address of global slot 0. Address 0 is the null pointer and is never valid.
+    pub const GLOBAL_BASE: i64 = 0x1000;
+
+    /// Address of the first cell of the read-only data segment holding string literals.
+    pub const DATA_BASE: i64 = 0x80000;
+
+    /// Address of the first cell of the stack region holding local frames.
+    pub const STACK_BASE: i64 = 0x100000;
+
+    /// Address of the first heap cell. Lower addresses are never heap memory,
+    /// so the null pointer and small integers cannot be mistaken for pointers.
+    pub const HEAP_BASE: i64 = 0x200000;
+
+    /// Cells in the globals region, below the data segment; the compiler
+    /// rejects programs whose globals need more.
+    pub const GLOBAL_CELLS: usize = (DATA_BASE - GLOBAL_BASE) as usize;
+
+    /// Cells in the stack region, which local frames may not grow beyond.
+    pub const STACK_CELLS: usize = (HEAP_BASE - STACK_BASE) as usize;
+
+    /// Default limit on the cells of globals and frames together: both regions full.
+    pub const DEFAULT_MEMORY_CELLS: usize = GLOBAL_CELLS + STACK_CELLS;
+
+    /// Default heap size in cells, used unless `--heap N` says otherwise.
+    pub const DEFAULT_HEAP_CELLS: usize = 1 << 20;
+
+    /// A heap block: its length in cells and whether it has been freed.
+    #[derive(Debug, Clone, Copy)]
+    struct Block {
+        len: i64,
+        live: bool,
+    }
+
+    /// The `malloc`/`free` heap: cells addressed from `HEAP_BASE`, handed out
+    /// by a bump pointer and reused first-fit once freed.
+    ///
+    /// Freed blocks stay in the block map, so a use after free or a double free
+    /// is reported until the block is handed out again.
+    #[derive(Debug)]
+    pub struct Heap {
+        cells: Vec<Value>,
+        blocks: BTreeMap<i64, Block>, // Keyed by start address.
+        capacity: usize,              // Most cells the heap may grow to.
+    }
+
+    impl Default for Heap {
+        fn default() -> Self {
+            Heap::new(DEFAULT_HEAP_CELLS)
+        }
+    }
+
+    impl Heap {
+        /// Creates an empty heap that may grow to `capacity` cells.
+        pub fn new(capacity: usize) -> Self {
+            Heap { cells: Vec::new(), blocks: BTreeMap::new(), capacity }
+        }
+
+        /// Allocates a zero-filled block of `len` cells and returns its address.
+        ///
+        /// As in C, running out of heap is not an error: the result is the null
+        /// pointer 0, which the program is expected to check.
+        fn malloc(&mut self, len: i64) -> Result<i64, String> {
+            if len < 0 {
+                return Err(format!("malloc of negative size {}", len));
+            }
+            let len = len.max(1);
+            let reuse = self.blocks.iter_mut().find(|(_, block)| !block.live && block.len >= len);
+            if let Some((&start, block)) = reuse {
+                block.live = true;
+                let first = (start - HEAP_BASE) as usize;
+                self.cells[first..first + block.len as usize].fill(Value::default());
+                return Ok(start);
+            }
+            if len as u64 > (self.capacity - self.cells.len()) as u64 {
+                return Ok(0);
+            }
+            let start = HEAP_BASE + self.cells.len() as i64;
+            self.cells.resize(self.cells.len() + len as usize, Value::default());
+            self.blocks.insert(start, Block { len, live: true });
+            Ok(start)
+        }
+
+        /// Releases the block starting at `addr`; freeing null does nothing.
+        fn free(&mut self, addr: i64) -> Result<(), String> {
+            if addr == 0 {
+                return Ok(());
+            }
+            match self.blocks.get_mut(&addr) {
+                Some(block) if block.live => {
+                    block.live = false;
+                    Ok(())
+                },
+                Some(_) => Err(format!("double free of address {}", addr)),
+                None => Err(format!("free of invalid pointer {}", addr)),
+            }
+        }
+
+        /// Returns the cell at `addr`, which must lie inside a live block.
+        fn cell(&mut self, addr: i64) -> Result<&mut Value, String> {
+            match self.blocks.range(..=addr).next_back() {
+                Some((&start, block)) if addr < start + block.len => {
+                    if block.live {
+                        Ok(&mut self.cells[(addr - HEAP_BASE) as usize])
+                    } else {
+                        Err(format!("use after free at address {}", addr))
+                    }
+                },
+                _ => Err(format!("invalid memory access at address {}", addr)),
+            }
+        }
+    }
+
+    /// The flat address space seen by `Load` and `Store`: the globals area from
+    /// `GLOBAL_BASE`, read-only data from `DATA_BASE`, local frames from
+    /// `STACK_BASE`, then the heap from `HEAP_BASE`. Each address names one cell.
+    struct Memory<'g> {
+        data: &'g [Value],
+        globals: &'g mut [Value],
+        stack: Vec<Value>, // Frames, each starting with an unused cell so locals number from 1.
+        bp: usize,         // Index in `stack` of the current frame.
+        frame_len: usize,  // Cells in the current frame, including the unused one.
+        stack_cells: usize, // Most cells the frames may use together.
+        heap: Heap,
+    }
+
+    impl<'g> Memory<'g> {
+        /// Sets up the regions; the frames may use whatever of `memory_cells`
+        /// the globals leave, up to the size of the stack region.
+        fn new(data: &'g [Value], globals: &'g mut [Value], heap_cells: usize, memory_cells: usize) -> Self {
+            let stack_cells = memory_cells.saturating_sub(globals.len()).min(STACK_CELLS);
+            Memory { data, globals, stack: Vec::new(), bp: 0, frame_len: 0, stack_cells, heap: Heap::new(heap_cells) }
+        }
+
+        /// Allocates a zero-filled frame of `size` locals on top of the stack
+        /// region, failing if it would run past the memory limit or the region.
+        fn enter(&mut self, size: usize) -> Result<(), String> {
+            let top = self.stack.len().saturating_add(size).saturating_add(1);
+            if top > self.stack_cells {
+                return Err(format!("stack overflow: frames need {} cells, more than the {} available", top, self.stack_cells));
+            }
+            self.bp = self.stack.len();
+            self.frame_len = size + 1;
+            self.stack.resize(top, Value::default());
+            Ok(())
+        }
+
+        /// Releases the current frame and makes the caller's frame, saved by
+        /// `Call`, current again.
+        fn leave(&mut self, caller: &Frame) {
+            self.stack.truncate(self.bp);
+            self.bp = caller.bp;
+            self.frame_len = caller.frame_len;
+        }
+
+        /// Returns the cell of local `offset` in the current frame, if it is one.
+        /// Cell 0 of a frame is never a local, so a stray `Ld(0)` is caught too.
+        fn local(&mut self, offset: i64) -> Option<&mut Value> {
+            if offset >= 1 && (offset as usize) < self.frame_len {
+                Some(&mut self.stack[self.bp + offset as usize])
+            } else {
+                None
+            }
+        }
+
+        /// Returns the address of local `offset` in the current frame.
+        fn local_address(&self, offset: i64) -> i64 {
+            STACK_BASE + self.bp as i64 + offset
+        }
+
+        /// Reads the cell at `addr`.
+        fn load(&mut self, addr: i64) -> Result<Value, String> {
+            if (DATA_BASE..STACK_BASE).contains(&addr) {
+                return match self.data.get((addr - DATA_BASE) as usize) {
+                    Some(&val) => Ok(val),
+                    None => Err(format!("invalid memory access at address {}", addr)),
+                };
+            }
+            self.cell(addr).copied()
+        }
+
+        /// Returns the writable cell at `addr`, or an error if no region maps it.
+        fn cell(&mut self, addr: i64) -> Result<&mut Value, String> {
+            if addr >= HEAP_BASE {
+                return self.heap.cell(addr);
+            }
+            let (region, index): (&mut [Value], i64) = if addr >= STACK_BASE {
+                (&mut self.stack, addr - STACK_BASE)
+            } else if addr - DATA_BASE < self.data.len() as i64 && addr >= DATA_BASE {
+                return Err(format!("write to read-only data at address {}", addr));
+            } else if addr >= DATA_BASE {
+                return Err(format!("invalid memory access at address {}", addr));
+            } else {
+                (self.globals, addr - GLOBAL_BASE)
+            };
+            if (0..region.len() as i64).contains(&index) {
+                Ok(&mut region[index as usize])
+            } else {
+                Err(format!("invalid memory access at address {}", addr))
+            }
+        }
+    }
+
+    /// Pops a value that must be an integer (an address or a size).
+    fn pop_int(stack: &mut Vec<Value>, op: &Opcode) -> Result<i64, String> {
+        match stack.pop() {
+            Some(Value::Int(n)) => Ok(n),
+            Some(Value::Float(_)) => Err(format!("{:?} expects an integer operand", op)),
+            None => Err(format!("Stack underflow in {:?}", op)),
+        }
+    }
+
+    /// C truthiness for a scalar: zero is false, anything else is true.
+    ///
+    /// Integer kinds (int, signed and unsigned char) are held as `Value::Int`,
+    /// already narrowed on store; a double is true unless it compares equal to
+    /// zero, so `-0.0` is false and NaN is true.
+    fn is_true(value: Value) -> bool {
+        match value {
+            Value::Int(n) => n != 0,
+            Value::Float(f) => f != 0.0,
+        }
+    }
+
+    /// Applies a binary arithmetic opcode, promoting to float if either side is
+    /// a float. Integer `Div` and `Mod` round as `division` selects.
+    fn arithmetic(op: &Opcode, a: Value, b: Value, division: Division) -> Result<Value, String> {
+        match (a, b) {
+            (Value::Int(a), Value::Int(b)) => match op {
+                Opcode::Add => Ok(Value::Int(a.wrapping_add(b))),
+                Opcode::Sub => Ok(Value::Int(a.wrapping_sub(b))),
+                Opcode::Mul => Ok(Value::Int(a.wrapping_mul(b))),
+                _ if b == 0 => Err("Division by zero".into()),
+                _ => {
+                    let mut quotient = a.wrapping_div(b);
+                    if division == Division::Floor && a.wrapping_rem(b) != 0 && (a < 0) != (b < 0) {
+                        quotient -= 1;
+                    }
+                    if *op == Opcode::Mod {
+                        Ok(Value::Int(a.wrapping_sub(quotient.wrapping_mul(b))))
+                    } else {
+                        Ok(Value::Int(quotient))
+                    }
+                },
+            },
+            _ if *op == Opcode::Mod => Err("Modulo operand must be an integer".into()),
+            _ => {
+                let (a, b) = (a.as_float(), b.as_float());
+                Ok(Value::Float(match op {
+                    Opcode::Add => a + b,
+                    Opcode::Sub => a - b,
+                    Opcode::Mul => a * b,
+                    _ => a / b,
+                }))
+            },
+        }
+    }
+
+    /// Applies a comparison opcode, comparing as floats if either side is a float.
+    fn compare(op: &Opcode, a: Value, b: Value) -> Value {
+        let ordering = match (a, b) {
+            (Value::Int(a), Value::Int(b)) => Some(a.cmp(&b)),
+            _ => a.as_float().partial_cmp(&b.as_float()),
+        };
+        // NaN is unordered: only `!=` holds.
+        let holds = match ordering {
+            Some(ordering) => match op {
+                Opcode::Eq => ordering.is_eq(),
+                Opcode::Ne => ordering.is_ne(),
+                Opcode::Lt => ordering.is_lt(),
+                Opcode::Gt => ordering.is_gt(),
+                Opcode::Le => ordering.is_le(),
+                _ => ordering.is_ge(),
+            },
+            None => *op == Opcode::Ne,
+        };
+        Value::Int(holds as i64)
+    }
+
+    /// Returns the number of slots needed to cover every offset `slot_of` selects.
+    fn slot_count(opcodes: &[Opcode], slot_of: impl Fn(&Opcode) -> Option<i64>) -> usize {
+        opcodes
+            .iter()
+            .filter_map(slot_of)
+            .filter(|offset| *offset >= 0)
+            .map(|offset| offset as usize + 1)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Pops an array index and returns the slot it selects in an array at
+    /// `offset` with `len` elements.
+    fn element_slot(stack: &mut Vec<Value>, offset: i64, len: i64) -> Result<i64, String> {
+        match stack.pop() {
+            Some(Value::Int(index)) if (0..len).contains(&index) => Ok(offset + index),
+            Some(Value::Int(index)) => Err(format!("array index {} out of bounds (length {})", index, len)),
+            Some(Value::Float(_)) => Err("array subscript is not an integer".into()),
+            None => Err("Stack underflow in array access".into()),
+        }
+    }
+
+    /// A caller's state saved by `Call` and restored by `Ret`.
+    #[derive(Debug, Clone, Copy)]
+    struct Frame {
+        return_pc: i64,   // Opcode after the `Call`.
+        bp: usize,        // The caller's frame in the stack region.
+        frame_len: usize, // Cells in the caller's frame.
+    }
+
+    /// Default limit on the calls that may be active at once; deeper recursion
+    /// is an error rather than exhausting the host's memory.
+    pub const DEFAULT_MAX_CALL_DEPTH: usize = 1 << 16;
+
+    /// What one `VmState::step` did.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum StepResult {
+        Running,           // An opcode ran and the program continues.
+        Halted(Value),     // The program has returned this result.
+        Breakpoint(usize), // Paused before the opcode at this breakpoint.
+        Watchpoint { name: String, old: Value, new: Value }, // Paused after a watched variable changed.
+    }
+
+    /// Implements a `Custom` opcode by rewriting the operand stack, which it
+    /// sees bottom first. An `Err` stops the program as a runtime error.
+    pub type CustomHandler = Box<dyn FnMut(&mut Vec<Value>) -> Result<(), String>>;
+
+    /// A variable watched by `VmState::watch` and the value it last had.
+    #[derive(Debug, Clone)]
+    struct Watch {
+        name: String,
+        class: SymbolClass,
+        offset: i64, // Slot in the current frame for a local, in the globals area for a global.
+        value: Value,
+    }
+
+    /// A run of the VM that advances one opcode at a time, keeping the program
+    /// counter, operand stack and memory between steps.
+    ///
+    /// `execute_with_globals` is a loop over `step`; a debugger can drive it directly.
+    pub struct VmState<'a> {
+        opcodes: &'a [Opcode],
+        pc: i64,
+        stack: Vec<Value>,
+        memory: Memory<'a>,
+        calls: Vec<Frame>, // Callers of the running function, innermost last.
+        args: Vec<Value>,  // Arguments popped by `Call`, stored into the new frame by `Ent`.
+        config: Config,
+        steps: u64,
+        result: Option<Value>, // Set once the program has returned.
+        breakpoints: BTreeSet<usize>,
+        watches: Vec<Watch>,
+        output: Option<&'a mut dyn Write>, // Where `Print` writes; standard output if unset.
+        visited: Option<Vec<bool>>, // Opcodes that have run, indexed by address; kept only with `Config::coverage`.
+        profile: Option<Profile>,   // Run counts per kind of opcode; kept only with `Config::profile`.
+        handlers: BTreeMap<u16, CustomHandler>, // Handlers for `Custom` opcodes, by id.
+        fault: Option<String>, // Why the program cannot start, reported by every step.
+    }
+
+    impl<'a> VmState<'a> {
+        /// Prepares to run `opcodes` from `entry` against caller-owned global
+        /// storage, which is grown (zero-filled) to cover every global the program uses.
+        /// If that needs more than `config.memory_cells` or the globals region
+        /// holds, the storage is left alone and the first step fails instead.
+        pub fn new(
+            opcodes: &'a [Opcode],
+            entry: usize,
+            data: &'a [Value],
+            globals: &'a mut Vec<Value>,
+            config: Config,
+        ) -> Self {
+            let needed = slot_count(opcodes, |op| match op {
+                Opcode::LdG(offset) | Opcode::StG(offset) => Some(*offset),
+                Opcode::LdGX(offset, len) | Opcode::StGX(offset, len) | Opcode::AddrG(offset, len) => Some(offset + len - 1),
+                _ => None,
+            });
+            let limit = config.memory_cells.min(GLOBAL_CELLS);
+            let fault = (needed > limit).then(|| format!("out of memory: globals need {} cells, more than the limit of {}", needed, limit));
+            if globals.len() < needed && fault.is_none() {
+                globals.resize(needed, Value::default());
+            }
+            VmState {
+                opcodes,
+                pc: entry as i64,
+                stack: Vec::new(),
+                memory: Memory::new(data, globals, config.heap_cells, config.memory_cells),
+                calls: Vec::new(),
+                args: Vec::new(),
+                config,
+                steps: 0,
+                result: None,
+                breakpoints: BTreeSet::new(),
+                watches: Vec::new(),
+                output: None,
+                visited: config.coverage.then(|| vec![false; opcodes.len()]),
+                profile: config.profile.then(Profile::default),
+                handlers: BTreeMap::new(),
+                fault,
+            }
+        }
+
+        /// Sends the output of `print` to `out` instead of standard output.
+        pub fn set_output(&mut self, out: &'a mut dyn Write) {
+            self.output = Some(out);
+        }
+
+        /// Makes `Custom(id)` run `handler`, replacing any handler registered
+        /// under the same id. Running a `Custom` opcode with no handler is a
+        /// runtime error.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn register_opcode(&mut self, id: u16, handler: impl FnMut(&mut Vec<Value>) -> Result<(), String> + 'static) {
+            self.handlers.insert(id, Box::new(handler));
+        }
+
+        /// Index of the next opcode to run, or of the failing one after an error.
+        pub fn pc(&self) -> usize {
+            self.pc as usize
+        }
+
+        /// The operand stack, bottom first.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn stack(&self) -> &[Value] {
+            &self.stack
+        }
+
+        /// Makes `run_to_breakpoint` pause before the opcode at `addr` runs.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn set_breakpoint(&mut self, addr: usize) {
+            self.breakpoints.insert(addr);
+        }
+
+        /// Removes the breakpoint at `addr`, if there is one.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn clear_breakpoint(&mut self, addr: usize) {
+            self.breakpoints.remove(&addr);
+        }
+
+        /// Makes `run_to_breakpoint` pause whenever the value of variable `name`,
+        /// as seen from the current `pc` of `program`, changes. A local is watched
+        /// in whichever frame is current.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn watch(&mut self, program: &Program, name: &str) -> Result<(), String> {
+            let symbol = program.variable_at(self.pc(), name).ok_or_else(|| format!("Undefined variable: {}", name))?;
+            let mut watch = Watch { name: name.to_string(), class: symbol.class.clone(), offset: symbol.offset, value: Value::default() };
+            watch.value = self.watched_value(&watch);
+            self.watches.push(watch);
+            Ok(())
+        }
+
+        /// Reads a watched variable; a local outside the current frame reads as zero.
+        fn watched_value(&mut self, watch: &Watch) -> Value {
+            let cell = match watch.class {
+                SymbolClass::Local => self.memory.local(watch.offset).map(|val| *val),
+                _ => self.memory.globals.get(watch.offset as usize).copied(),
+            };
+            cell.unwrap_or_default()
+        }
+
+        /// Returns the first watched variable whose value changed since it was
+        /// last checked, recording the new value.
+        fn changed_watch(&mut self) -> Option<StepResult> {
+            for i in 0..self.watches.len() {
+                let new = self.watched_value(&self.watches[i].clone());
+                let old = self.watches[i].value;
+                let same = match (old, new) {
+                    (Value::Float(a), Value::Float(b)) => a.to_bits() == b.to_bits(), // NaN is unchanged.
+                    _ => old == new,
+                };
+                if !same {
+                    self.watches[i].value = new;
+                    return Some(StepResult::Watchpoint { name: self.watches[i].name.clone(), old, new });
+                }
+            }
+            None
+        }
+
+        /// Steps until the program returns, a watched variable changes, or the
+        /// next opcode has a breakpoint.
+        ///
+        /// The opcode at the current `pc` always runs first, so calling this again
+        /// after a pause continues past the breakpoint.
+        #[cfg_attr(not(test), allow(dead_code))]
+        pub fn run_to_breakpoint(&mut self) -> Result<StepResult, String> {
+            loop {
+                match self.step()? {
+                    StepResult::Running => {
+                        if let Some(hit) = self.changed_watch() {
+                            return Ok(hit);
+                        }
+                        if self.breakpoints.contains(&self.pc()) {
+                            return Ok(StepResult::Breakpoint(self.pc()));
+                        }
+                    },
+                    done => return Ok(done),
+                }
+            }
+        }
+
+        /// Steps until the program returns, ignoring breakpoints and watches.
+        fn run(&mut self) -> Result<Value, RuntimeError> {
+            loop {
+                match self.step() {
+                    Ok(StepResult::Halted(result)) => return Ok(result),
+                    Ok(_) => {},
+                    Err(message) => {
+                        let stack = self.config.debug.then(|| std::mem::take(&mut self.stack));
+                        return Err(RuntimeError { pc: self.pc(), message, stack });
+                    },
+                }
+            }
+        }
+
+        /// Executes one opcode. After an error `pc` is left at the failing opcode;
+        /// once the program has returned, further steps report the same result.
+        ///
+        /// Locals live in the frame allocated by `Ent`. Any `Ld`/`St` outside it is a
+        /// code generation bug and is reported rather than reading or clobbering
+        /// unrelated storage.
+        pub fn step(&mut self) -> Result<StepResult, String> {
+            if let Some(result) = self.result {
+                return Ok(StepResult::Halted(result));
+            }
+            if let Some(fault) = &self.fault {
+                return Err(fault.clone());
+            }
+            let opcodes = self.opcodes;
+            if self.pc as usize >= opcodes.len() {
+                return Err("No Ret opcode encountered".into());
+            }
+            self.steps += 1;
+            if self.config.max_steps.is_some_and(|max| self.steps > max) {
+                return Err(format!("step limit of {} exceeded", self.steps - 1));
+            }
+            let deadline_due = self.steps.is_multiple_of(DEADLINE_INTERVAL);
+            if deadline_due && self.config.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Err("time limit exceeded".into());
+            }
+            if let Some(visited) = self.visited.as_mut() {
+                visited[self.pc as usize] = true;
+            }
+            if let Some(profile) = self.profile.as_mut() {
+                profile.record(&opcodes[self.pc as usize]);
+            }
+            match opcodes[self.pc as usize].clone() {
+                Opcode::Ent(size) => {
+                    if size < 0 {
+                        return Err("Invalid frame size in Ent".into());
+                    }
+                    self.memory.enter(size as usize)?;
+                    for (slot, arg) in (1..).zip(std::mem::take(&mut self.args)) {
+                        *self.memory.local(slot).ok_or("more arguments than locals in Ent")? = arg;
+                    }
+                    self.pc += 1;
+                },
+                Opcode::Imm(n) => {
+                    self.stack.push(Value::Int(n));
+                    self.pc += 1;
+                },
+                Opcode::FImm(f) => {
+                    self.stack.push(Value::Float(f));
+                    self.pc += 1;
+                },
+                Opcode::Lea(offset) => {
+                    self.stack.push(Value::Int(self.memory.local_address(offset)));
+                    self.pc += 1;
+                },
+                Opcode::Ld(offset) => {
+                    if let Some(&mut val) = self.memory.local(offset) {
+                        self.stack.push(val);
+                        self.pc += 1;
+                    } else {
+                        return Err(format!("read of non-local slot {}", offset));
+                    }
+                },
+                Opcode::St(offset) => {
+                    if let Some(&val) = self.stack.last() {
+                        if let Some(slot) = self.memory.local(offset) {
+                            *slot = val;
+                            self.pc += 1;
+                        } else {
+                            return Err(format!("write to non-local slot {}", offset));
+                        }
+                    } else {
+                        return Err("Stack underflow in St".into());
+                    }
+                },
+                Opcode::LdX(offset, len) => {
+                    let offset = element_slot(&mut self.stack, offset, len)?;
+                    if let Some(&mut val) = self.memory.local(offset) {
+                        self.stack.push(val);
+                        self.pc += 1;
+                    } else {
+                        return Err(format!("read of non-local slot {}", offset));
+                    }
+                },
+                Opcode::StX(offset, len) => {
+                    let val = self.stack.pop().ok_or("Stack underflow in StX")?;
+                    let offset = element_slot(&mut self.stack, offset, len)?;
+                    if let Some(slot) = self.memory.local(offset) {
+                        *slot = val;
+                        self.stack.push(val);
+                        self.pc += 1;
+                    } else {
+                        return Err(format!("write to non-local slot {}", offset));
+                    }
+                },
+                Opcode::LdGX(offset, len) => {
+                    let offset = element_slot(&mut self.stack, offset, len)?;
+                    match self.memory.globals.get(offset as usize) {
+                        Some(&val) => self.stack.push(val),
+                        None => return Err("Invalid global offset in LdGX".into()),
+                    }
+                    self.pc += 1;
+                },
+                Opcode::StGX(offset, len) => {
+                    let val = self.stack.pop().ok_or("Stack underflow in StGX")?;
+                    let offset = element_slot(&mut self.stack, offset, len)?;
+                    match self.memory.globals.get_mut(offset as usize) {
+                        Some(slot) => *slot = val,
+                        None => return Err("Invalid global offset in StGX".into()),
+                    }
+                    self.stack.push(val);
+                    self.pc += 1;
+                },
+                Opcode::AddrG(offset, _) => {
+                    self.stack.push(Value::Int(GLOBAL_BASE + offset));
+                    self.pc += 1;
+                },
+                Opcode::Addr(offset) => {
+                    self.stack.push(Value::Int(DATA_BASE + offset));
+                    self.pc += 1;
+                },
+                Opcode::Load => {
+                    let addr = pop_int(&mut self.stack, &Opcode::Load)?;
+                    self.stack.push(self.memory.load(addr)?);
+                    self.pc += 1;
+                },
+                Opcode::Store => {
+                    let val = self.stack.pop().ok_or("Stack underflow in Store")?;
+                    let addr = pop_int(&mut self.stack, &Opcode::Store)?;
+                    *self.memory.cell(addr)? = val;
+                    self.stack.push(val);
+                    self.pc += 1;
+                },
+                Opcode::Malloc => {
+                    let len = pop_int(&mut self.stack, &Opcode::Malloc)?;
+                    self.stack.push(Value::Int(self.memory.heap.malloc(len)?));
+                    self.pc += 1;
+                },
+                Opcode::Free => {
+                    let addr = pop_int(&mut self.stack, &Opcode::Free)?;
+                    self.memory.heap.free(addr)?;
+                    self.stack.push(Value::Int(0));
+                    self.pc += 1;
+                },
+                Opcode::Print(ty) => {
+                    let val = *self.stack.last().ok_or("Stack underflow in Print")?;
+                    let line = format!("{} : {}", crate::format_result(val, &ty), ty);
+                    let written = match self.output.as_mut() {
+                        Some(out) => writeln!(out, "{}", line),
+                        None => writeln!(io::stdout(), "{}", line),
+                    };
+                    written.map_err(|e| format!("print failed: {}", e))?;
+                    self.pc += 1;
+                },
+                Opcode::LdG(offset) => {
+                    if offset >= 0 && (offset as usize) < self.memory.globals.len() {
+                        self.stack.push(self.memory.globals[offset as usize]);
+                        self.pc += 1;
+                    } else {
+                        return Err("Invalid global offset in LdG".into());
+                    }
+                },
+                Opcode::StG(offset) => {
+                    if let Some(&val) = self.stack.last() {
+                        if offset >= 0 && (offset as usize) < self.memory.globals.len() {
+                            self.memory.globals[offset as usize] = val;
+                            self.pc += 1;
+                        } else {
+                            return Err("Invalid global offset in StG".into());
+                        }
+                    } else {
+                        return Err("Stack underflow in StG".into());
+                    }
+                },
+                Opcode::Pop => {
+                    if self.stack.pop().is_none() {
+                        return Err("Stack underflow in Pop".into());
+                    }
+                    self.pc += 1;
+                },
+                Opcode::Dup => {
+                    let top = *self.stack.last().ok_or("Stack underflow in Dup")?;
+                    self.stack.push(top);
+                    self.pc += 1;
+                },
+                Opcode::SignExt8 | Opcode::ZeroExt8 => {
+                    if let Some(top) = self.stack.last_mut() {
+                        let n = top.as_int();
+                        *top = Value::Int(if opcodes[self.pc as usize] == Opcode::SignExt8 {
+                            n as i8 as i64
+                        } else {
+                            n as u8 as i64
+                        });
+                        self.pc += 1;
+                    } else {
+                        return Err("Stack underflow in char conversion".into());
+                    }
+                },
+                Opcode::Bound(len) => {
+                    let index = element_slot(&mut self.stack, 0, len)?;
+                    self.stack.push(Value::Int(index));
+                    self.pc += 1;
+                },
+                Opcode::IToF | Opcode::FToI => {
+                    if let Some(top) = self.stack.last_mut() {
+                        *top = if opcodes[self.pc as usize] == Opcode::IToF {
+                            Value::Float(top.as_float())
+                        } else {
+                            Value::Int(top.as_int())
+                        };
+                        self.pc += 1;
+                    } else {
+                        return Err("Stack underflow in conversion".into());
+                    }
+                },
+                Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => {
+                    let op = &opcodes[self.pc as usize];
+                    if self.stack.len() < 2 {
+                        return Err(format!("Stack underflow in {:?}", op));
+                    }
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    self.stack.push(arithmetic(op, a, b, self.config.division)?);
+                    self.pc += 1;
+                },
+                Opcode::Eq | Opcode::Ne | Opcode::Lt | Opcode::Gt | Opcode::Le | Opcode::Ge => {
+                    let op = &opcodes[self.pc as usize];
+                    if self.stack.len() < 2 {
+                        return Err(format!("Stack underflow in {:?}", op));
+                    }
+                    let b = self.stack.pop().unwrap();
+                    let a = self.stack.pop().unwrap();
+                    self.stack.push(compare(op, a, b));
+                    self.pc += 1;
+                },
+                Opcode::Neg => {
+                    match self.stack.last_mut() {
+                        Some(Value::Int(n)) => *n = n.wrapping_neg(),
+                        Some(Value::Float(f)) => *f = -*f,
+                        None => return Err("Stack underflow in Neg".into()),
+                    }
+                    self.pc += 1;
+                },
+                Opcode::LogicalNot => {
+                    let value = self.stack.pop().ok_or("Stack underflow in LogicalNot")?;
+                    self.stack.push(Value::Int(!is_true(value) as i64));
+                    self.pc += 1;
+                },
+                Opcode::Not => {
+                    match self.stack.last_mut() {
+                        Some(Value::Int(n)) => *n = !*n,
+                        Some(Value::Float(_)) => return Err("Bitwise operand must be an integer".into()),
+                        None => return Err("Stack underflow in Not".into()),
+                    }
+                    self.pc += 1;
+                },
+                Opcode::Jmp(addr) => {
+                    self.pc = addr;
+                },
+                Opcode::Jz(addr) => {
+                    let cond = self.stack.pop().ok_or("Stack underflow in Jz")?;
+                    if !is_true(cond) {
+                        self.pc = addr;
+                    } else {
+                        self.pc += 1;
+                    }
+                },
+                Opcode::Call(addr, argc) => {
+                    if self.calls.len() >= self.config.max_call_depth {
+                        return Err(format!("call stack overflow (more than {} nested calls)", self.config.max_call_depth));
+                    }
+                    let first = self.stack.len().checked_sub(argc as usize).ok_or("Stack underflow in Call")?;
+                    self.args = self.stack.split_off(first);
+                    let memory = &self.memory;
+                    self.calls.push(Frame { return_pc: self.pc + 1, bp: memory.bp, frame_len: memory.frame_len });
+                    self.pc = addr;
+                },
+                Opcode::Ret => {
+                    let result = self.stack.pop().ok_or("Stack underflow in Ret")?;
+                    let Some(caller) = self.calls.pop() else {
+                        self.result = Some(result);
+                        return Ok(StepResult::Halted(result));
+                    };
+                    self.memory.leave(&caller);
+                    self.stack.push(result);
+                    self.pc = caller.return_pc;
+                },
+                Opcode::Custom(id) => {
+                    let handler = self.handlers.get_mut(&id).ok_or_else(|| format!("no handler registered for custom opcode {}", id))?;
+                    handler(&mut self.stack)?;
+                    self.pc += 1;
+                },
+            }
+            Ok(StepResult::Running)
+        }
+    }
+}
+
+//
+// Module: analysis
+//
+mod analysis {
+    //! Static checks over the opcode stream produced by the parser.
+    //!
+    //! These passes never execute the program; they reason about the opcodes
+    //! alone so that code generation bugs surface before the VM runs.
+
+    use crate::parser::Program;
+    use crate::vm::Opcode;
+
+    /// Returns how many values an opcode pops and pushes, in that order.
+    fn stack_effect(op: &Opcode) -> (i64, i64) {
+        match op {
+            Opcode::Imm(_) | Opcode::FImm(_) | Opcode::Ld(_) | Opcode::LdG(_) => (0, 1),
+            Opcode::Lea(_) | Opcode::AddrG(..) | Opcode::Addr(_) => (0, 1),
+            Opcode::St(_) | Opcode::StG(_) | Opcode::LdX(..) | Opcode::LdGX(..) => (1, 1),
+            Opcode::StX(..) | Opcode::StGX(..) | Opcode::Store => (2, 1),
+            Opcode::Load | Opcode::Malloc | Opcode::Free | Opcode::Print(_) => (1, 1),
+            Opcode::Pop => (1, 0),
+            Opcode::Dup => (1, 2),
+            Opcode::SignExt8 | Opcode::ZeroExt8 | Opcode::IToF | Opcode::FToI | Opcode::Neg | Opcode::LogicalNot | Opcode::Not => (1, 1),
+            Opcode::Bound(_) => (1, 1),
+            Opcode::Custom(_) => (1, 1), // Handlers are opaque; assume they rewrite the top value.
+            Opcode::Add | Opcode::Sub | Opcode::Mul | Opcode::Div | Opcode::Mod => (2, 1),
+            Opcode::Eq | Opcode::Ne | Opcode::Lt | Opcode::Gt | Opcode::Le | Opcode::Ge => (2, 1),
+            Opcode::Ent(_) | Opcode::Jmp(_) => (0, 0),
+            Opcode::Call(_, argc) => (*argc, 1),
+            Opcode::Jz(_) | Opcode::Ret => (1, 0),
+        }
+    }
+
+    /// Validates that the operand stack stays balanced along every path.
+    ///
+    /// Simulates the stack depth of each instruction, following both edges of
+    /// conditional jumps. An instruction reached with two different depths is a
+    /// join point with inconsistent stacks and is reported as a stack imbalance;
+    /// popping below the depth at entry is reported as an underflow.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn validate_stack(opcodes: &[Opcode]) -> Result<(), String> {
+        check_stack(opcodes, 0)
+    }
+
+    /// Checks stack balance along every path from `entry`.
+    fn check_stack(opcodes: &[Opcode], entry: usize) -> Result<(), String> {
+        let mut depths: Vec<Option<i64>> = vec![None; opcodes.len()];
+        let mut worklist: Vec<(usize, i64)> = vec![(entry, 0)];
+
+        while let Some((pc, depth)) = worklist.pop() {
+            if pc >= opcodes.len() {
+                continue;
+            }
+            match depths[pc] {
+                Some(seen) if seen == depth => continue,
+                Some(seen) => {
+                    return Err(format!(
+                        "Stack imbalance at opcode {}: depth {} on one path, {} on another",
+                        pc, seen, depth
+                    ));
+                }
+                None => depths[pc] = Some(depth),
+            }
+
+            let op = &opcodes[pc];
+            let (pops, pushes) = stack_effect(op);
+            if depth < pops {
+                return Err(format!("Stack underflow at opcode {} ({:?})", pc, op));
+            }
+            let next = depth - pops + pushes;
+
+            match op {
+                Opcode::Jmp(addr) => worklist.push((jump_target(pc, *addr, opcodes.len())?, next)),
+                Opcode::Jz(addr) => {
+                    worklist.push((jump_target(pc, *addr, opcodes.len())?, next));
+                    worklist.push((pc + 1, next));
+                },
+                Opcode::Ret => {},
+                _ => worklist.push((pc + 1, next)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a compiled program before it is run: the entry point and every
+    /// jump target are in range, every call enters a function, variable operands name valid slots, the
+    /// operand stack stays balanced, and no path runs off the end without a `Ret`.
+    ///
+    /// Each function is checked from its own entry, with locals checked against
+    /// the frame its `Ent` allocates.
+    pub fn verify(program: &Program) -> Result<(), String> {
+        let opcodes = &program.opcodes;
+        let len = opcodes.len();
+        let entries: Vec<usize> = if program.functions.is_empty() {
+            vec![program.entry]
+        } else {
+            program.functions.iter().map(|f| f.entry).collect()
+        };
+        if let Some(&entry) = entries.iter().find(|&&entry| entry >= len) {
+            return Err(format!("Entry point {} out of range ({} opcodes)", entry, len));
+        }
+        for (pc, op) in opcodes.iter().enumerate() {
+            // Functions are laid out in order, so pc belongs to the last one starting at or before it.
+            let frame = match entries.iter().filter(|&&entry| entry <= pc).max().map(|&entry| &opcodes[entry]) {
+                Some(Opcode::Ent(size)) => *size,
+                _ => 0,
+            };
+            check_operands(pc, op, frame)?;
+            match *op {
+                Opcode::Jmp(addr) | Opcode::Jz(addr) => {
+                    jump_target(pc, addr, len)?;
+                },
+                Opcode::Call(addr, _) if !entries.iter().any(|&entry| entry as i64 == addr) => {
+                    return Err(format!("Call target {} is not a function entry at opcode {}", addr, pc));
+                },
+                Opcode::Call(addr, argc) => {
+                    let callee = program.functions.iter().find(|f| f.entry as i64 == addr);
+                    if let Some(callee) = callee.filter(|f| f.params.len() as i64 != argc) {
+                        return Err(format!(
+                            "Call at opcode {} passes {} arguments to '{}', which takes {}",
+                            pc, argc, callee.name, callee.params.len()
+                        ));
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        let mut seen = vec![false; len];
+        let mut worklist = entries.clone();
+        while let Some(pc) = worklist.pop() {
+            if std::mem::replace(&mut seen[pc], true) {
+                continue;
+            }
+            let successors = match opcodes[pc] {
+                Opcode::Ret => vec![],
+                Opcode::Jmp(addr) => vec![addr as usize],
+                Opcode::Jz(addr) => vec![addr as usize, pc + 1],
+                _ => vec![pc + 1],
+            };
+            for next in successors {
+                if next >= len {
+                    return Err(format!("Control runs off the end of the program after opcode {}", pc));
+                }
+                worklist.push(next);
+            }
+        }
+        entries.into_iter().try_for_each(|entry| check_stack(opcodes, entry))
+    }
+
+    /// Checks the slot operands of one opcode; locals must lie inside a frame
+    /// of `frame` slots numbered from 1.
+    fn check_operands(pc: usize, op: &Opcode, frame: i64) -> Result<(), String> {
+        let (offset, len, local) = match *op {
+            Opcode::Ld(offset) | Opcode::St(offset) | Opcode::Lea(offset) => (offset, 1, true),
+            Opcode::LdX(offset, len) | Opcode::StX(offset, len) => (offset, len, true),
+            Opcode::LdG(offset) | Opcode::StG(offset) => (offset, 1, false),
+            Opcode::LdGX(offset, len) | Opcode::StGX(offset, len) | Opcode::AddrG(offset, len) => (offset, len, false),
+            Opcode::Bound(len) => (0, len, false),
+            Opcode::Ent(size) if size < 0 => return Err(format!("Negative frame size {} at opcode {}", size, pc)),
+            _ => return Ok(()),
+        };
+        if len < 1 {
+            Err(format!("Invalid length {} at opcode {}", len, pc))
+        } else if !local && offset < 0 {
+            Err(format!("Negative global offset {} at opcode {}", offset, pc))
+        } else if local && (offset < 1 || offset + len - 1 > frame) {
+            Err(format!("Local offset {} outside the frame of {} slots at opcode {}", offset, frame, pc))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A maximal straight-line run of opcodes, `start..end`, entered only at `start`.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct BasicBlock {
+        pub start: usize,
+        pub end: usize,
+        pub successors: Vec<usize>, // Indices into the block list.
+    }
+
+    /// Splits the opcode stream into basic blocks and links them into a control-flow graph.
+    ///
+    /// A block starts at the program start, at every jump target and after every
+    /// jump or return. Jumps outside the program get no successor edge.
+    pub fn basic_blocks(opcodes: &[Opcode]) -> Vec<BasicBlock> {
+        let len = opcodes.len();
+        let mut leader = vec![false; len];
+        if len > 0 {
+            leader[0] = true;
+        }
+        for (pc, op) in opcodes.iter().enumerate() {
+            if let Opcode::Jmp(addr) | Opcode::Jz(addr) = op {
+                if let Ok(target) = jump_target(pc, *addr, len) {
+                    if target < len {
+                        leader[target] = true;
+                    }
+                }
+            }
+            if matches!(op, Opcode::Jmp(_) | Opcode::Jz(_) | Opcode::Ret) && pc + 1 < len {
+                leader[pc + 1] = true;
+            }
+        }
+
+        let starts: Vec<usize> = (0..len).filter(|&pc| leader[pc]).collect();
+        let block_of = |pc: usize| starts.binary_search(&pc).ok();
+        starts
+            .iter()
+            .enumerate()
+            .map(|(index, &start)| {
+                let end = starts.get(index + 1).copied().unwrap_or(len);
+                let fallthrough = if end < len { Some(index + 1) } else { None };
+                let jump = |addr: i64| jump_target(end - 1, addr, len).ok().and_then(block_of);
+                let successors = match opcodes[end - 1] {
+                    Opcode::Jmp(addr) => jump(addr).into_iter().collect(),
+                    Opcode::Jz(addr) => fallthrough.into_iter().chain(jump(addr)).collect(),
+                    Opcode::Ret => Vec::new(),
+                    _ => fallthrough.into_iter().collect(),
+                };
+                BasicBlock { start, end, successors }
+            })
+            .collect()
+    }
+
+    /// Returns the cyclomatic complexity (edges - nodes + 2) of the control-flow graph.
+    ///
+    /// Only blocks reachable from the first opcode are counted, so the implicit
+    /// `return 0` after an explicit return does not skew the result. Blocks that
+    /// return are joined to a single virtual exit node, making every return path
+    /// count as one decision's worth of complexity.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn complexity(opcodes: &[Opcode]) -> usize {
+        let blocks = basic_blocks(opcodes);
+        if blocks.is_empty() {
+            return 1;
+        }
+        graph_complexity(&blocks, &reachable_blocks(&blocks, 0))
+    }
+
+    /// Size and cyclomatic complexity of one function, as `--metrics` reports them.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct FunctionMetrics {
+        pub name: String,
+        pub opcodes: usize,    // Opcodes reachable from the function's entry.
+        pub complexity: usize, // Cyclomatic complexity of the function's own blocks.
+    }
+
+    /// Measures each function of `program`, in source order.
+    ///
+    /// A function's code is what its entry reaches without following calls, so
+    /// code jumped over (such as the functions inside a script's `main`) or
+    /// never reached is not counted.
+    pub fn function_metrics(program: &Program) -> Vec<FunctionMetrics> {
+        let blocks = basic_blocks(&program.opcodes);
+        program
+            .functions
+            .iter()
+            .filter_map(|function| {
+                let first = blocks.iter().position(|block| block.start == function.entry)?;
+                let reachable = reachable_blocks(&blocks, first);
+                let opcodes = blocks
+                    .iter()
+                    .zip(&reachable)
+                    .filter(|(_, &seen)| seen)
+                    .map(|(block, _)| block.end - block.start)
+                    .sum();
+                let complexity = graph_complexity(&blocks, &reachable);
+                Some(FunctionMetrics { name: function.name.clone(), opcodes, complexity })
+            })
+            .collect()
+    }
+
+    /// Marks the blocks reachable from block `first` along successor edges.
+    fn reachable_blocks(blocks: &[BasicBlock], first: usize) -> Vec<bool> {
+        let mut reachable = vec![false; blocks.len()];
+        let mut worklist = vec![first];
+        while let Some(index) = worklist.pop() {
+            if !std::mem::replace(&mut reachable[index], true) {
+                worklist.extend(&blocks[index].successors);
+            }
+        }
+        reachable
+    }
+
+    /// Returns edges - nodes + 2 over the `reachable` blocks, with every block
+    /// that returns joined to a virtual exit node.
+    fn graph_complexity(blocks: &[BasicBlock], reachable: &[bool]) -> usize {
+        let mut nodes = 1; // The virtual exit node.
+        let mut edges = 0;
+        for (block, _) in blocks.iter().zip(reachable).filter(|(_, &seen)| seen) {
+            nodes += 1;
+            edges += block.successors.len();
+            if block.successors.is_empty() {
+                edges += 1; // Edge to the exit node.
+            }
+        }
+        edges + 2 - nodes
+    }
+
+    /// Converts a jump operand into an opcode index, rejecting targets outside the program.
+    fn jump_target(pc: usize, addr: i64, len: usize) -> Result<usize, String> {
+        if addr < 0 || addr as usize > len {
+            Err(format!("Jump target {} out of range at opcode {}", addr, pc))
+        } else {
+            Ok(addr as usize)
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::lexer::tokenize;
+        use crate::parser::parse;
+
+        fn program(opcodes: Vec<Opcode>) -> Program {
+            let spans = vec![Default::default(); opcodes.len()];
+            Program {
+                opcodes,
+                spans,
+                entry: 0,
+                globals: 0,
+                frame_size: 0,
+                main_type: crate::parser::Type::Int,
+                data: Vec::new(),
+                functions: Vec::new(),
+                variables: Vec::new(),
+                ast: Default::default(),
+            }
+        }
+
+        #[test]
+        fn test_verify_accepts_compiled_program() {
+            let src = "int g[3]; int main() { int a, b[2]; a = 0; while (a < 3) { g[a] = b[1] = a; a = a + 1; } if (a) return g[2]; }";
+            let program = crate::parser::parse_spanned(crate::lexer::tokenize_spanned(src).unwrap()).unwrap();
+            assert_eq!(verify(&program), Ok(()));
+        }
+
+        #[test]
+        fn test_verify_rejects_malformed_programs() {
+            let cases = vec![
+                (vec![], "Entry point 0 out of range (0 opcodes)"),
+                (vec![Opcode::Imm(1), Opcode::Jz(7), Opcode::Ret], "Jump target 7 out of range at opcode 1"),
+                (vec![Opcode::LdG(-2), Opcode::Ret], "Negative global offset -2 at opcode 0"),
+                (vec![Opcode::Ent(1), Opcode::Ld(2), Opcode::Ret], "Local offset 2 outside the frame of 1 slots at opcode 1"),
+                (vec![Opcode::Ent(2), Opcode::Imm(0), Opcode::LdX(1, 0), Opcode::Ret], "Invalid length 0 at opcode 2"),
+                (vec![Opcode::Imm(1), Opcode::Pop], "Control runs off the end of the program after opcode 1"),
+                (vec![Opcode::Imm(1), Opcode::Jz(3), Opcode::Imm(2), Opcode::Ret], "Stack imbalance at opcode 3: depth 1 on one path, 0 on another"),
+                (vec![Opcode::Add, Opcode::Ret], "Stack underflow at opcode 0 (Add)"),
+                (vec![Opcode::Call(2, 0), Opcode::Ret, Opcode::Imm(1), Opcode::Ret], "Call target 2 is not a function entry at opcode 0"),
+            ];
+            for (opcodes, expected) in cases {
+                assert_eq!(verify(&program(opcodes)), Err(expected.to_string()));
+            }
+        }
+
+        #[test]
+        fn test_balanced_program() {
+            let src = "int main() { int a; a = 2; if (a) { a = a * 3; } else { a = 1; } return a; }";
+            let opcodes = parse(tokenize(src).unwrap()).unwrap();
+            assert!(validate_stack(&opcodes).is_ok());
+        }
+
+        #[test]
+        fn test_imbalanced_join_point() {
+            // The taken branch pushes an extra value before rejoining the fallthrough path.
+            let opcodes = vec![
+                Opcode::Imm(1),
+                Opcode::Jz(4),
+                Opcode::Imm(2),
+                Opcode::Imm(3),
+                Opcode::Ret,
+            ];
+            let err = validate_stack(&opcodes).unwrap_err();
+            assert!(err.contains("Stack imbalance"), "unexpected message: {}", err);
+        }
+
+        #[test]
+        fn test_underflow_detected() {
+            let opcodes = vec![Opcode::Imm(1), Opcode::Add, Opcode::Ret];
+            let err = validate_stack(&opcodes).unwrap_err();
+            assert!(err.contains("Stack underflow at opcode 1"), "unexpected message: {}", err);
+        }
+
+        #[test]
+        fn test_basic_blocks_split_at_branches() {
+            let src = "int main() { int a; a = 1; if (a) a = 2; return a; }";
+            let opcodes = parse(tokenize(src).unwrap()).unwrap();
+            let blocks = basic_blocks(&opcodes);
+            // Condition, then-branch, join (ending in return).
+            assert_eq!(blocks.len(), 3);
+            assert_eq!(blocks[0].successors, vec![1, 2]);
+            assert_eq!(blocks[1].successors, vec![2]);
+            assert!(blocks[2].successors.is_empty());
+            assert_eq!(blocks.last().unwrap().end, opcodes.len());
+        }
+
+        #[test]
+        fn test_complexity_of_straight_line_code() {
+            let src = "int main() { int a; a = 1; a = a + 2; return a; }";
+            let opcodes = parse(tokenize(src).unwrap()).unwrap();
+            assert_eq!(complexity(&opcodes), 1);
+        }
+
+        #[test]
+        fn test_complexity_of_branching_code() {
+            let single_if = parse(tokenize("int main() { int a; a = 1; if (a) a = 2; return a; }").unwrap()).unwrap();
+            assert_eq!(complexity(&single_if), 2);
+            let src = "int main() { int a; a = 3; while (a) { if (a) a = a - 1; else a = 0; } return a; }";
+            let nested = parse(tokenize(src).unwrap()).unwrap();
+            assert_eq!(complexity(&nested), 3);
+        }
+
+        #[test]
+        fn test_function_metrics() {
+            let src = "int flat() { return 1; }
+                int loops() { int i, n; for (i = 0; i < 3; i = i + 1) if (i || n) n = n + 1; return n; }
+                int main() { return flat() + loops(); }";
+            let program = crate::parser::parse_spanned(crate::lexer::tokenize_spanned(src).unwrap()).unwrap();
+            let metrics = function_metrics(&program);
+            let complexities: Vec<(&str, usize)> = metrics.iter().map(|m| (m.name.as_str(), m.complexity)).collect();
+            // The for, the if and each operand of `||` add a decision; calls add none.
+            assert_eq!(complexities, [("flat", 1), ("loops", 5), ("main", 1)]);
+            assert_eq!(metrics[0].opcodes, program.functions[1].entry);
+        }
+    }
+}
+
+//
+// Module: repl
+//
+mod repl {
+    //! An interactive read–eval–print loop built on a persistent `Session`.
+    //!
+    //! Each input is one of:
+    //!   - a declaration (starting with a type), added to the session's definitions;
+    //!   - a statement ending in `;`, run for its side effects;
+    //!   - an expression, whose value is printed.
+    //!
+    //! Globals keep their values between inputs until the session is reset,
+    //! and so do heap blocks and string literals, so pointers stored in
+    //! globals stay valid.
+
+    use crate::lexer::{tokenize, Token};
+    use crate::parser::{self, Type};
+    use crate::vm::{self, Value};
+    use crate::{compile_with_config, format_result, type_of};
+    use std::io::{self, BufRead, Write};
+
+    /// Accumulated definitions and memory shared by successive inputs.
+    #[derive(Debug, Default)]
+    pub struct Session {
+        definitions: String,
+        globals: Vec<Value>,
+        heap: vm::Heap,
+        data: Vec<Value>, // String literals of every input so far.
+    }
+
+    impl Session {
+        /// Creates an empty session.
+        pub fn new() -> Self {
+            Session::default()
+        }
+
+        /// Evaluates one input, returning the value and static type of an
+        /// expression, or `None` for declarations and statements.
+        pub fn eval(&mut self, line: &str) -> Result<Option<(Value, Type)>, String> {
+            let line = line.trim();
+            if line.is_empty() {
+                return Ok(None);
+            }
+            if is_declaration(line) {
+                let definitions = format!("{}{}\n", self.definitions, line);
+                self.run(&format!("{}int main() {{ return 0; }}", definitions))?;
+                self.definitions = definitions;
+                Ok(None)
+            } else if line.ends_with(';') || line.ends_with('}') {
+                self.run(&format!("{}int main() {{\n{}\nreturn 0; }}", self.definitions, line))?;
+                Ok(None)
+            } else {
+                // Type the expression first so that main returns it unconverted.
+                let ty = self.type_of(line)?;
+                let result = self.run(&format!("{}{} main() {{ return {}\n; }}", self.definitions, ty, line))?;
+                Ok(Some((result, ty)))
+            }
+        }
+
+        /// Forgets every definition, global value, heap block and string literal.
+        pub fn reset(&mut self) {
+            *self = Session::default();
+        }
+
+        /// Returns the static type of an expression over the session's
+        /// definitions, without running it.
+        pub fn type_of(&self, expr: &str) -> Result<Type, String> {
+            type_of(&self.definitions, expr).map_err(|e| e.to_string())
+        }
+
+        /// Compiles and runs a complete program against the session's memory.
+        fn run(&mut self, source: &str) -> Result<Value, String> {
+            let config = parser::Config { data: self.data.clone(), ..parser::Config::default() };
+            let program = compile_with_config(source, config).map_err(|e| e.to_string())?;
+            let result = vm::execute_with_memory(
+                &program.opcodes,
+                program.entry,
+                &program.data,
+                &mut self.globals,
+                &mut self.heap,
+                vm::Config::default(),
+            );
+            self.data = program.data;
+            result.map_err(|e| format!("Runtime error: {}", e.message))
+        }
+    }
+
+    /// Returns true if the input starts with a type specifier.
+    fn is_declaration(line: &str) -> bool {
+        matches!(
+            tokenize(line).ok().and_then(|tokens| tokens.into_iter().next()),
+            Some(
+                Token::Int | Token::Char | Token::Double | Token::Void | Token::Signed | Token::Unsigned | Token::Const | Token::Register
+            )
+        )
+    }
+
+    /// Runs the loop until end of input or `:quit`; `:reset` clears the session
+    /// and `:type EXPR` prints the type of an expression without running it.
+    ///
+    /// Errors are printed and the loop continues.
+    pub fn run_repl(input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        let mut session = Session::new();
+        loop {
+            write!(out, "c4> ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            match line.trim() {
+                ":quit" => return Ok(()),
+                ":reset" => session.reset(),
+                command if command.starts_with(":type ") => match session.type_of(&command[":type ".len()..]) {
+                    Ok(ty) => writeln!(out, "{}", ty)?,
+                    Err(e) => writeln!(out, "{}", e)?,
+                },
+                _ => match session.eval(&line) {
+                    Ok(Some((value, ty))) => writeln!(out, "= {} : {}", format_result(value, &ty), ty)?,
+                    Ok(None) => {},
+                    Err(e) => writeln!(out, "{}", e)?,
+                },
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_session_keeps_globals_between_inputs() {
+            let mut session = Session::new();
+            assert_eq!(session.eval("int x, y;"), Ok(None));
+            assert_eq!(session.eval("x = 4;"), Ok(None));
+            assert_eq!(session.eval("y = x * 2;"), Ok(None));
+            assert_eq!(session.eval("x + y"), Ok(Some((Value::Int(12), Type::Int))));
+        }
+
+        #[test]
+        fn test_session_keeps_heap_blocks_between_inputs() {
+            let mut session = Session::new();
+            session.eval("int *p;").unwrap();
+            session.eval("p = malloc(2);").unwrap();
+            assert_eq!(session.eval("*p = 3;"), Ok(None));
+            session.eval("p[1] = 4;").unwrap();
+            assert_eq!(session.eval("p[0] + p[1]"), Ok(Some((Value::Int(7), Type::Int))));
+        }
+
+        #[test]
+        fn test_session_keeps_string_literals_between_inputs() {
+            let mut session = Session::new();
+            session.eval("char *s, *t;").unwrap();
+            session.eval("s = \"hi\";").unwrap();
+            session.eval("t = \"yo\";").unwrap();
+            assert_eq!(session.eval("s[1]"), Ok(Some((Value::Int('i' as i64), Type::Char))));
+            assert_eq!(session.eval("t[0]"), Ok(Some((Value::Int('y' as i64), Type::Char))));
+        }
+
+        #[test]
+        fn test_session_reset_forgets_globals() {
+            let mut session = Session::new();
+            session.eval("int x;").unwrap();
+            session.eval("x = 3;").unwrap();
+            assert_eq!(session.eval("x"), Ok(Some((Value::Int(3), Type::Int))));
+            session.reset();
+            let err = session.eval("x").unwrap_err();
+            assert!(err.contains("Undefined variable: x"), "unexpected message: {}", err);
+        }
+
+        #[test]
+        fn test_repl_type_command() {
+            let mut input = "int *p, n;\n:type p\n:type n = 2.5\n:type m\nn\n".as_bytes();
+            let mut out = Vec::new();
+            run_repl(&mut input, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("c4> int*\n"), "unexpected output: {}", out);
+            assert!(out.contains("c4> int\n"), "unexpected output: {}", out);
+            assert!(out.contains("Undefined variable: m"), "unexpected output: {}", out);
+            // Typing an assignment does not run it.
+            assert!(out.contains("= 0 : int\n"), "unexpected output: {}", out);
+        }
+
+        #[test]
+        fn test_repl_type_command_ignores_function_scope() {
+            let mut session = Session::new();
+            session.eval("int f(int zz) { int yy; return zz; }").unwrap();
+            assert_eq!(session.type_of("f(1)"), Ok(Type::Int));
+            let err = session.type_of("zz").unwrap_err();
+            assert!(err.contains("Undefined variable: zz"), "unexpected message: {}", err);
+            assert!(session.type_of("yy").is_err());
+        }
+
+        #[test]
+        fn test_repl_loop_reports_errors_and_continues() {
+            let mut input = "int a;\na = 2;\nb\na + 1\n:reset\na\n".as_bytes();
+            let mut out = Vec::new();
+            run_repl(&mut input, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("Undefined variable: b"));
+            assert!(out.contains("= 3 : int\n"));
+            assert!(out.contains("Undefined variable: a"));
+        }
+
+        #[test]
+        fn test_repl_prints_value_with_type() {
+            let mut input = "2 + 2\n1.5 + 2\ndouble d;\nd = 7 / 2;\nd\n".as_bytes();
+            let mut out = Vec::new();
+            run_repl(&mut input, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.contains("= 4 : int\n"), "unexpected output: {}", out);
+            assert!(out.contains("= 3.5 : double\n"), "unexpected output: {}", out);
+            assert!(out.contains("= 3.0 : double\n"), "unexpected output: {}", out);
+        }
+    }
+}
+
+/// An error raised while turning source text into a `Program`.
+#[derive(Debug, Clone, PartialEq)]
+enum CompileError {
+    Preprocess(String),
+    Lex(String),
+    Parse(String),
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CompileError::Preprocess(e) => write!(f, "Preprocessing error: {}", e),
+            CompileError::Lex(e) => write!(f, "Lexing error: {}", e),
+            CompileError::Parse(e) => write!(f, "Parsing error: {}", e),
+        }
+    }
+}
+
+/// Preprocesses and compiles C source into a `Program` bundling the opcodes,
+/// their source spans, the entry address, the global cell count, and the largest frame size.
+fn compile_detailed(source: &str) -> Result<parser::Program, CompileError> {
+    compile_with_config(source, parser::Config::default())
+}
+
+/// Compiles like `compile_detailed`, under the given language settings.
+fn compile_with_config(source: &str, config: parser::Config) -> Result<parser::Program, CompileError> {
+    let source = preprocessor::preprocess(source, &[]).map_err(CompileError::Preprocess)?;
+    let tokens = lexer::tokenize_spanned(&source).map_err(CompileError::Lex)?;
+    parser::parse_with_config(tokens, config).map_err(CompileError::Parse)
+}
+
+/// Preprocesses and compiles a script, whose top-level statements form an
+/// implicit `main` returning the value of the last expression.
+fn compile_script(source: &str) -> Result<parser::Program, CompileError> {
+    let source = preprocessor::preprocess(source, &[]).map_err(CompileError::Preprocess)?;
+    let tokens = lexer::tokenize_spanned(&source).map_err(CompileError::Lex)?;
+    parser::parse_script(tokens).map_err(CompileError::Parse)
+}
+
+/// Returns the static type of the bare expression `expr`, resolving names
+/// against the global declarations in `definitions`. No code is run.
+fn type_of(definitions: &str, expr: &str) -> Result<parser::Type, CompileError> {
+    let lex = |source: &str| lexer::tokenize_spanned(source).map_err(CompileError::Lex);
+    parser::expression_type(lex(definitions)?, lex(expr)?).map_err(CompileError::Parse)
+}
+
+/// Resource limits for `run_sandboxed`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(not(test), allow(dead_code))]
+struct SandboxLimits {
+    timeout: Duration,    // Wall-clock time the program may run.
+    steps: u64,           // Most opcodes it may execute.
+    heap_cells: usize,    // Most cells `malloc` may hand out.
+    memory_cells: usize,  // Most cells its globals and local frames may use together.
+    call_depth: usize,    // Most calls that may be active at once.
+}
+
+impl Default for SandboxLimits {
+    fn default() -> Self {
+        SandboxLimits {
+            timeout: Duration::from_secs(1),
+            steps: 10_000_000,
+            heap_cells: 1 << 16,
+            memory_cells: 1 << 16,
+            call_depth: 1 << 10,
+        }
+    }
+}
+
+/// Compiles, verifies and runs untrusted source on a separate thread under
+/// `limits`, so that neither a runaway loop nor a huge allocation can take
+/// down the caller.
+///
+/// The VM checks the deadline itself, so a timed-out program stops rather than
+/// spinning on in the background; the caller additionally stops waiting shortly
+/// after the deadline in case the worker is stuck outside the VM loop.
+#[cfg_attr(not(test), allow(dead_code))]
+fn run_sandboxed(source: &str, limits: SandboxLimits) -> Result<vm::Value, String> {
+    let source = source.to_string();
+    let timeout = limits.timeout;
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = compile_detailed(&source).map_err(|e| e.to_string()).and_then(|program| {
+            analysis::verify(&program).map_err(|e| format!("Verification error: {}", e))?;
+            let config = vm::Config {
+                heap_cells: limits.heap_cells,
+                max_steps: Some(limits.steps),
+                deadline: Some(deadline),
+                memory_cells: limits.memory_cells,
+                max_call_depth: limits.call_depth,
+                ..vm::Config::default()
+            };
+            vm::execute_with_config(&program.opcodes, program.entry, &program.data, config).map_err(|e| {
+                match program.spans.get(e.pc) {
+                    Some(span) => format!("Runtime error at {}: {}", span, e.message),
+                    None => format!("Runtime error: {}", e.message),
+                }
+            })
+        });
+        // The receiver is gone if the caller already gave up waiting.
+        let _ = sender.send(result);
+    });
+    let timed_out = || format!("Program timed out after {} ms", timeout.as_millis());
+    match receiver.recv_timeout(timeout + Duration::from_millis(100)) {
+        Ok(Err(message)) if message.ends_with("time limit exceeded") => Err(timed_out()),
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(timed_out()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => Err("Program panicked during execution".to_string()),
+    }
+}
+
+/// Builds a report of the accepted language subset from the lexer's tables and
+/// the parser's operator mappings, so it cannot drift from the implementation.
+fn features_report() -> String {
+    let keywords: Vec<&str> = lexer::KEYWORDS.iter().map(|(word, _)| *word).collect();
+    let operators: Vec<&str> = lexer::OPERATORS
+        .iter()
+        .filter(|(_, token)| parser::supports_operator(token))
+        .map(|(text, _)| *text)
+        .collect();
+    let punctuation: Vec<&str> = lexer::PUNCTUATION.iter().map(|(text, _)| *text).collect();
+    let mut report = String::from("Supported C subset\n");
+    report.push_str(&format!("  keywords:    {}\n", keywords.join(" ")));
+    report.push_str(&format!("  operators:   {}\n", operators.join(" ")));
+    report.push_str(&format!("  punctuation: {}\n", punctuation.join(" ")));
+    report.push_str(&format!("  builtins:    {}\n", parser::BUILTINS.join(" ")));
+    report.push_str("  statements:\n");
+    for kind in parser::STATEMENT_KINDS {
+        report.push_str(&format!("    {}\n", kind));
+    }
+    report
+}
+
+/// Renders main's result according to its declared return type.
+///
+/// A `char` result is shown as the character followed by its code, e.g. `'A' (65)`.
+fn format_result(result: vm::Value, ty: &parser::Type) -> String {
+    match ty {
+        parser::Type::Int | parser::Type::Void | parser::Type::Ptr(_) | parser::Type::Struct(_) => result.as_int().to_string(),
+        parser::Type::Double => vm::Value::Float(result.as_float()).to_string(),
+        parser::Type::Char | parser::Type::UChar => {
+            let code = result.as_int();
+            let ch = char::from(code as u8);
+            format!("'{}' ({})", ch.escape_default(), code)
+        },
+    }
+}
+
+/// Presents the result of a run for `--print-result` and `--call`.
+///
+/// Embedders pass their own implementation to `run_with` to change how
+/// results are shown; `DefaultResultWriter` prints the usual message.
+trait ResultWriter {
+    /// Writes `result`, a value of static type `ty`, to `out`.
+    fn write_result(&mut self, out: &mut dyn Write, result: vm::Value, ty: &parser::Type) -> io::Result<()>;
+}
+
+/// Prints `Program executed successfully. Result: ...` using `format_result`.
+struct DefaultResultWriter;
+
+impl ResultWriter for DefaultResultWriter {
+    fn write_result(&mut self, out: &mut dyn Write, result: vm::Value, ty: &parser::Type) -> io::Result<()> {
+        writeln!(out, "Program executed successfully. Result: {}", format_result(result, ty))
+    }
+}
+
+/// Maps main's result to a process exit code as C does, clamped to 0–255.
+/// A `void` main exits with 0.
+fn exit_code(result: vm::Value, ty: &parser::Type) -> i32 {
+    match ty {
+        parser::Type::Void => 0,
+        _ => result.as_int().clamp(0, 255) as i32,
+    }
+}
+
+/// A pipeline stage whose intermediate representation can be printed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Tokens,
+    Ast,
+    Opcodes,
+}
+
+/// Command-line options.
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    filename: Option<String>, // Source file; absent only in REPL mode.
+    repl: bool,               // Start the interactive loop instead of compiling a file.
+    features: bool,           // Print the supported-subset report and exit.
+    emit: Vec<Stage>, // Stages to print, in pipeline order.
+    run: bool,        // Whether to execute after printing (cleared by --no-run).
+    verify: bool,     // Check the opcodes with the static verifier before running.
+    lint: bool,       // Warn about style problems such as mixed-tab indentation.
+    print_result: bool, // Print main's result and exit 0 instead of exiting with it.
+    call: Option<String>, // Function to run instead of main (set by --call NAME); its result is printed.
+    heap: usize,      // Heap size in cells for malloc (set by --heap N).
+    division: vm::Division, // Integer division rounding (set by --division=floor|truncate).
+    debug_vm: bool,   // Print the failing opcode and operand stack with runtime errors.
+    defines: Vec<(String, String)>, // Macros predefined by -D NAME[=VALUE], in order.
+    script: bool,     // Run top-level statements in an implicit main (set by --script).
+    metrics: Option<usize>, // Complexity above which --metrics[=N] flags a function.
+    args: Vec<String>,      // Arguments after the source file, passed to main as argv[1] onwards.
+}
+
+/// Complexity threshold of `--metrics` without `=N`: McCabe's suggested limit.
+const DEFAULT_COMPLEXITY_THRESHOLD: usize = 10;
+
+/// Parses the stage list of `--emit=tokens,ast,opcodes`.
+fn parse_stages(list: &str) -> Result<Vec<Stage>, String> {
+    let mut stages = Vec::new();
+    for name in list.split(',') {
+        let stage = match name.trim() {
+            "tokens" => Stage::Tokens,
+            "ast" => Stage::Ast,
+            "opcodes" => Stage::Opcodes,
+            other => return Err(format!("Unknown stage '{}' (expected tokens, ast or opcodes)", other)),
+        };
+        if !stages.contains(&stage) {
+            stages.push(stage);
+        }
+    }
+    Ok(stages)
+}
+
+/// Parses command-line arguments (excluding the program name).
+///
+/// `--tokens` and `--dump` are shorthands for `--emit=tokens` and `--emit=opcodes`.
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut filename = None;
+    let mut emit = Vec::new();
+    let mut run = true;
+    let mut repl = false;
+    let mut features = false;
+    let mut heap = vm::DEFAULT_HEAP_CELLS;
+    let mut verify = false;
+    let mut lint = false;
+    let mut print_result = false;
+    let mut call = None;
+    let mut division = vm::Division::Truncate;
+    let mut debug_vm = false;
+    let mut defines = Vec::new();
+    let mut script = false;
+    let mut metrics = None;
+    let mut program_args = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let stages = match arg.as_str() {
+            "--features" => { features = true; continue; },
+            "--heap" => {
+                let size = args.next().ok_or("--heap expects a size in cells")?;
+                heap = size.parse().map_err(|_| format!("Invalid heap size: {}", size))?;
+                continue;
+            },
+            "--repl" => { repl = true; continue; },
+            "--tokens" => vec![Stage::Tokens],
+            "--dump" => vec![Stage::Opcodes],
+            "--no-run" => { run = false; continue; },
+            "--verify" => { verify = true; continue; },
+            "--lint" => { lint = true; continue; },
+            "--print-result" => { print_result = true; continue; },
+            "--debug-vm" => { debug_vm = true; continue; },
+            "--script" => { script = true; continue; },
+            "--metrics" => { metrics = Some(DEFAULT_COMPLEXITY_THRESHOLD); continue; },
+            "--call" => {
+                call = Some(args.next().ok_or("--call expects a function name")?.clone());
+                continue;
+            },
+            "--division=truncate" => { division = vm::Division::Truncate; continue; },
+            "--division=floor" => { division = vm::Division::Floor; continue; },
+            "-D" => {
+                defines.push(parse_define(args.next().ok_or("-D expects a macro name")?)?);
+                continue;
+            },
+            _ if arg.starts_with("-D") => { defines.push(parse_define(&arg[2..])?); continue; },
+            _ if arg.starts_with("--metrics=") => {
+                let limit = &arg["--metrics=".len()..];
+                metrics = Some(limit.parse().map_err(|_| format!("Invalid complexity threshold: {}", limit))?);
+                continue;
+            },
+            _ => match arg.strip_prefix("--emit=") {
+                Some(list) => parse_stages(list)?,
+                None if arg.starts_with("--") => return Err(format!("Unknown option: {}", arg)),
+                None => {
+                    // Everything after the source file is for the program.
+                    filename = Some(arg.clone());
+                    program_args.extend(args.by_ref().cloned());
+                    continue;
+                },
+            },
+        };
+        for stage in stages {
+            if !emit.contains(&stage) {
+                emit.push(stage);
+            }
+        }
+    }
+    // Print stages in pipeline order regardless of how they were requested.
+    emit.sort_by_key(|stage| *stage as u8);
+    if filename.is_none() && !repl && !features {
+        return Err(
+            "Usage: c4 [--emit=tokens,ast,opcodes] [--no-run] [--print-result] [--call NAME] [--verify] [--lint] [--heap N] [--division=floor|truncate] [--debug-vm] [--script] [--metrics[=N]] [-D NAME[=VALUE]] <file.c> [args...] \
+             | c4 --repl | c4 --features"
+                .to_string(),
+        );
+    }
+    Ok(Options { filename, repl, features, emit, run, verify, lint, print_result, call, heap, division, debug_vm, defines, script, metrics, args: program_args })
+}
+
+/// Parses the argument of `-D`: `NAME` defines the macro as `1`, as C compilers do.
+fn parse_define(arg: &str) -> Result<(String, String), String> {
+    let (name, value) = arg.split_once('=').unwrap_or((arg, "1"));
+    let valid = name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if valid {
+        Ok((name.to_string(), value.to_string()))
+    } else {
+        Err(format!("Invalid macro name for -D: '{}'", name))
+    }
+}
+
+/// Formats the token stream, one token per line with its source position.
+fn format_tokens(tokens: &[lexer::Spanned<lexer::Token>]) -> String {
+    let mut out = String::from("== tokens ==\n");
+    for token in tokens {
+        out.push_str(&format!("{}\t{:?}\n", token.span, token.value));
+    }
+    out
+}
+
+/// Formats the syntax tree, one node per line, indented under its parent.
+fn format_ast(tree: &ast::Ast) -> String {
+    format!("== ast ==\n{}", tree.outline())
+}
+
+/// Formats the opcode stream, one opcode per line with its index and source position.
+fn format_opcodes(program: &parser::Program) -> String {
+    let mut out = format!(
+        "== opcodes ==\n; entry {}, {} global cells, frame size {}\n",
+        program.entry, program.globals, program.frame_size
+    );
+    for (index, (op, span)) in program.opcodes.iter().zip(&program.spans).enumerate() {
+        out.push_str(&format!("{:4}  {:<12} ; {}\n", index, format!("{:?}", op), span));
+    }
+    out
+}
+
+/// Formats the size and complexity of each function, marking those whose
+/// complexity exceeds `threshold` as candidates for refactoring.
+fn format_metrics(program: &parser::Program, threshold: usize) -> String {
+    let metrics = analysis::function_metrics(program);
+    let width = metrics.iter().map(|m| m.name.len()).chain(["function".len()]).max().unwrap_or(0);
+    let mut out = format!("== metrics ==\n; complexity threshold {}\n", threshold);
+    out.push_str(&format!("{:<width$}  opcodes  complexity\n", "function"));
+    for m in metrics {
+        let line = format!("{:<width$}  {:>7}  {:>10}", m.name, m.opcodes, m.complexity);
+        if m.complexity > threshold {
+            out.push_str(&format!("{}  above threshold\n", line));
+        } else {
+            out.push_str(&format!("{}\n", line));
+        }
+    }
+    out
+}
+
+/// Runs the compiler driver, writing program output to `out` and diagnostics to `err`.
+/// The REPL reads its lines from `input`.
+///
+/// Returns the process exit code.
+fn run(args: &[String], input: &mut dyn BufRead, out: &mut dyn Write, err: &mut dyn Write) -> io::Result<i32> {
+    run_with(args, input, out, err, &mut DefaultResultWriter)
+}
+
+/// Like `run`, but shows printed results through `results`.
+fn run_with(
+    args: &[String],
+    input: &mut dyn BufRead,
+    out: &mut dyn Write,
+    err: &mut dyn Write,
+    results: &mut dyn ResultWriter,
+) -> io::Result<i32> {
+    let options = match parse_args(args) {
+        Ok(options) => options,
+        Err(e) => {
+            writeln!(err, "{}", e)?;
+            return Ok(1);
+        }
+    };
+    if options.features {
+        write!(out, "{}", features_report())?;
+        return Ok(0);
+    }
+    let filename = match &options.filename {
+        Some(filename) if !options.repl => filename,
+        _ => {
+            repl::run_repl(input, out)?;
+            return Ok(0);
+        }
+    };
+    let source = match fs::read_to_string(filename) {
+        Ok(source) => source,
+        Err(e) => {
+            writeln!(err, "Error reading {}: {}", filename, e)?;
+            return Ok(1);
+        }
+    };
+    // Later stages, including --lint and --emit=tokens, see the preprocessed text.
+    let source = match preprocessor::preprocess(&source, &options.defines) {
+        Ok(source) => source,
+        Err(e) => {
+            writeln!(err, "{}", CompileError::Preprocess(e))?;
+            return Ok(1);
+        }
+    };
+
+    if options.lint {
+        if let Ok((_, warnings)) = lexer::tokenize_linted(&source) {
+            for warning in warnings {
+                writeln!(err, "Warning at {}: {}", warning.span, warning.value)?;
+            }
+        }
+    }
+    if options.emit.contains(&Stage::Tokens) {
+        if let Ok(tokens) = lexer::tokenize_spanned(&source) {
+            write!(out, "{}", format_tokens(&tokens))?;
+        }
+    }
+    // Lexing and parsing.
+    let compiled = if options.script { compile_script(&source) } else { compile_detailed(&source) };
+    let program = match compiled {
+        Ok(program) => program,
+        Err(e) => {
+            writeln!(err, "{}", e)?;
+            return Ok(1);
+        }
+    };
+    if options.emit.contains(&Stage::Ast) {
+        write!(out, "{}", format_ast(&program.ast))?;
+    }
+    if options.emit.contains(&Stage::Opcodes) {
+        write!(out, "{}", format_opcodes(&program))?;
+    }
+    if let Some(threshold) = options.metrics {
+        write!(out, "{}", format_metrics(&program, threshold))?;
+    }
+    if options.verify {
+        if let Err(e) = analysis::verify(&program) {
+            writeln!(err, "Verification error: {}", e)?;
+            return Ok(1);
+        }
+    }
+    if !options.run {
+        return Ok(0);
+    }
+
+    // Execution.
+    let (entry, ret_type) = match &options.call {
+        Some(name) => match program.function(name) {
+            Some(function) if !function.params.is_empty() => {
+                writeln!(err, "--call cannot pass arguments to '{}', which takes {}", name, function.params.len())?;
+                return Ok(1);
+            },
+            Some(function) => (function.entry, &function.ret_type),
+            None => {
+                writeln!(err, "Undefined function '{}'", name)?;
+                return Ok(1);
+            },
+        },
+        None => (program.entry, &program.main_type),
+    };
+    let config = vm::Config {
+        heap_cells: options.heap,
+        division: options.division,
+        debug: options.debug_vm,
+        ..vm::Config::default()
+    };
+    // As in c4, main sees the source file as argv[0] and the arguments after it.
+    let mut data = program.data.clone();
+    let mut argv = vec![filename.clone()];
+    argv.extend(options.args.iter().cloned());
+    let main_args = vm::append_argv(&mut data, &argv);
+    let main_params = program.function("main").map_or(0, |main| main.params.len());
+    let args = if options.call.is_some() { &[][..] } else { &main_args[..main_params] };
+    match vm::execute_with_output(&program.opcodes, entry, &data, args, config, out) {
+        Ok(result) if options.print_result || options.call.is_some() => {
+            results.write_result(out, result, ret_type)?;
+            Ok(0)
+        },
+        Ok(result) => Ok(exit_code(result, ret_type)),
+        Err(e) => {
+            match program.spans.get(e.pc) {
+                Some(span) => writeln!(err, "Runtime error at {}: {}", span, e.message)?,
+                None => writeln!(err, "Runtime error: {}", e.message)?,
+            }
+            if let Some(dump) = e.debug_dump(&program.opcodes) {
+                writeln!(err, "VM state at {}", dump)?;
+            }
+            Ok(1)
+        }
+    }
+}
+
+//
+// Main entry point
+//
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let code = run(&args, &mut io::stdin().lock(), &mut io::stdout(), &mut io::stderr()).unwrap_or(1);
+    process::exit(code);
+}
+
+#[cfg(test)]
+mod additional_tests {
+    use super::{compile_detailed, compile_script, features_report, format_result, parse_args, run, run_sandboxed, run_with, type_of};
+    use super::{CompileError, ResultWriter, SandboxLimits, Stage};
+    use crate::{analysis, ast, lexer, parser, preprocessor, vm};
+    use crate::lexer::tokenize;
+    use crate::parser::{parse, Type};
+    use crate::vm::{execute, Opcode, Value};
+
+    /// Test a nested if–else construct.
+    #[test]
+    fn test_nested_if_else() {
+        let source = r#"
+        int main() {
+            if (1) {
+                if (0) {
+                    return 1;
+                } else {
+                    return 2;
+                }
+            } else {
+                return 3;
+            }
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        // In this case, the outer condition is true (1), so we go into the inner if.
+        // The inner condition is false (0), so the else branch returns 2.
+        assert_eq!(result, Value::Int(2));
+    }
+
+    /// Test a nested while loop.
+    #[test]
+    fn test_nested_while_loops() {
+        // This test simulates a nested loop that decrements a variable.
+        // Note: Our minimal compiler only supports basic arithmetic and control flow.
+        // The following code initializes i to 3, then uses a nested loop to decrement it.
+        let source = r#"
+        int main() {
+            int i;
+            i = 3;
+            while (i) {
+                while (i - 1) {
+                    i = i - 1;
+                }
+                i = 0;
+            }
+            return i;
+        }
+        "#;
+        // Depending on how the compiler handles variable declarations and expressions,
+        // the expected result should be 0.
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(0));
+    }
+
+    /// Test that an undefined variable causes an error.
+    #[test]
+    fn test_undefined_variable_error() {
+        let source = r#"
+        int main() {
+            return x;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let parse_result = parse(tokens);
+        assert!(parse_result.is_err(), "Parsing should fail due to undefined variable");
+    }
+
+    /// Test that division by a zero that is known at compile time is a compile
+    /// error, while a zero computed at run time is a runtime error.
+    #[test]
+    fn test_division_by_zero_error() {
+        let source = r#"
+        int main() {
+            return 10 / 0;
+        }
+        "#;
+        let tokens = lexer::tokenize_spanned(source).expect("Failed to tokenize");
+        let err = parser::parse_spanned(tokens).expect_err("Parsing should fail with division by zero");
+        assert_eq!(err, "division by zero at 3:23");
+        let err = |source: &str| compile_detailed(source).unwrap_err().to_string();
+        assert!(err("int main() { int x; x = 7; return x % (2 - 2); }").contains("division by zero at 1:37"));
+        assert!(err("int main() { int x; x = 7; x /= 0; return x; }").contains("division by zero at 1:30"));
+
+        // A zero divisor that only exists at run time, and double division, still compile.
+        let source = "int main() { int x; x = 0; return 10 / x; }";
+        let opcodes = parse(tokenize(source).unwrap()).expect("Failed to parse");
+        assert_eq!(execute(opcodes), Err("Division by zero".to_string()));
+        let opcodes = parse(tokenize("int main() { int c; c = 1; return 1 / (c ? 0 : 1) + (2.5 / 0 > 1); }").unwrap()).unwrap();
+        assert_eq!(execute(opcodes), Err("Division by zero".to_string()));
+    }
+
+    /// Test that invalid syntax produces a parse error.
+    #[test]
+    fn test_invalid_syntax_error() {
+        let source = r#"
+        int main( { return 0; }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let parse_result = parse(tokens);
+        assert!(parse_result.is_err(), "Parsing should fail due to invalid syntax");
+    }
+
+    /// A simple self-hosting test that uses a minimal C source resembling the compiler's own code.
+    #[test]
+    fn test_self_hosting() {
+        // This is a minimal C program that our compiler should handle.
+        let source = r#"
+        int main() {
+            return 42;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(42));
+    }
+
+    /// Test long arithmetic expression with precedence
+    #[test]
+    fn test_complex_expression() {
+        let source = r#"
+        int main() {
+            return 2 + 3 * 4 - 6 / 2;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(2 + 3 * 4 - 6 / 2));
+    }
+
+    /// Test multiple variable declarations and usage
+    #[test]
+    fn test_multiple_variables() {
+        let source = r#"
+        int main() {
+            int a, b;
+            a = 5;
+            b = 10;
+            return a + b;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(15));
+    }
+
+    /// Test unmatched parentheses to simulate a syntax error
+    #[test]
+    fn test_unmatched_parentheses() {
+        let source = r#"
+        int main() {
+            return (5 + 2;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let parse_result = parse(tokens);
+        assert!(parse_result.is_err(), "Expected error due to unmatched parentheses");
+    }
+
+    /// Test that an `if` without parentheses reports the missing '(' by construct.
+    #[test]
+    fn test_if_without_parentheses_error() {
+        let source = r#"
+        int main() {
+            int x;
+            if x { return 1; }
+            return 0;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let err = parse(tokens).expect_err("Parsing should fail without parentheses");
+        assert!(err.contains("Expected '(' after 'if'"), "unexpected message: {}", err);
+    }
+
+    /// Test that a `while` without parentheses reports the missing '(' by construct.
+    #[test]
+    fn test_while_without_parentheses_error() {
+        let source = r#"
+        int main() {
+            int x;
+            while x { x = 0; }
+            return 0;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let err = parse(tokens).expect_err("Parsing should fail without parentheses");
+        assert!(err.contains("Expected '(' after 'while'"), "unexpected message: {}", err);
+    }
+
+    /// Test that main without an explicit return implicitly returns 0.
+    #[test]
+    fn test_main_without_return_yields_zero() {
+        let source = r#"
+        int main() { }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(0));
+    }
+
+    /// Test that a body falling off the end after statements still returns 0.
+    #[test]
+    fn test_fall_through_after_statements_yields_zero() {
+        let source = r#"
+        int main() {
+            int a;
+            a = 7;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(0));
+    }
+
+    /// Test that a runtime error can be mapped back to the offending source line.
+    #[test]
+    fn test_runtime_error_reports_source_location() {
+        let source = "int main() {\n    int a, z;\n    a = 4; z = 0;\n    return a / z;\n}\n";
+        let parser::Program { opcodes, spans, .. } = compile_detailed(source).expect("Failed to compile");
+        let err = vm::execute_traced(opcodes).expect_err("Execution should fail");
+        assert_eq!(err.message, "Division by zero");
+        assert_eq!(spans[err.pc].line, 4);
+        assert_eq!(spans[err.pc].to_string(), "4:14");
+    }
+
+    /// Test that a char-returning main is printed as a character with its code.
+    #[test]
+    fn test_char_main_result_printed_as_character() {
+        let source = r#"
+        char main() {
+            return 65;
+        }
+        "#;
+        let program = compile_detailed(source).expect("Failed to compile");
+        let result = vm::execute(program.opcodes).expect("Execution failed");
+        assert_eq!(format_result(result, &program.main_type), "'A' (65)");
+        assert_eq!(format_result(Value::Int(10), &parser::Type::Char), "'\\n' (10)");
+        assert_eq!(format_result(Value::Int(65), &parser::Type::Int), "65");
+    }
+
+    /// Test that a parenthesized comma expression yields its last operand.
+    #[test]
+    fn test_comma_expression_yields_last_value() {
+        let source = r#"
+        int main() {
+            int a, b;
+            b = (a = 3, a + 4);
+            return (1, 2, b);
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(7));
+    }
+
+    /// Test that a parenthesized comma expression is a single call argument:
+    /// `f((1, 2), 3)` passes two arguments, 2 and 3.
+    #[test]
+    fn test_comma_expression_as_call_argument() {
+        let source = "int f(int a, int b) { return a * 10 + b; } int main() { return f((1, 2), 3); }";
+        let program = compile_detailed(source).unwrap();
+        let calls: Vec<i64> = program.opcodes.iter().filter_map(|op| match op {
+            Opcode::Call(_, argc) => Some(*argc),
+            _ => None,
+        }).collect();
+        assert_eq!(calls, [2]);
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(23));
+
+        let three = "int f(int a, int b, int c) { return a; } int main() { return f((1, 2), 3); }";
+        let err = compile_detailed(three).unwrap_err().to_string();
+        assert!(err.contains("too few arguments to function 'f' (expected 3, found 2)"), "unexpected error: {}", err);
+    }
+
+    /// Test that a comma expression has its last operand's type and drops the others, int or double.
+    #[test]
+    fn test_comma_expression_mixes_value_kinds() {
+        use vm::StepResult;
+        assert_eq!(type_of("", "(1, 2.5)").unwrap(), Type::Double);
+        assert_eq!(type_of("", "(2.5, 1)").unwrap(), Type::Int);
+        let program = compile_script("(1, 2.5);").unwrap();
+        assert_eq!(program.main_type, Type::Double);
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        loop {
+            if let StepResult::Halted(result) = state.step().unwrap() {
+                assert_eq!(result, Value::Float(2.5));
+                break;
+            }
+        }
+        assert!(state.stack().is_empty(), "left behind: {:?}", state.stack());
+        let program = compile_script("(0.5, 1, 2.5, 3);").unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(3));
+    }
+
+    /// Test that assignment is an expression whose value can be reused.
+    #[test]
+    fn test_chained_assignment() {
+        let source = r#"
+        int main() {
+            int a, b;
+            a = b = 5;
+            return a + b;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(10));
+    }
+
+    /// Test that `--emit` selects stages in pipeline order and `--no-run` skips execution.
+    #[test]
+    fn test_emit_flag_parsing() {
+        let args: Vec<String> = ["--emit=opcodes,tokens", "--no-run", "prog.c"].iter().map(|s| s.to_string()).collect();
+        let options = parse_args(&args).expect("Failed to parse arguments");
+        assert_eq!(options.emit, vec![Stage::Tokens, Stage::Opcodes]);
+        assert!(!options.run);
+        assert_eq!(options.filename.as_deref(), Some("prog.c"));
+        assert!(parse_args(&["--emit=bogus".to_string(), "prog.c".to_string()]).is_err());
+        let options = parse_args(&["--emit=opcodes,ast".to_string(), "prog.c".to_string()]).unwrap();
+        assert_eq!(options.emit, vec![Stage::Ast, Stage::Opcodes]);
+        assert!(!options.verify);
+        assert!(parse_args(&["--verify".to_string(), "prog.c".to_string()]).unwrap().verify);
+        assert!(parse_args(&["--lint".to_string(), "prog.c".to_string()]).unwrap().lint);
+    }
+
+    /// Test that main's return value becomes the exit code, clamped to 0–255,
+    /// unless `--print-result` asks for the printed result.
+    #[test]
+    fn test_exit_code_from_main() {
+        let path = std::env::temp_dir().join("c4_exit_code.c");
+        let run_file = |source: &str, flags: &[&str]| {
+            std::fs::write(&path, source).expect("Failed to write source");
+            let mut args: Vec<String> = flags.iter().map(|s| s.to_string()).collect();
+            args.push(path.display().to_string());
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+            (code, String::from_utf8(out).unwrap())
+        };
+        assert_eq!(run_file("int main() { return 3; }", &[]), (3, String::new()));
+        assert_eq!(run_file("int main() { return 300; }", &[]).0, 255);
+        assert_eq!(run_file("int main() { return 0 - 1; }", &[]).0, 0);
+        assert_eq!(run_file("double main() { return 7.9; }", &[]).0, 7);
+        assert_eq!(
+            run_file("int main() { return 3; }", &["--print-result"]),
+            (0, "Program executed successfully. Result: 3\n".to_string())
+        );
+    }
+
+    /// Test that `--debug-vm` reports the failing opcode, its pc and the operand stack.
+    #[test]
+    fn test_debug_vm_dumps_stack_on_error() {
+        let opcodes = vec![Opcode::Imm(7), Opcode::Imm(1), Opcode::Add, Opcode::Add];
+        let err = vm::execute_from(&opcodes, 0).unwrap_err();
+        assert_eq!(err.stack, None);
+        assert_eq!(err.debug_dump(&opcodes), None);
+
+        let config = vm::Config { debug: true, ..vm::Config::default() };
+        let err = vm::execute_with_config(&opcodes, 0, &[], config).unwrap_err();
+        assert_eq!(err.pc, 3);
+        assert_eq!(err.message, "Stack underflow in Add");
+        let dump = err.debug_dump(&opcodes).unwrap();
+        assert!(dump.starts_with("pc 3: Add, stack ["), "unexpected dump: {}", dump);
+
+        let path = std::env::temp_dir().join("c4_debug_vm.c");
+        std::fs::write(&path, "int main() { int a[2]; return 5 + a[3]; }").expect("Failed to write source");
+        let args = vec!["--debug-vm".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        assert_eq!(run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed"), 1);
+        let err = String::from_utf8(err).unwrap();
+        assert!(err.contains("Runtime error at 1:38: array index 3 out of bounds (length 2)"), "unexpected output: {}", err);
+        assert!(err.contains("VM state at pc 3: LdX(1, 2), stack [5] (top last)"), "unexpected output: {}", err);
+    }
+
+    /// Test single-stepping the VM and observing the operand stack after each step.
+    #[test]
+    fn test_vm_single_step() {
+        use vm::StepResult;
+        let opcodes = vec![Opcode::Ent(0), Opcode::Imm(2), Opcode::Imm(3), Opcode::Mul, Opcode::Ret];
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&opcodes, 0, &[], &mut globals, vm::Config::default());
+        let mut stacks = Vec::new();
+        while let StepResult::Running = state.step().unwrap() {
+            stacks.push((state.pc(), state.stack().to_vec()));
+        }
+        assert_eq!(
+            stacks,
+            vec![
+                (1, vec![]),
+                (2, vec![Value::Int(2)]),
+                (3, vec![Value::Int(2), Value::Int(3)]),
+                (4, vec![Value::Int(6)]),
+            ]
+        );
+        assert!(state.stack().is_empty());
+        // A halted program keeps reporting its result.
+        assert_eq!(state.step(), Ok(StepResult::Halted(Value::Int(6))));
+
+        let opcodes = vec![Opcode::Imm(1), Opcode::Add];
+        let mut state = vm::VmState::new(&opcodes, 0, &[], &mut globals, vm::Config::default());
+        assert_eq!(state.step(), Ok(StepResult::Running));
+        assert_eq!(state.step(), Err("Stack underflow in Add".to_string()));
+        assert_eq!(state.pc(), 1);
+    }
+
+    /// Test that the trace of the opcodes a program executes has a stable text form.
+    #[test]
+    fn test_trace_format_is_stable() {
+        use vm::StepResult;
+        let program = compile_detailed("int main() { int i; i = 0; while (i < 1) i = i + 1; return i * 2.5; }").unwrap();
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        let mut executed = Vec::new();
+        loop {
+            executed.push(program.opcodes[state.pc()].clone());
+            if let StepResult::Halted(_) = state.step().unwrap() {
+                break;
+            }
+        }
+        let trace = vm::trace_format(&executed);
+        let expected = [
+            "1> ENT  1", "2> IMM  0", "3> ST   1", "4> POP", "5> LD   1",
+            "6> IMM  1", "7> LT", "8> BZ   14", "9> LD   1", "10> IMM  1",
+            "11> ADD", "12> ST   1", "13> POP", "14> JMP  4", "15> LD   1",
+            "16> IMM  1", "17> LT", "18> BZ   14", "19> LD   1", "20> FIMM 2.5",
+            "21> MUL", "22> FTOI", "23> LEV",
+        ];
+        assert_eq!(trace, expected.map(|line| format!("{}\n", line)).concat());
+        assert_eq!(vm::trace_format(&[Opcode::Jz(7), Opcode::LdGX(0, 4), Opcode::Print(Type::Int)]), "1> BZ   7\n2> LDGX 0 4\n3> PRINT int\n");
+    }
+
+    /// Test that a breakpoint inside a loop pauses execution on every iteration.
+    #[test]
+    fn test_vm_breakpoint_in_loop() {
+        use vm::StepResult;
+        let source = "int main() {
+            int i, sum;
+            for (i = 0; i < 3; i = i + 1)
+                sum = sum + i;
+            return sum;
+        }";
+        let program = compile_detailed(source).unwrap();
+        let body = program.line_start(4).unwrap();
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        state.set_breakpoint(body);
+        for _ in 0..3 {
+            assert_eq!(state.run_to_breakpoint(), Ok(StepResult::Breakpoint(body)));
+            // Paused before `sum = sum + i` pushes anything.
+            assert!(state.stack().is_empty());
+        }
+        assert_eq!(state.run_to_breakpoint(), Ok(StepResult::Halted(Value::Int(3))));
+
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        state.set_breakpoint(body);
+        assert_eq!(state.run_to_breakpoint(), Ok(StepResult::Breakpoint(body)));
+        state.clear_breakpoint(body);
+        assert_eq!(state.run_to_breakpoint(), Ok(StepResult::Halted(Value::Int(3))));
+    }
+
+    /// Test running a custom opcode through a registered handler.
+    #[test]
+    fn test_vm_custom_opcode_handler() {
+        use vm::StepResult;
+        // while (n < 100) n = double(n);
+        let opcodes = vec![
+            Opcode::Ent(1),
+            Opcode::Imm(3),
+            Opcode::St(1),
+            Opcode::Pop,
+            Opcode::Ld(1),
+            Opcode::Imm(100),
+            Opcode::Lt,
+            Opcode::Jz(13),
+            Opcode::Ld(1),
+            Opcode::Custom(7),
+            Opcode::St(1),
+            Opcode::Pop,
+            Opcode::Jmp(4),
+            Opcode::Ld(1),
+            Opcode::Ret,
+        ];
+        assert_eq!(analysis::validate_stack(&opcodes), Ok(()));
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counter = calls.clone();
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&opcodes, 0, &[], &mut globals, vm::Config::default());
+        state.register_opcode(7, move |stack: &mut Vec<Value>| {
+            counter.set(counter.get() + 1);
+            match stack.last_mut() {
+                Some(Value::Int(n)) => *n *= 2,
+                _ => return Err("double expects an integer".to_string()),
+            }
+            Ok(())
+        });
+        assert_eq!(state.run_to_breakpoint(), Ok(StepResult::Halted(Value::Int(192))));
+        assert_eq!(calls.get(), 6);
+        assert!(vm::trace_format(&opcodes).contains("10> CUSTOM 7\n"));
+
+        // An unregistered id, or a handler error, is a runtime error at the opcode.
+        let opcodes = [Opcode::Imm(1), Opcode::Custom(9), Opcode::Ret];
+        let err = vm::execute_from(&opcodes, 0).unwrap_err();
+        assert_eq!((err.pc, err.message.as_str()), (1, "no handler registered for custom opcode 9"));
+        let mut state = vm::VmState::new(&opcodes, 0, &[], &mut globals, vm::Config::default());
+        state.register_opcode(9, |_: &mut Vec<Value>| Err("rejected".to_string()));
+        assert_eq!(state.run_to_breakpoint(), Err("rejected".to_string()));
+        assert_eq!(state.pc(), 1);
+    }
+
+    /// Test that branching pops its condition, so a long loop leaves nothing behind.
+    #[test]
+    fn test_loop_conditions_do_not_accumulate() {
+        use vm::StepResult;
+        let source = "int main() {
+            int i, odd;
+            i = 0;
+            while (i < 10000) {
+                if (i % 2 && i > 0) odd = odd + 1;
+                i = i + 1;
+            }
+            return odd;
+        }";
+        let program = compile_detailed(source).unwrap();
+        let test = program.line_start(4).unwrap();
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        state.set_breakpoint(test);
+        let mut passes = 0;
+        loop {
+            match state.run_to_breakpoint().unwrap() {
+                StepResult::Breakpoint(_) => {
+                    // Every test of the loop condition starts from an empty stack.
+                    assert!(state.stack().is_empty(), "stack grew to {:?}", state.stack());
+                    passes += 1;
+                },
+                StepResult::Halted(result) => {
+                    assert_eq!(result, Value::Int(5000));
+                    break;
+                },
+                other => panic!("unexpected stop: {:?}", other),
+            }
+        }
+        assert_eq!(passes, 10001);
+
+        let err = vm::execute_from(&[Opcode::Jz(0)], 0).unwrap_err();
+        assert_eq!(err.message, "Stack underflow in Jz");
+    }
+
+    /// Test that a watchpoint on a loop counter pauses on every increment.
+    #[test]
+    fn test_vm_watchpoint_on_loop_counter() {
+        use vm::StepResult;
+        let source = "int total;
+            int main() { int i; for (i = 0; i < 3; i = i + 1) total = total + 2; return total; }";
+        let program = compile_detailed(source).unwrap();
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        state.watch(&program, "i").unwrap();
+        assert_eq!(state.watch(&program, "nope"), Err("Undefined variable: nope".to_string()));
+        let mut changes = Vec::new();
+        loop {
+            match state.run_to_breakpoint().unwrap() {
+                StepResult::Watchpoint { name, old, new } => changes.push((name, old.as_int(), new.as_int())),
+                StepResult::Halted(result) => {
+                    assert_eq!(result, Value::Int(6));
+                    break;
+                },
+                other => panic!("unexpected pause: {:?}", other),
+            }
+        }
+        let i = |old, new| ("i".to_string(), old, new);
+        // `i = 0` leaves the zero-filled slot unchanged, so only increments pause.
+        assert_eq!(changes, vec![i(0, 1), i(1, 2), i(2, 3)]);
+
+        let mut globals = Vec::new();
+        let mut state = vm::VmState::new(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        state.watch(&program, "total").unwrap();
+        let mut totals = Vec::new();
+        while let StepResult::Watchpoint { new, .. } = state.run_to_breakpoint().unwrap() {
+            totals.push(new.as_int());
+        }
+        assert_eq!(totals, vec![2, 4, 6]);
+    }
+
+    /// Test reading the type of an expression without running it.
+    #[test]
+    fn test_type_of_expression() {
+        assert_eq!(type_of("", "2 + 3"), Ok(Type::Int));
+        assert_eq!(type_of("", "2 + 3.0"), Ok(Type::Double));
+        let definitions = "int *p; char c; unsigned char u; double d;";
+        assert_eq!(type_of(definitions, "p").map(|ty| ty.to_string()), Ok("int*".to_string()));
+        assert_eq!(type_of(definitions, "*p"), Ok(Type::Int));
+        assert_eq!(type_of(definitions, "c"), Ok(Type::Char));
+        assert_eq!(type_of(definitions, "u").unwrap().to_string(), "unsigned char");
+        assert_eq!(type_of(definitions, "c < d ? d : c"), Ok(Type::Double));
+        assert_eq!(type_of(definitions, "\"hi\"").unwrap().to_string(), "char*");
+        assert!(matches!(type_of(definitions, "q + 1"), Err(CompileError::Parse(e)) if e.contains("Undefined variable: q")));
+        assert!(matches!(type_of("", "1 $ 2"), Err(CompileError::Lex(_))));
+    }
+
+    /// Test that `-D` predefines macros for `#ifdef` blocks and substitution.
+    #[test]
+    fn test_command_line_defines() {
+        let path = std::env::temp_dir().join("c4_defines.c");
+        std::fs::write(&path, "int main() {\n#ifdef DEBUG\n  return LEVEL + DEBUG;\n#endif\n  return 0;\n}").expect("Failed to write source");
+        let run_with = |flags: &[&str]| {
+            let mut args: Vec<String> = flags.iter().map(|s| s.to_string()).collect();
+            args.push(path.display().to_string());
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+            (code, String::from_utf8(err).unwrap())
+        };
+        assert_eq!(run_with(&[]).0, 0);
+        assert_eq!(run_with(&["-D", "DEBUG", "-DLEVEL=40"]).0, 41);
+        assert_eq!(run_with(&["-D", "DEBUG=2", "-D", "LEVEL=(3 * 4)"]).0, 14);
+        let (code, err) = run_with(&["-D", "DEBUG"]);
+        assert_eq!(code, 1);
+        assert!(err.contains("Undefined variable: LEVEL"), "unexpected error: {}", err);
+
+        let options = parse_args(&["-DA=x=y".to_string(), "prog.c".to_string()]).unwrap();
+        assert_eq!(options.defines, vec![("A".to_string(), "x=y".to_string())]);
+        assert!(parse_args(&["-D".to_string()]).is_err());
+        assert!(parse_args(&["-D1X".to_string(), "prog.c".to_string()]).is_err());
+    }
+
+    /// Test compiling several functions and running each by name with `--call`.
+    #[test]
+    fn test_call_selects_entry_point() {
+        let path = std::env::temp_dir().join("c4_call_entry.c");
+        let source = "int shared;
+            int test_sum() { int i, s; s = 0; for (i = 1; i <= 4; i = i + 1) s = s + i; return s; }
+            char test_letter() { char c; c = 66; shared = 1; return c + shared; }
+            int main() { return 0; }";
+        std::fs::write(&path, source).expect("Failed to write source");
+        let call = |name: &str| {
+            let args = vec!["--call".to_string(), name.to_string(), path.display().to_string()];
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+            (code, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+        };
+        assert_eq!(call("test_sum"), (0, "Program executed successfully. Result: 10\n".to_string(), String::new()));
+        assert_eq!(call("test_letter"), (0, "Program executed successfully. Result: 'C' (67)\n".to_string(), String::new()));
+        assert_eq!(call("test_missing"), (1, String::new(), "Undefined function 'test_missing'\n".to_string()));
+
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(program.functions.len(), 3);
+        assert_eq!(program.entry, program.function("main").unwrap().entry);
+        assert_eq!(analysis::verify(&program), Ok(()));
+        let err = compile_detailed("int f() { return 1; } int f() { return 2; }").unwrap_err();
+        assert!(err.to_string().contains("Redefinition of function 'f' at 1:27"), "unexpected error: {}", err);
+    }
+
+    /// Test that arguments after the source file reach `main` as `argc` and `argv`.
+    #[test]
+    fn test_main_receives_argc_and_argv() {
+        let path = std::env::temp_dir().join("c4_main_args.c");
+        let run_file = |source: &str, extra: &[&str]| {
+            std::fs::write(&path, source).expect("Failed to write source");
+            let mut args = vec![path.display().to_string()];
+            args.extend(extra.iter().map(|s| s.to_string()));
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+            (code, String::from_utf8(err).unwrap())
+        };
+        let source = "int main(int argc, char **argv) { return argc; }";
+        assert_eq!(run_file(source, &["first", "--verify"]), (3, String::new()));
+        assert_eq!(run_file(source, &[]), (1, String::new()));
+
+        // argv[0] is the source file; each argument is a NUL-terminated string.
+        let source = "int main(int argc, char **argv) {
+            char *s; int n;
+            n = 0;
+            for (s = argv[2]; *s; s = s + 1) n = n * 10 + *s - 48;
+            return (argv[argc] == 0) + argv[1][0] + n;
+        }";
+        assert_eq!(run_file(source, &["A", "42"]), (1 + 65 + 42, String::new()));
+        assert_eq!(run_file("int main() { return 5; }", &["ignored"]), (5, String::new()));
+
+        let err = compile_detailed("int main(int argc) { return argc; }").unwrap_err();
+        assert!(err.to_string().contains("main must take no parameters or (int argc, char **argv) at 1:5"), "unexpected error: {}", err);
+        let options = parse_args(&["prog.c".to_string(), "-D".to_string(), "x".to_string()]).unwrap();
+        assert_eq!((options.defines.len(), options.args), (0, vec!["-D".to_string(), "x".to_string()]));
+    }
+
+    /// Test calling functions, including recursively, with each call getting its own locals.
+    #[test]
+    fn test_function_calls() {
+        let run_main = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry)
+        };
+        assert_eq!(run_main("int f() { return 7; } int main() { return f(); }"), Ok(Value::Int(7)));
+        let factorial = "int n;
+            int fact() { int k; if (n <= 1) return 1; k = n; n = n - 1; return k * fact(); }
+            int main() { n = 5; return fact(); }";
+        assert_eq!(run_main(factorial), Ok(Value::Int(120)));
+        // The callee's return type converts the result; the caller's locals survive the call.
+        let source = "double half() { int a; a = 9; return 1 / 2.0; }
+            int main() { int a; a = 4; return (half() + half()) * 10 + a; }";
+        assert_eq!(run_main(source), Ok(Value::Int(14)));
+
+        let program = compile_detailed("int f() { return f(); } int main() { return 0; }").unwrap();
+        let err = vm::execute_from(&program.opcodes, 0).unwrap_err();
+        assert!(err.message.starts_with("call stack overflow"), "unexpected error: {}", err.message);
+        let trace = vm::trace_format(&program.opcodes);
+        assert!(trace.starts_with("1> ENT  0\n2> JSR  0 0\n3> LEV\n"), "unexpected trace: {}", trace);
+
+        let err = compile_detailed("int main() { return g(); } int g() { return 1; }").unwrap_err();
+        assert!(err.to_string().contains("Undefined function 'g' at 1:21"), "unexpected error: {}", err);
+        let err = compile_detailed("int f() { return 1; } int main() { int f; return f(); }").unwrap_err();
+        assert!(err.to_string().contains("called object 'f' is not a function at 1:50"), "unexpected error: {}", err);
+    }
+
+    /// Test passing arguments: each is converted to its parameter's type and lands in the callee's frame.
+    #[test]
+    fn test_function_parameters() {
+        let run_main = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry)
+        };
+        assert_eq!(run_main("int add(int a, int b) { return a + b; } int main() { return add(2, 3); }"), Ok(Value::Int(5)));
+        let source = "int fact(int n) { if (n <= 1) return 1; return n * fact(n - 1); }
+            int main() { return fact(5); }";
+        assert_eq!(run_main(source), Ok(Value::Int(120)));
+        // Arguments convert like assignments; parameters are ordinary locals.
+        let source = "double scale(double x, char c, int *p) { int k; k = 2; *p = c; x = x * k; return x; }
+            int main(void) { int r; return scale(1, 300, &r) * 10 + r; }";
+        assert_eq!(run_main(source), Ok(Value::Int(64)));
+
+        let err = |source: &str| compile_detailed(source).unwrap_err().to_string();
+        let two = "int add(int a, int b) { return a + b; } ";
+        assert!(err(&format!("{}int main() {{ return add(1); }}", two)).contains("too few arguments to function 'add' (expected 2, found 1) at 1:61"));
+        assert!(err(&format!("{}int main() {{ return add(1, 2, 3); }}", two)).contains("too many arguments to function 'add' (expected 2, found 3)"));
+        assert!(err("int f(int a, char a) { return a; }").contains("Redefinition of parameter 'a' at 1:19"));
+        assert!(err("int f(void v) { return 0; }").contains("variable 'v' declared void"));
+        assert!(err("void g() {} int f(int a) { return a; } int main() { return f(g()); }").contains("void value not ignored"));
+
+        let mut program = compile_detailed(&format!("{}int main() {{ return add(1, 2); }}", two)).unwrap();
+        let call = program.opcodes.iter().position(|op| matches!(op, Opcode::Call(..))).unwrap();
+        program.opcodes[call] = Opcode::Call(0, 1);
+        assert_eq!(analysis::verify(&program), Err(format!("Call at opcode {} passes 1 arguments to 'add', which takes 2", call)));
+        let path = std::env::temp_dir().join("c4_call_params.c");
+        std::fs::write(&path, two).expect("Failed to write source");
+        let args = vec!["--call".to_string(), "add".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        assert_eq!(run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed"), 1);
+        assert_eq!(String::from_utf8(err).unwrap(), "--call cannot pass arguments to 'add', which takes 2\n");
+    }
+
+    /// Test that `--metrics=N` reports each function and flags those more complex than N.
+    #[test]
+    fn test_metrics_flags_complex_functions() {
+        let path = std::env::temp_dir().join("c4_metrics.c");
+        let source = "int simple() { return 2; }
+            int branchy() { int n; n = 5; while (n > 0) { if (n % 2) n = n - 1; else n = n - 3; } return n; }
+            int main() { return simple() + branchy(); }";
+        std::fs::write(&path, source).expect("Failed to write source");
+        let metrics = |flag: &str| {
+            let args = vec![flag.to_string(), "--no-run".to_string(), path.display().to_string()];
+            let (mut out, mut err) = (Vec::new(), Vec::new());
+            assert_eq!(run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed"), 0);
+            String::from_utf8(out).unwrap()
+        };
+        let expected = [
+            "== metrics ==",
+            "; complexity threshold 2",
+            "function  opcodes  complexity",
+            "simple          3           1",
+            "branchy        26           3  above threshold",
+            "main            5           1",
+        ];
+        assert_eq!(metrics("--metrics=2"), expected.map(|line| format!("{}\n", line)).concat());
+        let report = metrics("--metrics");
+        assert!(report.contains("; complexity threshold 10\n"), "unexpected report: {}", report);
+        assert!(!report.contains("above threshold"), "unexpected report: {}", report);
+        assert!(parse_args(&["--metrics=many".to_string(), "prog.c".to_string()]).is_err());
+    }
+
+    /// Test that `--emit=tokens,opcodes` prints both sections before running.
+    #[test]
+    fn test_emit_prints_requested_stages() {
+        let path = std::env::temp_dir().join("c4_emit_stages.c");
+        std::fs::write(&path, "int main() { int a; a = 2; return a + 3; }").expect("Failed to write source");
+        let args = vec!["--emit=tokens,opcodes".to_string(), "--print-result".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(code, 0);
+        let tokens_at = out.find("== tokens ==").expect("missing tokens section");
+        let opcodes_at = out.find("== opcodes ==").expect("missing opcodes section");
+        assert!(tokens_at < opcodes_at);
+        assert!(out.contains("Ident(\"main\")"));
+        assert!(out.contains("Add"));
+        assert!(out.ends_with("Result: 5\n"));
+    }
+
+    /// Test that `--emit=ast` prints the syntax tree between the tokens and the opcodes.
+    #[test]
+    fn test_emit_prints_ast() {
+        let path = std::env::temp_dir().join("c4_emit_ast.c");
+        std::fs::write(&path, "int main() {\n    return 2 + 3;\n}").expect("Failed to write source");
+        let args = vec!["--emit=opcodes,ast,tokens".to_string(), "--no-run".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(code, 0);
+        let tree = "== ast ==
+function main -> int ; 1:1-3:2
+  return ; 2:5-2:18
+    binary + ; 2:12-2:17
+      number 2 ; 2:12-2:13
+      number 3 ; 2:16-2:17
+";
+        let ast_at = out.find(tree).expect("missing ast section");
+        assert!(out.find("== tokens ==").unwrap() < ast_at);
+        assert!(ast_at < out.find("== opcodes ==").unwrap());
+    }
+
+    /// Test that 100-deep nested temporaries do not disturb variables.
+    #[test]
+    fn test_deeply_nested_expression_keeps_locals_intact() {
+        let nested = format!("{}1{}", "(1 + ".repeat(100), ")".repeat(100));
+        let source = format!(
+            "int main() {{ int a, b; a = 7; b = {}; return a * 1000 + b; }}",
+            nested
+        );
+        let tokens = tokenize(&source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(7 * 1000 + 101));
+    }
+
+    /// Test that variables beyond the old 32-slot reserve are not aliased by temporaries.
+    #[test]
+    fn test_many_locals_beyond_reserved_region() {
+        let names: Vec<String> = (0..40).map(|i| format!("v{}", i)).collect();
+        let source = format!(
+            "int main() {{ int {}; v39 = 5; v0 = 1; return (2 + 3) * (v39 + v0); }}",
+            names.join(", ")
+        );
+        let tokens = tokenize(&source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(30));
+    }
+
+    /// Test that stores to locals made while temporaries are pending on the
+    /// operand stack neither clobber the temporaries nor get clobbered by them.
+    #[test]
+    fn test_locals_separate_from_operand_stack() {
+        let source = "int main() {
+            int a, b, c;
+            c = (a = 2) * 10 + (b = a + 3) * (a + b);
+            a = a + (b * (c - (b = 1)));
+            return a * 10000 + b * 1000 + c;
+        }";
+        let program = compile_detailed(source).expect("Failed to compile");
+        assert_eq!(program.opcodes[program.entry], Opcode::Ent(3));
+        assert!(program.opcodes.iter().all(|op| !matches!(op, Opcode::Ld(n) | Opcode::St(n) if !(1..=3).contains(n))));
+        let result = vm::execute_from(&program.opcodes, program.entry).expect("Execution failed");
+        // c = 20 + 5 * 7 = 55, then a = 2 + 5 * (55 - 1) = 272 with b reset to 1.
+        assert_eq!(result, Value::Int(272 * 10000 + 1000 + 55));
+    }
+
+    /// Test that `compile_detailed` bundles the entry point and storage sizes.
+    #[test]
+    fn test_compile_detailed_program_fields() {
+        let source = r#"
+        int g;
+        int h;
+        int main() {
+            int a, b, c;
+            a = 1;
+            return a;
+        }
+        "#;
+        let program = compile_detailed(source).expect("Failed to compile");
+        assert_eq!(program.entry, 0);
+        assert_eq!(program.globals, 2);
+        assert_eq!(program.frame_size, 3);
+        assert_eq!(program.opcodes.len(), program.spans.len());
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry), Ok(Value::Int(1)));
+
+        assert!(matches!(compile_detailed("int main() { return @; }"), Err(CompileError::Lex(_))));
+        assert!(matches!(compile_detailed("int main() { return 1 }"), Err(CompileError::Parse(_))));
+    }
+
+    /// Test that a char holding 200 reads differently when signed and unsigned.
+    #[test]
+    fn test_signed_and_unsigned_char() {
+        let source = r#"
+        int main() {
+            signed char s;
+            unsigned char u;
+            char c;
+            s = 200;
+            u = 200;
+            c = 200;
+            return (s + 56) * 1000 + u + c;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        // s and plain c read back as -56; u keeps 200.
+        assert_eq!(result, Value::Int(200 - 56));
+    }
+
+    /// Test that the value of a char assignment is the narrowed value.
+    #[test]
+    fn test_char_assignment_value_is_narrowed() {
+        let source = r#"
+        int main() {
+            unsigned char u;
+            return u = 300;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(44));
+    }
+
+    /// Test that globals get distinct storage instead of sharing one slot.
+    #[test]
+    fn test_globals_have_distinct_slots() {
+        let source = r#"
+        int g, h;
+        int k;
+        int main() {
+            g = 3;
+            h = 4;
+            k = 5;
+            return g * 100 + h * 10 + k;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(345));
+    }
+
+    /// Test that `~0` yields -1.
+    #[test]
+    fn test_bitwise_not_of_zero() {
+        let tokens = tokenize("int main() { return ~0; }").expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        assert_eq!(execute(opcodes), Ok(Value::Int(-1)));
+    }
+
+    /// Test the bitwise complement operator, including nesting and precedence.
+    #[test]
+    fn test_bitwise_not() {
+        let source = r#"
+        int main() {
+            int a;
+            a = 5;
+            return ~0 * 100 + ~~a + ~a * 2;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(-100 + 5 + -6 * 2));
+    }
+
+    /// Test unary minus and logical not on constants, variables and doubles.
+    #[test]
+    fn test_unary_minus_and_logical_not() {
+        let run = |source: &str| execute(parse(tokenize(source).unwrap()).unwrap()).unwrap();
+        assert_eq!(run("int main() { return -7; }"), Value::Int(-7));
+        assert_eq!(run("int main() { return !0; }"), Value::Int(1));
+        let source = "int main() { int a; double d; a = 3; d = 2.5; return -a * 10 + !a + !!a * 100 + (-d < 0); }";
+        assert_eq!(run(source), Value::Int(-30 + 100 + 1));
+        let opcodes = parse(tokenize("int main() { int a; a = 0; return -a + !a; }").unwrap()).unwrap();
+        assert!(opcodes.contains(&Opcode::Neg) && opcodes.contains(&Opcode::LogicalNot));
+    }
+
+    /// Test that assigning to a non-lvalue is rejected with its location.
+    #[test]
+    fn test_assignment_requires_lvalue() {
+        for (source, location) in [
+            ("int main() {\n    1 = 2;\n}", "2:7"),
+            ("int main() {\n    int a, b;\n    a + b = 3;\n}", "3:11"),
+        ] {
+            let tokens = lexer::tokenize_spanned(source).expect("Failed to tokenize");
+            let err = parser::parse_spanned(tokens).expect_err("Parsing should fail");
+            assert_eq!(err, format!("lvalue required as left operand of assignment at {}", location));
+        }
+    }
+
+    /// Test that a parenthesized variable is still an lvalue.
+    #[test]
+    fn test_parenthesized_variable_is_lvalue() {
+        let source = r#"
+        int main() {
+            int a;
+            (a) = 9;
+            return a;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        assert_eq!(result, Value::Int(9));
+    }
+
+    /// Test that char conditions use the narrowed value for truthiness.
+    #[test]
+    fn test_char_values_as_conditions() {
+        let source = r#"
+        int main() {
+            unsigned char u;
+            signed char s;
+            int result;
+            result = 0;
+            u = 256;
+            if (u) { result = result + 1; }
+            s = 255;
+            if (s) { result = result + 10; }
+            while (u - 3) { u = u + 1; }
+            return result * 100 + u;
+        }
+        "#;
+        let tokens = tokenize(source).expect("Failed to tokenize");
+        let opcodes = parse(tokens).expect("Failed to parse");
+        let result = execute(opcodes).expect("Execution failed");
+        // 256 narrows to 0 (false); 255 narrows to -1 (true); the loop stops at 3.
+        assert_eq!(result, Value::Int(10 * 100 + 3));
+    }
+
+    /// Test that the features report reflects the lexer and parser tables.
+    #[test]
+    fn test_features_report() {
+        let report = features_report();
+        assert!(report.contains("while"));
+        assert!(report.contains(" + "));
+        assert!(report.contains("return expr;"));
+        assert!(report.contains("<="));
+    }
+
+    /// Test that an error in the second statement of a line reports its own column.
+    #[test]
+    fn test_error_column_with_statements_sharing_a_line() {
+        let source = "int main() {\n    int a; a = 1; a = ; return a;\n}";
+        let err = compile_detailed(source).unwrap_err();
+        assert!(matches!(err, CompileError::Parse(_)));
+        assert_eq!(
+            err.to_string(),
+            "Parsing error: Unexpected token in factor: Semicolon at 2:23"
+        );
+    }
+
+    /// Test that character literals evaluate to their character codes.
+    #[test]
+    fn test_character_literal_values() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_with_heap(&program.opcodes, program.entry, &program.data, vm::DEFAULT_HEAP_CELLS).unwrap()
+        };
+        assert_eq!(run("int main() { return 'A'; }"), Value::Int(65));
+        assert_eq!(run("int main() { return '\\n' * 100 + '\\0'; }"), Value::Int(1000));
+        let source = "int main() { char *s; int n; n = 0; for (s = \"2024\"; *s; s = s + 1) n = n * 10 + *s - '0'; return n; }";
+        assert_eq!(run(source), Value::Int(2024));
+        let err = compile_detailed("int main() { return 'ab'; }").unwrap_err();
+        assert_eq!(err.to_string(), "Lexing error: multi-character character literal at 1:21");
+    }
+
+    /// Test that lexer and parser errors on the third line report that line and the column.
+    #[test]
+    fn test_errors_report_line_and_column() {
+        let err = |third_line: &str| {
+            let source = format!("int g;\nint main() {{\n{}\n    return 0;\n}}", third_line);
+            compile_detailed(&source).unwrap_err().to_string()
+        };
+        assert_eq!(err("    g = 1 @ 2;"), "Lexing error: Unexpected character: @ at 3:11");
+        assert_eq!(err("    g = \"abc;"), "Lexing error: Unterminated string literal at 3:9");
+        assert_eq!(err("    g = 99999999999999999999;"), "Lexing error: number too large to fit in target type at 3:9");
+        assert_eq!(err("    g = 1"), "Parsing error: Expected Semicolon, found Return at 4:5");
+        assert_eq!(err("    int 5;"), "Parsing error: Expected identifier in local declaration, found Num(5) at 3:9");
+        assert_eq!(err("    unsigned int x;"), "Parsing error: Only unsigned char is supported at 3:5");
+        let err = compile_detailed("int g;\n\nint ;").unwrap_err().to_string();
+        assert_eq!(err, "Parsing error: Expected identifier after type, found Semicolon at 3:5");
+        let err = compile_detailed("int g;\n\nint a, ;").unwrap_err().to_string();
+        assert_eq!(err, "Parsing error: Expected identifier in global declaration, found Semicolon at 3:8");
+    }
+
+    /// Test that `const` and `register` are accepted and the variable stays readable.
+    #[test]
+    fn test_const_and_register_qualifiers() {
+        let source = "int main() { const int x = 5; register int y = x + 1; return x * y; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(30));
+    }
+
+    /// Test that a declaration initializer is stored into the new local.
+    #[test]
+    fn test_local_initializers() {
+        let source = "int main() { int a = 2, b, c = a * 3; b = 1; return a + b + c; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(9));
+    }
+
+    /// Test that a const variable can be initialized but not assigned afterwards.
+    #[test]
+    fn test_const_assignment_rejected() {
+        let ok = compile_detailed("int main() { const int x = 5; return x; }").unwrap();
+        assert_eq!(vm::execute_from(&ok.opcodes, ok.entry).unwrap(), Value::Int(5));
+
+        let err = compile_detailed("int main() { const int x = 5; x = 6; return x; }").unwrap_err();
+        assert_eq!(err.to_string(), "Parsing error: assignment of read-only variable 'x' at 1:33");
+    }
+
+    /// Test that double literals, promotion and conversions on store follow C.
+    #[test]
+    fn test_double_arithmetic_and_conversions() {
+        let source = "double main() { double d; int i; d = 1 / 2; i = 7.9; d = d + i / 2.0; return d; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(program.main_type, parser::Type::Double);
+        let result = vm::execute_from(&program.opcodes, program.entry).unwrap();
+        assert_eq!(result, Value::Float(3.5));
+        assert_eq!(format_result(result, &program.main_type), "3.5");
+
+        let truncated = compile_detailed("int main() { return 2.5 * 3; }").unwrap();
+        assert_eq!(vm::execute_from(&truncated.opcodes, truncated.entry).unwrap(), Value::Int(7));
+
+        let err = compile_detailed("int main() { return ~1.5; }").unwrap_err();
+        assert_eq!(err.to_string(), "Parsing error: wrong type argument to bit-complement at 1:21");
+    }
+
+    /// Test that loads and stores outside the frame allocated by `Ent` are rejected.
+    #[test]
+    fn test_non_local_slot_access_rejected() {
+        let read = vec![Opcode::Ent(1), Opcode::Ld(2), Opcode::Ret];
+        let err = vm::execute_traced(read).expect_err("Execution should fail");
+        assert_eq!(err, vm::RuntimeError { pc: 1, message: "read of non-local slot 2".to_string(), stack: None });
+
+        // Slot 0 is never a local, and without `Ent` there is no frame at all.
+        assert_eq!(execute(vec![Opcode::Ent(1), Opcode::Ld(0), Opcode::Ret]), Err("read of non-local slot 0".to_string()));
+        assert_eq!(execute(vec![Opcode::Ld(1), Opcode::Ret]), Err("read of non-local slot 1".to_string()));
+        let write = vec![Opcode::Ent(1), Opcode::Imm(5), Opcode::St(3), Opcode::Ret];
+        assert_eq!(execute(write), Err("write to non-local slot 3".to_string()));
+
+        let ok = vec![Opcode::Ent(1), Opcode::Imm(5), Opcode::St(1), Opcode::Pop, Opcode::Ld(1), Opcode::Ret];
+        assert_eq!(execute(ok), Ok(Value::Int(5)));
+    }
+
+    /// Test the conditional operator, including nesting and use inside a larger expression.
+    #[test]
+    fn test_ternary_operator() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run("int main() { return 1 ? 10 : 20; }"), Value::Int(10));
+        assert_eq!(run("int main() { return 0 ? 10 : 20; }"), Value::Int(20));
+        assert_eq!(run("int main() { int a; a = 2; return 1 + (a - 2 ? 10 : a ? 20 : 30); }"), Value::Int(21));
+        assert_eq!(run("double main() { return (1 ? 1 : 2.5) / 2; }"), Value::Float(0.5));
+        let err = compile_detailed("int main() { int a; 1 ? a : a = 3; return a; }").unwrap_err();
+        assert!(err.to_string().contains("lvalue required"), "unexpected error: {}", err);
+    }
+
+    /// Test filling and summing a two-dimensional array in row-major order.
+    #[test]
+    fn test_two_dimensional_array() {
+        let source = "int main() {
+            int m[3][4], i, j, sum;
+            i = 0;
+            while (3 - i) {
+                j = 0;
+                while (4 - j) { m[i][j] = i * 4 + j; j = j + 1; }
+                i = i + 1;
+            }
+            sum = 0; i = 0;
+            while (12 - i) { sum = sum + m[i / 4][i - i / 4 * 4]; i = i + 1; }
+            return sum * 100 + m[2][1];
+        }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(program.frame_size, 15);
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(6609));
+    }
+
+    /// Test global arrays, element assignment as an expression, and bounds errors.
+    #[test]
+    fn test_array_globals_and_bounds() {
+        let program = compile_detailed("int g, a[2][2], h; int main() { h = 5; a[1][0] = a[0][1] = 7; return a[1][0] + a[0][1] + h + g; }").unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(19));
+
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).map_err(|e| e.message)
+        };
+        assert_eq!(run("int main() { int a[2]; return a[2]; }"), Err("array index 2 out of bounds (length 2)".to_string()));
+        assert_eq!(run("int main() { int a[2]; a[0 - 1] = 3; return 0; }"), Err("array index -1 out of bounds (length 2)".to_string()));
+        assert_eq!(run("int m[2][3]; int main() { m[1][2] = 4; return m[1][2]; }"), Ok(Value::Int(4)));
+        assert_eq!(run("int main() { int m[2][3]; return m[0][3]; }"), Err("array index 3 out of bounds (length 3)".to_string()));
+        assert_eq!(run("int m[2][3]; int main() { return m[1][0 - 1]; }"), Err("array index -1 out of bounds (length 3)".to_string()));
+        assert_eq!(run("int main() { int m[2][3]; return m[2][0]; }"), Err("array index 2 out of bounds (length 2)".to_string()));
+
+        let err = compile_detailed("int main() { int m[2][2]; return m[1]; }").unwrap_err();
+        assert!(err.to_string().contains("must be indexed in all 2 dimensions"), "unexpected error: {}", err);
+    }
+
+    /// Test constant expressions as array sizes and rejection of bad sizes.
+    #[test]
+    fn test_constant_expression_array_sizes() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).map_err(|e| e.message)
+        };
+        assert_eq!(run("int main() { int a[2*3]; a[5] = 4; return a[5] + sizeof a / sizeof(int); }"), Ok(Value::Int(10)));
+        assert_eq!(run("int main() { int a[2*3]; return a[6]; }"), Err("array index 6 out of bounds (length 6)".to_string()));
+        assert_eq!(run("int g[1 < 2 ? 4 : 1][(7 + 1) % 3]; int main() { return sizeof g; }"), Ok(Value::Int(64)));
+        assert_eq!(run("int main() { char s[sizeof(int) - 1]; return sizeof s; }"), Ok(Value::Int(7)));
+
+        let err = |source: &str| compile_detailed(source).unwrap_err().to_string();
+        assert!(err("int main() { int a[2 - 3]; return 0; }").contains("Array size must be a positive integer constant, found -1 at 1:20"));
+        assert!(err("int main() { int a[]; return 0; }").contains("Array size must be a positive integer constant, found RBracket"));
+        assert!(err("int n; int main() { int a[n]; return 0; }").contains("expression is not a constant at 1:27"));
+        assert!(err("int main() { int a[1.5]; return 0; }").contains("constant expression has non-integer type 'double'"));
+        assert!(err("int main() { int a[1 / 0]; return 0; }").contains("division by zero at 1:22"));
+    }
+
+    /// Test that static locals keep their values across runs of their function
+    /// and are private to it.
+    #[test]
+    fn test_static_locals_persist_across_calls() {
+        let source = "int count;
+            int next() { static int count; count = count + 1; return count; }
+            int other() { static int count, seen[2]; count = count + 10; return count; }
+            int main() { return count; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(program.globals, 5);
+        let variables: Vec<(&str, i64)> = program.variables.iter().map(|sym| (sym.name.as_str(), sym.offset)).collect();
+        assert_eq!(variables, [("count", 0), ("next.count", 1), ("other.count", 2), ("other.seen", 3)]);
+        let locals: Vec<&str> = program.function("other").unwrap().locals.iter().map(|sym| sym.name.as_str()).collect();
+        assert_eq!(locals, ["count", "seen"]);
+        let mut globals = Vec::new();
+        let mut call = |name: &str| {
+            let entry = program.function(name).unwrap().entry;
+            vm::execute_with_globals(&program.opcodes, entry, &program.data, &mut globals, vm::Config::default()).unwrap()
+        };
+        assert_eq!(call("next"), Value::Int(1));
+        assert_eq!(call("next"), Value::Int(2));
+        assert_eq!(call("other"), Value::Int(10));
+        assert_eq!(call("next"), Value::Int(3));
+        assert_eq!(call("main"), Value::Int(0));
+
+        let err = compile_detailed("int f() { static int n = 1; return n; }").unwrap_err();
+        assert!(err.to_string().contains("Static local initializers are not supported at 1:24"), "unexpected error: {}", err);
+    }
+
+    /// Test allocating an array on the heap, writing and reading it, then freeing it.
+    #[test]
+    fn test_malloc_and_free() {
+        let source = "int main() {
+            int *a, i, sum;
+            a = malloc(5 * 8);
+            i = 0;
+            while (5 - i) { a[i] = i * i; i = i + 1; }
+            sum = *a + *(a + 4);
+            i = 1;
+            while (4 - i) { sum = sum + a[i]; i = i + 1; }
+            free(a);
+            return sum;
+        }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(30));
+    }
+
+    /// Test that heap misuse is reported instead of reading stale memory.
+    #[test]
+    fn test_heap_misuse_detected() {
+        let run = |body: &str| {
+            let program = compile_detailed(&format!("int main() {{ int *p; p = malloc(2); {} }}", body)).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).map_err(|e| e.message)
+        };
+        assert_eq!(run("free(p); return *p;"), Err(format!("use after free at address {}", vm::HEAP_BASE)));
+        assert_eq!(run("free(p); free(p); return 0;"), Err(format!("double free of address {}", vm::HEAP_BASE)));
+        assert_eq!(run("return p[2];"), Err(format!("invalid memory access at address {}", vm::HEAP_BASE + 2)));
+        assert_eq!(run("free(p); p = malloc(1); *p = 7; return *p;"), Ok(Value::Int(7)));
+
+        let err = compile_detailed("int main() { int x; x = free(malloc(1)); return x; }").unwrap_err();
+        assert!(err.to_string().contains("void value not ignored"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { int x; return *x; }").unwrap_err();
+        assert!(err.to_string().contains("invalid type argument of unary '*'"), "unexpected error: {}", err);
+    }
+
+    /// Test that string literals live in the data segment and can be walked through their address.
+    #[test]
+    fn test_string_literals_in_data_segment() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_with_heap(&program.opcodes, program.entry, &program.data, vm::DEFAULT_HEAP_CELLS)
+                .map_err(|e| e.message)
+        };
+        let walk = "int main() { char *s; int n; s = \"hello, \" \"world\"; n = 0; while (s[n]) n = n + 1; return n; }";
+        assert_eq!(run(walk), Ok(Value::Int(12)));
+        assert_eq!(run("int main() { return \"abc\"[1]; }"), Ok(Value::Int(98)));
+        assert_eq!(run("int main() { return \"abc\" - \"abc\" + (\"xy\" - \"abc\"); }"), Ok(Value::Int(4)));
+
+        let program = compile_detailed("int main() { char *a, *b; a = \"hi\"; b = \"hi\"; return 0; }").unwrap();
+        assert_eq!(program.data, vec![Value::Int(104), Value::Int(105), Value::Int(0)]);
+        assert_eq!(
+            run("int main() { char *s; s = \"hi\"; *s = 72; return 0; }"),
+            Err(format!("write to read-only data at address {}", vm::DATA_BASE))
+        );
+    }
+
+    /// Test that a string literal's address is pushed by `Addr` and that its
+    /// bytes take one data cell each, with the escape in `"hi\n"` decoded.
+    #[test]
+    fn test_string_literal_address_and_bytes() {
+        let program = compile_detailed("int main() { char *s; s = \"a\"; s = \"hi\\n\"; return s[2]; }").unwrap();
+        assert!(program.opcodes.contains(&Opcode::Addr(2)), "opcodes: {:?}", program.opcodes);
+        assert_eq!(program.data[2..], [104, 105, 10, 0].map(Value::Int));
+        let result = vm::execute_with_heap(&program.opcodes, program.entry, &program.data, vm::DEFAULT_HEAP_CELLS);
+        assert_eq!(result, Ok(Value::Int(10)));
+    }
+
+    /// Test a hand-written strlen that walks a char pointer to the terminator,
+    /// over a literal and over a copy built on the heap.
+    #[test]
+    fn test_strlen_by_pointer_iteration() {
+        let strlen = |text: &str| {
+            let source = format!(
+                "int main() {{
+                    char *s, *p, *copy, *q;
+                    s = \"{}\";
+                    p = s;
+                    while (*p) p = p + 1;
+                    copy = malloc(p - s + 1);
+                    q = copy;
+                    p = s;
+                    while (*q = *p) {{ p = p + 1; q = q + 1; }}
+                    p = copy;
+                    while (*p) p = p + 1;
+                    return p - copy;
+                }}",
+                text
+            );
+            let program = compile_detailed(&source).unwrap();
+            vm::execute_with_heap(&program.opcodes, program.entry, &program.data, vm::DEFAULT_HEAP_CELLS).unwrap()
+        };
+        assert_eq!(strlen("c4 in rust"), Value::Int(10));
+        assert_eq!(strlen("tab\\tnewline\\n"), Value::Int(12));
+        assert_eq!(strlen(""), Value::Int(0));
+    }
+
+    /// Test comparisons, including int/double promotion and pointer comparison.
+    #[test]
+    fn test_comparison_operators() {
+        let run = |expr: &str| {
+            let program = compile_detailed(&format!("int main() {{ int *p; p = malloc(2); return {}; }}", expr)).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run("(1 < 2) + (2 < 1) * 10 + (2 <= 2) * 100 + (3 >= 4) * 1000"), Value::Int(101));
+        assert_eq!(run("(1 == 1.0) + (2 != 2) * 10 + (1 > 0.5) * 100"), Value::Int(101));
+        assert_eq!(run("1 + 2 < 4 == 1"), Value::Int(1));
+        assert_eq!(run("(p < p + 1) + (p + 1 == p) * 10"), Value::Int(1));
+
+        let err = compile_detailed("int main() { int *p; return p < 1.5; }").unwrap_err();
+        assert!(err.to_string().contains("invalid operands to binary Lt"), "unexpected error: {}", err);
+    }
+
+    /// Test each comparison opcode on int pairs and double pairs, and `return 3 < 5;` end to end.
+    #[test]
+    fn test_comparison_opcodes() {
+        let ops = [Opcode::Eq, Opcode::Ne, Opcode::Lt, Opcode::Gt, Opcode::Le, Opcode::Ge];
+        // Results for (1, 2), (2, 2) and (2, 1), in the order of `ops`.
+        let expected = [[0, 1, 1, 0, 1, 0], [1, 0, 0, 0, 1, 1], [0, 1, 0, 1, 0, 1]];
+        for ((a, b), row) in [(1, 2), (2, 2), (2, 1)].into_iter().zip(expected) {
+            for (op, want) in ops.iter().zip(row) {
+                let ints = vec![Opcode::Imm(a), Opcode::Imm(b), op.clone(), Opcode::Ret];
+                assert_eq!(execute(ints), Ok(Value::Int(want)), "{} {:?} {}", a, op, b);
+                let floats = vec![Opcode::FImm(a as f64 / 4.0), Opcode::FImm(b as f64 / 4.0), op.clone(), Opcode::Ret];
+                assert_eq!(execute(floats), Ok(Value::Int(want)), "{} {:?} {} as doubles", a, op, b);
+            }
+        }
+        let eval = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(eval("int main() { return 3 < 5; }"), Value::Int(1));
+        assert_eq!(eval("int main() { return 5 == 4; }"), Value::Int(0));
+        assert_eq!(eval("int main() { int a, b; a = 2; b = 7; if (a < b) return 1; return 0; }"), Value::Int(1));
+    }
+
+    /// Test a loop controlled by a float comparison whose accumulator never
+    /// exactly reaches the bound.
+    #[test]
+    fn test_float_comparison_controls_loop() {
+        let source = "double main() { double f; int n; f = 0.0; n = 0; while (f < 1.0) { f = f + 0.1; n = n + 1; } return f * 100.0 + n; }";
+        let program = compile_detailed(source).unwrap();
+        let mut f = 0.0;
+        let mut n = 0;
+        while f < 1.0 {
+            f += 0.1;
+            n += 1;
+        }
+        // Ten additions of 0.1 fall just short of 1.0, so the loop runs an eleventh time.
+        assert_eq!(n, 11);
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Float(f * 100.0 + n as f64));
+    }
+
+    /// Test int counters against double bounds, including a counter that
+    /// lands exactly on the bound.
+    #[test]
+    fn test_int_counter_with_float_limit() {
+        let run = |condition: &str, step: &str| {
+            let source = format!("int main() {{ int i, n; i = 0; n = 0; while ({}) {{ i = i + {}; n = n + 1; }} return n * 100 + i; }}", condition, step);
+            let program = compile_detailed(&source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run("i < 10.0", "1"), Value::Int(1010));
+        assert_eq!(run("i <= 10.0", "1"), Value::Int(1111));
+        assert_eq!(run("i < 9.5", "1"), Value::Int(1010));
+        assert_eq!(run("10.0 > i", "2"), Value::Int(510));
+        assert_eq!(run("i != 10.0", "2"), Value::Int(510));
+        assert_eq!(run("i + 0.5 < 3", "1"), Value::Int(303));
+        assert_eq!(run("i * 1.0 == 0.0", "1"), Value::Int(101));
+        // A for loop whose int counter meets a double bound exactly.
+        let program = compile_detailed("int main() { int i, n; n = 0; for (i = 3; i > 0.0; i = i - 1) n = n + i; return n * 10 + i; }").unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(60));
+    }
+
+    /// Test that a sandboxed run stops an infinite loop by timeout or step limit.
+    #[test]
+    fn test_run_sandboxed_limits() {
+        use std::time::{Duration, Instant};
+        let limits = |timeout: Duration, steps: u64| SandboxLimits { timeout, steps, heap_cells: 16, ..SandboxLimits::default() };
+        let forever = "int main() { int n; n = 0; while (1) n = n + 1; return n; }";
+        let start = Instant::now();
+        let err = run_sandboxed(forever, limits(Duration::from_millis(50), u64::MAX)).unwrap_err();
+        assert_eq!(err, "Program timed out after 50 ms");
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        let limits = limits(Duration::from_secs(5), 1000);
+        let err = run_sandboxed(forever, limits).unwrap_err();
+        assert!(err.ends_with("step limit of 1000 exceeded"), "unexpected error: {}", err);
+
+        let heap = "int main() { int *p; p = malloc(100); return p == 0; }";
+        assert_eq!(run_sandboxed(heap, limits), Ok(Value::Int(1)));
+        assert_eq!(run_sandboxed("int main() { return 6 * 7; }", limits), Ok(Value::Int(42)));
+        assert!(run_sandboxed("int main() { return ; }", limits).unwrap_err().starts_with("Parsing error"));
+    }
+
+    /// Test that huge arrays, frames beyond the memory limit and deep recursion
+    /// are errors in the sandbox rather than allocations that abort the process.
+    #[test]
+    fn test_run_sandboxed_memory_limits() {
+        let limits = SandboxLimits { memory_cells: 1000, call_depth: 10, ..SandboxLimits::default() };
+        let err = run_sandboxed("int main() { int a[100000000000]; return 0; }", limits).unwrap_err();
+        assert!(err.contains(&format!("array is too large (100000000000 cells, at most {}) at 1:20", vm::STACK_CELLS)), "unexpected error: {}", err);
+        let err = run_sandboxed("int g[100000][100000]; int main() { return 0; }", limits).unwrap_err();
+        assert!(err.contains("array is too large (10000000000 cells"), "unexpected error: {}", err);
+
+        // Frames and globals share the memory limit.
+        assert_eq!(run_sandboxed("int main() { int a[990]; a[989] = 4; return a[989]; }", limits), Ok(Value::Int(4)));
+        let err = run_sandboxed("int main() { int a[5000]; return 0; }", limits).unwrap_err();
+        assert_eq!(err, "Runtime error at 1:10: stack overflow: frames need 5001 cells, more than the 1000 available");
+        let err = run_sandboxed("int g[600]; int main() { int a[500]; return g[0]; }", limits).unwrap_err();
+        assert!(err.ends_with("stack overflow: frames need 501 cells, more than the 400 available"), "unexpected error: {}", err);
+        let err = run_sandboxed("int g[2000]; int main() { return g[0]; }", limits).unwrap_err();
+        assert!(err.ends_with("out of memory: globals need 2000 cells, more than the limit of 1000"), "unexpected error: {}", err);
+
+        let recurse = "int f(int n) { return n ? f(n - 1) + 1 : 0; } int main() { return f(N); }";
+        assert_eq!(run_sandboxed(&recurse.replace('N', "8"), limits), Ok(Value::Int(8)));
+        let err = run_sandboxed(&recurse.replace('N', "50"), limits).unwrap_err();
+        assert!(err.ends_with("call stack overflow (more than 10 nested calls)"), "unexpected error: {}", err);
+    }
+
+    /// Test for loops with each clause left out, and with break and continue.
+    #[test]
+    fn test_for_loop_clauses() {
+        let run_main = |body: &str| {
+            let program = compile_detailed(&format!("int main() {{ int i, sum; sum = 0; {} return sum; }}", body)).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run_main("for (i = 1; i <= 5; i = i + 1) sum = sum + i;"), Value::Int(15));
+        assert_eq!(run_main("i = 1; for (; i <= 5; i = i + 1) sum = sum + i;"), Value::Int(15));
+        assert_eq!(run_main("for (i = 1; i <= 5;) { sum = sum + i; i = i + 1; }"), Value::Int(15));
+        assert_eq!(run_main("i = 1; for (;;) { if (i > 5) break; sum = sum + i; i = i + 1; }"), Value::Int(15));
+        // continue still runs the post clause.
+        assert_eq!(run_main("for (i = 0; i < 10; i = i + 1) { if (i % 2) continue; sum = sum + i; }"), Value::Int(20));
+    }
+
+    /// Test an assignment inside a for condition that drives the loop.
+    #[test]
+    fn test_for_condition_with_assignment() {
+        let source = "int main() { int i, j, sum; sum = 0; for (i = 0; (j = i * 2) < 10; i = i + 1) sum = sum + j; return sum * 100 + j * 10 + i; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(analysis::verify(&program), Ok(()));
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(2105));
+
+        let source = "int main() { int i; i = 3; for (;;) { if (i == 7) return i; i = i + 1; } }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(7));
+
+        let err = compile_detailed("int main() { int i; for (i = 0; i < 3; i = (i + 1) { } return i; }").unwrap_err();
+        assert!(err.to_string().contains("Expected ')' before end of input"), "unexpected error: {}", err);
+    }
+
+    /// Test a return three levels deep (for, if, while) leaving a helper at
+    /// once, with the helper called twice in a row from one program.
+    #[test]
+    fn test_return_from_nested_loops() {
+        let source = "int find(int target) {
+            int i, j;
+            for (i = 0; i < 10; i = i + 1) {
+                if (i > 2) {
+                    j = 0;
+                    while (1) {
+                        if (j == target) return i * 100 + j * 10 + target;
+                        j = j + 1;
+                    }
+                }
+            }
+            return 0 - 1;
+        }
+        int first, second;
+        int main() {
+            first = find(1);
+            second = find(2);
+            return first * 1000 + second;
+        }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(analysis::verify(&program), Ok(()));
+        let mut globals = Vec::new();
+        let result = vm::execute_with_globals(&program.opcodes, program.entry, &program.data, &mut globals, vm::Config::default());
+        assert_eq!(result.unwrap(), Value::Int(311322));
+        assert_eq!(globals, [Value::Int(311), Value::Int(322)]);
+    }
+
+    /// Test `-7 / 2` and `-7 % 2` under truncating (C) and floor division.
+    #[test]
+    fn test_division_rounding_policy() {
+        let run = |source: &str, division| {
+            let program = compile_detailed(source).unwrap();
+            let config = vm::Config { division, ..vm::Config::default() };
+            vm::execute_with_config(&program.opcodes, program.entry, &program.data, config).unwrap()
+        };
+        let source = "int main() { int a; a = 0 - 7; return (a / 2) * 100 + a % 2; }";
+        assert_eq!(run(source, vm::Division::Truncate), Value::Int(-3 * 100 - 1));
+        assert_eq!(run(source, vm::Division::Floor), Value::Int(-4 * 100 + 1));
+
+        // Exact quotients and operands of the same sign do not depend on the policy.
+        let source = "int main() { return (7 / 2) * 100 + (0 - 8) / (0 - 2) * 10 + 7 % 2; }";
+        assert_eq!(run(source, vm::Division::Floor), Value::Int(341));
+
+        let err = compile_detailed("int main() { return 7.0 % 2; }").unwrap_err();
+        assert!(err.to_string().contains("invalid operands to binary Mod"), "unexpected error: {}", err);
+        let args: Vec<String> = ["--division=floor", "prog.c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(parse_args(&args).unwrap().division, vm::Division::Floor);
+    }
+
+    /// Test `%`: integer remainder, a runtime error for a zero divisor, and no double operands.
+    #[test]
+    fn test_modulo_operator() {
+        let program = compile_detailed("int main() { return 17 % 5; }").unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(2));
+
+        let program = compile_detailed("int main() { int zero; zero = 0; return 10 % zero; }").unwrap();
+        let err = vm::execute_from(&program.opcodes, program.entry).unwrap_err();
+        assert_eq!(err.message, "Division by zero");
+        assert_eq!(program.spans[err.pc].to_string(), "1:44");
+        assert_eq!(execute(vec![Opcode::Imm(10), Opcode::Imm(0), Opcode::Mod, Opcode::Ret]), Err("Division by zero".to_string()));
+
+        // The VM refuses a double operand even if the parser's check is bypassed.
+        let floats = vec![Opcode::FImm(7.5), Opcode::Imm(2), Opcode::Mod, Opcode::Ret];
+        assert_eq!(execute(floats), Err("Modulo operand must be an integer".to_string()));
+    }
+
+    /// Test sizeof on scalar, pointer and array types and on unevaluated expressions.
+    #[test]
+    fn test_sizeof_types_and_expressions() {
+        let run = |expr: &str| {
+            let source = format!("int g[3][2]; int main() {{ int a[4], n; char c, *s; double d; n = 1; return {}; }}", expr);
+            let program = compile_detailed(&source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run("sizeof(int)"), Value::Int(8));
+        assert_eq!(run("sizeof(char)"), Value::Int(1));
+        assert_eq!(run("sizeof(unsigned char)"), Value::Int(1));
+        assert_eq!(run("sizeof(double)"), Value::Int(8));
+        assert_eq!(run("sizeof(int*)"), Value::Int(8));
+        assert_eq!(run("sizeof(char *)"), Value::Int(8));
+        assert_eq!(run("sizeof(int[4])"), Value::Int(32));
+        assert_eq!(run("sizeof(char[2][5])"), Value::Int(10));
+        assert_eq!(run("sizeof a"), Value::Int(32));
+        assert_eq!(run("sizeof(g)"), Value::Int(48));
+        assert_eq!(run("sizeof a[1] + sizeof c + sizeof s + sizeof *s"), Value::Int(8 + 1 + 8 + 1));
+        // The operand is not evaluated.
+        assert_eq!(run("sizeof(n = 5) * 10 + n"), Value::Int(81));
+
+        let err = compile_detailed("int main() { return sizeof(void); }").unwrap_err();
+        assert!(err.to_string().contains("invalid application of 'sizeof' to incomplete type 'void' at 1:21"), "unexpected error: {}", err);
+    }
+
+    /// Test that sizeof folds to the same constant in array sizes and in code.
+    #[test]
+    fn test_sizeof_folds_to_constant() {
+        let program = compile_detailed("int main() { char buf[sizeof(int) * 2]; return sizeof(int) * 2 == sizeof buf; }").unwrap();
+        let body = &program.opcodes[program.entry + 1..];
+        assert_eq!(body, [Opcode::Imm(16), Opcode::Imm(16), Opcode::Eq, Opcode::Ret]);
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(1));
+
+        // A `?:` arm ending just before the operator is a jump target and is not folded.
+        let program = compile_detailed("int main() { int c; c = 0; return (c ? 1 : 2) + 3; }").unwrap();
+        assert!(program.opcodes.contains(&Opcode::Add));
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(5));
+    }
+
+    /// Test a while loop left early by break, and one that skips even values with continue.
+    #[test]
+    fn test_while_with_break_and_continue() {
+        let source = "int main() { int i; i = 0; while (i < 10) { if (i == 3) break; i = i + 1; } return i; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(3));
+
+        let source = "int main() {
+            int i, odd;
+            i = 0; odd = 0;
+            while (i < 9) {
+                i = i + 1;
+                if (i % 2 == 0) continue;
+                odd = odd * 10 + i;
+            }
+            return odd;
+        }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(13579));
+    }
+
+    /// Test a do-while whose body breaks and continues on different iterations.
+    #[test]
+    fn test_do_while_with_break_and_continue() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        // i = 1, 2 are skipped by continue (which still tests the condition),
+        // 3 and 4 are added, and the loop breaks at 5 before adding it.
+        let source = "int main() {
+            int i, sum;
+            i = 0; sum = 0;
+            do {
+                i = i + 1;
+                if (i < 3) continue;
+                if (i == 5) break;
+                sum = sum + i * 10;
+            } while (i < 100);
+            return sum + i;
+        }";
+        assert_eq!(run(source), Value::Int(75));
+        // The body runs once even when the condition is false, and continue
+        // goes to that false test rather than back to the body.
+        assert_eq!(run("int main() { int n; n = 0; do { n = n + 1; continue; } while (0); return n; }"), Value::Int(1));
+        assert_eq!(run("int main() { int n; n = 0; do n = n + 2; while (n < 7); return n; }"), Value::Int(8));
+        assert_eq!(run("int main() { int n; n = 0; do { if (n > 3) break; n = n + 1; } while (1); return n; }"), Value::Int(4));
+
+        // while and for loops: continue runs the for's post clause.
+        assert_eq!(run("int main() { int i, n; n = 0; for (i = 0; i < 10; i = i + 1) { if (i % 2) continue; if (i > 6) break; n = n + i; } return n * 100 + i; }"), Value::Int(1208));
+        assert_eq!(run("int main() { int i; i = 0; while (1) { i = i + 1; if (i < 5) continue; break; } return i; }"), Value::Int(5));
+        assert_eq!(run("int main() { int i, j, n; n = 0; for (i = 0; i < 3; i = i + 1) for (j = 0; ; j = j + 1) { if (j == i) break; n = n + 1; } return n; }"), Value::Int(3));
+
+        let err = compile_detailed("int main() { if (1) break; return 0; }").unwrap_err();
+        assert!(err.to_string().contains("break statement not within loop or switch at 1:21"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() {\n  continue;\n}").unwrap_err();
+        assert!(err.to_string().contains("continue statement not within loop at 2:3"), "unexpected error: {}", err);
+    }
+
+    /// Test a switch whose `default` sits between cases: it runs only when no
+    /// case matches, and falls through into the case after it.
+    #[test]
+    fn test_switch_default_in_any_position() {
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        let classify = |x: i64| {
+            let source = format!(
+                "int main() {{
+                    int r;
+                    r = 0;
+                    switch ({} + 0) {{
+                    case 1: r = r + 1;
+                    case 2: r = r + 10; break;
+                    default: r = r + 100;
+                    case 3: r = r + 1000;
+                    case 4: r = r + 10000; break;
+                    case 5: r = r + 100000;
+                    }}
+                    return r;
+                }}",
+                x
+            );
+            run(&source)
+        };
+        assert_eq!(classify(1), Value::Int(11));
+        assert_eq!(classify(2), Value::Int(10));
+        assert_eq!(classify(3), Value::Int(11000));
+        assert_eq!(classify(4), Value::Int(10000));
+        assert_eq!(classify(5), Value::Int(100000));
+        assert_eq!(classify(9), Value::Int(11100));
+        assert_eq!(classify(-1), Value::Int(11100));
+
+        // default first, no default at all, and continue passing through to the loop.
+        assert_eq!(run("int main() { switch (2) { default: return 7; case 2: return 8; } }"), Value::Int(8));
+        assert_eq!(run("int main() { int r; r = 5; switch (r) { case 1: r = 0; } return r; }"), Value::Int(5));
+        let source = "int main() { int i, n; n = 0; for (i = 0; i < 6; i = i + 1) { switch (i % 3) { case 0: continue; case 1: n = n + 1; } n = n + 10; } return n; }";
+        assert_eq!(run(source), Value::Int(42));
+
+        let err = compile_detailed("int main() { switch (1) { default: break; default: break; } return 0; }").unwrap_err();
+        assert!(err.to_string().contains("multiple default labels in one switch at 1:43"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { switch (1) { case 2: case 1 + 1: break; } return 0; }").unwrap_err();
+        assert!(err.to_string().contains("duplicate case value 2 at 1:35"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { case 1: return 0; }").unwrap_err();
+        assert!(err.to_string().contains("case label not within a switch statement at 1:14"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { switch (1.5) { } return 0; }").unwrap_err();
+        assert!(err.to_string().contains("switch quantity not an integer at 1:14"), "unexpected error: {}", err);
+    }
+
+    /// Test that a `case` label inside a `while (0)` or `for (; 0;)` body still
+    /// reaches that body, which is otherwise dropped as never run.
+    #[test]
+    fn test_case_label_inside_constant_false_loop() {
+        let run = |source: String| {
+            let program = compile_detailed(&source).unwrap();
+            assert_eq!(analysis::verify(&program), Ok(()));
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        let inside_while = |x: i64| {
+            run(format!("int main() {{ switch ({} + 0) {{ case 0: return 9; while (0) {{ case 1: return 5; }} }} return 7; }}", x))
+        };
+        assert_eq!(inside_while(0), Value::Int(9));
+        assert_eq!(inside_while(1), Value::Int(5));
+        assert_eq!(inside_while(2), Value::Int(7));
+
+        // Entering the body through a label, the loop then tests its false
+        // condition and exits; break and continue leave it too.
+        let inside_for = |x: i64| {
+            run(format!(
+                "int main() {{ int n; n = 0; switch ({} + 0) {{ for (; 0; n = n + 1) {{ case 1: n = n + 10; continue; default: n = n + 100; break; }} n = n + 1000; }} return n; }}",
+                x
+            ))
+        };
+        assert_eq!(inside_for(1), Value::Int(1011));
+        assert_eq!(inside_for(2), Value::Int(1100));
+
+        // Without labels the body is still dropped.
+        let program = compile_detailed("int main() { int n; n = 1; while (0) n = 2; return n; }").unwrap();
+        assert!(!program.opcodes.contains(&Opcode::Imm(2)));
+    }
+
+    /// Test that frames filling the stack region exactly still work through
+    /// pointers, and that one cell more is a stack overflow rather than a
+    /// frame reaching into the heap.
+    #[test]
+    fn test_stack_region_boundary() {
+        let run = |source: String| {
+            let program = compile_detailed(&source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).map_err(|e| e.message)
+        };
+        // The frame is the array, x, p and the unused first cell.
+        let fill = |len: usize| format!("int main() {{ int a[{}]; int x; int *p; p = &x; *p = 5; return x; }}", len);
+        assert_eq!(run(fill(vm::STACK_CELLS - 3)), Ok(Value::Int(5)));
+        let overflow = format!("stack overflow: frames need {} cells, more than the {} available", vm::STACK_CELLS + 1, vm::STACK_CELLS);
+        assert_eq!(run(fill(vm::STACK_CELLS - 2)), Err(overflow));
+
+        let deep = "int f(int n) { int a[1000]; a[0] = n; return n ? f(n - 1) : a[0]; } int main() { return f(2000); }";
+        assert!(run(deep.to_string()).unwrap_err().starts_with("stack overflow"));
+    }
+
+    /// Test that globals filling their region work through pointers, that the
+    /// compiler rejects globals beyond it, and that an address past the data
+    /// segment is invalid for loads and stores alike.
+    #[test]
+    fn test_globals_region_limit() {
+        let last = vm::GLOBAL_CELLS - 1;
+        let fill = format!("int g[{}]; int main() {{ int *p; p = &g[{}]; *p = 3; return *p; }}", vm::GLOBAL_CELLS, last);
+        let program = compile_detailed(&fill).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(3));
+
+        let err = |source: String| compile_detailed(&source).unwrap_err().to_string();
+        let limit = vm::GLOBAL_CELLS;
+        let message = |name: &str, at: &str| format!("global storage for '{}' exceeds the limit of {} cells at {}", name, limit, at);
+        assert!(err(format!("int g[{}]; int h;", limit)).contains(&message("h", "1:20")));
+        assert!(err("int g[600000]; int main() { return 0; }".to_string()).contains(&message("g", "1:5")));
+        let source = format!("int g[{}]; int main() {{ static int s[2]; return 0; }}", limit - 1);
+        assert!(err(source).contains(&message("s", "1:40")));
+
+        // Even with more globals than the region holds, the cell after the
+        // region belongs to the data segment for loads and stores alike.
+        let past = vm::DATA_BASE + 1;
+        let mut globals = vec![Value::Int(0); vm::GLOBAL_CELLS + 10];
+        let store = [Opcode::Imm(past), Opcode::Imm(3), Opcode::Store, Opcode::Ret];
+        let load = [Opcode::Imm(past), Opcode::Load, Opcode::Ret];
+        let expected = format!("invalid memory access at address {}", past);
+        for opcodes in [&store[..], &load[..]] {
+            let result = vm::execute_with_globals(opcodes, 0, &[], &mut globals, vm::Config::default());
+            assert_eq!(result.unwrap_err().message, expected);
+        }
+    }
+
+    /// Runs main of both programs once per input, each run starting from the
+    /// input's values in the leading global slots, and asserts that they
+    /// return the same result and leave the globals in the same state.
+    fn assert_equivalent(src_a: &str, src_b: &str, inputs: &[&[i64]]) {
+        let a = compile_detailed(src_a).expect("Failed to compile the first program");
+        let b = compile_detailed(src_b).expect("Failed to compile the second program");
+        for input in inputs {
+            let run = |program: &parser::Program| {
+                let mut globals: Vec<Value> = input.iter().map(|&n| Value::Int(n)).collect();
+                let config = vm::Config { max_steps: Some(1_000_000), ..vm::Config::default() };
+                let result = vm::execute_with_globals(&program.opcodes, program.entry, &program.data, &mut globals, config);
+                (result.map_err(|e| e.message), globals)
+            };
+            assert_eq!(run(&a), run(&b), "programs differ on input {:?}", input);
+        }
+    }
+
+    /// Test that a hand-optimized loop is equivalent to the straightforward one.
+    #[test]
+    fn test_optimized_program_is_equivalent() {
+        let plain = "int n, last; int main() {
+            int i, sum;
+            sum = 0; i = 0;
+            while (i < n) { last = i * (1 + 1) * 2; sum = sum + last; i = i + 1; }
+            return sum;
+        }";
+        let optimized = "int n, last; int main() {
+            int i, sum;
+            for (i = sum = 0; i < n; i = i + 1) sum = sum + (last = 4 * i);
+            return sum;
+        }";
+        assert_equivalent(plain, optimized, &[&[0, 0], &[1, 7], &[10, 0], &[-3, 5]]);
+    }
+
+    /// Test macros and a block compiled in only when a macro is predefined.
+    #[test]
+    fn test_conditional_compilation() {
+        let source = "#define LIMIT 3
+            int main() {
+                int a[LIMIT], n;
+                n = sizeof a / sizeof(int);
+            #ifdef DEBUG
+                n = n * 100;
+            #endif
+                return n;
+            }";
+        let run = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        assert_eq!(run(source), Value::Int(3));
+        let debug = preprocessor::preprocess(source, &[("DEBUG".to_string(), String::new())]).unwrap();
+        assert_eq!(run(&debug), Value::Int(300));
+
+        let err = compile_detailed("int main() { return 0; }\n#endif").unwrap_err();
+        assert_eq!(err.to_string(), "Preprocessing error: #endif without #ifdef at 2:1");
+        // Blanked directive lines keep later line numbers intact.
+        let err = compile_detailed("#define ONE 1\n\nint main() { int x; x = ONE; return x / 0; }").unwrap_err();
+        assert!(err.to_string().contains("division by zero at 3:37"), "unexpected error: {}", err);
+    }
+
+    /// Test that an exhausted heap makes malloc return null instead of failing.
+    #[test]
+    fn test_malloc_out_of_memory() {
+        let source = "int main() { int *p, n; n = 0; p = malloc(3); while (p) { n = n + 1; p = malloc(3); } return n; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_with_heap(&program.opcodes, program.entry, &[], 10).unwrap(), Value::Int(3));
+        assert_eq!(vm::execute_with_heap(&program.opcodes, program.entry, &[], 0).unwrap(), Value::Int(0));
+
+        // Freed blocks are still reused once the heap is full.
+        let source = "int main() { int *p, *q; p = malloc(4); q = malloc(4); free(p); p = malloc(4); return p - q; }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_with_heap(&program.opcodes, program.entry, &[], 8).unwrap(), Value::Int(-4));
+
+        let args: Vec<String> = ["--heap", "64", "prog.c"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(parse_args(&args).unwrap().heap, 64);
+        assert_eq!(parse_args(&["--heap".to_string(), "lots".to_string()]).unwrap_err(), "Invalid heap size: lots");
+        assert!(parse_args(&["--heap".to_string()]).is_err());
+    }
+
+    /// Test `Load`/`Store` through computed addresses in the globals area and the heap.
+    #[test]
+    fn test_flat_memory_load_store() {
+        let base = vm::GLOBAL_BASE;
+        let opcodes = vec![
+            Opcode::AddrG(0, 3),
+            Opcode::Imm(2),
+            Opcode::Add,
+            Opcode::Imm(40),
+            Opcode::Store,       // g[2] = 40
+            Opcode::Pop,
+            Opcode::Imm(base + 2),
+            Opcode::Load,        // *(GLOBAL_BASE + 2)
+            Opcode::Imm(1),
+            Opcode::Malloc,
+            Opcode::Imm(2),
+            Opcode::Store,       // *malloc(1) = 2
+            Opcode::Add,
+            Opcode::Ret,
+        ];
+        assert_eq!(execute(opcodes), Ok(Value::Int(42)));
+
+        let stray = vec![Opcode::AddrG(0, 1), Opcode::Imm(1), Opcode::Add, Opcode::Load, Opcode::Ret];
+        assert_eq!(execute(stray), Err(format!("invalid memory access at address {}", base + 1)));
+        assert_eq!(execute(vec![Opcode::Imm(0), Opcode::Load, Opcode::Ret]), Err("invalid memory access at address 0".to_string()));
+    }
+
+    /// Test taking the address of globals and writing through the resulting pointers.
+    #[test]
+    fn test_address_of_globals() {
+        let source = "int g, a[4]; int main() { int *p, *q; p = &g; q = &a[1]; *p = 5; q[2] = 7; *&a[0] = 1; return g + a[3] + a[0] + (q - &a[0]); }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(14));
+
+        let err = compile_detailed("int main() { return &1; }").unwrap_err();
+        assert!(err.to_string().contains("lvalue required as unary '&' operand"), "unexpected error: {}", err);
+    }
+
+    /// Test taking the address of locals: writes through the pointer are seen by the local.
+    #[test]
+    fn test_address_of_locals() {
+        let source = "int main() { int x, a[3], *p; x = 1; p = &x; *p = 41; *(&x) = *(&x) + 1; p = &a[1]; p[1] = x; return a[2] + (p - &a[0]); }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(43));
+
+        let opcodes = vec![Opcode::Ent(1), Opcode::Lea(1), Opcode::Imm(9), Opcode::Store, Opcode::Pop, Opcode::Ld(1), Opcode::Ret];
+        assert_eq!(execute(opcodes), Ok(Value::Int(9)));
+        let past_frame = vec![Opcode::Ent(1), Opcode::Lea(2), Opcode::Load, Opcode::Ret];
+        assert_eq!(execute(past_frame), Err(format!("invalid memory access at address {}", vm::STACK_BASE + 2)));
+    }
+
+    /// Test that `print` writes each value with its type and yields the value.
+    #[test]
+    fn test_print_builtin() {
+        let source = "int main() { double d; char c; d = print(3.5); c = 65; print(c); return print(2+2) * 10; }";
+        let program = compile_detailed(source).unwrap();
+        let mut out = Vec::new();
+        let result = vm::execute_with_output(&program.opcodes, program.entry, &program.data, &[], vm::Config::default(), &mut out);
+        assert_eq!(result.unwrap(), Value::Int(40));
+        assert_eq!(String::from_utf8(out).unwrap(), "3.5 : double\n'A' (65) : char\n4 : int\n");
+
+        let err = compile_detailed("int main() { print(2) = 3; return 0; }").unwrap_err();
+        assert!(err.to_string().contains("lvalue"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { print(free(malloc(1))); return 0; }").unwrap_err();
+        assert!(err.to_string().contains("print expects a value, found 'void'"), "unexpected error: {}", err);
+    }
+
+    /// Test that `--script` runs top-level statements in order and prints the last expression.
+    #[test]
+    fn test_script_mode_returns_last_expression() {
+        let path = std::env::temp_dir().join("c4_script.c");
+        let source = "int n = 5, total = 0;\nint twice() { return 2; }\nwhile (n > 0) { total = total + n; n = n - 1; }\ntotal * 1.5\n";
+        std::fs::write(&path, source).expect("Failed to write source");
+        let args = vec!["--script".to_string(), "--print-result".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let code = run(&args, &mut "".as_bytes(), &mut out, &mut err).expect("I/O failed");
+        assert_eq!(code, 0, "stderr: {}", String::from_utf8_lossy(&err));
+        assert_eq!(String::from_utf8(out).unwrap(), "Program executed successfully. Result: 22.5\n");
+
+        let program = compile_script("int x; x = 3; x + 4;").unwrap();
+        assert_eq!(program.main_type, Type::Int);
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(7));
+        let program = compile_script("int x; x = 3; if (x) x = 4;").unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(0));
+        let err = compile_script("1 + 2 3").unwrap_err();
+        assert!(err.to_string().contains("Expected Semicolon"), "unexpected error: {}", err);
+    }
+
+    /// Test nested `&&` and `||`: each yields 0 or 1 and skips operands whose side effects must not happen.
+    #[test]
+    fn test_nested_logical_operators() {
+        let eval = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        for (a, b, c) in [(0, 0, 0), (0, 2, 3), (5, 0, 0), (5, 0, 7), (5, 2, 0), (1, 1, 1)] {
+            let (p, q, r) = (a != 0, b != 0, c != 0);
+            let expected = [p && (q || r), (p || q) && r, p && q || r, p || q && r, (p && q) as i64 == c]
+                .iter()
+                .fold(0, |acc, &bit| acc * 10 + bit as i64);
+            let source = format!(
+                "int main() {{ int a, b, c; a = {}; b = {}; c = {}; \
+                 return (a && (b || c)) * 10000 + ((a || b) && c) * 1000 + (a && b || c) * 100 + (a || b && c) * 10 + ((a && b) == c); }}",
+                a, b, c
+            );
+            assert_eq!(eval(&source), Value::Int(expected), "a={} b={} c={}", a, b, c);
+        }
+
+        // Skipped operands leave x untouched; evaluated ones assign it.
+        let source = "int x; int main() { int r; x = 7; r = 0 && (x = 1); r = r + (1 || (x = 2)); \
+                      r = r + ((0 || 0) && (x = 3)); r = r + (1 && (0 || (x = 4))); return x * 10 + r; }";
+        assert_eq!(eval(source), Value::Int(42));
+        let source = "int x; int main() { x = 0; if (x && (x = 9)) x = 5; while (x < 3 || (x = 100) == 0) x = x + 1; return x; }";
+        assert_eq!(eval(source), Value::Int(100));
+        assert_eq!(eval("int main() { return 2.5 && 0.5 || 0; }"), Value::Int(1));
+    }
+
+    /// Test that `||` and `&&` skip an operand that would fail once the result is known,
+    /// and that their results are normalized to 0 or 1.
+    #[test]
+    fn test_logical_operators_short_circuit() {
+        let eval = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).map_err(|e| e.message)
+        };
+        assert_eq!(eval("int main() { int z; z = 0; return 7 || (1/z); }"), Ok(Value::Int(1)));
+        assert_eq!(eval("int main() { int z; z = 0; return z && (1/z); }"), Ok(Value::Int(0)));
+        assert_eq!(eval("int main() { int z; z = 0; return 0 || (1/z); }"), Err("Division by zero".to_string()));
+        assert_eq!(eval("int main() { int z; z = 0; return 1 && (1/z); }"), Err("Division by zero".to_string()));
+        assert_eq!(eval("int main() { return (5 || 0) + (0 || -3) + (4 && 9); }"), Ok(Value::Int(3)));
+    }
+
+    /// Test that a custom `ResultWriter` receives the result instead of the default message.
+    #[test]
+    fn test_custom_result_writer() {
+        struct Recorder(Vec<(Value, Type)>);
+        impl ResultWriter for Recorder {
+            fn write_result(&mut self, out: &mut dyn std::io::Write, result: Value, ty: &Type) -> std::io::Result<()> {
+                self.0.push((result, ty.clone()));
+                writeln!(out, "{} => {}", ty, result)
+            }
+        }
+        let path = std::env::temp_dir().join("c4_result_writer.c");
+        std::fs::write(&path, "double main() { return 7 / 2.0; }").expect("Failed to write source");
+        let args = vec!["--print-result".to_string(), path.display().to_string()];
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let mut recorder = Recorder(Vec::new());
+        let code = run_with(&args, &mut "".as_bytes(), &mut out, &mut err, &mut recorder).expect("I/O failed");
+        assert_eq!(code, 0);
+        assert_eq!(recorder.0, vec![(Value::Float(3.5), Type::Double)]);
+        assert_eq!(String::from_utf8(out).unwrap(), "double => 3.5\n");
+    }
+
+    /// Test a `for` loop stepping its counter with `+=`, and compound assignment to other lvalues.
+    #[test]
+    fn test_for_post_compound_assignment() {
+        let eval = |source: &str| {
+            let program = compile_detailed(source).unwrap();
+            vm::execute_from(&program.opcodes, program.entry).unwrap()
+        };
+        // Visits 0, 2, 4, 6, 8 and leaves i at 10.
+        let source = "int main() { int i, r, n; r = 0; n = 0; for (i = 0; i < 10; i += 2) { r = r * 10 + i; n += 1; } return (r * 100 + i) * 10 + n; }";
+        assert_eq!(eval(source), Value::Int(2468105));
+        let source = "int main() { int i, r; r = 0; for (i = 20; i > 0; i -= 3, r += 1) {} return r * 100 + i; }";
+        assert_eq!(eval(source), Value::Int(699));
+
+        let source = "int g[3]; int main() { int a[2], *p, x; double d; char c; \
+                      a[1] = 5; a[1] *= 3; g[2] = 7; g[1 + 1] %= 4; p = &a[0]; *p = 1; *p += 40; p += 1; *p -= 1; \
+                      d = 1; d /= 4; c = 120; c += 10; x = 2; x *= 2 + 1; \
+                      return a[0] + a[1] + g[2] + (d == 0.25) + (c < 0) + x * 1000; }";
+        assert_eq!(eval(source), Value::Int(41 + 14 + 3 + 1 + 1 + 6000));
+
+        let err = compile_detailed("int main() { int *p; p *= 2; return 0; }").unwrap_err();
+        assert!(err.to_string().contains("invalid operands to binary Mul ('int*' and 'int')"), "unexpected error: {}", err);
+        let err = compile_detailed("int main() { 3 += 1; return 0; }").unwrap_err();
+        assert!(err.to_string().contains("lvalue required as left operand of assignment"), "unexpected error: {}", err);
+    }
+
+    /// Test that `->` is rejected with a clear error unless its operand is a pointer to a struct.
+    #[test]
+    fn test_arrow_requires_pointer_to_struct() {
+        let err = compile_detailed("int main() { int x, *p; p = &x; return p->x; }").unwrap_err();
+        assert_eq!(err.to_string(), "Parsing error: invalid type argument of '->' (have 'int*') at 1:41");
+        let err = compile_detailed("int main() { int n; n = 1; n->y = 2; return 0; }").unwrap_err();
+        assert!(err.to_string().contains("invalid type argument of '->' (have 'int')"), "unexpected error: {}", err);
+        // Written with spaces, the tokens are still minus and greater-than.
+        assert_eq!(tokenize("a - > b").unwrap()[1..3], [lexer::Token::Minus, lexer::Token::Gt]);
+    }
+
+    /// Test struct members through `.` and `->`: a struct local whose address
+    /// is taken, a global, a struct from `malloc`, and a nested member.
+    #[test]
+    fn test_struct_members() {
+        let source = "
+struct point { int x; int y; };
+struct node { int value; struct node *next; struct point at; };
+struct point origin;
+int main() {
+    struct point p, *q;
+    struct node *n;
+    p.x = 3;
+    q = &p;
+    q->y = q->x * 10;
+    q->x += 1;
+    n = malloc(sizeof(struct node));
+    n->next = n;
+    n->next->value = 5;
+    n->at.y = 6;
+    (&origin)->x = 100;
+    return p.x + p.y + n->value + n->at.y + (*q).x + origin.x;
+}";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(analysis::verify(&program), Ok(()));
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(4 + 30 + 5 + 6 + 4 + 100));
+        let sizes = "struct node { int value; struct node *next; char tag; }; int main() { struct node s; return sizeof(struct node) * 100 + sizeof s; }";
+        let program = compile_detailed(sizes).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(1717));
+    }
+
+    /// Test that struct values are rejected where a scalar is needed, and
+    /// that unknown members and incomplete structs are reported.
+    #[test]
+    fn test_struct_errors() {
+        let cases = [
+            ("struct p { int x; }; int main() { struct p s; return s + 1; }", "used 'struct p' value where a scalar is required at 1:56"),
+            ("struct p { int x; }; int main() { struct p s; if (s) return 1; return 0; }", "used 'struct p' value where a scalar is required at 1:51"),
+            ("struct p { int x; }; int main() { struct p s; return s.y; }", "'struct p' has no member named 'y' at 1:56"),
+            ("struct p { int x; }; int main() { struct p s, t; s = t; return 0; }", "lvalue required as left operand of assignment at 1:52"),
+            ("struct p { int x; }; int main() { struct p s, *q; q = &s; q = q + 1; return 0; }", "arithmetic on 'struct p*' and 'int' is not supported at 1:65"),
+            ("int main() { struct q s; return 0; }", "storage size of 's' isn't known (incomplete type 'struct q') at 1:23"),
+            ("struct q; int main() { struct q *p; p = 0; return p->x; }", "invalid use of incomplete type 'struct q' at 1:52"),
+            ("struct p { int x; }; struct p { int y; }; int main() { return 0; }", "redefinition of 'struct p' at 1:29"),
+            ("int main() { int x; return x.y; }", "request for a member in 'int', which is not a struct at 1:29"),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(compile_detailed(source).unwrap_err().to_string(), format!("Parsing error: {}", expected), "{}", source);
+        }
+    }
+
+    /// Test that a function-like macro call expands and evaluates.
+    #[test]
+    fn test_function_like_macro_evaluates() {
+        let source = "#define MAX(a, b) ((a) > (b) ? (a) : (b))\nint main() { int x; x = 4; return MAX(3, 5) * 10 + MAX(x, 1 + 1); }";
+        let program = compile_detailed(source).unwrap();
+        assert_eq!(vm::execute_from(&program.opcodes, program.entry).unwrap(), Value::Int(54));
+    }
+
+    /// Test that printing a program's syntax tree with `to_c` and compiling
+    /// the result gives the same tree, apart from source ranges, and the same
+    /// opcodes as the original.
+    #[test]
+    fn test_to_c_round_trip() {
+        let source = "
+            int total;   // running sum
+            struct pair { int first; char *name; struct pair *next; };
+            int add(int a, int b) { return (a + b); }
+            int main(int argc, char **argv) {
+                static int calls; const int limit = (10);
+                int i, grid[2][3]; char *s; double d; struct pair p, *q;
+                s = \"hi\\n\"; d = 2.5 * (1 + 2); calls += 1; q = &p; q->first = (sizeof(int *[2]) - (3 - 1));
+                p.next = q; grid[1][2] = ((q->next)->first);
+                for (i = 0; (i < 4); i = i + 1) { if (i % 2) total = add(total, i); else if (!i) continue; else total -= 1; }
+                for (;;) break;
+                while (i > 0) i = (i - 1), total += 2;
+                do { i = i + 1; } while (i < 2);
+                switch (total) { case 2: { break; } default: total = ((total > limit) ? limit : total); }
+                return total ? -(-total) : (!argc + (d > 1.0)) * grid[1][2];
+            }";
+        let original = compile_detailed(source).unwrap();
+        let printed = ast::to_c(&original.ast);
+        let reparsed = compile_detailed(&printed).unwrap();
+        assert_eq!(reparsed.ast.without_ranges(), original.ast.without_ranges());
+        assert_eq!(ast::to_c(&reparsed.ast), printed);
+        assert_eq!(reparsed.opcodes, original.opcodes);
+        assert_eq!(reparsed.data, original.data);
+    }
+
+    /// Test that coverage marks the taken branch of an `if` and not the untaken `else`.
+    #[test]
+    fn test_coverage_skips_untaken_else() {
+        let program = compile_detailed("int main() { int x; x = 1; if (x) x = 20; else x = 30; return x; }").unwrap();
+        let position = |op: Opcode| program.opcodes.iter().position(|o| *o == op).unwrap();
+        let config = vm::Config { coverage: true, ..vm::Config::default() };
+        let (result, visited, _) = vm::execute_debug(&program.opcodes, program.entry, &program.data, config);
+        assert_eq!(result, Ok(Value::Int(20)));
+        let visited = visited.expect("coverage was requested");
+        assert_eq!(visited.len(), program.opcodes.len());
+        assert!(visited[position(Opcode::Imm(20))]);
+        assert!(!visited[position(Opcode::Imm(30))]);
+        assert!(visited[program.entry] && visited[program.opcodes.len() - 1]);
+
+        // Coverage is off by default, and kept for a failed run.
+        let (_, visited, _) = vm::execute_debug(&program.opcodes, program.entry, &program.data, vm::Config::default());
+        assert_eq!(visited, None);
+        let opcodes = [Opcode::Imm(1), Opcode::Imm(0), Opcode::Div, Opcode::Ret];
+        let (result, visited, _) = vm::execute_debug(&opcodes, 0, &[], config);
+        assert_eq!(result.unwrap_err().pc, 2);
+        assert_eq!(visited, Some(vec![true, true, true, false]));
+    }
+
+    /// Test that profiling counts each kind of opcode once per execution, so a
+    /// loop's body and back edge are counted once per iteration.
+    #[test]
+    fn test_profile_counts_loop_opcodes() {
+        let source = "int main() { int i, s; i = 0; s = 0; while (i < 10) { s = s + i; i = i + 1; } return s; }";
+        let program = compile_detailed(source).unwrap();
+        let config = vm::Config { profile: true, ..vm::Config::default() };
+        let (result, visited, profile) = vm::execute_debug(&program.opcodes, program.entry, &program.data, config);
+        assert_eq!(result, Ok(Value::Int(45)));
+        assert_eq!(visited, None);
+        let profile = profile.expect("profiling was requested");
+        assert_eq!(profile.count(&Opcode::Add), 20);
+        assert_eq!(profile.count(&Opcode::Jmp(0)), 10);
+        assert_eq!(profile.count(&Opcode::Jz(0)), 11);
+        assert_eq!(profile.count(&Opcode::Div), 0);
+        assert!(profile.total() > 40);
+
+        // Profiling is off by default.
+        let (_, _, profile) = vm::execute_debug(&program.opcodes, program.entry, &program.data, vm::Config::default());
+        assert_eq!(profile, None);
+    }
+}